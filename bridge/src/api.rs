@@ -1,13 +1,19 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::iter;
+use std::sync::Arc;
 use std::time::Duration;
 use serde::de::DeserializeOwned;
 use serde_json::Value;
-use futures::{Future, Stream, Poll};
+use futures::{future, Future, Stream, Poll, Async};
 use tokio_timer::{Timer, Interval, Timeout};
-use web3::{self, api, Transport};
+use web3::{self, api, Transport, DuplexTransport};
 use web3::api::Namespace;
 use web3::types::{Log, Filter, H256, U256, FilterBuilder, Bytes, Address, CallRequest, BlockNumber};
 use web3::helpers::{self, CallResult};
 use error::{Error, ErrorKind};
+use config::Finality;
+use rpc;
 
 /// Imperative alias for web3 function.
 pub use web3::confirm::send_raw_transaction_with_confirmation;
@@ -102,6 +108,34 @@ pub fn block_number<T: Transport>(transport: T) -> ApiCall<U256, T::Out> {
 	}
 }
 
+/// Number and hash of a block.
+/// The only parts of `eth_getBlockByNumber` response used by the bridge.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct BlockRef {
+	pub number: Option<U256>,
+	pub hash: Option<H256>,
+}
+
+/// Imperative wrapper for web3 function.
+pub fn block_by_number<T: Transport>(transport: T, number: u64) -> ApiCall<Option<BlockRef>, T::Out> {
+	let block = helpers::serialize(&BlockNumber::Number(number));
+	let include_txs = helpers::serialize(&false);
+	ApiCall {
+		future: CallResult::new(transport.execute("eth_getBlockByNumber", vec![block, include_txs])),
+		message: "eth_getBlockByNumber",
+	}
+}
+
+/// Fetches block by tag, e.g. `finalized` or `safe`, which are not supported by `BlockNumber`.
+pub fn block_by_tag<T: Transport>(transport: T, tag: &str) -> ApiCall<Option<BlockRef>, T::Out> {
+	let block = Value::String(tag.into());
+	let include_txs = helpers::serialize(&false);
+	ApiCall {
+		future: CallResult::new(transport.execute("eth_getBlockByNumber", vec![block, include_txs])),
+		message: "eth_getBlockByNumber",
+	}
+}
+
 /// Imperative wrapper for web3 function.
 pub fn balance<T: Transport>(transport: T, address: Address, block: Option<BlockNumber>) -> ApiCall<U256, T::Out> {
 	// we are not using Eth.balance() because it converts None block into `latest`
@@ -115,6 +149,91 @@ pub fn balance<T: Transport>(transport: T, address: Address, block: Option<Block
 	}
 }
 
+/// Imperative wrapper for web3 function.
+pub fn storage<T: Transport>(transport: T, address: Address, key: H256) -> ApiCall<H256, T::Out> {
+	// read against the pending state, the same as simulated transactions
+	let address = helpers::serialize(&address);
+	let key = helpers::serialize(&key);
+	let block = helpers::serialize(&BlockNumber::Pending);
+	ApiCall {
+		future: CallResult::new(transport.execute("eth_getStorageAt", vec![address, key, block])),
+		message: "eth_getStorageAt",
+	}
+}
+
+/// Block and outcome of a mined transaction.
+/// The only parts of `eth_getTransactionReceipt` response used by the bridge.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ReceiptStatus {
+	#[serde(rename = "blockNumber")]
+	pub block_number: Option<U256>,
+	/// `1` if the transaction succeeded, `0` if it reverted. Missing on chains before Byzantium.
+	pub status: Option<U256>,
+}
+
+impl ReceiptStatus {
+	pub fn is_reverted(&self) -> bool {
+		self.status.map_or(false, |status| status.is_zero())
+	}
+}
+
+/// Fetches the receipt of a transaction, `None` until it's mined.
+pub fn transaction_receipt<T: Transport>(transport: T, hash: H256) -> ApiCall<Option<ReceiptStatus>, T::Out> {
+	let hash = helpers::serialize(&hash);
+	ApiCall {
+		future: CallResult::new(transport.execute("eth_getTransactionReceipt", vec![hash])),
+		message: "eth_getTransactionReceipt",
+	}
+}
+
+/// Base fees and priority fees of recent blocks.
+/// The only parts of `eth_feeHistory` response used by the bridge.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct FeeHistory {
+	/// Base fees of the blocks, followed by the base fee of the next block.
+	#[serde(rename = "baseFeePerGas")]
+	pub base_fee_per_gas: Vec<U256>,
+	/// Priority fees paid in the blocks at the requested percentile.
+	#[serde(default)]
+	pub reward: Vec<Vec<U256>>,
+}
+
+impl FeeHistory {
+	/// Base fee of the next block.
+	pub fn next_base_fee(&self) -> Option<U256> {
+		self.base_fee_per_gas.last().cloned()
+	}
+
+	/// Median of the priority fees paid in the blocks, `None` if the blocks are empty.
+	pub fn priority_fee(&self) -> Option<U256> {
+		let mut fees = self.reward.iter()
+			.filter_map(|reward| reward.first().cloned())
+			.filter(|fee| !fee.is_zero())
+			.collect::<Vec<_>>();
+		fees.sort();
+		fees.get(fees.len() / 2).cloned()
+	}
+}
+
+/// Fetches the fees of `blocks` latest blocks, with the priority fees paid at `percentile`.
+pub fn fee_history<T: Transport>(transport: T, blocks: u64, percentile: f64) -> ApiCall<FeeHistory, T::Out> {
+	let blocks = helpers::serialize(&U256::from(blocks));
+	let newest = helpers::serialize(&BlockNumber::Latest);
+	let percentiles = helpers::serialize(&vec![percentile]);
+	ApiCall {
+		future: CallResult::new(transport.execute("eth_feeHistory", vec![blocks, newest, percentiles])),
+		message: "eth_feeHistory",
+	}
+}
+
+/// Imperative wrapper for web3 function.
+pub fn code<T: Transport>(transport: T, address: Address) -> ApiCall<Bytes, T::Out> {
+	ApiCall {
+		future: api::Eth::new(transport).code(address, None),
+		message: "eth_getCode",
+	}
+}
+
 /// Imperative wrapper for web3 function.
 pub fn send_raw_transaction<T: Transport>(transport: T, tx: Bytes) -> ApiCall<H256, T::Out> {
 	ApiCall {
@@ -142,6 +261,40 @@ pub fn call<T: Transport>(transport: T, address: Address, payload: Bytes) -> Api
 	}
 }
 
+/// Executes a transaction sending `payload` from `from` to `address` against the pending state, without sending it.
+pub fn simulate_transaction<T: Transport>(transport: T, from: Address, address: Address, gas: U256, gas_price: U256, payload: Bytes) -> ApiCall<Bytes, T::Out> {
+	let future = api::Eth::new(transport).call(CallRequest {
+		from: Some(from),
+		to: address,
+		gas: Some(gas),
+		gas_price: Some(gas_price),
+		value: None,
+		data: Some(payload),
+	}, Some(BlockNumber::Pending));
+
+	ApiCall {
+		future,
+		message: "eth_call",
+	}
+}
+
+/// Estimates the gas used by a transaction sending `payload` from `from` to `address`.
+pub fn estimate_gas<T: Transport>(transport: T, from: Address, address: Address, payload: Bytes) -> ApiCall<U256, T::Out> {
+	let future = api::Eth::new(transport).estimate_gas(CallRequest {
+		from: Some(from),
+		to: address,
+		gas: None,
+		gas_price: None,
+		value: None,
+		data: Some(payload),
+	}, None);
+
+	ApiCall {
+		future,
+		message: "eth_estimateGas",
+	}
+}
+
 /// Returns a eth_sign-compatible hash of data to sign.
 /// The data is prepended with special message to prevent
 /// chosen-plaintext attacks.
@@ -154,6 +307,31 @@ pub fn eth_data_hash(mut data: Vec<u8>) -> H256 {
 	keccak(message_data)
 }
 
+/// Stream of numbers of new chain heads.
+pub type NewHeadsStream = Box<Stream<Item = u64, Error = Error>>;
+
+/// Source of new chain heads pushed by the node.
+pub trait NewHeads {
+	/// Creates new subscription.
+	fn subscribe(&self) -> NewHeadsStream;
+}
+
+/// `NewHeads` backed by `eth_subscribe("newHeads")`.
+pub struct SubscribeNewHeads<T>(pub T);
+
+impl<T: DuplexTransport + Clone + 'static> NewHeads for SubscribeNewHeads<T> where T::Out: 'static, T::NotificationStream: 'static {
+	fn subscribe(&self) -> NewHeadsStream {
+		let stream = api::EthSubscribe::new(self.0.clone())
+			.subscribe_new_heads()
+			.flatten_stream()
+			.map_err(|e| Error::from(ErrorKind::Web3(e)))
+			.and_then(|head| head.number
+				.map(|number| number.low_u64())
+				.ok_or_else(|| ErrorKind::OtherError("new head without a block number".into()).into()));
+		Box::new(stream)
+	}
+}
+
 /// Used for `LogStream` initialization.
 pub struct LogStreamInit {
 	pub after: u64,
@@ -161,6 +339,26 @@ pub struct LogStreamInit {
 	pub request_timeout: Duration,
 	pub poll_interval: Duration,
 	pub confirmations: usize,
+	/// Maximum number of blocks requested by a single `eth_getLogs` call.
+	/// `None` means the whole confirmed range is requested at once.
+	pub max_block_range: Option<u64>,
+	/// Hash of block `after`, if known. Used to detect chain reorganizations.
+	pub after_hash: Option<H256>,
+	/// New heads subscription driving the stream. If `None`, or if the subscription
+	/// drops, the node is polled every `poll_interval`.
+	pub heads: Option<Arc<NewHeads>>,
+	/// How the last confirmed block is determined.
+	pub finality: Finality,
+}
+
+/// Endpoints asked for the same logs as the main `LogStream` transport.
+pub struct LogQuorum<T> {
+	pub endpoints: Vec<T>,
+	/// Number of endpoints, including the main transport, which must report a log
+	/// identically for it to be emitted.
+	pub required: usize,
+	/// Number of times a range is requested before `LogQuorumNotReached` is returned.
+	pub max_attempts: u32,
 }
 
 /// Contains all logs matching `LogStream` filter in inclusive range `[from, to]`.
@@ -168,20 +366,122 @@ pub struct LogStreamInit {
 pub struct LogStreamItem {
 	pub from: u64,
 	pub to: u64,
+	/// Hash of block `to` at the time logs were fetched.
+	pub hash: H256,
 	pub logs: Vec<Log>,
 }
 
+/// Number of the most recent checkpoints remembered by `LogStream`.
+const MAX_CHECKPOINTS: usize = 16;
+
+/// Minimal number of blocks `LogStream` rewinds by when a reorganization
+/// went deeper than all remembered checkpoints.
+const MIN_REORG_REWIND: u64 = 10;
+
+/// Parts of `eth_getLogs` error messages returned by providers refusing
+/// to serve a range because it's too large or contains too many results.
+/// Generic messages like "limit exceeded" are left out, since providers use them for rate limits as well.
+const LOG_RANGE_ERROR_MESSAGES: &[&str] = &[
+	// Infura, Nethermind
+	"query returned more than",
+	// Alchemy
+	"log response size exceeded",
+	// Alchemy, Ankr
+	"block range is too wide",
+	// BSC, Erigon
+	"exceed maximum block range",
+	// Cloudflare
+	"exceeded maximum block range",
+	// QuickNode
+	"eth_getlogs is limited to a",
+	// Geth
+	"query timeout exceeded",
+	// Besu
+	"requested range exceeds",
+];
+
+/// Returns `true` if `eth_getLogs` failed because the requested block range was too large,
+/// either because the provider rejected it or because the request timed out.
+fn is_log_range_error(err: &Error) -> bool {
+	match *err.kind() {
+		ErrorKind::Timeout(_) => true,
+		ErrorKind::Web3(web3::error::Error(web3::error::ErrorKind::Rpc(ref rpc_err), _)) => {
+			let message = rpc_err.message.to_lowercase();
+			LOG_RANGE_ERROR_MESSAGES.iter().any(|m| message.contains(m))
+		},
+		_ => false,
+	}
+}
+
+/// Identifies a log reported by an endpoint.
+type LogKey = (Option<H256>, Option<U256>, Address, Vec<H256>, Vec<u8>);
+
+fn log_key(log: &Log) -> LogKey {
+	(log.transaction_hash, log.log_index, log.address, log.topics.clone(), log.data.0.clone())
+}
+
+type SettledResult<T> = Result<Result<T, Error>, Error>;
+
+/// Turns a failure into a successful result, so that a single endpoint
+/// doesn't fail the `join_all` of all of them.
+fn settle<T>(result: Result<T, Error>) -> SettledResult<T> {
+	Ok(result)
+}
+
+/// `eth_getLogs` call which never fails, but resolves to its result.
+type SettledLogs<F> = future::Then<ApiCall<Vec<Log>, F>, SettledResult<Vec<Log>>, fn(Result<Vec<Log>, Error>) -> SettledResult<Vec<Log>>>;
+
+/// Returns `true` if the node responded with an error, as opposed to not responding at all.
+fn is_rpc_error(err: &Error) -> bool {
+	match *err.kind() {
+		ErrorKind::Web3(web3::error::Error(web3::error::ErrorKind::Rpc(_), _)) => true,
+		_ => false,
+	}
+}
+
+/// Returns true if `err` means that the node doesn't know the finality tag,
+/// as opposed to failing to return the block for a moment.
+fn is_unsupported_tag_error(err: &Error) -> bool {
+	match *err.kind() {
+		ErrorKind::Web3(web3::error::Error(web3::error::ErrorKind::Rpc(ref e), _)) => {
+			let message = e.message.to_lowercase();
+			e.code == rpc::ErrorCode::InvalidParams || message.contains("block tag") || message.contains("invalid block")
+		},
+		_ => false,
+	}
+}
+
 /// Log Stream state.
 enum LogStreamState<T: Transport> {
-	/// Log Stream is waiting for timer to poll.
+	/// Log Stream is waiting for a new head or for timer to poll.
 	Wait,
 	/// Fetching best block number.
 	FetchBlockNumber(Timeout<ApiCall<U256, T::Out>>),
-	/// Fetching logs for new best block.
+	/// Fetching the block with the finality tag.
+	FetchFinalizedBlock(Timeout<ApiCall<Option<BlockRef>, T::Out>>),
+	/// Checking that the last checkpoint is still part of the canonical chain.
+	VerifyCheckpoint {
+		block: u64,
+		hash: H256,
+		future: Timeout<ApiCall<Option<BlockRef>, T::Out>>,
+	},
+	/// New confirmed blocks are known, logs for them should be requested.
+	Ready,
+	/// Logs for the next chunk of the confirmed range should be requested.
+	NextChunk,
+	/// Fetching hash of the last block of a chunk.
+	FetchChunkHash {
+		from: u64,
+		to: u64,
+		future: Timeout<ApiCall<Option<BlockRef>, T::Out>>,
+	},
+	/// Fetching logs for a chunk of the confirmed range from the main transport
+	/// and all quorum endpoints.
 	FetchLogs {
 		from: u64,
 		to: u64,
-		future: Timeout<ApiCall<Vec<Log>, T::Out>>,
+		hash: H256,
+		future: Timeout<future::JoinAll<Vec<SettledLogs<T::Out>>>>,
 	},
 	/// All logs has been fetched.
 	NextItem(Option<LogStreamItem>),
@@ -189,28 +489,228 @@ enum LogStreamState<T: Transport> {
 
 /// Creates new `LogStream`.
 pub fn log_stream<T: Transport>(transport: T, timer: Timer, init: LogStreamInit) -> LogStream<T> {
+	let quorum = LogQuorum {
+		endpoints: Vec::new(),
+		required: 1,
+		max_attempts: 1,
+	};
+	quorum_log_stream(transport, quorum, timer, init)
+}
+
+/// Creates new `LogStream` emitting only logs confirmed by the `quorum` of endpoints.
+pub fn quorum_log_stream<T: Transport>(transport: T, quorum: LogQuorum<T>, timer: Timer, init: LogStreamInit) -> LogStream<T> {
 	LogStream {
+		heads_stream: init.heads.as_ref().map(|heads| heads.subscribe()),
+		heads: init.heads,
+		finality_tag: init.finality.tag(),
 		transport,
+		quorum,
 		interval: timer.interval(init.poll_interval),
 		timer,
 		state: LogStreamState::Wait,
 		after: init.after,
+		last_confirmed: init.after,
 		filter: init.filter,
 		confirmations: init.confirmations,
 		request_timeout: init.request_timeout,
+		max_block_range: init.max_block_range,
+		block_range: init.max_block_range,
+		checkpoints: init.after_hash.map(|hash| (init.after, hash)).into_iter().collect(),
+		quorum_failures: 0,
 	}
 }
 
 /// Stream of confirmed logs.
 pub struct LogStream<T: Transport> {
 	transport: T,
+	quorum: LogQuorum<T>,
 	timer: Timer,
 	interval: Interval,
 	state: LogStreamState<T>,
 	after: u64,
+	/// Last confirmed block seen by the stream. Logs are fetched in chunks until `after` reaches it.
+	last_confirmed: u64,
 	filter: FilterBuilder,
 	confirmations: usize,
 	request_timeout: Duration,
+	max_block_range: Option<u64>,
+	/// Block span of the next `eth_getLogs` call. Halved every time a provider
+	/// refuses a range, reset to `max_block_range` once the stream catches up.
+	block_range: Option<u64>,
+	/// Most recent `(block, hash)` pairs of emitted items, oldest first.
+	checkpoints: VecDeque<(u64, H256)>,
+	heads: Option<Arc<NewHeads>>,
+	/// Active new heads subscription.
+	heads_stream: Option<NewHeadsStream>,
+	/// Tag of the last confirmed block. `None` if confirmations are depth-based,
+	/// either by configuration or because the node doesn't support the tag.
+	finality_tag: Option<&'static str>,
+	/// Number of consecutive attempts in which the quorum didn't agree on the next range.
+	quorum_failures: u32,
+}
+
+impl<T: Transport> LogStream<T> {
+	/// Polls the new heads subscription and returns the most recent head, if any.
+	/// Drops the subscription if it has been closed or failed.
+	fn poll_new_heads(&mut self) -> Option<u64> {
+		let mut last_block = None;
+		let dropped = match self.heads_stream {
+			Some(ref mut stream) => loop {
+				match stream.poll() {
+					Ok(Async::Ready(Some(block))) => last_block = Some(block),
+					Ok(Async::NotReady) => break false,
+					Ok(Async::Ready(None)) => {
+						warn!("new heads subscription closed, falling back to polling");
+						break true;
+					},
+					Err(err) => {
+						warn!("new heads subscription failed ({}), falling back to polling", err);
+						break true;
+					},
+				}
+			},
+			None => false,
+		};
+
+		if dropped {
+			self.heads_stream = None;
+		}
+		last_block
+	}
+
+	/// Makes the stream request logs for range `[from, to]` again, in two halves.
+	fn split_range(&mut self, from: u64, to: u64, err: &str) -> LogStreamState<T> {
+		let range = (to - from + 1) / 2;
+		warn!("eth_getLogs for blocks {}-{} failed ({}), retrying with {} blocks per request", from, to, err, range);
+		self.block_range = Some(range);
+		LogStreamState::NextChunk
+	}
+
+	/// Returns logs of range `[from, to]` which were reported identically by the quorum of endpoints.
+	/// `results` hold responses of the main transport followed by the quorum endpoints.
+	fn verify_logs(&self, from: u64, to: u64, results: Vec<Result<Vec<Log>, Error>>) -> Result<Vec<Log>, Error> {
+		let total = results.len();
+		// logs in the order they were first reported, with the number of endpoints reporting them
+		let mut logs: Vec<Log> = Vec::new();
+		let mut votes: HashMap<LogKey, usize> = HashMap::new();
+
+		for (index, result) in results.into_iter().enumerate() {
+			let reported = match result {
+				Ok(reported) => reported,
+				Err(err) => if index == 0 {
+					return Err(err);
+				} else {
+					warn!("log quorum endpoint #{} failed to return logs for blocks {}-{}: {}", index, from, to, err);
+					continue;
+				},
+			};
+
+			let mut seen = HashSet::new();
+			for log in reported {
+				if log.removed == Some(true) {
+					warn!("dropping removed log {:?}", log.transaction_hash);
+					continue;
+				}
+				let key = log_key(&log);
+				// an endpoint reporting a log twice still has a single vote
+				if !seen.insert(key.clone()) {
+					continue;
+				}
+				let count = votes.entry(key).or_insert(0);
+				if *count == 0 {
+					logs.push(log);
+				}
+				*count += 1;
+			}
+		}
+
+		if total == 1 {
+			return Ok(logs);
+		}
+
+		let mut verified = Vec::with_capacity(logs.len());
+		let mut unverified = 0;
+		for log in logs {
+			let count = votes[&log_key(&log)];
+			if count >= self.quorum.required {
+				if count < total {
+					warn!("log {:?}/{:?} in blocks {}-{} reported by only {} of {} endpoints", log.transaction_hash, log.log_index, from, to, count, total);
+				}
+				verified.push(log);
+			} else {
+				error!("log {:?}/{:?} in blocks {}-{} reported by {} of {} endpoints, {} required", log.transaction_hash, log.log_index, from, to, count, total, self.quorum.required);
+				unverified += 1;
+			}
+		}
+
+		if unverified > 0 {
+			return Err(ErrorKind::LogQuorumNotReached(from, to).into());
+		}
+		Ok(verified)
+	}
+
+	/// Handles new best block number.
+	fn on_last_block(&mut self, last_block: u64) -> LogStreamState<T> {
+		let last_confirmed_block = last_block.saturating_sub(self.confirmations as u64);
+		self.on_last_confirmed_block(last_confirmed_block)
+	}
+
+	/// Handles new last confirmed block number.
+	fn on_last_confirmed_block(&mut self, last_confirmed_block: u64) -> LogStreamState<T> {
+		if last_confirmed_block > self.after {
+			self.last_confirmed = last_confirmed_block;
+		}
+		self.verify_last_checkpoint()
+	}
+
+	/// Returns the state fetching the last confirmed block, either directly
+	/// by its tag or by the best block number.
+	fn fetch_last_confirmed(&self) -> LogStreamState<T> {
+		match self.finality_tag {
+			Some(tag) => LogStreamState::FetchFinalizedBlock(self.timer.timeout(block_by_tag(&self.transport, tag), self.request_timeout)),
+			None => LogStreamState::FetchBlockNumber(self.timer.timeout(block_number(&self.transport), self.request_timeout)),
+		}
+	}
+
+	/// Uses depth-based confirmations for this poll after the node failed to return the block
+	/// with the finality tag. If the node doesn't support the tag, they are used from now on.
+	fn fallback_to_depth(&mut self, reason: &str, unsupported: bool) -> LogStreamState<T> {
+		if unsupported {
+			if let Some(tag) = self.finality_tag.take() {
+				warn!("node doesn't support {} block ({}), falling back to {} confirmations", tag, reason, self.confirmations);
+			}
+		} else if let Some(tag) = self.finality_tag {
+			warn!("cannot fetch {} block ({}), using {} confirmations until the next poll", tag, reason, self.confirmations);
+		}
+		LogStreamState::FetchBlockNumber(self.timer.timeout(block_number(&self.transport), self.request_timeout))
+	}
+
+	/// Returns the state verifying the most recent checkpoint,
+	/// or `Ready` if no checkpoint is known.
+	fn verify_last_checkpoint(&self) -> LogStreamState<T> {
+		match self.checkpoints.back() {
+			Some(&(block, hash)) => LogStreamState::VerifyCheckpoint {
+				block,
+				hash,
+				future: self.timer.timeout(block_by_number(&self.transport, block), self.request_timeout),
+			},
+			None => LogStreamState::Ready,
+		}
+	}
+
+	/// Returns the state fetching the next chunk after `self.after`.
+	fn fetch_next_chunk(&self) -> LogStreamState<T> {
+		let from = self.after + 1;
+		let to = match self.block_range {
+			Some(range) => cmp::min(self.last_confirmed, from + range.max(1) - 1),
+			None => self.last_confirmed,
+		};
+		LogStreamState::FetchChunkHash {
+			from,
+			to,
+			future: self.timer.timeout(block_by_number(&self.transport, to), self.request_timeout),
+		}
+	}
 }
 
 impl<T: Transport> Stream for LogStream<T> {
@@ -220,41 +720,128 @@ impl<T: Transport> Stream for LogStream<T> {
 	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
 		loop {
 			let next_state = match self.state {
-				LogStreamState::Wait => {
+				LogStreamState::Wait => if self.heads_stream.is_some() {
+					match self.poll_new_heads() {
+						Some(_) if self.finality_tag.is_some() => self.fetch_last_confirmed(),
+						Some(last_block) => self.on_last_block(last_block),
+						None if self.heads_stream.is_some() => return Ok(Async::NotReady),
+						// subscription dropped, wait for the timer
+						None => LogStreamState::Wait,
+					}
+				} else {
 					let _ = try_stream!(self.interval.poll());
-					LogStreamState::FetchBlockNumber(self.timer.timeout(block_number(&self.transport), self.request_timeout))
+					if let Some(ref heads) = self.heads {
+						self.heads_stream = Some(heads.subscribe());
+					}
+					self.fetch_last_confirmed()
 				},
 				LogStreamState::FetchBlockNumber(ref mut future) => {
 					let last_block = try_ready!(future.poll()).low_u64();
-					let last_confirmed_block = last_block.saturating_sub(self.confirmations as u64);
-					if last_confirmed_block > self.after {
-						let from = self.after + 1;
-						let filter = self.filter.clone()
-							.from_block(from.into())
-							.to_block(last_confirmed_block.into())
-							.build();
-						LogStreamState::FetchLogs {
-							from: from,
-							to: last_confirmed_block,
-							future: self.timer.timeout(logs(&self.transport, &filter), self.request_timeout),
-						}
-					} else {
-						LogStreamState::Wait
+					self.on_last_block(last_block)
+				},
+				LogStreamState::FetchFinalizedBlock(ref mut future) => match future.poll() {
+					Ok(Async::NotReady) => return Ok(Async::NotReady),
+					Ok(Async::Ready(Some(BlockRef { number: Some(number), .. }))) => self.on_last_confirmed_block(number.low_u64()),
+					Ok(Async::Ready(_)) => self.fallback_to_depth("block not found", false),
+					Err(ref err) if is_rpc_error(err) => self.fallback_to_depth(&err.to_string(), is_unsupported_tag_error(err)),
+					Err(err) => return Err(err),
+				},
+				LogStreamState::VerifyCheckpoint { ref mut future, block, hash } => {
+					match try_ready!(future.poll()).and_then(|b| b.hash) {
+						Some(canonical) if canonical == hash => LogStreamState::Ready,
+						// a node lagging behind, e.g. one the bridge just failed over to, doesn't know the block yet
+						None => {
+							warn!("block {} ({}) not found, verifying it again on the next poll", block, hash);
+							LogStreamState::Wait
+						},
+						Some(_) => {
+							warn!("block {} ({}) is no longer part of the canonical chain, rewinding", block, hash);
+							self.checkpoints.pop_back();
+							match self.checkpoints.back() {
+								Some(&(previous, _)) => self.after = previous,
+								None => {
+									let rewind = cmp::max(self.confirmations as u64, MIN_REORG_REWIND);
+									self.after = block.saturating_sub(rewind);
+								},
+							}
+							self.block_range = self.max_block_range;
+							self.verify_last_checkpoint()
+						},
 					}
 				},
-				LogStreamState::FetchLogs { ref mut future, from, to } => {
-					let logs = try_ready!(future.poll());
-					let item = LogStreamItem {
+				LogStreamState::Ready => if self.last_confirmed > self.after {
+					LogStreamState::NextChunk
+				} else {
+					LogStreamState::Wait
+				},
+				LogStreamState::NextChunk => self.fetch_next_chunk(),
+				LogStreamState::FetchChunkHash { ref mut future, from, to } => {
+					let hash = try_ready!(future.poll())
+						.and_then(|b| b.hash)
+						.ok_or_else(|| ErrorKind::OtherError(format!("block {} not found", to)))?;
+					let filter = self.filter.clone()
+						.from_block(from.into())
+						.to_block(to.into())
+						.build();
+					let requests = iter::once(&self.transport)
+						.chain(self.quorum.endpoints.iter())
+						.map(|transport| logs(transport, &filter).then(settle as fn(Result<Vec<Log>, Error>) -> SettledResult<Vec<Log>>))
+						.collect::<Vec<_>>();
+					LogStreamState::FetchLogs {
 						from,
 						to,
-						logs,
-					};
+						hash,
+						future: self.timer.timeout(future::join_all(requests), self.request_timeout),
+					}
+				},
+				LogStreamState::FetchLogs { ref mut future, from, to, hash } => {
+					match future.poll() {
+						Ok(Async::NotReady) => return Ok(Async::NotReady),
+						Ok(Async::Ready(results)) => {
+							let range_error = results.iter()
+								.filter_map(|result| result.as_ref().err())
+								.find(|err| is_log_range_error(err))
+								.map(|err| err.to_string());
+							match range_error {
+								Some(ref err) if to > from => self.split_range(from, to, err),
+								_ => {
+									let logs = match self.verify_logs(from, to, results) {
+										Ok(logs) => logs,
+										Err(Error(ErrorKind::LogQuorumNotReached(..), _)) if self.quorum_failures + 1 < self.quorum.max_attempts => {
+											self.quorum_failures += 1;
+											warn!("log quorum not reached for blocks {}-{} (attempt {} of {}), retrying on the next poll", from, to, self.quorum_failures, self.quorum.max_attempts);
+											self.state = LogStreamState::Wait;
+											continue;
+										},
+										Err(err) => return Err(err),
+									};
+									self.quorum_failures = 0;
+									let item = LogStreamItem {
+										from,
+										to,
+										hash,
+										logs,
+									};
 
-					self.after = to;
-					LogStreamState::NextItem(Some(item))
+									self.after = to;
+									self.checkpoints.push_back((to, hash));
+									if self.checkpoints.len() > MAX_CHECKPOINTS {
+										self.checkpoints.pop_front();
+									}
+									LogStreamState::NextItem(Some(item))
+								},
+							}
+						},
+						Err(ref err) if to > from && is_log_range_error(err) => self.split_range(from, to, &err.to_string()),
+						Err(err) => return Err(err),
+					}
 				},
 				LogStreamState::NextItem(ref mut item) => match item.take() {
-					None => LogStreamState::Wait,
+					None if self.after < self.last_confirmed => LogStreamState::NextChunk,
+					None => {
+						self.block_range = self.max_block_range;
+						LogStreamState::Wait
+					},
 					some => return Ok(some.into()),
 				},
 			};
@@ -263,3 +850,44 @@ impl<T: Transport> Stream for LogStream<T> {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use web3;
+	use rpc::{self, ErrorCode};
+	use error::{Error, ErrorKind};
+	use super::{is_log_range_error, is_unsupported_tag_error};
+
+	fn rpc_error(message: &str) -> Error {
+		rpc_error_with_code(ErrorCode::ServerError(-32005), message)
+	}
+
+	fn rpc_error_with_code(code: ErrorCode, message: &str) -> Error {
+		let err = rpc::Error {
+			code,
+			message: message.into(),
+			data: None,
+		};
+		ErrorKind::Web3(web3::error::ErrorKind::Rpc(err).into()).into()
+	}
+
+	#[test]
+	fn test_is_log_range_error() {
+		assert!(is_log_range_error(&rpc_error("query returned more than 10000 results")));
+		assert!(is_log_range_error(&rpc_error("Log response size exceeded. You can make eth_getLogs requests with up to a 2K block range")));
+		assert!(is_log_range_error(&rpc_error("exceed maximum block range: 5000")));
+		assert!(is_log_range_error(&rpc_error("eth_getLogs is limited to a 10,000 range")));
+		assert!(is_log_range_error(&ErrorKind::Timeout("eth_getLogs").into()));
+		assert!(!is_log_range_error(&rpc_error("daily request count exceeded, request rate limited")));
+		assert!(!is_log_range_error(&rpc_error("project ID request rate limit exceeded")));
+		assert!(!is_log_range_error(&rpc_error("upstream request timeout")));
+	}
+
+	#[test]
+	fn test_is_unsupported_tag_error() {
+		assert!(is_unsupported_tag_error(&rpc_error_with_code(ErrorCode::InvalidParams, "Invalid params: unknown block tag")));
+		assert!(is_unsupported_tag_error(&rpc_error("invalid block number")));
+		assert!(!is_unsupported_tag_error(&rpc_error("request failed")));
+		assert!(!is_unsupported_tag_error(&ErrorKind::Timeout("eth_getBlockByNumber").into()));
+	}
+}
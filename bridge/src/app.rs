@@ -3,16 +3,18 @@ use tokio_core::reactor::{Handle};
 use tokio_timer::{self, Timer};
 use web3::Transport;
 use error::{Error, ResultExt, ErrorKind};
-use config::Config;
+use config::{Config, Node};
 use contracts::{home, foreign};
 use web3::transports::http::Http;
+use api::NewHeads;
+use transport::Connection;
+use head_tracker::HeadTracker;
 use std::time::Duration;
 
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 
-use ethcore::ethstore::{EthStore,accounts_dir::RootDiskDirectory};
-use ethcore::account_provider::{AccountProvider, AccountProviderSettings};
+use signer::{Signer, create_signer};
 
 pub struct App<T> where T: Transport {
 	pub config: Config,
@@ -22,12 +24,24 @@ pub struct App<T> where T: Transport {
 	pub foreign_bridge: foreign::ForeignBridge,
 	pub timer: Timer,
 	pub running: Arc<AtomicBool>,
-	pub keystore: AccountProvider,
+	pub signer: Box<Signer>,
+	/// Whether transactions are only signed and logged, never sent.
+	pub dry_run: bool,
 }
 
 pub struct Connections<T> where T: Transport {
 	pub home: T,
 	pub foreign: T,
+	/// New heads of home. Node subscription, if supported by the transport,
+	/// until `App` replaces it with a head tracker shared by all log streams.
+	pub home_heads: Option<Arc<NewHeads>>,
+	/// New heads of foreign. Node subscription, if supported by the transport,
+	/// until `App` replaces it with a head tracker shared by all log streams.
+	pub foreign_heads: Option<Arc<NewHeads>>,
+	/// Endpoints verifying logs fetched from home.
+	pub home_log_quorum: Vec<T>,
+	/// Endpoints verifying logs fetched from foreign.
+	pub foreign_log_quorum: Vec<T>,
 }
 
 impl Connections<Http>  {
@@ -44,7 +58,36 @@ impl Connections<Http>  {
 
 		let result = Connections {
 			home,
-			foreign
+			foreign,
+			home_heads: None,
+			foreign_heads: None,
+			home_log_quorum: Vec::new(),
+			foreign_log_quorum: Vec::new(),
+		};
+		Ok(result)
+	}
+}
+
+impl Connections<Connection> {
+	/// Connects to home and foreign nodes using the transports their configs specify.
+	/// `timer` limits requests to nodes with fallback endpoints.
+	pub fn new(handle: &Handle, timer: &Timer, home: &Node, foreign: &Node) -> Result<Self, Error> {
+		let home_log_quorum = log_quorum_connections(handle, home)
+			.chain_err(|| "Cannot connect to home log quorum rpc")?;
+		let foreign_log_quorum = log_quorum_connections(handle, foreign)
+			.chain_err(|| "Cannot connect to foreign log quorum rpc")?;
+		let home = Connection::for_node(home, handle, timer)
+			.chain_err(|| "Cannot connect to home node rpc")?;
+		let foreign = Connection::for_node(foreign, handle, timer)
+			.chain_err(|| "Cannot connect to foreign node rpc")?;
+
+		let result = Connections {
+			home_heads: home.new_heads(),
+			foreign_heads: foreign.new_heads(),
+			home,
+			foreign,
+			home_log_quorum,
+			foreign_log_quorum,
 		};
 		Ok(result)
 	}
@@ -55,28 +98,54 @@ impl<T: Transport> Connections<T> {
 		Connections {
 			home: &self.home,
 			foreign: &self.foreign,
+			home_heads: self.home_heads.clone(),
+			foreign_heads: self.foreign_heads.clone(),
+			home_log_quorum: self.home_log_quorum.iter().collect(),
+			foreign_log_quorum: self.foreign_log_quorum.iter().collect(),
 		}
 	}
 }
 
+fn log_quorum_connections(handle: &Handle, node: &Node) -> Result<Vec<Connection>, Error> {
+	node.log_quorum_rpc_urls.iter()
+		.map(|url| Connection::new(url, handle, node.concurrent_http_requests))
+		.collect()
+}
+
 impl App<Http> {
 	pub fn new_http<P: AsRef<Path>>(config: Config, database_path: P, handle: &Handle, running: Arc<AtomicBool>) -> Result<Self, Error> {
-		let home_url:String = format!("{}:{}", config.home.rpc_host, config.home.rpc_port);
-		let foreign_url:String = format!("{}:{}", config.foreign.rpc_host, config.foreign.rpc_port);
+		let home = config.home.rpc_url().ok_or_else(|| ErrorKind::ConfigError("home rpc_host is not set".into()))?;
+		let foreign = config.foreign.rpc_url().ok_or_else(|| ErrorKind::ConfigError("foreign rpc_host is not set".into()))?;
+		let connections = Connections::new_http(handle, &home, config.home.concurrent_http_requests, &foreign, config.foreign.concurrent_http_requests)?;
+		App::with_connections(config, database_path, connections, running)
+	}
+}
 
-		let connections = Connections::new_http(handle, home_url.as_ref(), config.home.concurrent_http_requests, foreign_url.as_ref(), config.foreign.concurrent_http_requests)?;
-		let keystore = EthStore::open(Box::new(RootDiskDirectory::at(&config.keystore))).map_err(|e| ErrorKind::KeyStore(e))?;
+impl App<Connection> {
+	/// Creates new `App` connected to each node over IPC if its `ipc_path` is set,
+	/// otherwise over HTTP or WebSocket depending on its `rpc_host`.
+	pub fn new<P: AsRef<Path>>(config: Config, database_path: P, handle: &Handle, running: Arc<AtomicBool>) -> Result<Self, Error> {
+		let timer = create_timer(&config);
+		let connections = Connections::new(handle, &timer, &config.home, &config.foreign)?;
+		App::with_timer(config, database_path, connections, timer, running)
+	}
+}
 
-		let keystore = AccountProvider::new(Box::new(keystore), AccountProviderSettings {
-			enable_hardware_wallets: false,
-			hardware_wallet_classic_key: false,
-			unlock_keep_secret: true,
-			blacklisted_accounts: vec![],
-		});
-		keystore.unlock_account_permanently(config.home.account, config.home.password()?).map_err(|e| ErrorKind::AccountError(e))?;
-		keystore.unlock_account_permanently(config.foreign.account, config.foreign.password()?).map_err(|e| ErrorKind::AccountError(e))?;
+impl<T: Transport + 'static> App<T> where T::Out: 'static {
+	/// Creates new `App` using already established connections.
+	pub fn with_connections<P: AsRef<Path>>(config: Config, database_path: P, connections: Connections<T>, running: Arc<AtomicBool>) -> Result<Self, Error> {
+		let timer = create_timer(&config);
+		App::with_timer(config, database_path, connections, timer, running)
+	}
+
+	fn with_timer<P: AsRef<Path>>(config: Config, database_path: P, mut connections: Connections<T>, timer: Timer, running: Arc<AtomicBool>) -> Result<Self, Error> {
+		let signer = create_signer(&config, &timer)?;
 
-		let max_timeout = config.clone().home.request_timeout.max(config.clone().foreign.request_timeout);
+		// all log streams of a chain follow a single head tracker
+		let home_heads = HeadTracker::new(connections.home.clone(), timer.clone(), config.home.poll_interval, config.home.request_timeout, connections.home_heads.take());
+		connections.home_heads = Some(Arc::new(home_heads));
+		let foreign_heads = HeadTracker::new(connections.foreign.clone(), timer.clone(), config.foreign.poll_interval, config.foreign.request_timeout, connections.foreign_heads.take());
+		connections.foreign_heads = Some(Arc::new(foreign_heads));
 
 		let result = App {
 			config,
@@ -84,15 +153,21 @@ impl App<Http> {
 			connections,
 			home_bridge: home::HomeBridge::default(),
 			foreign_bridge: foreign::ForeignBridge::default(),
-			// it is important to build a timer with a max timeout that can accommodate the longest timeout requested,
-			// otherwise it will result in a bizarrely inadequate behaviour of timing out nearly immediately
-			timer: tokio_timer::wheel().max_timeout(max_timeout)
-				.tick_duration(Duration::from_millis(100))
-				.num_slots((max_timeout.as_secs() as usize * 10).next_power_of_two())
-				.build(),
+			timer,
 			running,
-			keystore,
+			signer,
+			dry_run: false,
 		};
 		Ok(result)
 	}
 }
+
+fn create_timer(config: &Config) -> Timer {
+	let max_timeout = config.home.request_timeout.max(config.foreign.request_timeout);
+	// it is important to build a timer with a max timeout that can accommodate the longest timeout requested,
+	// otherwise it will result in a bizarrely inadequate behaviour of timing out nearly immediately
+	tokio_timer::wheel().max_timeout(max_timeout)
+		.tick_duration(Duration::from_millis(100))
+		.num_slots((max_timeout.as_secs() as usize * 10).next_power_of_two())
+		.build()
+}
@@ -85,7 +85,7 @@ impl<T: Transport + Clone> Future for Deploy<T> {
 							let main_tx = Transaction {
 								nonce: U256::zero(),
 								gas_price: self.app.config.txs.home_deploy.gas_price.into(),
-								gas: self.app.config.txs.home_deploy.gas.into(),
+								gas: self.app.config.txs.home_deploy.gas.unwrap_or_default().into(),
 								action: Action::Create,
 								value: U256::zero(),
 								data: main_data.into(),
@@ -94,7 +94,7 @@ impl<T: Transport + Clone> Future for Deploy<T> {
 							let test_tx = Transaction {
 								nonce: U256::zero(),
 								gas_price: self.app.config.txs.foreign_deploy.gas_price.into(),
-								gas: self.app.config.txs.foreign_deploy.gas.into(),
+								gas: self.app.config.txs.foreign_deploy.gas.unwrap_or_default().into(),
 								action: Action::Create,
 								value: U256::zero(),
 								data: test_data.into(),
@@ -118,7 +118,8 @@ impl<T: Transport + Clone> Future for Deploy<T> {
 				},
 				#[cfg(feature = "deploy")]
 				DeployState::Deploying(ref mut future) => {
-					let (main_receipt, test_receipt) = try_ready!(future.poll());
+					let (main_sent, test_sent) = try_ready!(future.poll());
+					let (main_receipt, test_receipt) = (main_sent.result, test_sent.result);
 					let database = Database {
 						home_contract_address: main_receipt.contract_address.expect("contract creation receipt must have an address; qed"),
 						foreign_contract_address: test_receipt.contract_address.expect("contract creation receipt must have an address; qed"),
@@ -127,6 +128,10 @@ impl<T: Transport + Clone> Future for Deploy<T> {
 						checked_deposit_relay: main_receipt.block_number.low_u64(),
 						checked_withdraw_relay: test_receipt.block_number.low_u64(),
 						checked_withdraw_confirm: test_receipt.block_number.low_u64(),
+						checked_deposit_relay_hash: Some(main_receipt.block_hash),
+						checked_withdraw_relay_hash: Some(test_receipt.block_hash),
+						checked_withdraw_confirm_hash: Some(test_receipt.block_hash),
+						..Database::default()
 					};
 					return Ok(Deployed::New(database).into())
 				},
@@ -1,17 +1,23 @@
+use std::collections::{HashSet, VecDeque};
 use std::sync::{Arc, RwLock};
-use futures::{self, Future, Stream, stream::{Collect, FuturesUnordered, futures_unordered}, Poll};
+use futures::{self, Stream, Poll};
 use web3::Transport;
-use web3::types::{U256, Address, Bytes, Log, FilterBuilder};
+use web3::types::{U256, H256, Address, Bytes, Log, FilterBuilder};
 use ethabi::RawLog;
+use keccak_hash::keccak;
 use api::{LogStream, self};
 use error::{Error, ErrorKind, Result};
-use database::Database;
+use database::{Database, EventId};
+use ledger::{Transfer, TransferKind};
 use contracts::{home, foreign};
 use util::web3_filter;
 use app::App;
 use ethcore_transaction::{Transaction, Action};
 use super::nonce::{NonceCheck, SendRawTransaction};
-use super::BridgeChecked;
+use super::{BridgeChecked, EventBatch, PendingTransactions};
+use super::retry::{RetryLogs, RevertedEvents, retry_logs};
+use super::gas_estimate::{GasEstimates, estimate_gas, sendable};
+use super::simulation::{self, Payout, RevertProbe, Simulations, simulate, skip_reverted};
 use itertools::Itertools;
 
 fn deposits_filter(home: &home::HomeBridge, address: Address) -> FilterBuilder {
@@ -30,46 +36,110 @@ fn deposit_relay_payload(home: &home::HomeBridge, foreign: &foreign::ForeignBrid
 	Ok(payload.into())
 }
 
+/// State of `ForeignBridge` which tells why a deposit relay sent by `account` reverts.
+fn deposit_relay_probe(account: Address, transfer: &Transfer) -> RevertProbe {
+	// `keccak256(recipient, value, transactionHash)`
+	let mut message = transfer.recipient.0.to_vec();
+	message.extend_from_slice(&H256::from(transfer.value).0);
+	message.extend_from_slice(&transfer.source_tx_hash.0);
+	let hash = simulation::sender_hash(account, keccak(&message));
+	RevertProbe {
+		handled: simulation::mapping_key(hash, simulation::FOREIGN_DEPOSITS_SIGNED_SLOT),
+		authority: Some(simulation::mapping_key(simulation::address_key(account), simulation::FOREIGN_AUTHORITIES_SLOT)),
+		payout: Payout::Tokens(simulation::FOREIGN_ERC20_TOKEN_SLOT.into(), transfer.value),
+	}
+}
+
+fn deposit_transfer(home: &home::HomeBridge, event: EventId, log: &Log, gas_price: U256) -> Result<Transfer> {
+	let raw_log = RawLog {
+		topics: log.topics.clone(),
+		data: log.data.0.clone(),
+	};
+	let deposit_log = home.events().deposit().parse_log(raw_log)?;
+	Ok(Transfer::new(TransferKind::DepositRelay, event, event.transaction_hash, deposit_log.recipient, deposit_log.value, gas_price))
+}
+
 /// State of deposits relay.
 enum DepositRelayState<T: Transport> {
 	/// Deposit relay is waiting for logs.
 	Wait,
+	/// Simulating the deposit relays.
+	Simulate {
+		future: Simulations<T>,
+		deposits: Vec<(Transfer, Bytes)>,
+		/// Gas limit the deposits are relayed with.
+		gas: u64,
+		checkpoint: Option<(u64, H256)>,
+	},
+	/// Estimating gas of the deposit relays.
+	EstimateGas {
+		future: GasEstimates<T>,
+		deposits: Vec<(Transfer, Bytes)>,
+		checkpoint: Option<(u64, H256)>,
+	},
 	/// Relaying deposits in progress.
 	RelayDeposits {
-		future: Collect<FuturesUnordered<NonceCheck<T, SendRawTransaction<T>>>>,
-		block: u64,
+		future: EventBatch<Transfer, NonceCheck<T, SendRawTransaction<T>>>,
+		/// Block checked once the deposits are relayed, `None` for reverted deposits relayed again.
+		checkpoint: Option<(u64, H256)>,
 	},
 	/// All deposits till given block has been relayed.
-	Yield(Option<u64>),
+	Yield(Option<(u64, H256)>),
 }
 
-pub fn create_deposit_relay<T: Transport + Clone>(app: Arc<App<T>>, init: &Database, foreign_balance: Arc<RwLock<Option<U256>>>, foreign_chain_id: u64, foreign_gas_price: Arc<RwLock<u64>>) -> DepositRelay<T> {
+pub fn create_deposit_relay<T: Transport + Clone>(app: Arc<App<T>>, init: &Database, foreign_balance: Arc<RwLock<Option<U256>>>, foreign_chain_id: u64, foreign_gas_price: Arc<RwLock<u64>>, gas_limit: Arc<RwLock<u64>>, foreign_pending: PendingTransactions, reverted: RevertedEvents) -> DepositRelay<T> {
 	let logs_init = api::LogStreamInit {
 		after: init.checked_deposit_relay,
 		request_timeout: app.config.home.request_timeout,
 		poll_interval: app.config.home.poll_interval,
 		confirmations: app.config.home.required_confirmations,
+		max_block_range: app.config.home.max_block_range,
+		after_hash: init.checked_deposit_relay_hash,
+		heads: app.connections.home_heads.clone(),
+		finality: app.config.home.finality,
 		filter: deposits_filter(&app.home_bridge, init.home_contract_address),
 	};
+	let log_quorum = api::LogQuorum {
+		endpoints: app.connections.home_log_quorum.clone(),
+		required: app.config.home.log_quorum,
+		max_attempts: app.config.home.log_quorum_max_attempts,
+	};
 	DepositRelay {
-		logs: api::log_stream(app.connections.home.clone(), app.timer.clone(), logs_init),
+		logs: api::quorum_log_stream(app.connections.home.clone(), log_quorum, app.timer.clone(), logs_init),
+		retry_logs: retry_logs(app.connections.home.clone(), app.timer.clone(), app.config.home.request_timeout,
+			deposits_filter(&app.home_bridge, init.home_contract_address), reverted),
 		foreign_contract: init.foreign_contract_address,
 		state: DepositRelayState::Wait,
+		relayed: init.relayed_deposits.iter().cloned().collect(),
+		queued: VecDeque::new(),
 		app,
 		foreign_balance,
 		foreign_chain_id,
 		foreign_gas_price,
+		gas_limit,
+		foreign_pending,
 	}
 }
 
 pub struct DepositRelay<T: Transport> {
 	app: Arc<App<T>>,
 	logs: LogStream<T>,
+	/// Logs of deposits whose relay reverted.
+	retry_logs: RetryLogs<T>,
 	state: DepositRelayState<T>,
+	/// Deposits already relayed in blocks which are not fully checked yet.
+	relayed: HashSet<EventId>,
+	/// Deposits which are not relayed, because they can't be decoded or their relay would revert,
+	/// and transfers about to be sent, which are recorded as pending first.
+	queued: VecDeque<BridgeChecked>,
 	foreign_contract: Address,
 	foreign_balance: Arc<RwLock<Option<U256>>>,
 	foreign_chain_id: u64,
 	foreign_gas_price: Arc<RwLock<u64>>,
+	/// Gas limit of deposit relays set in the foreign contract.
+	gas_limit: Arc<RwLock<u64>>,
+	/// Relay transactions which are not mined yet.
+	foreign_pending: PendingTransactions,
 }
 
 impl<T: Transport> Stream for DepositRelay<T> {
@@ -78,56 +148,127 @@ impl<T: Transport> Stream for DepositRelay<T> {
 
 	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
 		loop {
+			if let Some(queued) = self.queued.pop_front() {
+				return Ok(Some(queued).into());
+			}
+
 			let next_state = match self.state {
 				DepositRelayState::Wait => {
-					let foreign_balance = self.foreign_balance.read().unwrap();
-					if foreign_balance.is_none() {
+					if self.foreign_balance.read().unwrap().is_none() {
 						warn!("foreign contract balance is unknown");
 						return Ok(futures::Async::NotReady);
 					}
-					let item = try_stream!(self.logs.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "polling home for deposits")));
-					let len = item.logs.len();
-					info!("got {} new deposits to relay", len);
+					let gas = self.app.config.txs.deposit_relay.gas_limit(*self.gas_limit.read().unwrap());
+					if gas == 0 {
+						return Err(ErrorKind::ConfigError("deposit relay gas limit is not set".into()).into());
+					}
+					let retry = self.retry_logs.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "fetching reverted deposits"))?;
+					let (logs, checkpoint) = match retry {
+						futures::Async::Ready(Some(logs)) => (logs, None),
+						_ => {
+							let item = try_stream!(self.logs.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "polling home for deposits")));
+							(item.logs, Some((item.to, item.hash)))
+						},
+					};
+					let logs = logs
+						.into_iter()
+						.map(|log| Ok((EventId::from_log(&log)?, log)))
+						.collect::<Result<Vec<_>>>()?;
+					if checkpoint.is_none() {
+						info!("relaying {} reverted deposits again", logs.len());
+						for (id, _) in &logs {
+							self.relayed.remove(id);
+						}
+					}
+					let (relayed, logs): (Vec<_>, Vec<_>) = logs.into_iter().partition(|(id, _)| self.relayed.contains(id));
+					if !relayed.is_empty() {
+						info!("skipping {} deposits relayed before restart", relayed.len());
+					}
+					info!("got {} new deposits to relay", logs.len());
 
-					let gas = U256::from(self.app.config.txs.deposit_relay.gas);
 					let gas_price = U256::from(*self.foreign_gas_price.read().unwrap());
-					let balance_required = gas * gas_price * U256::from(item.logs.len());
-					
-					if balance_required > *foreign_balance.as_ref().unwrap() {
-						return Err(ErrorKind::InsufficientFunds.into())
+					let mut decoded = Vec::new();
+					for (id, log) in logs {
+						let deposit = deposit_transfer(&self.app.home_bridge, id, &log, gas_price)
+							.and_then(|transfer| Ok((transfer, deposit_relay_payload(&self.app.home_bridge, &self.app.foreign_bridge, log)?)));
+						match deposit {
+							Ok(deposit) => decoded.push(deposit),
+							Err(err) => self.queued.push_back(BridgeChecked::DeadLetter(TransferKind::DepositRelay, id, err.to_string())),
+						}
 					}
-					let deposits = item.logs
-						.into_iter()
-						.map(|log| deposit_relay_payload(&self.app.home_bridge, &self.app.foreign_bridge, log))
-						.collect::<Result<Vec<_>>>()?
+					let account = self.app.config.foreign.account;
+					let payloads = decoded.iter()
+						.map(|(transfer, payload)| (payload.clone(), U256::from(gas), gas_price, deposit_relay_probe(account, transfer)))
+						.collect::<Vec<_>>();
+					DepositRelayState::Simulate {
+						future: simulate(&self.app.connections.foreign, &self.app.timer, self.app.config.foreign.request_timeout,
+							account, self.foreign_contract, &payloads),
+						deposits: decoded,
+						gas,
+						checkpoint,
+					}
+				},
+				DepositRelayState::Simulate { ref mut future, ref mut deposits, gas, checkpoint } => {
+					let outcomes = try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "simulating deposit relays")));
+					let deposits = skip_reverted(deposits.drain(..).collect(), outcomes, &mut self.queued);
+
+					let payloads = deposits.iter().map(|(_, payload)| payload.clone()).collect::<Vec<_>>();
+					DepositRelayState::EstimateGas {
+						future: estimate_gas(&self.app.connections.foreign, &self.app.timer, self.app.config.foreign.request_timeout,
+							self.app.config.foreign.account, self.foreign_contract, &self.app.config.txs.deposit_relay, gas, &payloads),
+						deposits,
+						checkpoint,
+					}
+				},
+				DepositRelayState::EstimateGas { ref mut future, ref mut deposits, checkpoint } => {
+					let gas = try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "estimating gas of deposit relays")));
+					let balance = self.foreign_balance.read().unwrap().expect("balance is known before relaying; qed");
+					let deposits = sendable(deposits.drain(..).collect(), gas, balance, &mut self.queued)?;
+					let len = deposits.len();
+					self.queued.extend(deposits.iter().map(|(transfer, _, _)| BridgeChecked::Transfer(*transfer)));
+					let deposits = deposits
 						.into_iter()
-						.map(|payload| {
+						.map(|(transfer, payload, gas)| {
 							let tx = Transaction {
 								gas,
-								gas_price,
+								gas_price: transfer.gas_price,
 								value: U256::zero(),
 								data: payload.0,
 								nonce: U256::zero(),
 								action: Action::Call(self.foreign_contract.clone()),
 							};
-							api::send_transaction_with_nonce(self.app.connections.foreign.clone(), self.app.clone(), self.app.config.foreign.clone(),
-															 tx, self.foreign_chain_id, SendRawTransaction(self.app.connections.foreign.clone()))
+							(transfer, api::send_transaction_with_nonce(self.app.connections.foreign.clone(), self.app.clone(), self.app.config.foreign.clone(),
+																  tx, self.foreign_chain_id, SendRawTransaction { transport: self.app.connections.foreign.clone(), dry_run: self.app.dry_run }))
 						}).collect_vec();
 
 					info!("relaying {} deposits", len);
 					DepositRelayState::RelayDeposits {
-						future: futures_unordered(deposits).collect(),
-						block: item.to,
+						future: EventBatch::new(deposits),
+						checkpoint,
 					}
 				},
-				DepositRelayState::RelayDeposits { ref mut future, block } => {
-					let _ = try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "relaying deposit to foreign")));
-					info!("deposit relay completed");
-					DepositRelayState::Yield(Some(block))
+				DepositRelayState::RelayDeposits { ref mut future, checkpoint } => {
+					match try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "relaying deposit to foreign"))) {
+						Some((transfer, sent)) => {
+							if !self.app.dry_run {
+								self.foreign_pending.add(&sent)?;
+							}
+							let transfer = transfer.sent(sent.result, sent.transaction.nonce);
+							self.relayed.insert(transfer.event);
+							return Ok(Some(BridgeChecked::Transfer(transfer)).into());
+						},
+						None => {
+							info!("deposit relay completed");
+							if let Some((block, _)) = checkpoint {
+								self.relayed.retain(|id| id.block_number > block);
+							}
+							DepositRelayState::Yield(checkpoint)
+						},
+					}
 				},
 				DepositRelayState::Yield(ref mut block) => match block.take() {
 					None => DepositRelayState::Wait,
-					Some(v) => return Ok(Some(BridgeChecked::DepositRelay(v)).into()),
+					Some((block, hash)) => return Ok(Some(BridgeChecked::DepositRelay(block, hash)).into()),
 				}
 			};
 			self.state = next_state;
@@ -0,0 +1,100 @@
+use std::fmt;
+use futures::{Async, Future, Poll, Stream};
+use futures::stream::{FuturesUnordered, futures_unordered};
+use error::Error;
+
+/// Future handling a single event.
+struct EventFuture<E, F> {
+	event: E,
+	future: F,
+}
+
+impl<E: Copy, F: Future<Error = Error>> Future for EventFuture<E, F> {
+	type Item = (E, F::Item);
+	type Error = (E, Error);
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		match self.future.poll() {
+			Ok(Async::Ready(item)) => Ok(Async::Ready((self.event, item))),
+			Ok(Async::NotReady) => Ok(Async::NotReady),
+			Err(err) => Err((self.event, err)),
+		}
+	}
+}
+
+/// Handles a batch of events concurrently, yielding the events together with
+/// the results of their futures as they succeed.
+///
+/// Unlike `collect()`, a failure doesn't cancel the rest of the batch. The batch runs
+/// to completion and only then fails with the first error.
+pub struct EventBatch<E, F> {
+	futures: FuturesUnordered<EventFuture<E, F>>,
+	error: Option<Error>,
+}
+
+impl<E: Copy, F: Future<Error = Error>> EventBatch<E, F> {
+	pub fn new<I: IntoIterator<Item = (E, F)>>(events: I) -> Self {
+		EventBatch {
+			futures: futures_unordered(events.into_iter().map(|(event, future)| EventFuture { event, future })),
+			error: None,
+		}
+	}
+}
+
+impl<E: Copy + fmt::Display, F: Future<Error = Error>> Stream for EventBatch<E, F> {
+	type Item = (E, F::Item);
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		loop {
+			match self.futures.poll() {
+				Ok(Async::Ready(Some(item))) => return Ok(Async::Ready(Some(item))),
+				Ok(Async::Ready(None)) => return match self.error.take() {
+					Some(err) => Err(err),
+					None => Ok(Async::Ready(None)),
+				},
+				Ok(Async::NotReady) => return Ok(Async::NotReady),
+				Err((event, err)) => {
+					error!("handling event {} failed: {}", event, err);
+					if self.error.is_none() {
+						self.error = Some(err);
+					}
+				},
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::{future, Future, Stream};
+	use database::EventId;
+	use error::{Error, ErrorKind};
+	use super::EventBatch;
+
+	fn event(n: u64) -> EventId {
+		EventId {
+			block_number: 1,
+			transaction_hash: n.into(),
+			log_index: 0u64.into(),
+		}
+	}
+
+	#[test]
+	fn failed_event_does_not_cancel_batch() {
+		let futures: Vec<future::FutureResult<(), Error>> = vec![
+			future::ok(()),
+			future::err(ErrorKind::OtherError("failed".into()).into()),
+			future::ok(()),
+		];
+		let batch = EventBatch::new((1..4).map(event).zip(futures));
+
+		let results = batch.then(|result| Ok::<_, ()>(result)).collect().wait().unwrap();
+		assert_eq!(results.len(), 3);
+		// the error is reported once all other events are handled
+		assert!(results[2].is_err());
+		let done: Vec<_> = results.into_iter().filter_map(Result::ok).map(|(id, _)| id).collect();
+		assert!(done.contains(&event(1)));
+		assert!(done.contains(&event(3)));
+	}
+}
@@ -0,0 +1,120 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+use futures::{Async, Future, Poll, Stream};
+use tokio_timer::{Interval, Timeout, Timer};
+use web3::Transport;
+use web3::types::U256;
+use api::{self, ApiCall, FeeHistory};
+use config::Node;
+use error::Error;
+
+/// Number of blocks whose priority fees are taken into account.
+const FEE_HISTORY_BLOCKS: u64 = 20;
+const FEE_HISTORY_INTERVAL: Duration = Duration::from_secs(30);
+
+enum FeeHistoryState<T: Transport> {
+	Wait,
+	Fetch(Timeout<ApiCall<FeeHistory, T::Out>>),
+}
+
+/// Reads the fees of EIP-1559 transactions from `eth_feeHistory`.
+///
+/// Yields the maximum fee per gas, twice the base fee of the next block plus the priority fee,
+/// and updates the priority fee of the node. The priority fee is the median of the fees paid
+/// in recent blocks at the percentile matching `gas_price_speed`.
+pub struct FeeHistoryStream<T: Transport> {
+	transport: T,
+	node: Node,
+	timer: Timer,
+	interval: Interval,
+	last_fee: u64,
+	state: FeeHistoryState<T>,
+}
+
+impl<T: Transport> FeeHistoryStream<T> {
+	pub fn new(transport: T, node: &Node, timer: &Timer) -> Self {
+		FeeHistoryStream {
+			transport,
+			node: node.clone(),
+			timer: timer.clone(),
+			interval: timer.interval_at(Instant::now(), FEE_HISTORY_INTERVAL),
+			last_fee: node.default_gas_price,
+			state: FeeHistoryState::Wait,
+		}
+	}
+}
+
+/// Maximum fee and priority fee paying for the next block, both at most `max_fee`.
+fn fees(history: &FeeHistory, default_priority_fee: u64, max_fee: u64) -> Option<(u64, u64)> {
+	let base_fee = history.next_base_fee()?;
+	let priority_fee = history.priority_fee().unwrap_or(default_priority_fee.into());
+	let max_fee = U256::from(max_fee);
+	let fee = cmp::min(base_fee * U256::from(2) + priority_fee, max_fee);
+	Some((fee.low_u64(), cmp::min(priority_fee, fee).low_u64()))
+}
+
+impl<T: Transport> Stream for FeeHistoryStream<T> {
+	type Item = u64;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		loop {
+			let next_state = match self.state {
+				FeeHistoryState::Wait => {
+					let _ = try_stream!(self.interval.poll());
+					let request = api::fee_history(&self.transport, FEE_HISTORY_BLOCKS, self.node.gas_price_speed.percentile());
+					FeeHistoryState::Fetch(self.timer.timeout(request, self.node.request_timeout))
+				},
+				FeeHistoryState::Fetch(ref mut future) => {
+					let fee = match future.poll() {
+						Ok(Async::NotReady) => return Ok(Async::NotReady),
+						Ok(Async::Ready(history)) => match fees(&history, self.node.default_priority_fee, self.node.max_gas_price) {
+							Some((fee, priority_fee)) => {
+								*self.node.info.priority_fee.write().unwrap() = priority_fee;
+								if fee != self.last_fee {
+									info!("Max fee: {} gwei, priority fee: {} gwei", (fee as f64) / 1_000_000_000.0, (priority_fee as f64) / 1_000_000_000.0);
+								}
+								fee
+							},
+							None => {
+								error!("Missing base fee in the eth_feeHistory response of {}", self.node.endpoint());
+								self.last_fee
+							},
+						},
+						Err(e) => {
+							error!("Error while fetching fee history: {:?}", e);
+							self.last_fee
+						},
+					};
+					self.last_fee = fee;
+					self.state = FeeHistoryState::Wait;
+					return Ok(Async::Ready(Some(fee)));
+				},
+			};
+			self.state = next_state;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use api::FeeHistory;
+	use super::fees;
+
+	#[test]
+	fn test_fees() {
+		let history = FeeHistory {
+			base_fee_per_gas: vec![90.into(), 100.into()],
+			reward: vec![vec![3.into()], vec![0.into()], vec![5.into()]],
+		};
+		assert_eq!(Some((205, 5)), fees(&history, 1, 1000));
+		assert_eq!(Some((150, 5)), fees(&history, 1, 150));
+
+		let empty = FeeHistory {
+			base_fee_per_gas: vec![100.into()],
+			reward: vec![vec![0.into()]],
+		};
+		assert_eq!(Some((201, 1)), fees(&empty, 1, 1000));
+		assert_eq!(None, fees(&FeeHistory { base_fee_per_gas: vec![], reward: vec![] }, 1, 1000));
+	}
+}
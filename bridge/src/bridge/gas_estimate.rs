@@ -0,0 +1,166 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::Duration;
+use futures::{Async, Future, Poll};
+use futures::future::{JoinAll, Then, join_all};
+use tokio_timer::{Timeout, Timer};
+use web3::{self, Transport};
+use web3::types::{Address, Bytes, U256};
+use api::{self, ApiCall};
+use config::TransactionConfig;
+use error::{Error, ErrorKind};
+use ledger::Transfer;
+use rpc_failure::RpcFailure;
+use super::BridgeChecked;
+
+/// Outcome of a single estimate, `None` if it's not known.
+type Settled = Result<Option<U256>, Error>;
+
+type Estimate<T> = Then<Timeout<ApiCall<U256, <T as Transport>::Out>>, Result<Settled, Error>, fn(Result<U256, Error>) -> Result<Settled, Error>>;
+
+/// An estimate the node fails, e.g. because the transaction would revert, fails the transaction.
+/// A failure every transaction of the bridge account would run into, e.g. insufficient funds, stops the bridge.
+/// If the node can't be reached the configured gas is used, so that a single call doesn't fail all of them.
+fn settle_estimate(result: Result<U256, Error>) -> Result<Settled, Error> {
+	let err = match result {
+		Ok(gas) => return Ok(Ok(Some(gas))),
+		Err(err) => err,
+	};
+	let failure = match *err.kind() {
+		ErrorKind::Web3(web3::error::Error(web3::error::ErrorKind::Rpc(ref rpc_err), _)) => Some(RpcFailure::classify(rpc_err)),
+		_ => None,
+	};
+	match failure {
+		Some(failure) if failure.is_account_failure() => Err(err),
+		Some(_) => Ok(Err(err)),
+		None => {
+			warn!("gas estimation failed, using the gas limit: {}", err);
+			Ok(Ok(None))
+		},
+	}
+}
+
+/// Gas of a transaction which is estimated to use `estimate`, raised by `multiplier` and capped at `cap`.
+fn gas_with_margin(estimate: U256, multiplier: f64, cap: U256) -> U256 {
+	let gas = U256::from((estimate.low_u64() as f64 * multiplier).ceil() as u64);
+	if gas > cap {
+		warn!("estimated gas {} is above the gas limit {}", gas, cap);
+	}
+	cmp::min(gas, cap)
+}
+
+/// Gas of transactions of a single kind, in the order of their payloads.
+///
+/// With `estimate_gas` it's the `eth_estimateGas` of each payload raised by `gas_multiplier`, or the node's error
+/// if it fails to estimate it. Otherwise, or if the node can't be reached, it's `gas`, which caps the estimates as well.
+pub struct GasEstimates<T: Transport> {
+	estimates: Option<JoinAll<Vec<Estimate<T>>>>,
+	count: usize,
+	cap: U256,
+	multiplier: f64,
+}
+
+pub fn estimate_gas<T: Transport>(transport: &T, timer: &Timer, request_timeout: Duration, from: Address, to: Address, config: &TransactionConfig, gas: u64, payloads: &[Bytes]) -> GasEstimates<T> {
+	let estimates = if config.estimate_gas {
+		let estimates = payloads.iter()
+			.map(|payload| timer.timeout(api::estimate_gas(transport.clone(), from, to, payload.clone()), request_timeout)
+				.then(settle_estimate as fn(Result<U256, Error>) -> Result<Settled, Error>))
+			.collect();
+		Some(join_all(estimates))
+	} else {
+		None
+	};
+
+	GasEstimates {
+		estimates,
+		count: payloads.len(),
+		cap: gas.into(),
+		multiplier: config.gas_multiplier,
+	}
+}
+
+impl<T: Transport> Future for GasEstimates<T> {
+	type Item = Vec<Result<U256, Error>>;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		let estimates = match self.estimates {
+			Some(ref mut estimates) => try_ready!(estimates.poll()),
+			None => return Ok(Async::Ready((0..self.count).map(|_| Ok(self.cap)).collect())),
+		};
+
+		let gas = estimates.into_iter()
+			.map(|estimate| estimate.map(|estimate| estimate.map_or(self.cap, |estimate| gas_with_margin(estimate, self.multiplier, self.cap))))
+			.collect();
+		Ok(Async::Ready(gas))
+	}
+}
+
+/// Keeps the transfers, in order, whose gas is known. The other ones are handed to the bridge through `queued` as failed attempts.
+/// Fails if the bridge account can't pay for all of the transactions with `balance`.
+pub fn sendable<P>(transfers: Vec<(Transfer, P)>, gas: Vec<Result<U256, Error>>, balance: U256, queued: &mut VecDeque<BridgeChecked>) -> Result<Vec<(Transfer, P, U256)>, Error> {
+	let mut required = U256::zero();
+	let mut kept = Vec::new();
+	for ((transfer, payload), gas) in transfers.into_iter().zip(gas) {
+		match gas {
+			Ok(gas) => {
+				required = required + gas * transfer.gas_price;
+				kept.push((transfer, payload, gas));
+			},
+			Err(err) => {
+				error!("estimating gas of {} {} failed: {}", transfer.kind, transfer.event, err);
+				queued.push_back(BridgeChecked::Failed(transfer.kind, transfer.event, format!("gas estimation failed: {}", err)));
+			},
+		}
+	}
+
+	if required > balance {
+		return Err(ErrorKind::InsufficientFunds.into());
+	}
+	Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::VecDeque;
+	use web3::types::U256;
+	use database::EventId;
+	use ledger::{Transfer, TransferKind};
+	use bridge::BridgeChecked;
+	use error::{Error, ErrorKind};
+	use super::{gas_with_margin, sendable};
+
+	#[test]
+	fn test_gas_with_margin() {
+		assert_eq!(U256::from(120_000), gas_with_margin(100_000.into(), 1.2, 200_000.into()));
+		assert_eq!(U256::from(150_000), gas_with_margin(100_000.into(), 1.2, 150_000.into()));
+		assert_eq!(U256::from(21_001), gas_with_margin(21_000.into(), 1.00001, 200_000.into()));
+	}
+
+	#[test]
+	fn test_sendable() {
+		let transfer = |n: u64| {
+			let event = EventId {
+				block_number: n,
+				transaction_hash: n.into(),
+				log_index: 0u64.into(),
+			};
+			Transfer::new(TransferKind::DepositRelay, event, n.into(), 0xaau64.into(), 1u64.into(), 10u64.into())
+		};
+		let (first, second, third) = (transfer(1), transfer(2), transfer(3));
+		let gas = || vec![Ok(U256::from(100)), Err(Error::from(ErrorKind::OtherError("reverted".into()))), Ok(U256::from(50))];
+
+		// the transfer whose gas is not known fails alone
+		let mut queued = VecDeque::new();
+		let kept = sendable(vec![(first, ()), (second, ()), (third, ())], gas(), 1500.into(), &mut queued).unwrap();
+		assert_eq!(vec![(first, (), U256::from(100)), (third, (), U256::from(50))], kept);
+		assert_eq!(vec![BridgeChecked::Failed(TransferKind::DepositRelay, second.event, "gas estimation failed: reverted".into())], Vec::from(queued));
+
+		// a balance too low for the transactions is not a failure of any of them
+		let mut queued = VecDeque::new();
+		match sendable(vec![(first, ()), (second, ()), (third, ())], gas(), 1499.into(), &mut queued) {
+			Err(Error(ErrorKind::InsufficientFunds, _)) => (),
+			result => panic!("expected insufficient funds, got {:?}", result.map(|kept| kept.len())),
+		}
+	}
+}
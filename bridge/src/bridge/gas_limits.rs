@@ -0,0 +1,245 @@
+use std::iter;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use futures::{Async, Future, Poll, Stream};
+use futures::future::{JoinAll, join_all};
+use tokio_timer::Timeout;
+use web3::Transport;
+use web3::types::{Address, Bytes, U256};
+use api::{self, ApiCall, LogStream};
+use app::App;
+use database::Database;
+use error::{Error, ErrorKind};
+use util::web3_filter;
+
+/// Gas limits of the relay transactions set in the bridge contracts, 0 until they are read.
+#[derive(Debug, Clone, Default)]
+pub struct GasLimits {
+	/// Limit of `ForeignBridge.deposit`, set in `ForeignBridge`.
+	pub deposit_relay: Arc<RwLock<u64>>,
+	/// Limit of `ForeignBridge.submitSignature`, set in `ForeignBridge`.
+	pub withdraw_confirm: Arc<RwLock<u64>>,
+	/// Limit of `HomeBridge.withdraw`, set in `HomeBridge`.
+	pub withdraw_relay: Arc<RwLock<u64>>,
+}
+
+/// Gas limit getter of a bridge contract and the limit it updates.
+struct GasLimit {
+	name: &'static str,
+	payload: Bytes,
+	value: Arc<RwLock<u64>>,
+	/// `gas` of the transactions, which overrides the limit.
+	configured: Option<u64>,
+}
+
+/// State of the gas limits of a bridge contract.
+enum GasLimitsState<T: Transport> {
+	/// Gas limits are about to be read.
+	Read,
+	/// Reading the gas limits in progress.
+	Reading(JoinAll<Vec<Timeout<ApiCall<Bytes, T::Out>>>>),
+	/// Waiting for `GasConsumptionLimitsUpdated`.
+	Wait,
+}
+
+/// Reads the gas limits of a bridge contract, and reads them again whenever the contract emits `GasConsumptionLimitsUpdated`.
+///
+/// Yields every time the limits are read.
+pub struct GasLimitsStream<T: Transport> {
+	app: Arc<App<T>>,
+	transport: T,
+	contract: Address,
+	request_timeout: Duration,
+	limits: Vec<GasLimit>,
+	logs: LogStream<T>,
+	loaded: bool,
+	state: GasLimitsState<T>,
+}
+
+pub fn create_home_gas_limits<T: Transport + Clone>(app: Arc<App<T>>, init: &Database, limits: &GasLimits) -> GasLimitsStream<T> {
+	let filter = app.home_bridge.events().gas_consumption_limits_updated().create_filter();
+	let logs_init = api::LogStreamInit {
+		after: init.checked_deposit_relay,
+		request_timeout: app.config.home.request_timeout,
+		poll_interval: app.config.home.poll_interval,
+		confirmations: app.config.home.required_confirmations,
+		max_block_range: app.config.home.max_block_range,
+		after_hash: init.checked_deposit_relay_hash,
+		heads: app.connections.home_heads.clone(),
+		finality: app.config.home.finality,
+		filter: web3_filter(filter, iter::once(init.home_contract_address)),
+	};
+	let log_quorum = api::LogQuorum {
+		endpoints: app.connections.home_log_quorum.clone(),
+		required: app.config.home.log_quorum,
+		max_attempts: app.config.home.log_quorum_max_attempts,
+	};
+
+	GasLimitsStream {
+		logs: api::quorum_log_stream(app.connections.home.clone(), log_quorum, app.timer.clone(), logs_init),
+		transport: app.connections.home.clone(),
+		contract: init.home_contract_address,
+		request_timeout: app.config.home.request_timeout,
+		limits: vec![
+			GasLimit {
+				name: "withdraw relay",
+				payload: app.home_bridge.functions().gas_limit_withdraw_relay().input().into(),
+				value: limits.withdraw_relay.clone(),
+				configured: app.config.txs.withdraw_relay.gas,
+			},
+		],
+		loaded: false,
+		state: GasLimitsState::Read,
+		app,
+	}
+}
+
+pub fn create_foreign_gas_limits<T: Transport + Clone>(app: Arc<App<T>>, init: &Database, limits: &GasLimits) -> GasLimitsStream<T> {
+	let filter = app.foreign_bridge.events().gas_consumption_limits_updated().create_filter();
+	let logs_init = api::LogStreamInit {
+		after: init.checked_withdraw_confirm,
+		request_timeout: app.config.foreign.request_timeout,
+		poll_interval: app.config.foreign.poll_interval,
+		confirmations: app.config.foreign.required_confirmations,
+		max_block_range: app.config.foreign.max_block_range,
+		after_hash: init.checked_withdraw_confirm_hash,
+		heads: app.connections.foreign_heads.clone(),
+		finality: app.config.foreign.finality,
+		filter: web3_filter(filter, iter::once(init.foreign_contract_address)),
+	};
+	let log_quorum = api::LogQuorum {
+		endpoints: app.connections.foreign_log_quorum.clone(),
+		required: app.config.foreign.log_quorum,
+		max_attempts: app.config.foreign.log_quorum_max_attempts,
+	};
+
+	GasLimitsStream {
+		logs: api::quorum_log_stream(app.connections.foreign.clone(), log_quorum, app.timer.clone(), logs_init),
+		transport: app.connections.foreign.clone(),
+		contract: init.foreign_contract_address,
+		request_timeout: app.config.foreign.request_timeout,
+		limits: vec![
+			GasLimit {
+				name: "deposit relay",
+				payload: app.foreign_bridge.functions().gas_limit_deposit_relay().input().into(),
+				value: limits.deposit_relay.clone(),
+				configured: app.config.txs.deposit_relay.gas,
+			},
+			GasLimit {
+				name: "withdraw confirm",
+				payload: app.foreign_bridge.functions().gas_limit_withdraw_confirm().input().into(),
+				value: limits.withdraw_confirm.clone(),
+				configured: app.config.txs.withdraw_confirm.gas,
+			},
+		],
+		loaded: false,
+		state: GasLimitsState::Read,
+		app,
+	}
+}
+
+/// Decodes the `uint256` returned by a gas limit getter.
+fn decode_gas_limit(data: &[u8]) -> Option<u64> {
+	if data.len() != 32 {
+		return None;
+	}
+	let limit = U256::from(data);
+	if limit > U256::from(u64::max_value()) {
+		return None;
+	}
+	Some(limit.low_u64())
+}
+
+impl<T: Transport> GasLimitsStream<T> {
+	/// Returns true once the gas limits have been read.
+	pub fn is_loaded(&self) -> bool {
+		self.loaded
+	}
+
+	fn update(&mut self, results: &[Bytes]) -> Result<(), Error> {
+		let decoded = results.iter()
+			.map(|data| decode_gas_limit(&data.0))
+			.collect::<Option<Vec<_>>>()
+			.ok_or_else(|| ErrorKind::OtherError(format!("{:?} doesn't have valid gas consumption limits", self.contract)))?;
+
+		for (limit, value) in self.limits.iter().zip(decoded) {
+			// the bridge doesn't run without knowing the gas of its transactions
+			if value == 0 && limit.configured.is_none() {
+				return Err(ErrorKind::ConfigError(format!("{} gas limit is not set in {:?} and no `gas` is configured for it", limit.name, self.contract)).into());
+			}
+
+			let mut current = limit.value.write().unwrap();
+			if *current != value || !self.loaded {
+				info!("{} gas limit: {}", limit.name, value);
+			}
+			*current = value;
+		}
+		self.loaded = true;
+		Ok(())
+	}
+}
+
+impl<T: Transport> Stream for GasLimitsStream<T> {
+	type Item = ();
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		loop {
+			let next_state = match self.state {
+				GasLimitsState::Read => {
+					let calls = self.limits.iter()
+						.map(|limit| self.app.timer.timeout(api::call(self.transport.clone(), self.contract, limit.payload.clone()), self.request_timeout))
+						.collect();
+					GasLimitsState::Reading(join_all(calls))
+				},
+				GasLimitsState::Reading(ref mut future) => {
+					let result = match future.poll() {
+						Ok(Async::NotReady) => return Ok(Async::NotReady),
+						Ok(Async::Ready(results)) => self.update(&results),
+						Err(err) => Err(err),
+					};
+					if let Err(err) = result {
+						let unset = match *err.kind() {
+							ErrorKind::ConfigError(_) => true,
+							_ => false,
+						};
+						if !self.loaded || unset {
+							return Err(err);
+						}
+						// limits read before are kept until they can be read again
+						error!("Error while reading gas limits: {:?}", err);
+					}
+					self.state = GasLimitsState::Wait;
+					return Ok(Async::Ready(Some(())));
+				},
+				GasLimitsState::Wait => {
+					let item = try_stream!(self.logs.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "polling for gas consumption limits updates")));
+					if item.logs.is_empty() {
+						continue;
+					}
+					info!("gas consumption limits of {:?} updated", self.contract);
+					GasLimitsState::Read
+				},
+			};
+			self.state = next_state;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::decode_gas_limit;
+
+	#[test]
+	fn test_decode_gas_limit() {
+		let mut word = vec![0u8; 32];
+		word[29] = 0x01;
+		word[30] = 0x86;
+		word[31] = 0xa0;
+		assert_eq!(Some(100_000), decode_gas_limit(&word));
+		assert_eq!(None, decode_gas_limit(&[]));
+
+		word[0] = 1;
+		assert_eq!(None, decode_gas_limit(&word));
+	}
+}
@@ -143,7 +143,8 @@ mod tests {
 	use super::*;
 	use error::{Error, ErrorKind};
 	use futures::{Async, future::{err, ok, FutureResult}};
-	use config::{Node, NodeInfo, DEFAULT_CONCURRENCY};
+	use config::{Node, NodeInfo, Finality, DEFAULT_CONCURRENCY, DEFAULT_RPC_MAX_FAILURES, DEFAULT_RPC_PRIMARY_RETRY_INTERVAL, DEFAULT_LOG_QUORUM_MAX_ATTEMPTS};
+	use config::{DEFAULT_GAS_PRICE_BUMP_BLOCKS, DEFAULT_GAS_PRICE_BUMP_PERCENT, DEFAULT_MAX_GAS_PRICE_WEI, DEFAULT_PRIORITY_FEE_WEI, TransactionType};
 	use tokio_timer::Timer;
 	use std::time::Duration;
 	use std::path::PathBuf;
@@ -168,8 +169,15 @@ mod tests {
 			request_timeout: Duration::from_secs(5),
 			poll_interval: Duration::from_secs(1),
 			required_confirmations: 0,
-			rpc_host: "https://rpc".into(),
+			rpc_host: Some("https://rpc".into()),
 			rpc_port: 443,
+			ipc_path: None,
+			fallback_rpc_urls: vec![],
+			rpc_max_failures: DEFAULT_RPC_MAX_FAILURES,
+			rpc_primary_retry_interval: Duration::from_secs(DEFAULT_RPC_PRIMARY_RETRY_INTERVAL),
+			log_quorum_rpc_urls: vec![],
+			log_quorum: 1,
+			log_quorum_max_attempts: DEFAULT_LOG_QUORUM_MAX_ATTEMPTS,
 			password: PathBuf::from("password"),
 			info: NodeInfo::default(),
 			gas_price_oracle_url: Some("https://gas.price".into()),
@@ -177,6 +185,13 @@ mod tests {
 			gas_price_timeout: Duration::from_secs(5),
 			default_gas_price: 15_000_000_000,
 			concurrent_http_requests: DEFAULT_CONCURRENCY,
+			max_block_range: None,
+			finality: Finality::Depth,
+			gas_price_bump_blocks: DEFAULT_GAS_PRICE_BUMP_BLOCKS,
+			gas_price_bump_percent: DEFAULT_GAS_PRICE_BUMP_PERCENT,
+			max_gas_price: DEFAULT_MAX_GAS_PRICE_WEI,
+			transaction_type: TransactionType::Legacy,
+			default_priority_fee: DEFAULT_PRIORITY_FEE_WEI,
 		};
 		let timer = Timer::default();
 		let mut stream = GasPriceStream::new_with_retriever(&node, ErroredRequest, &timer);
@@ -211,8 +226,15 @@ mod tests {
 			request_timeout: Duration::from_secs(5),
 			poll_interval: Duration::from_secs(1),
 			required_confirmations: 0,
-			rpc_host: "https://rpc".into(),
+			rpc_host: Some("https://rpc".into()),
 			rpc_port: 443,
+			ipc_path: None,
+			fallback_rpc_urls: vec![],
+			rpc_max_failures: DEFAULT_RPC_MAX_FAILURES,
+			rpc_primary_retry_interval: Duration::from_secs(DEFAULT_RPC_PRIMARY_RETRY_INTERVAL),
+			log_quorum_rpc_urls: vec![],
+			log_quorum: 1,
+			log_quorum_max_attempts: DEFAULT_LOG_QUORUM_MAX_ATTEMPTS,
 			password: PathBuf::from("password"),
 			info: NodeInfo::default(),
 			gas_price_oracle_url: Some("https://gas.price".into()),
@@ -220,6 +242,13 @@ mod tests {
 			gas_price_timeout: Duration::from_secs(5),
 			default_gas_price: 15_000_000_000,
 			concurrent_http_requests: DEFAULT_CONCURRENCY,
+			max_block_range: None,
+			finality: Finality::Depth,
+			gas_price_bump_blocks: DEFAULT_GAS_PRICE_BUMP_BLOCKS,
+			gas_price_bump_percent: DEFAULT_GAS_PRICE_BUMP_PERCENT,
+			max_gas_price: DEFAULT_MAX_GAS_PRICE_WEI,
+			transaction_type: TransactionType::Legacy,
+			default_priority_fee: DEFAULT_PRIORITY_FEE_WEI,
 		};
 		let timer = Timer::default();
 		let mut stream = GasPriceStream::new_with_retriever(&node, BadJson, &timer);
@@ -254,8 +283,15 @@ mod tests {
 			request_timeout: Duration::from_secs(5),
 			poll_interval: Duration::from_secs(1),
 			required_confirmations: 0,
-			rpc_host: "https://rpc".into(),
+			rpc_host: Some("https://rpc".into()),
 			rpc_port: 443,
+			ipc_path: None,
+			fallback_rpc_urls: vec![],
+			rpc_max_failures: DEFAULT_RPC_MAX_FAILURES,
+			rpc_primary_retry_interval: Duration::from_secs(DEFAULT_RPC_PRIMARY_RETRY_INTERVAL),
+			log_quorum_rpc_urls: vec![],
+			log_quorum: 1,
+			log_quorum_max_attempts: DEFAULT_LOG_QUORUM_MAX_ATTEMPTS,
 			password: PathBuf::from("password"),
 			info: NodeInfo::default(),
 			gas_price_oracle_url: Some("https://gas.price".into()),
@@ -263,6 +299,13 @@ mod tests {
 			gas_price_timeout: Duration::from_secs(5),
 			default_gas_price: 15_000_000_000,
 			concurrent_http_requests: DEFAULT_CONCURRENCY,
+			max_block_range: None,
+			finality: Finality::Depth,
+			gas_price_bump_blocks: DEFAULT_GAS_PRICE_BUMP_BLOCKS,
+			gas_price_bump_percent: DEFAULT_GAS_PRICE_BUMP_PERCENT,
+			max_gas_price: DEFAULT_MAX_GAS_PRICE_WEI,
+			transaction_type: TransactionType::Legacy,
+			default_priority_fee: DEFAULT_PRIORITY_FEE_WEI,
 		};
 		let timer = Timer::default();
 		let mut stream = GasPriceStream::new_with_retriever(&node, UnexpectedJson, &timer);
@@ -296,8 +339,15 @@ mod tests {
 			request_timeout: Duration::from_secs(5),
 			poll_interval: Duration::from_secs(1),
 			required_confirmations: 0,
-			rpc_host: "https://rpc".into(),
+			rpc_host: Some("https://rpc".into()),
 			rpc_port: 443,
+			ipc_path: None,
+			fallback_rpc_urls: vec![],
+			rpc_max_failures: DEFAULT_RPC_MAX_FAILURES,
+			rpc_primary_retry_interval: Duration::from_secs(DEFAULT_RPC_PRIMARY_RETRY_INTERVAL),
+			log_quorum_rpc_urls: vec![],
+			log_quorum: 1,
+			log_quorum_max_attempts: DEFAULT_LOG_QUORUM_MAX_ATTEMPTS,
 			password: PathBuf::from("password"),
 			info: NodeInfo::default(),
 			gas_price_oracle_url: Some("https://gas.price".into()),
@@ -305,6 +355,13 @@ mod tests {
 			gas_price_timeout: Duration::from_secs(5),
 			default_gas_price: 15_000_000_000,
 			concurrent_http_requests: DEFAULT_CONCURRENCY,
+			max_block_range: None,
+			finality: Finality::Depth,
+			gas_price_bump_blocks: DEFAULT_GAS_PRICE_BUMP_BLOCKS,
+			gas_price_bump_percent: DEFAULT_GAS_PRICE_BUMP_PERCENT,
+			max_gas_price: DEFAULT_MAX_GAS_PRICE_WEI,
+			transaction_type: TransactionType::Legacy,
+			default_priority_fee: DEFAULT_PRIORITY_FEE_WEI,
 		};
 		let timer = Timer::default();
 		let mut stream = GasPriceStream::new_with_retriever(&node, NonObjectJson, &timer);
@@ -338,8 +395,15 @@ mod tests {
 			request_timeout: Duration::from_secs(5),
 			poll_interval: Duration::from_secs(1),
 			required_confirmations: 0,
-			rpc_host: "https://rpc".into(),
+			rpc_host: Some("https://rpc".into()),
 			rpc_port: 443,
+			ipc_path: None,
+			fallback_rpc_urls: vec![],
+			rpc_max_failures: DEFAULT_RPC_MAX_FAILURES,
+			rpc_primary_retry_interval: Duration::from_secs(DEFAULT_RPC_PRIMARY_RETRY_INTERVAL),
+			log_quorum_rpc_urls: vec![],
+			log_quorum: 1,
+			log_quorum_max_attempts: DEFAULT_LOG_QUORUM_MAX_ATTEMPTS,
 			password: PathBuf::from("password"),
 			info: NodeInfo::default(),
 			gas_price_oracle_url: Some("https://gas.price".into()),
@@ -347,6 +411,13 @@ mod tests {
 			gas_price_timeout: Duration::from_secs(5),
 			default_gas_price: 15_000_000_000,
 			concurrent_http_requests: DEFAULT_CONCURRENCY,
+			max_block_range: None,
+			finality: Finality::Depth,
+			gas_price_bump_blocks: DEFAULT_GAS_PRICE_BUMP_BLOCKS,
+			gas_price_bump_percent: DEFAULT_GAS_PRICE_BUMP_PERCENT,
+			max_gas_price: DEFAULT_MAX_GAS_PRICE_WEI,
+			transaction_type: TransactionType::Legacy,
+			default_priority_fee: DEFAULT_PRIORITY_FEE_WEI,
 		};
 		let timer = Timer::default();
 		let mut stream = GasPriceStream::new_with_retriever(&node, CorrectJson, &timer);
@@ -0,0 +1,164 @@
+use std::sync::Arc;
+use futures::{Future, Poll};
+use futures::future::{JoinAll, Join, join_all};
+use tokio_timer::Timeout;
+use web3::Transport;
+use web3::types::{Address, Bytes, U256};
+use ethabi;
+use api::{self, ApiCall};
+use app::App;
+use config::Node;
+use database::Database;
+use error::{Error, ErrorKind};
+
+type ContractCalls<T> = JoinAll<Vec<Timeout<ApiCall<Bytes, <T as Transport>::Out>>>>;
+
+/// State of the database initialization.
+enum InitState<T: Transport> {
+	/// Initialization is waiting to happen.
+	Wait,
+	/// Fetching the code, the deployment block and a gas limit of both contracts.
+	FetchContracts {
+		future: Join<ContractCalls<T>, ContractCalls<T>>,
+	},
+}
+
+/// Builds a new database from the deployment data of existing bridge contracts.
+pub struct Init<T: Transport> {
+	app: Arc<App<T>>,
+	home_contract: Address,
+	foreign_contract: Address,
+	state: InitState<T>,
+}
+
+pub fn create_init<T: Transport + Clone>(app: Arc<App<T>>, home_contract: Address, foreign_contract: Address) -> Init<T> {
+	Init {
+		app,
+		home_contract,
+		foreign_contract,
+		state: InitState::Wait,
+	}
+}
+
+/// Calls made to a bridge contract, in the order expected by `deployed_at_block`.
+fn contract_calls<T: Transport + Clone>(app: &App<T>, transport: &T, node: &Node, address: Address, deployed_at_block: Vec<u8>, gas_limit: Vec<u8>) -> ContractCalls<T> {
+	join_all(vec![
+		app.timer.timeout(api::code(transport.clone(), address), node.request_timeout),
+		app.timer.timeout(api::call(transport.clone(), address, deployed_at_block.into()), node.request_timeout),
+		app.timer.timeout(api::call(transport.clone(), address, gas_limit.into()), node.request_timeout),
+	])
+}
+
+/// Checks that the contract at `address` is the expected bridge contract and returns the block it has been deployed at.
+///
+/// Both bridge contracts have a payable fallback, so calls to functions the contract doesn't have
+/// return no data rather than fail.
+fn deployed_at_block<D, G>(contract: &str, address: Address, results: &[Bytes], decode_deployed_at_block: D, decode_gas_limit: G) -> Result<u64, Error> where
+	D: FnOnce(&[u8]) -> ethabi::Result<U256>,
+	G: FnOnce(&[u8]) -> ethabi::Result<U256>,
+{
+	let not_a_bridge = |reason: &str| -> Error {
+		ErrorKind::OtherError(format!("{:?} is not a {} contract: {}", address, contract, reason)).into()
+	};
+
+	if results[0].0.is_empty() {
+		return Err(not_a_bridge("there is no code at the address"));
+	}
+
+	let block = decode_deployed_at_block(&results[1].0).map_err(|_| not_a_bridge("`deployedAtBlock` is not available"))?;
+	decode_gas_limit(&results[2].0).map_err(|_| not_a_bridge("gas consumption limits are not available"))?;
+	if block.is_zero() {
+		return Err(not_a_bridge("`deployedAtBlock` is not set"));
+	}
+
+	Ok(block.low_u64())
+}
+
+impl<T: Transport + Clone> Future for Init<T> {
+	type Item = Database;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		loop {
+			let next_state = match self.state {
+				InitState::Wait => {
+					let app = &self.app;
+					let home = contract_calls(app, &app.connections.home, &app.config.home, self.home_contract,
+						app.home_bridge.functions().deployed_at_block().input(),
+						app.home_bridge.functions().gas_limit_withdraw_relay().input());
+					let foreign = contract_calls(app, &app.connections.foreign, &app.config.foreign, self.foreign_contract,
+						app.foreign_bridge.functions().deployed_at_block().input(),
+						app.foreign_bridge.functions().gas_limit_deposit_relay().input());
+					InitState::FetchContracts {
+						future: home.join(foreign),
+					}
+				},
+				InitState::FetchContracts { ref mut future } => {
+					let (home, foreign) = try_ready!(future.poll());
+					let functions = (self.app.home_bridge.functions(), self.app.foreign_bridge.functions());
+					let home_deploy = deployed_at_block("HomeBridge", self.home_contract, &home,
+						|data| functions.0.deployed_at_block().output(data),
+						|data| functions.0.gas_limit_withdraw_relay().output(data))?;
+					let foreign_deploy = deployed_at_block("ForeignBridge", self.foreign_contract, &foreign,
+						|data| functions.1.deployed_at_block().output(data),
+						|data| functions.1.gas_limit_deposit_relay().output(data))?;
+
+					info!("HomeBridge deployed at block {}, ForeignBridge deployed at block {}", home_deploy, foreign_deploy);
+					let database = Database {
+						home_contract_address: self.home_contract,
+						foreign_contract_address: self.foreign_contract,
+						home_deploy: Some(home_deploy),
+						foreign_deploy: Some(foreign_deploy),
+						checked_deposit_relay: home_deploy,
+						checked_withdraw_relay: foreign_deploy,
+						checked_withdraw_confirm: foreign_deploy,
+						..Database::default()
+					};
+					return Ok(database.into());
+				},
+			};
+			self.state = next_state;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rustc_hex::FromHex;
+	use web3::types::Bytes;
+	use contracts::home;
+	use super::deployed_at_block;
+
+	fn check(results: &[Bytes]) -> Result<u64, String> {
+		let home = home::HomeBridge::default();
+		let functions = home.functions();
+		deployed_at_block("HomeBridge", 0x10u64.into(), results,
+			|data| functions.deployed_at_block().output(data),
+			|data| functions.gas_limit_withdraw_relay().output(data))
+			.map_err(|err| err.to_string())
+	}
+
+	fn word(n: u8) -> Bytes {
+		let mut word = vec![0u8; 32];
+		word[31] = n;
+		word.into()
+	}
+
+	#[test]
+	fn test_deployed_at_block() {
+		let code: Bytes = "6060".from_hex().unwrap().into();
+		assert_eq!(Ok(100), check(&[code, word(100), word(1)]));
+	}
+
+	#[test]
+	fn test_deployed_at_block_without_code() {
+		assert!(check(&[Bytes(vec![]), Bytes(vec![]), Bytes(vec![])]).unwrap_err().contains("no code"));
+	}
+
+	#[test]
+	fn test_deployed_at_block_not_a_bridge() {
+		let code: Bytes = "6060".from_hex().unwrap().into();
+		assert!(check(&[code.clone(), Bytes(vec![]), Bytes(vec![])]).unwrap_err().contains("deployedAtBlock"));
+		assert!(check(&[code, word(100), Bytes(vec![])]).unwrap_err().contains("gas consumption limits"));
+	}
+}
@@ -6,15 +6,25 @@ mod deposit_relay;
 mod withdraw_confirm;
 mod withdraw_relay;
 mod gas_price;
+mod event_batch;
+mod init;
+mod transaction_tracker;
+mod retry;
+mod fee_history;
+mod gas_estimate;
+mod gas_limits;
+mod simulation;
 
-use std::fs;
 use std::sync::{Arc, RwLock};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use futures::{Stream, Poll, Async};
 use web3::Transport;
-use web3::types::U256;
+use web3::types::{U256, H256};
 use app::App;
-use database::Database;
+use database::{Database, EventId};
+use config::{Transactions, TransactionType};
+use ledger::{Ledger, Transfer, TransferKind, TransferStatus, DeadLetterStatus, ledger_path};
 use error::{Error, ErrorKind};
 use tokio_core::reactor::Handle;
 
@@ -25,18 +35,65 @@ pub use self::deposit_relay::{DepositRelay, create_deposit_relay};
 pub use self::withdraw_relay::{WithdrawRelay, create_withdraw_relay};
 pub use self::withdraw_confirm::{WithdrawConfirm, create_withdraw_confirm};
 pub use self::gas_price::StandardGasPriceStream;
-
-/// Last block checked by the bridge components.
-#[derive(Clone, Copy)]
+pub use self::event_batch::EventBatch;
+pub use self::init::{Init, create_init};
+pub use self::transaction_tracker::{PendingTransactions, TransactionTracker, create_transaction_tracker};
+pub use self::retry::{Retries, RevertedEvents};
+pub use self::fee_history::FeeHistoryStream;
+pub use self::gas_limits::{GasLimits, GasLimitsStream, create_home_gas_limits, create_foreign_gas_limits};
+
+/// Progress of the bridge components: the last block (number and hash) checked,
+/// a single transfer handled within a block which is not fully checked yet,
+/// an event which doesn't need or can't be handled, or a change of a transaction sent by the bridge.
+#[derive(Debug, PartialEq, Clone)]
 pub enum BridgeChecked {
-	DepositRelay(u64),
-	WithdrawRelay(u64),
-	WithdrawConfirm(u64),
+	DepositRelay(u64, H256),
+	WithdrawRelay(u64, H256),
+	WithdrawConfirm(u64, H256),
+	Transfer(Transfer),
+	/// Event can't be handled for the given reason and goes straight to the dead letters.
+	DeadLetter(TransferKind, EventId, String),
+	/// Attempt to handle the event failed for the given reason, e.g. its transaction has been rejected.
+	/// The event is handled again until it fails `max_attempts` times.
+	Failed(TransferKind, EventId, String),
+	/// Event has been handled already, its transaction would revert.
+	Skipped(TransferKind, EventId),
+	/// Transaction with the given hash has been mined.
+	TransactionMined(H256),
+	/// Transaction with the given hash has been mined, but reverted.
+	TransactionReverted(H256),
+	/// Nonce of the transaction with the given hash has been used by another transaction, it will never be mined.
+	TransactionDropped(H256),
+	/// Stuck transaction has been replaced by one with a higher gas price.
+	TransactionReplaced(H256, H256, U256),
+}
+
+/// How often the ledger is checked for dead letters an operator asked to retry.
+const RETRY_REQUESTS_INTERVAL_SECS: u64 = 10;
+/// How long an event waits before it's handled again after its first failed attempt.
+/// The delay doubles with every further attempt.
+const RETRY_DELAY_SECS: u64 = 30;
+/// Longest an event waits before it's handled again.
+const MAX_RETRY_DELAY_SECS: u64 = 3600;
+
+/// How long an event which failed `attempts` times waits before it's handled again.
+fn retry_delay(attempts: u32) -> Duration {
+	let factor = 1u64.checked_shl(attempts.saturating_sub(1)).unwrap_or(u64::max_value());
+	Duration::from_secs(RETRY_DELAY_SECS.saturating_mul(factor).min(MAX_RETRY_DELAY_SECS))
 }
 
 pub struct Bridge<ES: Stream<Item = BridgeChecked>> {
-	path: PathBuf,
+	/// Database file, `None` in a dry run which doesn't save the progress.
+	path: Option<PathBuf>,
 	database: Database,
+	ledger: Ledger,
+	/// Reverted events handed back to the bridge components.
+	retries: Retries,
+	/// Retry policy of the events.
+	txs: Transactions,
+	/// How often and when the ledger has been last checked for dead letters to retry.
+	retry_requests_interval: Duration,
+	retry_requests_checked_at: Instant,
 	event_stream: ES,
 }
 
@@ -44,41 +101,196 @@ impl<ES: Stream<Item = BridgeChecked, Error = Error>> Stream for Bridge<ES> {
 	type Item = ();
 	type Error = Error;
 
+	/// Handles all events which are ready and saves the database once for all of them,
+	/// e.g. for every relayed event of a block and the block checkpoint.
 	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-		let check = try_stream!(self.event_stream.poll());
+		let mut changed = false;
+		if self.retry_requests_checked_at.elapsed() >= self.retry_requests_interval {
+			self.retry_requests_checked_at = Instant::now();
+			changed = self.requeue_dead_letters()?;
+		}
+
+		let polled = loop {
+			match self.event_stream.poll() {
+				Ok(Async::Ready(Some(check))) => {
+					self.handle(check)?;
+					changed = true;
+				},
+				other => break other,
+			}
+		};
+
+		// progress made before a failure is saved too
+		if changed {
+			self.save()?;
+		}
+
+		match polled? {
+			Async::Ready(None) => Ok(Async::Ready(None)),
+			_ if changed => Ok(Async::Ready(Some(()))),
+			_ => Ok(Async::NotReady),
+		}
+	}
+}
+
+impl<ES: Stream<Item = BridgeChecked>> Bridge<ES> {
+	/// Applies a single event to the database and the ledger.
+	fn handle(&mut self, check: BridgeChecked) -> Result<(), Error> {
 		match check {
-			BridgeChecked::DepositRelay(n) => {
+			BridgeChecked::DepositRelay(n, hash) => {
 				self.database.checked_deposit_relay = n;
+				self.database.checked_deposit_relay_hash = Some(hash);
+				self.database.relayed_deposits.retain(|event| event.block_number > n);
 			},
-			BridgeChecked::WithdrawRelay(n) => {
+			BridgeChecked::WithdrawRelay(n, hash) => {
 				self.database.checked_withdraw_relay = n;
+				self.database.checked_withdraw_relay_hash = Some(hash);
+				self.database.relayed_withdraws.retain(|event| event.block_number > n);
 			},
-			BridgeChecked::WithdrawConfirm(n) => {
+			BridgeChecked::WithdrawConfirm(n, hash) => {
 				self.database.checked_withdraw_confirm = n;
+				self.database.checked_withdraw_confirm_hash = Some(hash);
+				self.database.confirmed_withdraws.retain(|event| event.block_number > n);
+			},
+			// recorded before its transaction is sent, the event is not handled yet
+			BridgeChecked::Transfer(transfer) if transfer.status == TransferStatus::Pending => {
+				self.ledger.record(&transfer)?;
+			},
+			BridgeChecked::Transfer(transfer) => {
+				let (handled, reverted, _) = self.events(transfer.kind);
+				handled.push(transfer.event);
+				reverted.retain(|event| *event != transfer.event);
+				self.ledger.record(&transfer)?;
+			},
+			BridgeChecked::DeadLetter(kind, event, reason) => {
+				error!("{} event {} can't be handled ({}), moving it to the dead letters", kind, event, reason);
+				self.ledger.record_failure(kind, &event, &reason, 1)?;
+			},
+			BridgeChecked::Skipped(kind, event) => {
+				let (handled, reverted, _) = self.events(kind);
+				handled.push(event);
+				reverted.retain(|reverted| *reverted != event);
+			},
+			BridgeChecked::TransactionMined(hash) => {
+				self.ledger.update_status(&hash, TransferStatus::Mined)?;
+				if let Some(transfer) = self.ledger.transfer_sent_in(&hash)? {
+					self.ledger.clear_failures(transfer.kind, &transfer.event)?;
+				}
 			},
+			BridgeChecked::Failed(kind, event, reason) => {
+				self.failed(kind, event, &reason)?;
+			},
+			BridgeChecked::TransactionReverted(hash) => {
+				self.ledger.update_status(&hash, TransferStatus::Reverted)?;
+				match self.ledger.transfer_sent_in(&hash)? {
+					Some(transfer) => self.failed(transfer.kind, transfer.event, &format!("transaction {:?} reverted", hash))?,
+					None => warn!("reverted transaction {:?} is not in the ledger", hash),
+				}
+			},
+			// not a failure of the event, it's handled again right away
+			BridgeChecked::TransactionDropped(hash) => {
+				self.ledger.update_status(&hash, TransferStatus::Dropped)?;
+				match self.ledger.transfer_sent_in(&hash)? {
+					Some(transfer) => {
+						error!("transaction {:?} will never be mined, {} event {} will be handled again", hash, transfer.kind, transfer.event);
+						self.requeue(transfer.kind, transfer.event);
+					},
+					None => warn!("dropped transaction {:?} is not in the ledger", hash),
+				}
+			},
+			BridgeChecked::TransactionReplaced(old, new, gas_price) => {
+				self.ledger.replace_transaction(&old, &new, gas_price)?;
+			},
+		}
+		Ok(())
+	}
+
+	/// Records a failed attempt to handle `event` and hands it back to its bridge component once
+	/// its retry delay has passed, unless it failed too many times and becomes a dead letter.
+	fn failed(&mut self, kind: TransferKind, event: EventId, reason: &str) -> Result<(), Error> {
+		let max_attempts = self.max_attempts(kind);
+		let (status, attempts) = self.ledger.record_failure(kind, &event, reason, max_attempts)?;
+		let (handled, reverted, retry) = self.events(kind);
+		handled.retain(|handled| *handled != event);
+		if status == DeadLetterStatus::Dead {
+			error!("{} {}, moving event {} to the dead letters after {} attempts", kind, reason, event, max_attempts);
+			reverted.retain(|reverted| *reverted != event);
+		} else {
+			let delay = retry_delay(attempts);
+			error!("{} {}, event {} will be handled again in {} seconds", kind, reason, event, delay.as_secs());
+			if !reverted.contains(&event) {
+				reverted.push(event);
+			}
+			retry.push_after(event, delay);
+		}
+		Ok(())
+	}
+
+	fn save(&self) -> Result<(), Error> {
+		match self.path {
+			Some(ref path) => self.database.save(path),
+			None => Ok(()),
+		}
+	}
+
+	fn max_attempts(&self, kind: TransferKind) -> u32 {
+		match kind {
+			TransferKind::DepositRelay => self.txs.deposit_relay.max_attempts,
+			TransferKind::WithdrawRelay => self.txs.withdraw_relay.max_attempts,
+			TransferKind::WithdrawConfirm => self.txs.withdraw_confirm.max_attempts,
+		}
+	}
+
+	/// Hands the dead letters an operator asked to retry back to the bridge components.
+	/// Returns whether there were any.
+	fn requeue_dead_letters(&mut self) -> Result<bool, Error> {
+		let letters = self.ledger.take_retry_requests()?;
+		let requeued = !letters.is_empty();
+		for letter in letters {
+			info!("handling {} event {} again on request", letter.kind, letter.event);
+			self.requeue(letter.kind, letter.event);
+		}
+		Ok(requeued)
+	}
+
+	/// Hands `event` back to its bridge component right away.
+	fn requeue(&mut self, kind: TransferKind, event: EventId) {
+		let (handled, reverted, retry) = self.events(kind);
+		handled.retain(|handled| *handled != event);
+		if !reverted.contains(&event) {
+			reverted.push(event);
 		}
-		let file = fs::OpenOptions::new()
-			.write(true)
-			.create(true)
-			.open(&self.path)?;
+		retry.push(event);
+	}
 
-		self.database.save(file)?;
-		Ok(Async::Ready(Some(())))
+	/// Events of the given kind which are handled, events which reverted and the queue of events to retry.
+	fn events(&mut self, kind: TransferKind) -> (&mut Vec<EventId>, &mut Vec<EventId>, &RevertedEvents) {
+		match kind {
+			TransferKind::DepositRelay => (&mut self.database.relayed_deposits, &mut self.database.reverted_deposit_relays, &self.retries.deposit_relay),
+			TransferKind::WithdrawRelay => (&mut self.database.relayed_withdraws, &mut self.database.reverted_withdraw_relays, &self.retries.withdraw_relay),
+			TransferKind::WithdrawConfirm => (&mut self.database.confirmed_withdraws, &mut self.database.reverted_withdraw_confirms, &self.retries.withdraw_confirm),
+		}
 	}
 }
 
 
 /// Creates new bridge.
-pub fn create_bridge<'a, T: Transport + 'a + Clone>(app: Arc<App<T>>, init: &Database, handle: &Handle, home_chain_id: u64, foreign_chain_id: u64) -> Bridge<BridgeEventStream<'a, T>> {
-	Bridge {
-		path: app.database_path.clone(),
+pub fn create_bridge<'a, T: Transport + 'a + Clone>(app: Arc<App<T>>, init: &Database, ledger: Ledger, handle: &Handle, home_chain_id: u64, foreign_chain_id: u64) -> Result<Bridge<BridgeEventStream<'a, T>>, Error> {
+	let retries = Retries::from_database(init);
+	Ok(Bridge {
+		path: if app.dry_run { None } else { Some(app.database_path.clone()) },
 		database: init.clone(),
-		event_stream: create_bridge_event_stream(app, init, handle, home_chain_id, foreign_chain_id),
-	}
+		ledger,
+		retries: retries.clone(),
+		txs: app.config.txs.clone(),
+		retry_requests_interval: Duration::from_secs(RETRY_REQUESTS_INTERVAL_SECS),
+		retry_requests_checked_at: Instant::now(),
+		event_stream: create_bridge_event_stream(app, init, handle, home_chain_id, foreign_chain_id, retries)?,
+	})
 }
 
 /// Creates new bridge writing to custom backend.
-pub fn create_bridge_event_stream<'a, T: Transport + 'a + Clone>(app: Arc<App<T>>, init: &Database, handle: &Handle, home_chain_id: u64, foreign_chain_id: u64) -> BridgeEventStream<'a, T> {
+pub fn create_bridge_event_stream<'a, T: Transport + 'a + Clone>(app: Arc<App<T>>, init: &Database, handle: &Handle, home_chain_id: u64, foreign_chain_id: u64, retries: Retries) -> Result<BridgeEventStream<'a, T>, Error> {
 	let home_balance = Arc::new(RwLock::new(None));
 	let foreign_balance = Arc::new(RwLock::new(None));
 
@@ -96,19 +308,43 @@ pub fn create_bridge_event_stream<'a, T: Transport + 'a + Clone>(app: Arc<App<T>
 		None
 	};
 
+	// fees of EIP-1559 transactions come from the oracle if there is one,
+	// home always uses legacy transactions (see `Config::from_load_struct`)
+	let foreign_fee_stream = if app.config.foreign.transaction_type == TransactionType::Eip1559 && foreign_gas_stream.is_none() {
+		Some(FeeHistoryStream::new(app.connections.foreign.clone(), &app.config.foreign, &app.timer))
+	} else {
+		None
+	};
+
 	let home_gas_price = Arc::new(RwLock::new(app.config.home.default_gas_price));
 	let foreign_gas_price = Arc::new(RwLock::new(app.config.foreign.default_gas_price));
 
-	let deposit_relay = create_deposit_relay(app.clone(), init, foreign_balance.clone(), foreign_chain_id, foreign_gas_price.clone())
+	// transactions which are not mined yet are tracked again after a restart
+	let (home_pending, foreign_pending) = if app.dry_run {
+		(PendingTransactions::default(), PendingTransactions::default())
+	} else {
+		let path = ledger_path(&app.database_path);
+		(PendingTransactions::load(Ledger::open(&path)?, "home")?, PendingTransactions::load(Ledger::open(&path)?, "foreign")?)
+	};
+
+	let gas_limits = GasLimits::default();
+	let home_gas_limits = create_home_gas_limits(app.clone(), init, &gas_limits);
+	let foreign_gas_limits = create_foreign_gas_limits(app.clone(), init, &gas_limits);
+
+	let deposit_relay = create_deposit_relay(app.clone(), init, foreign_balance.clone(), foreign_chain_id, foreign_gas_price.clone(), gas_limits.deposit_relay.clone(), foreign_pending.clone(), retries.deposit_relay)
 		.map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "deposit_relay").into());
-	let withdraw_relay = create_withdraw_relay(app.clone(), init, home_balance.clone(), home_chain_id, home_gas_price.clone())
+	let withdraw_relay = create_withdraw_relay(app.clone(), init, home_balance.clone(), home_chain_id, gas_limits.withdraw_relay.clone(), home_pending.clone(), retries.withdraw_relay)
 		.map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "withdraw_relay").into());
-	let withdraw_confirm = create_withdraw_confirm(app.clone(), init, foreign_balance.clone(), foreign_chain_id, foreign_gas_price.clone())
+	let withdraw_confirm = create_withdraw_confirm(app.clone(), init, foreign_balance.clone(), foreign_chain_id, foreign_gas_price.clone(), gas_limits.withdraw_confirm.clone(), foreign_pending.clone(), retries.withdraw_confirm)
 		.map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "withdraw_confirm").into());
+	let home_tracker = create_transaction_tracker(app.clone(), app.connections.home.clone(), app.config.home.clone(), home_chain_id, home_pending, home_gas_price.clone())
+		.map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "home_transaction_tracker").into());
+	let foreign_tracker = create_transaction_tracker(app.clone(), app.connections.foreign.clone(), app.config.foreign.clone(), foreign_chain_id, foreign_pending, foreign_gas_price.clone())
+		.map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "foreign_transaction_tracker").into());
 
-	let bridge = Box::new(deposit_relay.select(withdraw_relay).select(withdraw_confirm));
+	let bridge = Box::new(deposit_relay.select(withdraw_relay).select(withdraw_confirm).select(home_tracker).select(foreign_tracker));
 
-	BridgeEventStream {
+	Ok(BridgeEventStream {
 		foreign_balance_check: create_balance_check(app.clone(), app.connections.foreign.clone(), app.config.foreign.clone()),
 		home_balance_check: create_balance_check(app.clone(), app.connections.home.clone(), app.config.home.clone()),
 		foreign_balance: foreign_balance.clone(),
@@ -118,9 +354,12 @@ pub fn create_bridge_event_stream<'a, T: Transport + 'a + Clone>(app: Arc<App<T>
 		running: app.running.clone(),
 		home_gas_stream,
 		foreign_gas_stream,
+		foreign_fee_stream,
 		home_gas_price,
 		foreign_gas_price,
-	}
+		home_gas_limits,
+		foreign_gas_limits,
+	})
 }
 
 enum BridgeStatus {
@@ -139,8 +378,11 @@ pub struct BridgeEventStream<'a, T: Transport + 'a> {
 	running: Arc<AtomicBool>,
 	home_gas_stream: Option<StandardGasPriceStream>,
 	foreign_gas_stream: Option<StandardGasPriceStream>,
+	foreign_fee_stream: Option<FeeHistoryStream<T>>,
 	home_gas_price: Arc<RwLock<u64>>,
 	foreign_gas_price: Arc<RwLock<u64>>,
+	home_gas_limits: GasLimitsStream<T>,
+	foreign_gas_limits: GasLimitsStream<T>,
 }
 
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -177,8 +419,26 @@ impl<'a, T: Transport + 'a> BridgeEventStream<'a, T> {
 			*foreign_price = try_bridge!(foreign_gas_stream.poll()).unwrap_or(*foreign_price);
 		}
 
+		if let Some(ref mut foreign_fee_stream) = self.foreign_fee_stream {
+			let mut foreign_price = self.foreign_gas_price.write().unwrap();
+			*foreign_price = try_bridge!(foreign_fee_stream.poll()).unwrap_or(*foreign_price);
+		}
+
 		Ok(Async::Ready(None))
 	}
+
+	/// Follows the gas limits set in the bridge contracts, ready once both of them have been read.
+	/// Fails if a limit the transactions depend on is not set when it's read first.
+	fn get_gas_limits(&mut self) -> Poll<Option<()>, Error> {
+		while let Async::Ready(Some(())) = self.home_gas_limits.poll()? {}
+		while let Async::Ready(Some(())) = self.foreign_gas_limits.poll()? {}
+
+		if self.home_gas_limits.is_loaded() && self.foreign_gas_limits.is_loaded() {
+			Ok(Async::Ready(None))
+		} else {
+			Ok(Async::NotReady)
+		}
+	}
 }
 
 impl<'a, T: Transport + 'a> Stream for BridgeEventStream<'a, T> {
@@ -193,6 +453,10 @@ impl<'a, T: Transport + 'a> Stream for BridgeEventStream<'a, T> {
 						Async::NotReady => return Ok(Async::NotReady),
 						_ => (),
 					}
+					match self.get_gas_limits()? {
+						Async::NotReady => return Ok(Async::NotReady),
+						_ => (),
+					}
 					BridgeStatus::Wait
 				},
 				BridgeStatus::Wait => {
@@ -201,6 +465,7 @@ impl<'a, T: Transport + 'a> Stream for BridgeEventStream<'a, T> {
 					}
 
 					let _ = self.get_gas_prices();
+					self.get_gas_limits()?;
 
 					let item = try_stream!(self.bridge.poll());
 					BridgeStatus::NextItem(Some(item))
@@ -221,38 +486,57 @@ impl<'a, T: Transport + 'a> Stream for BridgeEventStream<'a, T> {
 #[cfg(test)]
 mod tests {
 	extern crate tempdir;
+	use std::path::{Path, PathBuf};
+	use std::time::{Duration, Instant};
 	use self::tempdir::TempDir;
-	use database::Database;
-	use super::{Bridge, BridgeChecked};
-	use error::Error;
+	use database::{Database, EventId};
+	use ledger::{Ledger, LedgerQuery, Transfer, TransferKind, TransferStatus, DeadLetterStatus};
+	use config::Transactions;
+	use super::{Bridge, BridgeChecked, Retries, retry_delay};
+	use error::{Error, ErrorKind};
 	use tokio_core::reactor::Core;
 	use futures::{Stream, stream};
 
-	#[test]
-	fn test_database_updates() {
+	/// Database path in a temporary directory, which is removed once the returned `TempDir` is dropped.
+	fn database_path() -> (TempDir, PathBuf) {
 		let tempdir = TempDir::new("test_file_backend").unwrap();
-		let mut path = tempdir.path().to_owned();
-		path.push("db");
+		let path = tempdir.path().join("db");
+		(tempdir, path)
+	}
 
-		let bridge = Bridge {
-			path: path.clone(),
-			database: Database::default(),
-			event_stream: stream::iter_ok::<_, Error>(vec![BridgeChecked::DepositRelay(1)]),
-		};
+	/// Bridge saving to `path`, with an in-memory ledger and looking for retry requests on every poll.
+	fn test_bridge<S: Stream<Item = BridgeChecked>>(path: &Path, database: Database, txs: Transactions, event_stream: S) -> Bridge<S> {
+		Bridge {
+			path: Some(path.to_owned()),
+			database,
+			ledger: Ledger::open_in_memory().unwrap(),
+			retries: Retries::default(),
+			txs,
+			retry_requests_interval: Duration::from_secs(0),
+			retry_requests_checked_at: Instant::now(),
+			event_stream,
+		}
+	}
+
+	#[test]
+	fn test_database_updates() {
+		let (_tempdir, path) = database_path();
+		let bridge = test_bridge(&path, Database::default(), Transactions::default(), stream::iter_ok::<_, Error>(vec![BridgeChecked::DepositRelay(1, 1u64.into())]));
 
 		let mut event_loop = Core::new().unwrap();
 		let _ = event_loop.run(bridge.collect());
 
 		let db = Database::load(&path).unwrap();
 		assert_eq!(1, db.checked_deposit_relay);
+		assert_eq!(Some(1u64.into()), db.checked_deposit_relay_hash);
 		assert_eq!(0, db.checked_withdraw_confirm);
 		assert_eq!(0, db.checked_withdraw_relay);
 
-		let bridge = Bridge {
-			path: path.clone(),
-			database: Database::default(),
-			event_stream: stream::iter_ok::<_, Error>(vec![BridgeChecked::DepositRelay(2), BridgeChecked::WithdrawConfirm(3), BridgeChecked::WithdrawRelay(2)]),
-		};
+		let bridge = test_bridge(&path, Database::default(), Transactions::default(), stream::iter_ok::<_, Error>(vec![
+			BridgeChecked::DepositRelay(2, 2u64.into()),
+			BridgeChecked::WithdrawConfirm(3, 3u64.into()),
+			BridgeChecked::WithdrawRelay(2, 2u64.into()),
+		]));
 
 		let mut event_loop = Core::new().unwrap();
 		let _ = event_loop.run(bridge.collect());
@@ -261,5 +545,215 @@ mod tests {
 		assert_eq!(2, db.checked_deposit_relay);
 		assert_eq!(3, db.checked_withdraw_confirm);
 		assert_eq!(2, db.checked_withdraw_relay);
+		assert_eq!(Some(3u64.into()), db.checked_withdraw_confirm_hash);
+	}
+
+	#[test]
+	fn test_database_not_saved_without_path() {
+		let (_tempdir, path) = database_path();
+		let mut bridge = test_bridge(&path, Database::default(), Transactions::default(), stream::iter_ok::<_, Error>(vec![BridgeChecked::DepositRelay(1, 1u64.into())]));
+		bridge.path = None;
+
+		let mut event_loop = Core::new().unwrap();
+		assert!(event_loop.run(bridge.collect()).is_ok());
+		assert!(!path.exists());
+	}
+
+	#[test]
+	fn test_database_saved_before_failure() {
+		let (_tempdir, path) = database_path();
+
+		let bridge = test_bridge(&path, Database::default(), Transactions::default(), stream::iter_result(vec![
+			Ok(BridgeChecked::DepositRelay(1, 1u64.into())),
+			Ok(BridgeChecked::WithdrawRelay(2, 2u64.into())),
+			Err(Error::from(ErrorKind::OtherError("connection lost".into()))),
+		]));
+
+		let mut event_loop = Core::new().unwrap();
+		assert!(event_loop.run(bridge.collect()).is_err());
+
+		let db = Database::load(&path).unwrap();
+		assert_eq!(1, db.checked_deposit_relay);
+		assert_eq!(2, db.checked_withdraw_relay);
+	}
+
+	#[test]
+	fn test_database_records_events_until_block_checked() {
+		let (_tempdir, path) = database_path();
+
+		let event = |block_number: u64, n: u64| EventId {
+			block_number,
+			transaction_hash: n.into(),
+			log_index: 0u64.into(),
+		};
+		let transfer = |kind, event| BridgeChecked::Transfer(
+			Transfer::new(kind, event, 1u64.into(), 2u64.into(), 3u64.into(), 4u64.into()).sent(5u64.into(), 6u64.into())
+		);
+
+		let mut bridge = test_bridge(&path, Database::default(), Transactions::default(), stream::iter_ok::<_, Error>(vec![
+			transfer(TransferKind::DepositRelay, event(2, 1)),
+			transfer(TransferKind::DepositRelay, event(3, 2)),
+			transfer(TransferKind::WithdrawConfirm, event(2, 3)),
+		]));
+
+		let mut event_loop = Core::new().unwrap();
+		let _ = event_loop.run(bridge.by_ref().collect());
+		assert_eq!(3, bridge.ledger.query(&LedgerQuery::default()).unwrap().len());
+
+		let db = Database::load(&path).unwrap();
+		assert_eq!(vec![event(2, 1), event(3, 2)], db.relayed_deposits);
+		assert_eq!(vec![event(2, 3)], db.confirmed_withdraws);
+		assert!(db.relayed_withdraws.is_empty());
+
+		let bridge = test_bridge(&path, db, Transactions::default(), stream::iter_ok::<_, Error>(vec![BridgeChecked::DepositRelay(2, 2u64.into())]));
+
+		let mut event_loop = Core::new().unwrap();
+		let _ = event_loop.run(bridge.collect());
+
+		let db = Database::load(&path).unwrap();
+		assert_eq!(2, db.checked_deposit_relay);
+		assert_eq!(vec![event(3, 2)], db.relayed_deposits);
+		assert_eq!(vec![event(2, 3)], db.confirmed_withdraws);
+	}
+
+	#[test]
+	fn test_reverted_transfer_is_kept_for_retry() {
+		let (_tempdir, path) = database_path();
+
+		let event = EventId {
+			block_number: 2,
+			transaction_hash: 1u64.into(),
+			log_index: 0u64.into(),
+		};
+		let transfer = Transfer::new(TransferKind::DepositRelay, event, 1u64.into(), 2u64.into(), 3u64.into(), 4u64.into())
+			.sent(5u64.into(), 6u64.into());
+
+		let mut bridge = test_bridge(&path, Database::default(), Transactions::default(), stream::iter_ok::<_, Error>(vec![
+			BridgeChecked::Transfer(transfer),
+			BridgeChecked::TransactionReverted(5u64.into()),
+		]));
+
+		let mut event_loop = Core::new().unwrap();
+		let _ = event_loop.run(bridge.by_ref().collect());
+		let transfers = bridge.ledger.query(&LedgerQuery::default()).unwrap();
+		assert_eq!(TransferStatus::Reverted, transfers[0].status);
+
+		let db = Database::load(&path).unwrap();
+		assert!(db.relayed_deposits.is_empty());
+		assert_eq!(vec![event], db.reverted_deposit_relays);
+
+		// relayed again
+		let bridge = test_bridge(&path, db, Transactions::default(), stream::iter_ok::<_, Error>(vec![BridgeChecked::Transfer(transfer.sent(7u64.into(), 8u64.into()))]));
+
+		let mut event_loop = Core::new().unwrap();
+		let _ = event_loop.run(bridge.collect());
+
+		let db = Database::load(&path).unwrap();
+		assert_eq!(vec![event], db.relayed_deposits);
+		assert!(db.reverted_deposit_relays.is_empty());
+	}
+
+	#[test]
+	fn test_dropped_transfer_is_handled_again_without_counting_an_attempt() {
+		let (_tempdir, path) = database_path();
+
+		let event = EventId {
+			block_number: 2,
+			transaction_hash: 1u64.into(),
+			log_index: 0u64.into(),
+		};
+		let transfer = Transfer::new(TransferKind::DepositRelay, event, 1u64.into(), 2u64.into(), 3u64.into(), 4u64.into())
+			.sent(5u64.into(), 6u64.into());
+
+		let mut bridge = test_bridge(&path, Database::default(), Transactions::default(), stream::iter_ok::<_, Error>(vec![
+			BridgeChecked::Transfer(transfer),
+			BridgeChecked::TransactionDropped(5u64.into()),
+		]));
+
+		let mut event_loop = Core::new().unwrap();
+		let _ = event_loop.run(bridge.by_ref().collect());
+		let transfers = bridge.ledger.query(&LedgerQuery::default()).unwrap();
+		assert_eq!(TransferStatus::Dropped, transfers[0].status);
+		assert!(bridge.ledger.dead_letters().unwrap().is_empty());
+
+		let db = Database::load(&path).unwrap();
+		assert!(db.relayed_deposits.is_empty());
+		assert_eq!(vec![event], db.reverted_deposit_relays);
+	}
+
+	#[test]
+	fn test_transfer_reverted_too_often_is_dead_letter() {
+		let (_tempdir, path) = database_path();
+
+		let event = EventId {
+			block_number: 2,
+			transaction_hash: 1u64.into(),
+			log_index: 0u64.into(),
+		};
+		let transfer = Transfer::new(TransferKind::DepositRelay, event, 1u64.into(), 2u64.into(), 3u64.into(), 4u64.into())
+			.sent(5u64.into(), 6u64.into());
+		let mut txs = Transactions::default();
+		txs.deposit_relay.max_attempts = 1;
+
+		let mut bridge = test_bridge(&path, Database::default(), txs, stream::iter_ok::<_, Error>(vec![
+			BridgeChecked::Transfer(transfer),
+			BridgeChecked::TransactionReverted(5u64.into()),
+		]));
+
+		let mut event_loop = Core::new().unwrap();
+		let _ = event_loop.run(bridge.by_ref().collect());
+		let letters = bridge.ledger.dead_letters().unwrap();
+		assert_eq!(1, letters.len());
+		assert_eq!(DeadLetterStatus::Dead, letters[0].status);
+
+		let db = Database::load(&path).unwrap();
+		assert!(db.relayed_deposits.is_empty());
+		assert!(db.reverted_deposit_relays.is_empty());
+
+		// handled again once an operator asks for it
+		assert!(bridge.ledger.retry_dead_letter(letters[0].id).unwrap());
+		bridge.event_stream = stream::iter_ok::<_, Error>(vec![BridgeChecked::DepositRelay(1, 1u64.into())]);
+		let _ = event_loop.run(bridge.by_ref().collect());
+
+		let db = Database::load(&path).unwrap();
+		assert_eq!(vec![event], db.reverted_deposit_relays);
+		assert_eq!(DeadLetterStatus::Retrying, bridge.ledger.dead_letters().unwrap()[0].status);
+	}
+
+	#[test]
+	fn test_failed_attempts_are_counted() {
+		let (_tempdir, path) = database_path();
+
+		let event = EventId {
+			block_number: 2,
+			transaction_hash: 1u64.into(),
+			log_index: 0u64.into(),
+		};
+		let failed = || BridgeChecked::Failed(TransferKind::DepositRelay, event, "gas estimation failed".into());
+		let mut txs = Transactions::default();
+		txs.deposit_relay.max_attempts = 2;
+
+		let mut bridge = test_bridge(&path, Database::default(), txs, stream::iter_ok::<_, Error>(vec![failed()]));
+
+		let mut event_loop = Core::new().unwrap();
+		let _ = event_loop.run(bridge.by_ref().collect());
+		let letters = bridge.ledger.dead_letters().unwrap();
+		assert_eq!((1, DeadLetterStatus::Retrying), (letters[0].attempts, letters[0].status));
+		assert_eq!(vec![event], Database::load(&path).unwrap().reverted_deposit_relays);
+
+		bridge.event_stream = stream::iter_ok::<_, Error>(vec![failed()]);
+		let _ = event_loop.run(bridge.by_ref().collect());
+		let letters = bridge.ledger.dead_letters().unwrap();
+		assert_eq!((2, DeadLetterStatus::Dead, "gas estimation failed"), (letters[0].attempts, letters[0].status, letters[0].reason.as_str()));
+		assert!(Database::load(&path).unwrap().reverted_deposit_relays.is_empty());
+	}
+
+	#[test]
+	fn test_retry_delay_grows_with_attempts() {
+		assert_eq!(Duration::from_secs(30), retry_delay(1));
+		assert_eq!(Duration::from_secs(60), retry_delay(2));
+		assert_eq!(Duration::from_secs(240), retry_delay(4));
+		assert_eq!(Duration::from_secs(3600), retry_delay(8));
+		assert_eq!(Duration::from_secs(3600), retry_delay(100));
 	}
 }
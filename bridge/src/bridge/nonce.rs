@@ -1,21 +1,24 @@
-use futures::{Future, Async, Poll, future::{MapErr}};
+use futures::{Future, Async, Poll, future::{self, Either, FutureResult, MapErr}};
 use tokio_timer::Timeout;
 use web3::{self, Transport};
 use web3::types::{U256, H256, Bytes};
 use ethcore_transaction::Transaction;
+use keccak_hash::keccak;
+use rustc_hex::ToHex;
 use api::{self, ApiCall};
 use error::{Error, ErrorKind};
 use config::Node;
-use transaction::prepare_raw_transaction;
+use transaction::{priority_fee, sign_transaction};
+use signer::SignFuture;
 use app::App;
 use std::sync::Arc;
-use rpc;
+use rpc_failure::RpcFailure;
 
 /// State of balance checking.
 enum NonceCheckState<T: Transport, S: TransactionSender> {
 	/// Ready
 	Ready,
-	/// Ready to request nonce
+	/// Ready to request the number of transactions the node knows
 	Reacquire,
 	/// Nonce request is in progress.
 	NonceRequest {
@@ -23,9 +26,15 @@ enum NonceCheckState<T: Transport, S: TransactionSender> {
 	},
 	/// Nonce available
 	Nonce(U256),
+	/// Signing the transaction with the nonce.
+	Sign {
+		future: SignFuture<Bytes>,
+		nonce: U256,
+	},
 	/// Transaction is in progress
 	TransactionRequest {
 		future: Timeout<S::Future>,
+		raw: Bytes,
 	},
 }
 
@@ -35,6 +44,8 @@ pub struct NonceCheck<T: Transport, S: TransactionSender> {
 	state: NonceCheckState<T, S>,
 	node: Node,
 	transaction: Transaction,
+	/// Priority fee of the transaction if it's an EIP-1559 one.
+	priority_fee: Option<U256>,
 	chain_id: u64,
 	sender: S,
 }
@@ -55,27 +66,32 @@ pub fn send_transaction_with_nonce<T: Transport + Clone, S: TransactionSender>(t
 		transport,
 		node,
 		transaction,
+		priority_fee: None,
 		chain_id,
 		sender,
 	}
 }
 
+/// Transaction sent by `NonceCheck`.
+pub struct SentTransaction<R> {
+	/// Transaction as it has been sent, with its nonce set.
+	pub transaction: Transaction,
+	/// Signed transaction.
+	pub raw: Bytes,
+	/// Priority fee of an EIP-1559 transaction.
+	pub priority_fee: Option<U256>,
+	/// Result of the `TransactionSender`.
+	pub result: R,
+}
+
 impl<T: Transport, S: TransactionSender> Future for NonceCheck<T, S> {
-	type Item = S::T;
+	type Item = SentTransaction<S::T>;
 	type Error = Error;
 
 	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
 		loop {
 			let next_state = match self.state {
-				NonceCheckState::Ready => {
-					let result = NonceCheckState::Nonce(self.node.info.nonce.read().unwrap().clone());
-					{
-						let mut node_nonce = self.node.info.nonce.write().unwrap();
-						let (next, _) = node_nonce.overflowing_add(U256::one());
-						*node_nonce = next;
-					}
-					result
-				},
+				NonceCheckState::Ready => NonceCheckState::Nonce(self.node.info.nonces.next()),
 				NonceCheckState::Reacquire => {
 					NonceCheckState::NonceRequest {
 						future: self.app.timer.timeout(api::eth_get_transaction_count(&self.transport, self.node.account, None),
@@ -83,38 +99,88 @@ impl<T: Transport, S: TransactionSender> Future for NonceCheck<T, S> {
 					}
 				},
 				NonceCheckState::NonceRequest { ref mut future } => {
-					let nonce = try_ready!(future.poll());
-					let mut node_nonce = self.node.info.nonce.write().unwrap();
-					*node_nonce = nonce;
-					NonceCheckState::Nonce(nonce)
+					let count = try_ready!(future.poll());
+					let unknown = self.node.info.nonces.sync(count);
+					if !unknown.is_zero() {
+						warn!("{} transactions from {} not sent by the bridge found on {}", unknown, self.node.account, self.node.endpoint());
+					}
+					NonceCheckState::Ready
 				},
-				NonceCheckState::Nonce(mut nonce) => {
+				NonceCheckState::Nonce(nonce) => {
 					self.transaction.nonce = nonce;
-					match prepare_raw_transaction(self.transaction.clone(), &self.app, &self.node, self.chain_id) {
-						Ok(tx) => NonceCheckState::TransactionRequest {
-							future: self.app.timer.timeout(self.sender.send(tx), self.node.request_timeout)
+					self.priority_fee = priority_fee(&self.node);
+					NonceCheckState::Sign {
+						future: sign_transaction(self.transaction.clone(), self.priority_fee, &self.app, &self.node, self.chain_id),
+						nonce,
+					}
+				},
+				NonceCheckState::Sign { ref mut future, nonce } => {
+					match future.poll() {
+						Ok(Async::Ready(tx)) => NonceCheckState::TransactionRequest {
+							future: self.app.timer.timeout(self.sender.send(tx.clone()), self.node.request_timeout),
+							raw: tx,
+						},
+						Ok(Async::NotReady) => return Ok(Async::NotReady),
+						Err(e) => {
+							self.node.info.nonces.release(nonce);
+							return Err(e);
 						},
-						Err(e) => return Err(e),
 					}
 				},
-				NonceCheckState::TransactionRequest { ref mut future } => {
+				NonceCheckState::TransactionRequest { ref mut future, ref raw } => {
 					match future.poll() {
-						Ok(Async::Ready(t)) => return Ok(Async::Ready(t)),
+						Ok(Async::Ready(result)) => return Ok(Async::Ready(SentTransaction {
+							transaction: self.transaction.clone(),
+							raw: raw.clone(),
+							priority_fee: self.priority_fee,
+							result,
+						})),
 						Ok(Async::NotReady) => return Ok(Async::NotReady),
 						Err(e) => match e {
 							Error(ErrorKind::Web3(web3::error::Error(web3::error::ErrorKind::Rpc(rpc_err), _)), _) => {
-								if rpc_err.code == rpc::ErrorCode::ServerError(-32010) && rpc_err.message.ends_with("incrementing the nonce.") {
-									// restart the process
+								let failure = RpcFailure::classify(&rpc_err);
+								if failure.is_nonce_used() {
+									// someone else used the nonce, send the transaction with another one
+									warn!("nonce {} of {} is already used on {}, sending the transaction again", self.transaction.nonce, self.node.account, self.node.endpoint());
+									self.node.info.nonces.skip(self.transaction.nonce);
+									NonceCheckState::Reacquire
+								} else if failure == RpcFailure::ReplacementUnderpriced {
+									// most likely a transaction of the bridge whose send seemed to fail, so the nonce
+									// stays in flight until it's mined, and the node tells which nonce is free
+									warn!("nonce {} of {} is taken by a pending transaction on {}, sending the transaction again", self.transaction.nonce, self.node.account, self.node.endpoint());
 									NonceCheckState::Reacquire
-								} else if rpc_err.code == rpc::ErrorCode::ServerError(-32010) && rpc_err.message.ends_with("already imported.") {
-									let hash = self.transaction.hash(Some(self.chain_id));
-									info!("{} already imported on {}, skipping", hash, self.node.rpc_host);
-									return Ok(Async::Ready(self.sender.ignore(hash)))
+								} else if failure == RpcFailure::AlreadyKnown {
+									let hash = keccak(&raw.0);
+									info!("{} already imported on {}, skipping", hash, self.node.endpoint());
+									return Ok(Async::Ready(SentTransaction {
+										transaction: self.transaction.clone(),
+										raw: raw.clone(),
+										priority_fee: self.priority_fee,
+										result: self.sender.ignore(hash),
+									}))
 								} else {
+									// the node answered with an error, so it didn't accept the transaction
+									self.node.info.nonces.release(self.transaction.nonce);
 									return Err(ErrorKind::Web3(web3::error::ErrorKind::Rpc(rpc_err).into()).into());
 								}
 							},
-							e => return Err(From::from(e)),
+							e => {
+								// the transaction might have reached the node before the request timed out or the connection failed,
+								// so the nonce stays in flight until the transaction is mined or the nonce is used by another one
+								let hash = keccak(&raw.0);
+								match self.sender.unconfirmed(hash) {
+									Some(result) => {
+										warn!("sending {:?} to {} failed, tracking it in case it has been received: {}", hash, self.node.endpoint(), e);
+										return Ok(Async::Ready(SentTransaction {
+											transaction: self.transaction.clone(),
+											raw: raw.clone(),
+											priority_fee: self.priority_fee,
+											result,
+										}))
+									},
+									None => return Err(From::from(e)),
+								}
+							},
 						},
 					}
 				},
@@ -129,22 +195,38 @@ pub trait TransactionSender {
 	type Future : Future<Item = Self::T, Error = Error>;
 	fn send(&self, tx: Bytes) -> Self::Future;
 	fn ignore(&self, hash: H256) -> Self::T;
+	/// Result of a transaction which might not have reached the node, `None` if it can't be known without it.
+	fn unconfirmed(&self, hash: H256) -> Option<Self::T>;
 }
 
-pub struct SendRawTransaction<T: Transport>(pub T);
+/// Sends transactions with `eth_sendRawTransaction`, or only logs them in a dry run.
+pub struct SendRawTransaction<T: Transport> {
+	pub transport: T,
+	/// Only logs the transactions instead of sending them.
+	pub dry_run: bool,
+}
 
 impl<T: Transport + Clone> TransactionSender for SendRawTransaction<T> {
 	type T = H256;
-	type Future = ApiCall<Self::T, T::Out>;
+	type Future = Either<ApiCall<Self::T, T::Out>, FutureResult<Self::T, Error>>;
 
 	fn send(&self, tx: Bytes) -> <Self as TransactionSender>::Future {
-		api::send_raw_transaction(self.0.clone(), tx)
+		if self.dry_run {
+			let hash = keccak(&tx.0);
+			info!("dry run, transaction {:?} not sent: 0x{}", hash, tx.0.to_hex());
+			return Either::B(future::ok(hash));
+		}
+		Either::A(api::send_raw_transaction(self.transport.clone(), tx))
 	}
 
 	fn ignore(&self, hash: H256) -> Self::T {
 		hash
 	}
 
+	fn unconfirmed(&self, hash: H256) -> Option<Self::T> {
+		Some(hash)
+	}
+
 }
 
 use std::time::Duration;
@@ -167,6 +249,10 @@ impl<T: Transport + Clone> TransactionSender for TransactionWithConfirmation<T>
 		receipt
 	}
 
+	fn unconfirmed(&self, _hash: H256) -> Option<Self::T> {
+		None
+	}
+
 }
 
 fn web3_error_to_error(err: web3::Error) -> Error {
@@ -0,0 +1,188 @@
+use std::cmp;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures::{Async, Future, Poll, Stream};
+use futures::task::{self, Task};
+use futures::future::{JoinAll, join_all};
+use tokio_timer::{Sleep, Timeout, Timer};
+use web3::Transport;
+use web3::types::{FilterBuilder, Log};
+use api::{self, ApiCall};
+use database::{Database, EventId};
+use error::Error;
+
+/// Events whose transactions reverted, shared between the `Bridge` which learns about them
+/// and the component which handles them again.
+#[derive(Clone, Default)]
+pub struct RevertedEvents {
+	inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+	events: Vec<EventId>,
+	/// Events handed back once the given time has come.
+	delayed: Vec<(Instant, EventId)>,
+	/// Components waiting for events to handle again.
+	tasks: Vec<Task>,
+}
+
+impl RevertedEvents {
+	fn new(events: &[EventId]) -> Self {
+		RevertedEvents {
+			inner: Arc::new(Mutex::new(Inner {
+				events: events.to_vec(),
+				delayed: Vec::new(),
+				tasks: Vec::new(),
+			})),
+		}
+	}
+
+	pub fn push(&self, event: EventId) {
+		let mut inner = self.inner.lock().expect("reverted events lock poisoned");
+		inner.delayed.retain(|&(_, delayed)| delayed != event);
+		inner.events.push(event);
+		for task in inner.tasks.drain(..) {
+			task.notify();
+		}
+	}
+
+	/// Hands `event` back once `delay` has passed, so that an event which keeps failing is not handled again right away.
+	pub fn push_after(&self, event: EventId, delay: Duration) {
+		let mut inner = self.inner.lock().expect("reverted events lock poisoned");
+		inner.delayed.retain(|&(_, delayed)| delayed != event);
+		inner.delayed.push((Instant::now() + delay, event));
+		for task in inner.tasks.drain(..) {
+			task.notify();
+		}
+	}
+
+	/// Takes all events which are due, together with the time the next delayed event is due at.
+	/// If there are none, the current task is woken up by the next push.
+	fn take(&self) -> (Vec<EventId>, Option<Instant>) {
+		let mut inner = self.inner.lock().expect("reverted events lock poisoned");
+		let now = Instant::now();
+		let (due, delayed): (Vec<_>, Vec<_>) = mem::replace(&mut inner.delayed, Vec::new())
+			.into_iter()
+			.partition(|&(at, _)| at <= now);
+		inner.delayed = delayed;
+		inner.events.extend(due.into_iter().map(|(_, event)| event));
+		if inner.events.is_empty() && !inner.tasks.iter().any(|task| task.will_notify_current()) {
+			inner.tasks.push(task::current());
+		}
+		let next = inner.delayed.iter().map(|&(at, _)| at).min();
+		(inner.events.drain(..).collect(), next)
+	}
+}
+
+/// Reverted events of all bridge components.
+#[derive(Clone, Default)]
+pub struct Retries {
+	pub deposit_relay: RevertedEvents,
+	pub withdraw_relay: RevertedEvents,
+	pub withdraw_confirm: RevertedEvents,
+}
+
+impl Retries {
+	/// Events which reverted before the bridge has been restarted.
+	pub fn from_database(database: &Database) -> Self {
+		Retries {
+			deposit_relay: RevertedEvents::new(&database.reverted_deposit_relays),
+			withdraw_relay: RevertedEvents::new(&database.reverted_withdraw_relays),
+			withdraw_confirm: RevertedEvents::new(&database.reverted_withdraw_confirms),
+		}
+	}
+}
+
+/// Fetches the logs of reverted events again, so that they can be handled like new ones.
+///
+/// The stream never ends. It's not ready while there is nothing to retry
+/// and is woken up once a reverted event is pushed or a delayed one is due.
+pub struct RetryLogs<T: Transport> {
+	transport: T,
+	timer: Timer,
+	request_timeout: Duration,
+	filter: FilterBuilder,
+	reverted: RevertedEvents,
+	/// Wakes the stream up to look for delayed events which are due.
+	sleep: Option<Sleep>,
+	fetch: Option<(Vec<EventId>, JoinAll<Vec<Timeout<ApiCall<Vec<Log>, T::Out>>>>)>,
+}
+
+pub fn retry_logs<T: Transport>(transport: T, timer: Timer, request_timeout: Duration, filter: FilterBuilder, reverted: RevertedEvents) -> RetryLogs<T> {
+	RetryLogs {
+		transport,
+		timer,
+		request_timeout,
+		filter,
+		reverted,
+		sleep: None,
+		fetch: None,
+	}
+}
+
+impl<T: Transport> Stream for RetryLogs<T> {
+	type Item = Vec<Log>;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		while self.fetch.is_none() {
+			let (events, next) = self.reverted.take();
+			if events.is_empty() {
+				let next = match next {
+					Some(next) => next,
+					None => return Ok(Async::NotReady),
+				};
+				if self.sleep.is_none() {
+					// the timer can't sleep longer than the request timeout, if it's not enough it sleeps again
+					let now = Instant::now();
+					let delay = if next > now { next - now } else { Duration::from_secs(0) };
+					self.sleep = Some(self.timer.sleep(cmp::min(delay, self.request_timeout)));
+				}
+				try_ready!(self.sleep.as_mut().expect("sleep has been set above; qed").poll());
+				self.sleep = None;
+				continue;
+			}
+
+			let requests = events.iter()
+				.map(|event| {
+					let filter = self.filter.clone()
+						.from_block(event.block_number.into())
+						.to_block(event.block_number.into())
+						.build();
+					self.timer.timeout(api::logs(&self.transport, &filter), self.request_timeout)
+				})
+				.collect();
+			self.fetch = Some((events, join_all(requests)));
+		}
+
+		let result = match self.fetch {
+			Some((_, ref mut future)) => future.poll(),
+			None => unreachable!("fetch has been set above; qed"),
+		};
+
+		match result {
+			Ok(Async::NotReady) => Ok(Async::NotReady),
+			Ok(Async::Ready(logs)) => {
+				let (events, _) = self.fetch.take().expect("fetch has been polled above; qed");
+				let logs = logs.into_iter()
+					.flat_map(|logs| logs)
+					.filter(|log| EventId::from_log(log).map(|id| events.contains(&id)).unwrap_or(false))
+					.collect::<Vec<_>>();
+				if logs.len() < events.len() {
+					warn!("{} of {} reverted events are no longer on the chain", events.len() - logs.len(), events.len());
+				}
+				Ok(Async::Ready(Some(logs)))
+			},
+			Err(err) => {
+				// keep the events for the next attempt
+				let (events, _) = self.fetch.take().expect("fetch has been polled above; qed");
+				for event in events {
+					self.reverted.push(event);
+				}
+				Err(err)
+			},
+		}
+	}
+}
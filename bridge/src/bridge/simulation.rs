@@ -0,0 +1,402 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::time::Duration;
+use futures::{Async, Future, Poll};
+use futures::future::{JoinAll, Then, join_all};
+use tokio_timer::{Timeout, Timer};
+use ethabi::{self, ParamType, Token};
+use keccak_hash::keccak;
+use rustc_hex::FromHex;
+use web3::{self, Transport};
+use web3::types::{Address, Bytes, H256, U256};
+use rpc::{self, ErrorCode, Value};
+use api::{self, ApiCall};
+use contracts::erc20;
+use error::{Error, ErrorKind};
+use ledger::Transfer;
+use rpc_failure::RpcFailure;
+use super::BridgeChecked;
+
+/// Selector of `Error(string)`, which revert reasons are encoded with.
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+/// Storage slot of `HomeBridge.withdraws`, see `contracts/bridge.sol`.
+pub const HOME_WITHDRAWS_SLOT: u64 = 5;
+/// Storage slot of `ForeignBridge.authorities`.
+pub const FOREIGN_AUTHORITIES_SLOT: u64 = 6;
+/// Storage slot of `ForeignBridge.messages_signed`.
+pub const FOREIGN_MESSAGES_SIGNED_SLOT: u64 = 9;
+/// Storage slot of `ForeignBridge.deposits_signed`.
+pub const FOREIGN_DEPOSITS_SIGNED_SLOT: u64 = 11;
+/// Storage slot of `ForeignBridge.erc20token`.
+pub const FOREIGN_ERC20_TOKEN_SLOT: u64 = 13;
+
+/// Storage key of the value of `key` in the mapping at storage slot `slot`.
+pub fn mapping_key(key: H256, slot: u64) -> H256 {
+	let mut preimage = key.0.to_vec();
+	preimage.extend_from_slice(&H256::from(slot).0);
+	keccak(&preimage)
+}
+
+/// Key of `address` in a mapping, the address padded to 32 bytes.
+pub fn address_key(address: Address) -> H256 {
+	let mut key = [0u8; 32];
+	key[12..].copy_from_slice(&address.0);
+	key.into()
+}
+
+/// Hash the bridge contracts keep track of what `sender` signed by, `keccak256(sender, hash)`.
+pub fn sender_hash(sender: Address, hash: H256) -> H256 {
+	let mut preimage = sender.0.to_vec();
+	preimage.extend_from_slice(&hash.0);
+	keccak(&preimage)
+}
+
+/// Contract state which tells why a transaction reverts, read when the node doesn't give the reason.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RevertProbe {
+	/// Storage key of the flag the bridge contract sets once the event is handled.
+	pub handled: H256,
+	/// Storage key of the flag the bridge contract sets for its authorities, `None` if anyone may send the transaction.
+	pub authority: Option<H256>,
+	/// Funds the bridge contract pays out.
+	pub payout: Payout,
+}
+
+/// Funds a bridge contract pays out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payout {
+	/// Transaction doesn't pay out anything.
+	Nothing,
+	/// Ether of the bridge contract.
+	Ether(U256),
+	/// Tokens of the bridge contract, in the token whose address the bridge contract stores under the given key.
+	Tokens(H256, U256),
+}
+
+/// Reason a transaction is not sent because it would revert.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkipReason {
+	/// Event has been handled already, by this validator or, for withdraws, by anyone.
+	AlreadyHandled,
+	/// Bridge account is not an authority of the bridge contract.
+	NotAuthority,
+	/// Bridge contract doesn't hold enough tokens to pay out.
+	OutOfTokens,
+	/// Transaction reverts for another reason, or with the reason given by the node, which isn't interpreted:
+	/// the bridge contracts revert without a reason, only their state tells reverts apart.
+	Reverted(Option<String>),
+}
+
+impl fmt::Display for SkipReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			SkipReason::AlreadyHandled => write!(f, "simulation: already handled"),
+			SkipReason::NotAuthority => write!(f, "simulation: not an authority"),
+			SkipReason::OutOfTokens => write!(f, "simulation: bridge out of tokens"),
+			SkipReason::Reverted(Some(ref reason)) => write!(f, "simulation: reverted ({})", reason),
+			SkipReason::Reverted(None) => write!(f, "simulation: reverted"),
+		}
+	}
+}
+
+/// Returns the revert reason, if any, if `err` says the call reverted. Returns `None` for any other error.
+fn revert(err: &rpc::Error) -> Option<Option<String>> {
+	let message = err.message.to_lowercase();
+	let reverted = err.code == ErrorCode::ServerError(3)
+		|| message.contains("revert")
+		|| message.starts_with("vm execution error");
+	if !reverted {
+		return None;
+	}
+
+	let data_reason = match err.data {
+		Some(Value::String(ref data)) => decode_revert_data(data),
+		_ => None,
+	};
+	// geth puts the reason into the message as well
+	let message_reason = err.message.splitn(2, ": ").nth(1).map(str::to_owned);
+	Some(data_reason.or(message_reason))
+}
+
+/// Decodes the `Error(string)` returned by a reverted call, found in `data` like `Reverted 0x08c379a0...`.
+fn decode_revert_data(data: &str) -> Option<String> {
+	let hex = &data[data.find("0x")? + 2..];
+	let bytes: Vec<u8> = hex.from_hex().ok()?;
+	if bytes.len() < 4 || bytes[..4] != ERROR_SELECTOR {
+		return None;
+	}
+	match ethabi::decode(&[ParamType::String], &bytes[4..]).ok()?.pop() {
+		Some(Token::String(reason)) => Some(reason),
+		_ => None,
+	}
+}
+
+/// Outcome of a simulation: `None` if the transaction succeeds, the reason to skip it if it reverts,
+/// or the node's error if it fails to simulate it.
+type Outcome = Result<Option<SkipReason>, Error>;
+
+/// A simulation the node fails for another reason than a revert fails the transaction.
+/// A failure every transaction of the bridge account would run into, e.g. insufficient funds, stops the bridge.
+/// If the node can't be reached the transaction is sent without being simulated, so that a single call doesn't fail all of them.
+fn settle_simulation(result: Result<Bytes, Error>) -> Result<Outcome, Error> {
+	match result {
+		Ok(_) => Ok(Ok(None)),
+		Err(Error(ErrorKind::Web3(web3::error::Error(web3::error::ErrorKind::Rpc(rpc_err), _)), _)) => match revert(&rpc_err) {
+			Some(reason) => Ok(Ok(Some(SkipReason::Reverted(reason)))),
+			None if RpcFailure::classify(&rpc_err).is_account_failure() => Err(ErrorKind::Web3(web3::error::ErrorKind::Rpc(rpc_err).into()).into()),
+			None => Ok(Err(ErrorKind::Web3(web3::error::ErrorKind::Rpc(rpc_err).into()).into())),
+		},
+		Err(err) => {
+			warn!("simulation failed, sending the transaction anyway: {}", err);
+			Ok(Ok(None))
+		},
+	}
+}
+
+type Simulation<T> = Then<Timeout<ApiCall<Bytes, <T as Transport>::Out>>, Result<Outcome, Error>, fn(Result<Bytes, Error>) -> Result<Outcome, Error>>;
+
+/// Contract state read by a `Probe`.
+enum ProbeState<T: Transport> {
+	/// Reading the flag set once the event is handled.
+	Handled(Timeout<ApiCall<H256, T::Out>>),
+	/// Reading the flag set for authorities.
+	Authority(Timeout<ApiCall<H256, T::Out>>),
+	/// Reading the ether balance of the bridge contract.
+	Balance(Timeout<ApiCall<U256, T::Out>>),
+	/// Reading the address of the token.
+	Token(Timeout<ApiCall<H256, T::Out>>),
+	/// Reading the token balance of the bridge contract.
+	TokenBalance(Timeout<ApiCall<Bytes, T::Out>>),
+}
+
+/// Reads the state of the bridge contract to tell why a transaction reverts without a reason.
+///
+/// Yields the reason, or the error which prevents reading the state.
+struct Probe<T: Transport> {
+	transport: T,
+	timer: Timer,
+	request_timeout: Duration,
+	contract: Address,
+	probe: RevertProbe,
+	state: ProbeState<T>,
+}
+
+impl<T: Transport> Probe<T> {
+	fn new(transport: T, timer: Timer, request_timeout: Duration, contract: Address, probe: RevertProbe) -> Self {
+		let state = ProbeState::Handled(timer.timeout(api::storage(transport.clone(), contract, probe.handled), request_timeout));
+		Probe { transport, timer, request_timeout, contract, probe, state }
+	}
+
+	fn storage(&self, key: H256) -> Timeout<ApiCall<H256, T::Out>> {
+		self.timer.timeout(api::storage(self.transport.clone(), self.contract, key), self.request_timeout)
+	}
+
+	/// Reads the funds paid out, if any.
+	fn payout(&self) -> Option<ProbeState<T>> {
+		match self.probe.payout {
+			Payout::Nothing => None,
+			Payout::Ether(_) => Some(ProbeState::Balance(self.timer.timeout(api::balance(self.transport.clone(), self.contract, None), self.request_timeout))),
+			Payout::Tokens(key, _) => Some(ProbeState::Token(self.storage(key))),
+		}
+	}
+
+	fn poll_reason(&mut self) -> Poll<SkipReason, Error> {
+		loop {
+			let next_state = match self.state {
+				ProbeState::Handled(ref mut future) => {
+					if !try_ready!(future.poll()).is_zero() {
+						return Ok(Async::Ready(SkipReason::AlreadyHandled));
+					}
+					match self.probe.authority {
+						Some(key) => Some(ProbeState::Authority(self.storage(key))),
+						None => self.payout(),
+					}
+				},
+				ProbeState::Authority(ref mut future) => {
+					if try_ready!(future.poll()).is_zero() {
+						return Ok(Async::Ready(SkipReason::NotAuthority));
+					}
+					self.payout()
+				},
+				ProbeState::Balance(ref mut future) => {
+					let balance = try_ready!(future.poll());
+					return Ok(Async::Ready(self.reason(balance)));
+				},
+				ProbeState::Token(ref mut future) => {
+					let token = Address::from(&try_ready!(future.poll()).0[12..]);
+					let payload = erc20::ERC20::default().functions().balance_of().input(self.contract);
+					Some(ProbeState::TokenBalance(self.timer.timeout(api::call(self.transport.clone(), token, payload.into()), self.request_timeout)))
+				},
+				ProbeState::TokenBalance(ref mut future) => {
+					let balance = erc20::ERC20::default().functions().balance_of().output(&try_ready!(future.poll()).0)?;
+					return Ok(Async::Ready(self.reason(balance)));
+				},
+			};
+
+			match next_state {
+				Some(state) => self.state = state,
+				None => return Ok(Async::Ready(SkipReason::Reverted(None))),
+			}
+		}
+	}
+
+	/// Reason of the revert given the `balance` the bridge contract pays out of.
+	fn reason(&self, balance: U256) -> SkipReason {
+		match self.probe.payout {
+			Payout::Ether(value) | Payout::Tokens(_, value) if balance < value => SkipReason::OutOfTokens,
+			_ => SkipReason::Reverted(None),
+		}
+	}
+}
+
+impl<T: Transport> Future for Probe<T> {
+	type Item = Outcome;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		match self.poll_reason() {
+			Ok(Async::Ready(reason)) => Ok(Async::Ready(Ok(Some(reason)))),
+			Ok(Async::NotReady) => Ok(Async::NotReady),
+			Err(err) => Ok(Async::Ready(Err(err))),
+		}
+	}
+}
+
+/// State of `Simulations`.
+enum SimulationsState<T: Transport> {
+	/// Simulating the transactions.
+	Simulate(JoinAll<Vec<Simulation<T>>>),
+	/// Reading the contract state for the transactions which revert without a reason, at the given positions.
+	Probe {
+		outcomes: Vec<Outcome>,
+		positions: Vec<usize>,
+		future: JoinAll<Vec<Probe<T>>>,
+	},
+}
+
+/// Simulations of transactions of a single kind, in the order of their payloads.
+///
+/// Yields `None` for every transaction which succeeds, and the reason to skip it for every one which reverts.
+/// The reason of a revert the node doesn't explain is told by the state of the bridge contract.
+pub struct Simulations<T: Transport> {
+	transport: T,
+	timer: Timer,
+	request_timeout: Duration,
+	contract: Address,
+	probes: Vec<RevertProbe>,
+	state: SimulationsState<T>,
+}
+
+/// Simulates every transaction sending one of `payloads` from `from` to `to`, with the given gas and gas price.
+/// The state of `to` described by the probe of the payload is read if the transaction reverts without a reason.
+pub fn simulate<T: Transport>(transport: &T, timer: &Timer, request_timeout: Duration, from: Address, to: Address, payloads: &[(Bytes, U256, U256, RevertProbe)]) -> Simulations<T> {
+	let simulations = payloads.iter()
+		.map(|&(ref payload, gas, gas_price, _)| timer.timeout(api::simulate_transaction(transport.clone(), from, to, gas, gas_price, payload.clone()), request_timeout)
+			.then(settle_simulation as fn(Result<Bytes, Error>) -> Result<Outcome, Error>))
+		.collect();
+
+	Simulations {
+		transport: transport.clone(),
+		timer: timer.clone(),
+		request_timeout,
+		contract: to,
+		probes: payloads.iter().map(|&(_, _, _, ref probe)| probe.clone()).collect(),
+		state: SimulationsState::Simulate(join_all(simulations)),
+	}
+}
+
+impl<T: Transport> Future for Simulations<T> {
+	type Item = Vec<Outcome>;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		loop {
+			let next_state = match self.state {
+				SimulationsState::Simulate(ref mut future) => {
+					let outcomes = try_ready!(future.poll());
+					let positions = outcomes.iter()
+						.enumerate()
+						.filter(|&(_, outcome)| match *outcome {
+							Ok(Some(SkipReason::Reverted(None))) => true,
+							_ => false,
+						})
+						.map(|(position, _)| position)
+						.collect::<Vec<_>>();
+					if positions.is_empty() {
+						return Ok(Async::Ready(outcomes));
+					}
+					let probes = positions.iter()
+						.map(|&position| Probe::new(self.transport.clone(), self.timer.clone(), self.request_timeout, self.contract, self.probes[position].clone()))
+						.collect();
+					SimulationsState::Probe {
+						outcomes,
+						positions,
+						future: join_all(probes),
+					}
+				},
+				SimulationsState::Probe { ref mut outcomes, ref positions, ref mut future } => {
+					let reasons = try_ready!(future.poll());
+					for (&position, reason) in positions.iter().zip(reasons) {
+						outcomes[position] = reason;
+					}
+					return Ok(Async::Ready(mem::replace(outcomes, Vec::new())));
+				},
+			};
+			self.state = next_state;
+		}
+	}
+}
+
+/// Keeps the transfers whose simulation succeeds. The others are handed to the bridge through `skipped`,
+/// as skipped events if they have been handled already, as failed attempts if they could not be simulated,
+/// otherwise as dead letters.
+pub fn skip_reverted<P>(transfers: Vec<(Transfer, P)>, outcomes: Vec<Outcome>, skipped: &mut VecDeque<BridgeChecked>) -> Vec<(Transfer, P)> {
+	transfers.into_iter()
+		.zip(outcomes)
+		.filter_map(|((transfer, payload), outcome)| match outcome {
+			Err(err) => {
+				error!("simulating {} {} failed: {}", transfer.kind, transfer.event, err);
+				skipped.push_back(BridgeChecked::Failed(transfer.kind, transfer.event, format!("simulation failed: {}", err)));
+				None
+			},
+			Ok(None) => Some((transfer, payload)),
+			Ok(Some(SkipReason::AlreadyHandled)) => {
+				info!("{} event {} has been handled already, skipping it", transfer.kind, transfer.event);
+				skipped.push_back(BridgeChecked::Skipped(transfer.kind, transfer.event));
+				None
+			},
+			Ok(Some(reason)) => {
+				skipped.push_back(BridgeChecked::DeadLetter(transfer.kind, transfer.event, reason.to_string()));
+				None
+			},
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use rpc::{self, ErrorCode, Value};
+	use super::{SkipReason, revert};
+
+	fn skip_reason(code: i64, message: &str, data: Option<&str>) -> Option<SkipReason> {
+		revert(&rpc::Error {
+			code: ErrorCode::ServerError(code),
+			message: message.into(),
+			data: data.map(|data| Value::String(data.into())),
+		}).map(SkipReason::Reverted)
+	}
+
+	#[test]
+	fn test_skip_reason() {
+		assert_eq!(Some(SkipReason::Reverted(None)), skip_reason(-32015, "VM execution error.", Some("Reverted 0x")));
+		assert_eq!(Some(SkipReason::Reverted(None)), skip_reason(-32000, "execution reverted", None));
+		// reasons are passed on as they are, they don't tell the state of the bridge contract
+		assert_eq!(Some(SkipReason::Reverted(Some("deposit already signed".into()))), skip_reason(3, "execution reverted: deposit already signed", None));
+		assert_eq!(Some(SkipReason::Reverted(Some("insufficient balance".into()))), skip_reason(3, "execution reverted: insufficient balance", None));
+		assert_eq!(Some(SkipReason::Reverted(Some("not an authority".into()))), skip_reason(3, "execution reverted", Some("0x08c379a0000000000000000000000000000000000000000000000000000000000000002000000000000000000000000000000000000000000000000000000000000000106e6f7420616e20617574686f7269747900000000000000000000000000000000")));
+		assert_eq!(Some(SkipReason::Reverted(Some("paused".into()))), skip_reason(3, "execution reverted: paused", None));
+		assert_eq!(None, skip_reason(-32000, "insufficient funds for gas * price + value", None));
+	}
+}
@@ -0,0 +1,474 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use futures::{Async, Future, Poll, Stream};
+use futures::future::{self, Either, FutureResult, Join, JoinAll, Then, join_all};
+use tokio_timer::{Interval, Timeout};
+use web3::Transport;
+use web3::types::{BlockNumber, Bytes, H256, U256};
+use ethcore_transaction::{Action, Transaction};
+use keccak_hash::keccak;
+use api::{self, ApiCall, ReceiptStatus};
+use app::App;
+use config::Node;
+use error::Error;
+use ledger::{Ledger, PendingTransaction};
+use signer::SignFuture;
+use transaction::{priority_fee, sign_transaction};
+use super::BridgeChecked;
+use super::nonce::SentTransaction;
+
+/// Ledger keeping the pending transactions of a chain across restarts.
+struct PendingStore {
+	ledger: Mutex<Ledger>,
+	chain: &'static str,
+}
+
+/// Transactions sent on a chain which are not mined yet, shared between
+/// the components sending them and the chain's `TransactionTracker`.
+#[derive(Clone, Default)]
+pub struct PendingTransactions {
+	inner: Arc<Mutex<Vec<PendingTransaction>>>,
+	/// `None` if the transactions are forgotten on restart, e.g. in a dry run.
+	store: Option<Arc<PendingStore>>,
+}
+
+impl PendingTransactions {
+	/// Tracks the transactions sent on `chain` which were not mined when the bridge stopped
+	/// and keeps the pending transactions in `ledger` from now on.
+	pub fn load(ledger: Ledger, chain: &'static str) -> Result<Self, Error> {
+		let pending = ledger.pending_transactions(chain)?;
+		if !pending.is_empty() {
+			info!("tracking {} transactions sent on {} before the restart", pending.len(), chain);
+		}
+		Ok(PendingTransactions {
+			inner: Arc::new(Mutex::new(pending)),
+			store: Some(Arc::new(PendingStore {
+				ledger: Mutex::new(ledger),
+				chain,
+			})),
+		})
+	}
+
+	/// Starts tracking a sent transaction.
+	pub fn add<R>(&self, sent: &SentTransaction<R>) -> Result<(), Error> {
+		self.push(sent.transaction.clone(), sent.raw.clone(), sent.priority_fee, true)
+	}
+
+	/// Starts tracking a sent transaction which has to pay the gas price it has been sent with,
+	/// e.g. a withdraw relay paying the gas price of its message. It's broadcast again, but never replaced.
+	pub fn add_at_fixed_gas_price<R>(&self, sent: &SentTransaction<R>) -> Result<(), Error> {
+		self.push(sent.transaction.clone(), sent.raw.clone(), sent.priority_fee, false)
+	}
+
+	fn push(&self, transaction: Transaction, raw: Bytes, priority_fee: Option<U256>, replaceable: bool) -> Result<(), Error> {
+		let pending = PendingTransaction {
+			transaction,
+			hashes: vec![keccak(&raw.0)],
+			raw,
+			priority_fee,
+			sent_at: None,
+			replaceable,
+		};
+		let mut inner = self.inner.lock().expect("pending transactions lock poisoned");
+		inner.push(pending);
+		self.persist(&inner)
+	}
+
+	/// Keeps `pending` in the ledger, so that the transactions are tracked again after a restart.
+	fn persist(&self, pending: &[PendingTransaction]) -> Result<(), Error> {
+		match self.store {
+			Some(ref store) => store.ledger.lock().expect("ledger lock poisoned").set_pending_transactions(store.chain, pending),
+			None => Ok(()),
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.inner.lock().expect("pending transactions lock poisoned").is_empty()
+	}
+
+	/// Nonces of all pending transactions.
+	fn nonces(&self) -> Vec<U256> {
+		self.inner.lock().expect("pending transactions lock poisoned")
+			.iter()
+			.map(|pending| pending.transaction.nonce)
+			.collect()
+	}
+
+	/// Hashes of all versions of all pending transactions.
+	fn hashes(&self) -> Vec<H256> {
+		self.inner.lock().expect("pending transactions lock poisoned")
+			.iter()
+			.flat_map(|pending| pending.hashes.iter().cloned())
+			.collect()
+	}
+}
+
+/// Gas used by a transfer to an account.
+const GAS_TRANSFER: u64 = 21_000;
+
+/// Returns the gas price a transaction sent with `gas_price` is replaced with, or `None` if it's already at `max`.
+fn bumped_gas_price(gas_price: U256, percent: u64, max: U256) -> Option<U256> {
+	if gas_price >= max {
+		return None;
+	}
+
+	let bumped = gas_price * U256::from(100 + percent) / U256::from(100);
+	Some(cmp::min(cmp::max(bumped, gas_price + U256::one()), max))
+}
+
+type Rebroadcast<T> = Either<
+	Then<Timeout<ApiCall<H256, <T as Transport>::Out>>, Result<(), Error>, fn(Result<H256, Error>) -> Result<(), Error>>,
+	FutureResult<(), Error>
+>;
+
+/// Rebroadcasting fails whenever the node already knows the transaction, so errors are only logged.
+fn ignore_rebroadcast_error(result: Result<H256, Error>) -> Result<(), Error> {
+	if let Err(err) = result {
+		debug!("rebroadcasting transaction failed: {}", err);
+	}
+	Ok(())
+}
+
+/// Transaction the tracker signs.
+enum Signing {
+	/// Transfer of nothing to the bridge account, filling an unused nonce.
+	Filler(Transaction, Option<U256>),
+	/// Replacement, with a higher gas price, of the pending transaction with the given hash.
+	Replacement(H256, Transaction, Option<U256>),
+}
+
+type Signed = Then<SignFuture<Bytes>, Result<Result<Bytes, Error>, Error>, fn(Result<Bytes, Error>) -> Result<Result<Bytes, Error>, Error>>;
+
+/// A transaction which can't be signed doesn't stop signing the others.
+fn settle_signing(result: Result<Bytes, Error>) -> Result<Result<Bytes, Error>, Error> {
+	Ok(result)
+}
+
+/// State of the transaction tracker.
+enum TransactionTrackerState<T: Transport> {
+	/// Waiting for the next check.
+	Wait,
+	/// Fetching the best block and the number of transactions mined from the account.
+	FetchHead {
+		future: Join<Timeout<ApiCall<U256, T::Out>>, Timeout<ApiCall<U256, T::Out>>>,
+	},
+	/// Fetching receipts of all pending transactions.
+	FetchReceipts {
+		future: JoinAll<Vec<Timeout<ApiCall<Option<ReceiptStatus>, T::Out>>>>,
+		hashes: Vec<H256>,
+		block: u64,
+		nonce: U256,
+	},
+	/// Signing fillers of unused nonces and replacements of transactions pending for too long.
+	Sign {
+		future: JoinAll<Vec<Signed>>,
+		signing: Vec<Signing>,
+		/// Hashes of the pending transactions to broadcast again, in their order.
+		rebroadcast: Vec<H256>,
+		block: u64,
+	},
+	/// Sending transactions which are not mined yet again.
+	Rebroadcast {
+		future: JoinAll<Vec<Rebroadcast<T>>>,
+	},
+	/// Yielding mined, reverted, replaced and dropped transactions.
+	Yield,
+}
+
+/// Watches transactions sent on a chain until they are mined and reports the ones which reverted.
+///
+/// On every new block transactions which are not mined yet are broadcast again. Once a transaction
+/// has been pending for `gas_price_bump_blocks`, it's replaced by one with the same nonce and
+/// a gas price higher by `gas_price_bump_percent`, up to `max_gas_price`, unless it has to pay
+/// a fixed gas price. A transaction whose nonce has been used by another one is reported as dropped.
+/// Nonces left unused by transactions which never reached the node are filled with transfers of nothing
+/// to the bridge account, otherwise the transactions sent after them would never be mined.
+pub struct TransactionTracker<T: Transport> {
+	app: Arc<App<T>>,
+	transport: T,
+	node: Node,
+	chain_id: u64,
+	pending: PendingTransactions,
+	gas_price: Arc<RwLock<u64>>,
+	interval: Interval,
+	last_block: u64,
+	items: VecDeque<BridgeChecked>,
+	/// Failure to sign a filler or a replacement, returned once the items are yielded.
+	failure: Option<Error>,
+	state: TransactionTrackerState<T>,
+}
+
+pub fn create_transaction_tracker<T: Transport + Clone>(app: Arc<App<T>>, transport: T, node: Node, chain_id: u64, pending: PendingTransactions, gas_price: Arc<RwLock<u64>>) -> TransactionTracker<T> {
+	// transactions sent before a restart might not be known to the node anymore
+	for nonce in pending.nonces() {
+		node.info.nonces.restore(nonce);
+	}
+
+	TransactionTracker {
+		interval: app.timer.interval(node.poll_interval),
+		app,
+		transport,
+		node,
+		chain_id,
+		pending,
+		gas_price,
+		last_block: 0,
+		items: VecDeque::new(),
+		failure: None,
+		state: TransactionTrackerState::Wait,
+	}
+}
+
+impl<T: Transport + Clone> TransactionTracker<T> {
+	/// Sends `raw` again, unless it's a dry run.
+	fn rebroadcast(&self, raw: Bytes) -> Rebroadcast<T> {
+		if self.app.dry_run {
+			debug!("dry run, transaction {:?} not broadcast again", keccak(&raw.0));
+			return Either::B(future::ok(()));
+		}
+		Either::A(self.app.timer.timeout(api::send_raw_transaction(self.transport.clone(), raw), self.node.request_timeout)
+			.then(ignore_rebroadcast_error as fn(Result<H256, Error>) -> Result<(), Error>))
+	}
+
+	/// Fillers of the nonces left unused. Nothing is sent in a dry run, so no nonce is left unused there.
+	fn fill_gaps(&self) -> Vec<Signing> {
+		if self.app.dry_run {
+			return Vec::new();
+		}
+		self.node.info.nonces.take_gaps()
+			.into_iter()
+			.map(|nonce| {
+				let transaction = Transaction {
+					nonce,
+					gas_price: (*self.gas_price.read().unwrap()).into(),
+					gas: GAS_TRANSFER.into(),
+					action: Action::Call(self.node.account),
+					value: U256::zero(),
+					data: Vec::new(),
+				};
+				Signing::Filler(transaction, priority_fee(&self.node))
+			})
+			.collect()
+	}
+
+	/// Drops mined transactions and picks the ones pending for too long to be replaced.
+	/// Returns the transactions to sign and the hashes of the pending ones to broadcast again.
+	fn update(&mut self, checked: &[H256], mined: &[(H256, ReceiptStatus)], block: u64, nonce: U256) -> Result<(Vec<Signing>, Vec<H256>), Error> {
+		let unknown = self.node.info.nonces.confirm(nonce);
+		if !unknown.is_zero() {
+			warn!("{} transactions from {} not sent by the bridge have been mined on {}", unknown, self.node.account, self.node.endpoint());
+		}
+		let mut signing = self.fill_gaps();
+		let mut rebroadcast = Vec::new();
+
+		let inner = self.pending.inner.clone();
+		let mut pending = inner.lock().expect("pending transactions lock poisoned");
+		let mut still_pending = Vec::new();
+		for mut tx in pending.drain(..) {
+			if !checked.contains(&tx.hash()) {
+				// sent after the receipts have been requested
+				still_pending.push(tx);
+				continue;
+			}
+
+			if let Some(&(hash, ref receipt)) = mined.iter().find(|&&(ref hash, _)| tx.hashes.contains(hash)) {
+				if receipt.is_reverted() {
+					error!("transaction {:?} has been mined on {}, but reverted", hash, self.node.endpoint());
+					self.items.push_back(BridgeChecked::TransactionReverted(hash));
+				} else {
+					info!("transaction {:?} has been mined on {}", hash, self.node.endpoint());
+					self.items.push_back(BridgeChecked::TransactionMined(hash));
+				}
+				continue;
+			}
+
+			if tx.transaction.nonce < nonce {
+				error!("nonce {} of transaction {:?} has been used by another transaction, no longer tracking it", tx.transaction.nonce, tx.hash());
+				self.items.push_back(BridgeChecked::TransactionDropped(tx.hash()));
+				continue;
+			}
+
+			// the head of an endpoint the bridge failed over to may be behind the one the transaction was seen at
+			let sent_at = *tx.sent_at.get_or_insert(block);
+			if tx.replaceable && block.saturating_sub(sent_at) >= self.node.gas_price_bump_blocks {
+				match bumped_gas_price(tx.transaction.gas_price, self.node.gas_price_bump_percent, self.node.max_gas_price.into()) {
+					Some(gas_price) => {
+						let mut transaction = tx.transaction.clone();
+						transaction.gas_price = gas_price;
+						// EIP-1559 replacements must raise the priority fee as well
+						let priority_fee = tx.priority_fee
+							.map(|fee| bumped_gas_price(fee, self.node.gas_price_bump_percent, gas_price).unwrap_or(gas_price));
+						signing.push(Signing::Replacement(tx.hash(), transaction, priority_fee));
+					},
+					None => {
+						warn!("transaction {:?} not mined for {} blocks, but its gas price is already at max_gas_price", tx.hash(), block.saturating_sub(sent_at));
+						tx.sent_at = Some(block);
+					},
+				}
+			}
+
+			rebroadcast.push(tx.hash());
+			still_pending.push(tx);
+		}
+
+		*pending = still_pending;
+		self.pending.persist(&pending)?;
+		Ok((signing, rebroadcast))
+	}
+
+	/// Tracks the signed fillers and replacements. Returns rebroadcasts of the fillers and of the transactions in `rebroadcast`.
+	///
+	/// A transaction which can't be signed doesn't stop tracking the others,
+	/// the first such failure is kept in `self.failure`.
+	fn signed(&mut self, signing: Vec<Signing>, signed: Vec<Result<Bytes, Error>>, rebroadcast: &[H256], block: u64) -> Result<Vec<Rebroadcast<T>>, Error> {
+		let mut raws = Vec::new();
+		let mut replacements = Vec::new();
+		for (signing, signed) in signing.into_iter().zip(signed) {
+			match (signing, signed) {
+				(Signing::Filler(transaction, priority_fee), Ok(raw)) => {
+					info!("filling unused nonce {} of {} with transaction {:?}", transaction.nonce, self.node.account, keccak(&raw.0));
+					self.pending.push(transaction, raw.clone(), priority_fee, true)?;
+					raws.push(raw);
+				},
+				(Signing::Filler(transaction, _), Err(err)) => {
+					// the nonce is filled on the next block
+					error!("filling unused nonce {} of {} failed: {}", transaction.nonce, self.node.account, err);
+					self.node.info.nonces.release(transaction.nonce);
+					self.failure.get_or_insert(err);
+				},
+				(Signing::Replacement(hash, transaction, priority_fee), Ok(raw)) => replacements.push((hash, transaction, priority_fee, raw)),
+				// the replacement is signed again on the next block
+				(Signing::Replacement(hash, _, _), Err(err)) => {
+					error!("replacing transaction {:?} failed: {}", hash, err);
+					self.failure.get_or_insert(err);
+				},
+			}
+		}
+
+		let inner = self.pending.inner.clone();
+		let mut pending = inner.lock().expect("pending transactions lock poisoned");
+		for (replaced, transaction, priority_fee, raw) in replacements {
+			if let Some(tx) = pending.iter_mut().find(|tx| tx.hash() == replaced) {
+				let hash = keccak(&raw.0);
+				let gas_price = transaction.gas_price;
+				info!("transaction {:?} not mined for {} blocks, replacing it with {:?} at gas price {}", replaced, block.saturating_sub(tx.sent_at.unwrap_or(block)), hash, gas_price);
+				self.items.push_back(BridgeChecked::TransactionReplaced(replaced, hash, gas_price));
+				tx.transaction = transaction;
+				tx.raw = raw;
+				tx.priority_fee = priority_fee;
+				tx.hashes.push(hash);
+				tx.sent_at = Some(block);
+			}
+		}
+		raws.extend(rebroadcast.iter()
+			.filter_map(|hash| pending.iter().find(|tx| tx.hashes.contains(hash)))
+			.map(|tx| tx.raw.clone()));
+		self.pending.persist(&pending)?;
+		Ok(raws.into_iter().map(|raw| self.rebroadcast(raw)).collect())
+	}
+}
+
+impl<T: Transport + Clone> Stream for TransactionTracker<T> {
+	type Item = BridgeChecked;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		loop {
+			let next_state = match self.state {
+				TransactionTrackerState::Wait => {
+					let _ = try_ready!(self.interval.poll());
+					if self.pending.is_empty() {
+						continue;
+					}
+
+					let block = api::block_number(self.transport.clone());
+					let nonce = api::eth_get_transaction_count(self.transport.clone(), self.node.account, Some(BlockNumber::Latest));
+					TransactionTrackerState::FetchHead {
+						future: self.app.timer.timeout(block, self.node.request_timeout)
+							.join(self.app.timer.timeout(nonce, self.node.request_timeout)),
+					}
+				},
+				TransactionTrackerState::FetchHead { ref mut future } => {
+					let (block, nonce) = try_ready!(future.poll());
+					let block = block.low_u64();
+					if block <= self.last_block {
+						TransactionTrackerState::Wait
+					} else {
+						self.last_block = block;
+						let hashes = self.pending.hashes();
+						let receipts = hashes.iter()
+							.map(|hash| self.app.timer.timeout(api::transaction_receipt(self.transport.clone(), *hash), self.node.request_timeout))
+							.collect::<Vec<_>>();
+						TransactionTrackerState::FetchReceipts {
+							future: join_all(receipts),
+							hashes,
+							block,
+							nonce,
+						}
+					}
+				},
+				TransactionTrackerState::FetchReceipts { ref mut future, ref hashes, block, nonce } => {
+					let receipts = try_ready!(future.poll());
+					let mined = hashes.iter()
+						.zip(receipts)
+						.filter_map(|(hash, receipt)| receipt.map(|receipt| (*hash, receipt)))
+						.collect::<Vec<_>>();
+					let checked = hashes.clone();
+					let (signing, rebroadcast) = self.update(&checked, &mined, block, nonce)?;
+					let signed = signing.iter()
+						.map(|signing| {
+							let (transaction, priority_fee) = match *signing {
+								Signing::Filler(ref transaction, priority_fee) => (transaction.clone(), priority_fee),
+								Signing::Replacement(_, ref transaction, priority_fee) => (transaction.clone(), priority_fee),
+							};
+							sign_transaction(transaction, priority_fee, &self.app, &self.node, self.chain_id)
+								.then(settle_signing as fn(Result<Bytes, Error>) -> Result<Result<Bytes, Error>, Error>)
+						})
+						.collect();
+					TransactionTrackerState::Sign {
+						future: join_all(signed),
+						signing,
+						rebroadcast,
+						block,
+					}
+				},
+				TransactionTrackerState::Sign { ref mut future, ref mut signing, ref mut rebroadcast, block } => {
+					let signed = try_ready!(future.poll());
+					let signing = signing.drain(..).collect();
+					let rebroadcast = rebroadcast.drain(..).collect::<Vec<_>>();
+					TransactionTrackerState::Rebroadcast {
+						future: join_all(self.signed(signing, signed, &rebroadcast, block)?),
+					}
+				},
+				TransactionTrackerState::Rebroadcast { ref mut future } => {
+					let _ = try_ready!(future.poll());
+					TransactionTrackerState::Yield
+				},
+				TransactionTrackerState::Yield => match self.items.pop_front() {
+					Some(item) => return Ok(Async::Ready(Some(item))),
+					None => match self.failure.take() {
+						Some(err) => return Err(err),
+						None => TransactionTrackerState::Wait,
+					},
+				},
+			};
+			self.state = next_state;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use web3::types::U256;
+	use super::bumped_gas_price;
+
+	#[test]
+	fn test_bumped_gas_price() {
+		assert_eq!(Some(U256::from(120)), bumped_gas_price(100.into(), 20, 1000.into()));
+		// the price always grows, even if the percentage rounds down to nothing
+		assert_eq!(Some(U256::from(2)), bumped_gas_price(1.into(), 20, 1000.into()));
+		assert_eq!(Some(U256::from(110)), bumped_gas_price(100.into(), 20, 110.into()));
+		assert_eq!(None, bumped_gas_price(110.into(), 20, 110.into()));
+	}
+}
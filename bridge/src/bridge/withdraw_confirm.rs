@@ -1,19 +1,26 @@
+use std::collections::{HashSet, VecDeque};
 use std::sync::{Arc, RwLock};
 use std::ops;
-use futures::{self, Future, Stream, stream::{Collect, FuturesUnordered, futures_unordered}, Poll};
+use futures::{self, Future, Stream, Poll};
+use futures::future::{JoinAll, join_all};
 use web3::Transport;
-use web3::types::{U256, H520, Address, Bytes, FilterBuilder};
+use web3::types::{U256, H256, H520, Address, Bytes, FilterBuilder};
+use keccak_hash::keccak;
 use api::{self, LogStream};
 use app::App;
 use contracts::foreign;
 use util::web3_filter;
-use database::Database;
+use database::{Database, EventId};
+use ledger::{Transfer, TransferKind};
 use error::{Error, ErrorKind};
 use message_to_mainnet::{MessageToMainnet, MESSAGE_LENGTH};
+use signer::SignFuture;
 use ethcore_transaction::{Transaction, Action};
-use itertools::Itertools;
 use super::nonce::{NonceCheck, SendRawTransaction};
-use super::BridgeChecked;
+use super::{BridgeChecked, EventBatch, PendingTransactions};
+use super::retry::{RetryLogs, RevertedEvents, retry_logs};
+use super::gas_estimate::{GasEstimates, estimate_gas, sendable};
+use super::simulation::{self, Payout, RevertProbe, Simulations, simulate, skip_reverted};
 
 fn withdraws_filter(foreign: &foreign::ForeignBridge, address: Address) -> FilterBuilder {
 	let filter = foreign.events().withdraw().create_filter();
@@ -25,47 +32,107 @@ fn withdraw_submit_signature_payload(foreign: &foreign::ForeignBridge, withdraw_
 	foreign.functions().submit_signature().input(signature.0.to_vec(), withdraw_message).into()
 }
 
+/// State of `ForeignBridge` which tells why a signature of `withdraw_message` submitted by `account` reverts.
+fn withdraw_confirm_probe(account: Address, withdraw_message: &[u8]) -> RevertProbe {
+	let hash = simulation::sender_hash(account, keccak(withdraw_message));
+	RevertProbe {
+		handled: simulation::mapping_key(hash, simulation::FOREIGN_MESSAGES_SIGNED_SLOT),
+		authority: Some(simulation::mapping_key(simulation::address_key(account), simulation::FOREIGN_AUTHORITIES_SLOT)),
+		payout: Payout::Nothing,
+	}
+}
+
 /// State of withdraw confirmation.
 enum WithdrawConfirmState<T: Transport> {
 	/// Withdraw confirm is waiting for logs.
 	Wait,
+	/// Signing the withdraw messages.
+	Sign {
+		future: JoinAll<Vec<SignFuture<H520>>>,
+		transfers: Vec<Transfer>,
+		messages: Vec<Vec<u8>>,
+		/// Gas limit the signatures are submitted with.
+		gas: u64,
+		checkpoint: Option<(u64, H256)>,
+	},
+	/// Simulating the signature submissions.
+	Simulate {
+		future: Simulations<T>,
+		confirmations: Vec<(Transfer, Bytes)>,
+		/// Gas limit the signatures are submitted with.
+		gas: u64,
+		checkpoint: Option<(u64, H256)>,
+	},
+	/// Estimating gas of the signature submissions.
+	EstimateGas {
+		future: GasEstimates<T>,
+		confirmations: Vec<(Transfer, Bytes)>,
+		checkpoint: Option<(u64, H256)>,
+	},
 	/// Confirming withdraws.
 	ConfirmWithdraws {
-		future: Collect<FuturesUnordered<NonceCheck<T, SendRawTransaction<T>>>>,
-		block: u64,
+		future: EventBatch<Transfer, NonceCheck<T, SendRawTransaction<T>>>,
+		/// Block checked once the withdraws are confirmed, `None` for reverted confirmations sent again.
+		checkpoint: Option<(u64, H256)>,
 	},
 	/// All withdraws till given block has been confirmed.
-	Yield(Option<u64>),
+	Yield(Option<(u64, H256)>),
 }
 
-pub fn create_withdraw_confirm<T: Transport + Clone>(app: Arc<App<T>>, init: &Database, foreign_balance: Arc<RwLock<Option<U256>>>, foreign_chain_id: u64, foreign_gas_price: Arc<RwLock<u64>>) -> WithdrawConfirm<T> {
+pub fn create_withdraw_confirm<T: Transport + Clone>(app: Arc<App<T>>, init: &Database, foreign_balance: Arc<RwLock<Option<U256>>>, foreign_chain_id: u64, foreign_gas_price: Arc<RwLock<u64>>, gas_limit: Arc<RwLock<u64>>, foreign_pending: PendingTransactions, reverted: RevertedEvents) -> WithdrawConfirm<T> {
 	let logs_init = api::LogStreamInit {
 		after: init.checked_withdraw_confirm,
 		request_timeout: app.config.foreign.request_timeout,
 		poll_interval: app.config.foreign.poll_interval,
 		confirmations: app.config.foreign.required_confirmations,
+		max_block_range: app.config.foreign.max_block_range,
+		after_hash: init.checked_withdraw_confirm_hash,
+		heads: app.connections.foreign_heads.clone(),
+		finality: app.config.foreign.finality,
 		filter: withdraws_filter(&app.foreign_bridge, init.foreign_contract_address.clone()),
 	};
+	let log_quorum = api::LogQuorum {
+		endpoints: app.connections.foreign_log_quorum.clone(),
+		required: app.config.foreign.log_quorum,
+		max_attempts: app.config.foreign.log_quorum_max_attempts,
+	};
 
 	WithdrawConfirm {
-		logs: api::log_stream(app.connections.foreign.clone(), app.timer.clone(), logs_init),
+		logs: api::quorum_log_stream(app.connections.foreign.clone(), log_quorum, app.timer.clone(), logs_init),
+		retry_logs: retry_logs(app.connections.foreign.clone(), app.timer.clone(), app.config.foreign.request_timeout,
+			withdraws_filter(&app.foreign_bridge, init.foreign_contract_address), reverted),
 		foreign_contract: init.foreign_contract_address,
 		state: WithdrawConfirmState::Wait,
+		confirmed: init.confirmed_withdraws.iter().cloned().collect(),
+		queued: VecDeque::new(),
 		app,
 		foreign_balance,
 		foreign_chain_id,
 		foreign_gas_price,
+		gas_limit,
+		foreign_pending,
 	}
 }
 
 pub struct WithdrawConfirm<T: Transport> {
 	app: Arc<App<T>>,
 	logs: LogStream<T>,
+	/// Logs of withdraws whose confirmation reverted.
+	retry_logs: RetryLogs<T>,
 	state: WithdrawConfirmState<T>,
+	/// Withdraws already confirmed in blocks which are not fully checked yet.
+	confirmed: HashSet<EventId>,
+	/// Withdraws which are not confirmed, because they can't be decoded or their confirmation would revert,
+	/// and transfers about to be sent, which are recorded as pending first.
+	queued: VecDeque<BridgeChecked>,
 	foreign_contract: Address,
 	foreign_balance: Arc<RwLock<Option<U256>>>,
 	foreign_chain_id: u64,
 	foreign_gas_price: Arc<RwLock<u64>>,
+	/// Gas limit of signature submissions set in the foreign contract.
+	gas_limit: Arc<RwLock<u64>>,
+	/// Confirmation transactions which are not mined yet.
+	foreign_pending: PendingTransactions,
 }
 
 impl<T: Transport> Stream for WithdrawConfirm<T> {
@@ -75,57 +142,120 @@ impl<T: Transport> Stream for WithdrawConfirm<T> {
 	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
 		// borrow checker...
 		let app = &self.app;
-		let gas = self.app.config.txs.withdraw_confirm.gas.into();
 		let gas_price = U256::from(*self.foreign_gas_price.read().unwrap());
+		let gas_limit = app.config.txs.withdraw_confirm.gas_limit(*self.gas_limit.read().unwrap());
 		let contract = self.foreign_contract.clone();
 		loop {
+			if let Some(queued) = self.queued.pop_front() {
+				return Ok(Some(queued).into());
+			}
+
 			let next_state = match self.state {
 				WithdrawConfirmState::Wait => {
-					let foreign_balance = self.foreign_balance.read().unwrap();
-					if foreign_balance.is_none() {
+					if self.foreign_balance.read().unwrap().is_none() {
 						warn!("foreign contract balance is unknown");
 						return Ok(futures::Async::NotReady);
 					}
+					if gas_limit == 0 {
+						return Err(ErrorKind::ConfigError("withdraw confirm gas limit is not set".into()).into());
+					}
 
-					let item = try_stream!(self.logs.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "polling foreign for withdrawals")));
-					let len = item.logs.len();
-					info!("got {} new withdraws to sign", len);
-					let mut messages = item.logs
+					let retry = self.retry_logs.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "fetching reverted withdraw confirmations"))?;
+					let (logs, checkpoint) = match retry {
+						futures::Async::Ready(Some(logs)) => (logs, None),
+						_ => {
+							let item = try_stream!(self.logs.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "polling foreign for withdrawals")));
+							(item.logs, Some((item.to, item.hash)))
+						},
+					};
+					let logs = logs
 						.into_iter()
-						.map(|log| {
-							 info!("withdraw is ready for signature submission. tx hash {}", log.transaction_hash.unwrap());
-							 Ok(MessageToMainnet::from_log(log)?.to_bytes())
-						})
+						.map(|log| Ok((EventId::from_log(&log)?, log)))
 						.collect::<Result<Vec<_>, Error>>()?;
+					if checkpoint.is_none() {
+						info!("confirming {} withdraws again after their confirmations reverted", logs.len());
+						for (id, _) in &logs {
+							self.confirmed.remove(id);
+						}
+					}
+					let (confirmed, logs): (Vec<_>, Vec<_>) = logs.into_iter().partition(|(id, _)| self.confirmed.contains(id));
+					if !confirmed.is_empty() {
+						info!("skipping {} withdraws confirmed before restart", confirmed.len());
+					}
+					info!("got {} new withdraws to sign", logs.len());
+					let mut withdraws = Vec::new();
+					for (id, log) in logs {
+						match MessageToMainnet::from_log(log) {
+							Ok(message) => {
+								info!("withdraw is ready for signature submission. tx hash {}", message.sidenet_transaction_hash);
+								let transfer = Transfer::new(TransferKind::WithdrawConfirm, id, message.sidenet_transaction_hash, message.recipient, message.value, gas_price);
+								withdraws.push((transfer, message.to_bytes()));
+							},
+							Err(err) => self.queued.push_back(BridgeChecked::DeadLetter(TransferKind::WithdrawConfirm, id, err.to_string())),
+						}
+					}
+					let (transfers, messages): (Vec<_>, Vec<_>) = withdraws.into_iter().unzip();
 
 					info!("signing");
-
-					let signatures = messages.clone()
-						.into_iter()
-						.map(|message|
-							app.keystore.sign(self.app.config.foreign.account, None, api::eth_data_hash(message)))
-						.map_results(|sig| H520::from(sig.into_electrum()))
-						.fold_results(vec![], |mut acc, sig| {
-							acc.push(sig);
-							acc
-						})
-						.map_err(|e| ErrorKind::SignError(e))?;
-
-					let block = item.to;
-
-					let balance_required = gas * gas_price * U256::from(signatures.len());
-					if balance_required > *foreign_balance.as_ref().unwrap() {
-						return Err(ErrorKind::InsufficientFunds.into())
+					let signatures = messages.iter()
+						.map(|message| app.signer.sign_message(app.config.foreign.account, message.clone()))
+						.collect::<Vec<_>>();
+					WithdrawConfirmState::Sign {
+						future: join_all(signatures),
+						transfers,
+						messages,
+						gas: gas_limit,
+						checkpoint,
 					}
-
+				},
+				WithdrawConfirmState::Sign { ref mut future, ref mut transfers, ref mut messages, gas, checkpoint } => {
+					let signatures = try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "signing withdraws")));
 					info!("signing complete");
-					let confirmations = messages
+					let probes = messages.iter()
+						.map(|message| withdraw_confirm_probe(app.config.foreign.account, message))
+						.collect::<Vec<_>>();
+					let payloads = messages
 						.drain(ops::RangeFull)
 						.zip(signatures.into_iter())
 						.map(|(withdraw_message, signature)| {
 							 withdraw_submit_signature_payload(&app.foreign_bridge, withdraw_message, signature)
 						})
-						.map(|payload| {
+						.collect::<Vec<_>>();
+
+					let simulated = payloads.iter()
+						.zip(probes)
+						.map(|(payload, probe)| (payload.clone(), U256::from(gas), gas_price, probe))
+						.collect::<Vec<_>>();
+					WithdrawConfirmState::Simulate {
+						future: simulate(&app.connections.foreign, &app.timer, app.config.foreign.request_timeout,
+							app.config.foreign.account, contract, &simulated),
+						confirmations: transfers.drain(..).zip(payloads).collect(),
+						gas,
+						checkpoint,
+					}
+				},
+				WithdrawConfirmState::Simulate { ref mut future, ref mut confirmations, gas, checkpoint } => {
+					let outcomes = try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "simulating signature submissions")));
+					let confirmations = skip_reverted(confirmations.drain(..).collect(), outcomes, &mut self.queued);
+
+					let payloads = confirmations.iter().map(|(_, payload)| payload.clone()).collect::<Vec<_>>();
+					WithdrawConfirmState::EstimateGas {
+						future: estimate_gas(&app.connections.foreign, &app.timer, app.config.foreign.request_timeout,
+							app.config.foreign.account, contract, &app.config.txs.withdraw_confirm, gas, &payloads),
+						confirmations,
+						checkpoint,
+					}
+				},
+				WithdrawConfirmState::EstimateGas { ref mut future, ref mut confirmations, checkpoint } => {
+					let gas = try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "estimating gas of signature submissions")));
+					let balance = self.foreign_balance.read().unwrap().expect("balance is known before relaying; qed");
+					let confirmations = sendable(confirmations.drain(..).collect(), gas, balance, &mut self.queued)?;
+					let len = confirmations.len();
+
+					self.queued.extend(confirmations.iter().map(|(transfer, _, _)| BridgeChecked::Transfer(*transfer)));
+					let confirmations = confirmations
+						.into_iter()
+						.map(|(transfer, payload, gas)| {
 							let tx = Transaction {
 								gas,
 								gas_price,
@@ -134,27 +264,41 @@ impl<T: Transport> Stream for WithdrawConfirm<T> {
 								nonce: U256::zero(),
 								action: Action::Call(contract),
 							};
-							api::send_transaction_with_nonce(self.app.connections.foreign.clone(), self.app.clone(), self.app.config.foreign.clone(),
-															 tx, self.foreign_chain_id, SendRawTransaction(self.app.connections.foreign.clone()))
+							(transfer, api::send_transaction_with_nonce(self.app.connections.foreign.clone(), self.app.clone(), self.app.config.foreign.clone(),
+															 tx, self.foreign_chain_id, SendRawTransaction { transport: self.app.connections.foreign.clone(), dry_run: self.app.dry_run }))
 						}).collect_vec();
 
 					info!("submitting {} signatures", len);
 					WithdrawConfirmState::ConfirmWithdraws {
-						future: futures_unordered(confirmations).collect(),
-						block,
+						future: EventBatch::new(confirmations),
+						checkpoint,
 					}
 				},
-				WithdrawConfirmState::ConfirmWithdraws { ref mut future, block } => {
-					let _ = try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "sending signature submissions to foreign")));
-					info!("submitting signatures complete");
-					WithdrawConfirmState::Yield(Some(block))
+				WithdrawConfirmState::ConfirmWithdraws { ref mut future, checkpoint } => {
+					match try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "sending signature submissions to foreign"))) {
+						Some((transfer, sent)) => {
+							if !self.app.dry_run {
+								self.foreign_pending.add(&sent)?;
+							}
+							let transfer = transfer.sent(sent.result, sent.transaction.nonce);
+							self.confirmed.insert(transfer.event);
+							return Ok(Some(BridgeChecked::Transfer(transfer)).into());
+						},
+						None => {
+							info!("submitting signatures complete");
+							if let Some((block, _)) = checkpoint {
+								self.confirmed.retain(|id| id.block_number > block);
+							}
+							WithdrawConfirmState::Yield(checkpoint)
+						},
+					}
 				},
 				WithdrawConfirmState::Yield(ref mut block) => match block.take() {
 					None => {
 						info!("waiting for new withdraws that should get signed");
 						WithdrawConfirmState::Wait
 					},
-					Some(v) => return Ok(Some(BridgeChecked::WithdrawConfirm(v)).into()),
+					Some((block, hash)) => return Ok(Some(BridgeChecked::WithdrawConfirm(block, hash)).into()),
 				}
 			};
 			self.state = next_state;
@@ -1,21 +1,26 @@
+use std::collections::{HashSet, VecDeque};
 use std::sync::{Arc, RwLock};
-use futures::{self, Future, Stream, stream::{Collect, FuturesUnordered, futures_unordered}, Poll};
+use futures::{self, Future, Stream, Poll};
 use futures::future::{JoinAll, join_all, Join};
 use tokio_timer::Timeout;
 use web3::Transport;
-use web3::types::{U256, Address, FilterBuilder, Log, Bytes};
-use ethabi::{RawLog, self};
+use web3::types::{U256, H256, Address, FilterBuilder, Log, Bytes};
+use ethabi::RawLog;
 use app::App;
 use api::{self, LogStream, ApiCall};
 use contracts::foreign;
 use util::web3_filter;
-use database::Database;
+use database::{Database, EventId};
+use ledger::{Transfer, TransferKind};
 use error::{self, Error, ErrorKind};
-use message_to_mainnet::MessageToMainnet;
+use message_to_mainnet::{MessageToMainnet, MESSAGE_LENGTH};
 use signature::Signature;
 use ethcore_transaction::{Transaction, Action};
 use super::nonce::{NonceCheck, SendRawTransaction};
-use super::BridgeChecked;
+use super::{BridgeChecked, EventBatch, PendingTransactions};
+use super::retry::{RetryLogs, RevertedEvents, retry_logs};
+use super::gas_estimate::{GasEstimates, estimate_gas, sendable};
+use super::simulation::{self, Payout, RevertProbe, Simulations, simulate, skip_reverted};
 use itertools::Itertools;
 
 /// returns a filter for `ForeignBridge.CollectedSignatures` events
@@ -62,6 +67,27 @@ fn signatures_payload(foreign: &foreign::ForeignBridge, my_address: Address, log
 	}))
 }
 
+/// decodes the results of the calls to `ForeignBridge.message` and `ForeignBridge.signature`
+fn decode_withdraw(foreign: &foreign::ForeignBridge, message: &Bytes, signatures: &[Bytes]) -> error::Result<(Bytes, Vec<Signature>)> {
+	let message = Bytes(foreign.functions().message().output(message.0.as_slice())?);
+	if message.0.len() != MESSAGE_LENGTH {
+		bail!("message is {} bytes long, expected {}", message.0.len(), MESSAGE_LENGTH);
+	}
+	let signatures = signatures.iter()
+		.map(|signature| Signature::from_bytes(foreign.functions().signature().output(signature.0.as_slice())?.as_slice()))
+		.collect::<error::Result<Vec<_>>>()?;
+	Ok((message, signatures))
+}
+
+/// State of `HomeBridge` which tells why the relay of `transfer` reverts. Anyone may relay a withdraw.
+fn withdraw_relay_probe(transfer: &Transfer) -> RevertProbe {
+	RevertProbe {
+		handled: simulation::mapping_key(transfer.source_tx_hash, simulation::HOME_WITHDRAWS_SLOT),
+		authority: None,
+		payout: Payout::Ether(transfer.value),
+	}
+}
+
 /// state of the withdraw relay state machine
 pub enum WithdrawRelayState<T: Transport> {
 	Wait,
@@ -70,45 +96,85 @@ pub enum WithdrawRelayState<T: Transport> {
 			JoinAll<Vec<Timeout<ApiCall<Bytes, T::Out>>>>,
 			JoinAll<Vec<JoinAll<Vec<Timeout<ApiCall<Bytes, T::Out>>>>>>
 		>,
-		block: u64,
+		/// Ids of the withdraws, in the order of fetched messages.
+		ids: Vec<EventId>,
+		/// Gas limit the withdraws are relayed with.
+		gas: u64,
+		/// Block checked once the withdraws are relayed, `None` for reverted withdraws relayed again.
+		checkpoint: Option<(u64, H256)>,
+	},
+	Simulate {
+		future: Simulations<T>,
+		relays: Vec<(Transfer, Bytes)>,
+		gas: u64,
+		checkpoint: Option<(u64, H256)>,
+	},
+	EstimateGas {
+		future: GasEstimates<T>,
+		relays: Vec<(Transfer, Bytes)>,
+		checkpoint: Option<(u64, H256)>,
 	},
 	RelayWithdraws {
-		future: Collect<FuturesUnordered<NonceCheck<T, SendRawTransaction<T>>>>,
-		block: u64,
+		future: EventBatch<Transfer, NonceCheck<T, SendRawTransaction<T>>>,
+		checkpoint: Option<(u64, H256)>,
 	},
-	Yield(Option<u64>),
+	Yield(Option<(u64, H256)>),
 }
 
-pub fn create_withdraw_relay<T: Transport + Clone>(app: Arc<App<T>>, init: &Database, home_balance: Arc<RwLock<Option<U256>>>, home_chain_id: u64, home_gas_price: Arc<RwLock<u64>>) -> WithdrawRelay<T> {
+pub fn create_withdraw_relay<T: Transport + Clone>(app: Arc<App<T>>, init: &Database, home_balance: Arc<RwLock<Option<U256>>>, home_chain_id: u64, gas_limit: Arc<RwLock<u64>>, home_pending: PendingTransactions, reverted: RevertedEvents) -> WithdrawRelay<T> {
 	let logs_init = api::LogStreamInit {
 		after: init.checked_withdraw_relay,
 		request_timeout: app.config.foreign.request_timeout,
 		poll_interval: app.config.foreign.poll_interval,
 		confirmations: app.config.foreign.required_confirmations,
+		max_block_range: app.config.foreign.max_block_range,
+		after_hash: init.checked_withdraw_relay_hash,
+		heads: app.connections.foreign_heads.clone(),
+		finality: app.config.foreign.finality,
 		filter: collected_signatures_filter(&app.foreign_bridge, vec![init.foreign_contract_address]),
 	};
+	let log_quorum = api::LogQuorum {
+		endpoints: app.connections.foreign_log_quorum.clone(),
+		required: app.config.foreign.log_quorum,
+		max_attempts: app.config.foreign.log_quorum_max_attempts,
+	};
 
 	WithdrawRelay {
-		logs: api::log_stream(app.connections.foreign.clone(), app.timer.clone(), logs_init),
+		logs: api::quorum_log_stream(app.connections.foreign.clone(), log_quorum, app.timer.clone(), logs_init),
+		retry_logs: retry_logs(app.connections.foreign.clone(), app.timer.clone(), app.config.foreign.request_timeout,
+			collected_signatures_filter(&app.foreign_bridge, vec![init.foreign_contract_address]), reverted),
 		home_contract: init.home_contract_address,
 		foreign_contract: init.foreign_contract_address,
 		state: WithdrawRelayState::Wait,
+		relayed: init.relayed_withdraws.iter().cloned().collect(),
+		queued: VecDeque::new(),
 		app,
 		home_balance,
 		home_chain_id,
-		home_gas_price,
+		gas_limit,
+		home_pending,
 	}
 }
 
 pub struct WithdrawRelay<T: Transport> {
 	app: Arc<App<T>>,
 	logs: LogStream<T>,
+	/// Logs of withdraws whose relay reverted.
+	retry_logs: RetryLogs<T>,
 	state: WithdrawRelayState<T>,
+	/// Withdraws already relayed in blocks which are not fully checked yet.
+	relayed: HashSet<EventId>,
+	/// Withdraws which are not relayed, because they can't be decoded or their relay would revert,
+	/// and transfers about to be sent, which are recorded as pending first.
+	queued: VecDeque<BridgeChecked>,
 	foreign_contract: Address,
 	home_contract: Address,
 	home_balance: Arc<RwLock<Option<U256>>>,
 	home_chain_id: u64,
-	home_gas_price: Arc<RwLock<u64>>,
+	/// Gas limit of withdraw relays set in the home contract.
+	gas_limit: Arc<RwLock<u64>>,
+	/// Relay transactions which are not mined yet.
+	home_pending: PendingTransactions,
 }
 
 impl<T: Transport> Stream for WithdrawRelay<T> {
@@ -117,8 +183,7 @@ impl<T: Transport> Stream for WithdrawRelay<T> {
 
 	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
 		let app = &self.app;
-		let gas = self.app.config.txs.withdraw_relay.gas.into();
-		let gas_price = U256::from(*self.home_gas_price.read().unwrap());
+		let gas_limit = app.config.txs.withdraw_relay.gas_limit(*self.gas_limit.read().unwrap());
 		let contract = self.home_contract.clone();
 		let home = &self.app.config.home;
 		let t = &self.app.connections.home;
@@ -131,21 +196,55 @@ impl<T: Transport> Stream for WithdrawRelay<T> {
 		let foreign_request_timeout = self.app.config.foreign.request_timeout;
 
 		loop {
+			if let Some(queued) = self.queued.pop_front() {
+				return Ok(Some(queued).into());
+			}
+
 			let next_state = match self.state {
 				WithdrawRelayState::Wait => {
-					let item = try_stream!(self.logs.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "polling foreign for collected signatures")));
-					info!("got {} new signed withdraws to relay", item.logs.len());
-					let assignments = item.logs
+					if self.home_balance.read().unwrap().is_none() {
+						warn!("home contract balance is unknown");
+						return Ok(futures::Async::NotReady);
+					}
+					if gas_limit == 0 {
+						return Err(ErrorKind::ConfigError("withdraw relay gas limit is not set".into()).into());
+					}
+
+					let retry = self.retry_logs.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "fetching reverted withdraw relays"))?;
+					let (logs, checkpoint) = match retry {
+						futures::Async::Ready(Some(logs)) => (logs, None),
+						_ => {
+							let item = try_stream!(self.logs.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "polling foreign for collected signatures")));
+							(item.logs, Some((item.to, item.hash)))
+						},
+					};
+					let logs = logs
 						.into_iter()
-						.map(|log| signatures_payload(
-								foreign_bridge,
-								foreign_account,
-								 log))
+						.map(|log| Ok((EventId::from_log(&log)?, log)))
 						.collect::<error::Result<Vec<_>>>()?;
+					if checkpoint.is_none() {
+						info!("relaying {} reverted withdraws again", logs.len());
+						for (id, _) in &logs {
+							self.relayed.remove(id);
+						}
+					}
+					let (relayed, logs): (Vec<_>, Vec<_>) = logs.into_iter().partition(|(id, _)| self.relayed.contains(id));
+					if !relayed.is_empty() {
+						info!("skipping {} withdraws relayed before restart", relayed.len());
+					}
+					info!("got {} new signed withdraws to relay", logs.len());
+					let mut assignments = Vec::new();
+					for (id, log) in logs {
+						match signatures_payload(foreign_bridge, foreign_account, log) {
+							Ok(Some(assignment)) => assignments.push((id, assignment)),
+							Ok(None) => (),
+							Err(err) => self.queued.push_back(BridgeChecked::DeadLetter(TransferKind::WithdrawRelay, id, err.to_string())),
+						}
+					}
 
+					let ids = assignments.iter().map(|(id, _)| *id).collect();
 					let (signatures, messages): (Vec<_>, Vec<_>) = assignments.into_iter()
-						.filter_map(|a| a)
-						.map(|assignment| (assignment.signature_payloads, assignment.message_payload))
+						.map(|(_, assignment)| (assignment.signature_payloads, assignment.message_payload))
 						.unzip();
 
 					let message_calls = messages.into_iter()
@@ -172,90 +271,109 @@ impl<T: Transport> Stream for WithdrawRelay<T> {
 					info!("fetching messages and signatures");
 					WithdrawRelayState::FetchMessagesSignatures {
 						future: join_all(message_calls).join(join_all(signature_calls)),
-						block: item.to,
+						ids,
+						gas: gas_limit,
+						checkpoint,
 					}
 				},
-				WithdrawRelayState::FetchMessagesSignatures { ref mut future, block } => {
-					let home_balance = self.home_balance.read().unwrap();
-					if home_balance.is_none() {
-						warn!("home contract balance is unknown");
-						return Ok(futures::Async::NotReady);
-					}
-
+				WithdrawRelayState::FetchMessagesSignatures { ref mut future, ref mut ids, gas, checkpoint } => {
 					let (messages_raw, signatures_raw) = try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "fetching messages and signatures from foreign")));
 					info!("fetching messages and signatures complete");
 					assert_eq!(messages_raw.len(), signatures_raw.len());
 
-					let balance_required = gas * gas_price * U256::from(messages_raw.len());
-					if balance_required > *home_balance.as_ref().unwrap() {
-						return Err(ErrorKind::InsufficientFunds.into())
+					let mut withdraws = Vec::new();
+					for (id, (message, signatures)) in ids.drain(..).zip(messages_raw.iter().zip(signatures_raw.iter())) {
+						match decode_withdraw(foreign_bridge, message, signatures) {
+							Ok(withdraw) => withdraws.push((id, withdraw)),
+							Err(err) => self.queued.push_back(BridgeChecked::DeadLetter(TransferKind::WithdrawRelay, id, err.to_string())),
+						}
 					}
 
-					let messages = messages_raw
-						.iter()
-						.map(|message| {
-							app.foreign_bridge.functions().message().output(message.0.as_slice()).map(Bytes)
-						})
-						.collect::<ethabi::Result<Vec<_>>>()
-						.map_err(error::Error::from)?;
-
-					let len = messages.len();
-
-					let signatures = signatures_raw
-						.iter()
-						.map(|signatures|
-							signatures.iter().map(
-								|signature| {
-									Signature::from_bytes(
-										app.foreign_bridge
-											.functions()
-											.signature()
-											.output(signature.0.as_slice())?
-											.as_slice())
-								}
-							)
-							.collect::<Result<Vec<_>, Error>>()
-							.map_err(error::Error::from)
-						)
-						.collect::<error::Result<Vec<_>>>()?;
-
-					let relays = messages.into_iter()
-						.zip(signatures.into_iter())
-						.map(|(message, signatures)| {
+					let relays = withdraws.into_iter()
+						.map(|(id, (message, signatures))| {
 							let payload: Bytes = app.home_bridge.functions().withdraw().input(
 								signatures.iter().map(|x| x.v),
 								signatures.iter().map(|x| x.r),
 								signatures.iter().map(|x| x.s),
 								message.clone().0).into();
-							let gas_price = MessageToMainnet::from_bytes(message.0.as_slice()).mainnet_gas_price;
+							let withdraw = MessageToMainnet::from_bytes(message.0.as_slice());
+							let gas_price = withdraw.mainnet_gas_price;
+							let transfer = Transfer::new(TransferKind::WithdrawRelay, id, withdraw.sidenet_transaction_hash, withdraw.recipient, withdraw.value, gas_price);
+							(transfer, payload)
+						}).collect::<Vec<_>>();
+
+					let payloads = relays.iter()
+						.map(|(transfer, payload)| (payload.clone(), U256::from(gas), transfer.gas_price, withdraw_relay_probe(transfer)))
+						.collect::<Vec<_>>();
+					WithdrawRelayState::Simulate {
+						future: simulate(t, timer, home.request_timeout, home.account, contract, &payloads),
+						relays,
+						gas,
+						checkpoint,
+					}
+				},
+				WithdrawRelayState::Simulate { ref mut future, ref mut relays, gas, checkpoint } => {
+					let outcomes = try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "simulating withdraw relays")));
+					let relays = skip_reverted(relays.drain(..).collect(), outcomes, &mut self.queued);
+
+					let payloads = relays.iter().map(|(_, payload)| payload.clone()).collect::<Vec<_>>();
+					WithdrawRelayState::EstimateGas {
+						future: estimate_gas(t, timer, home.request_timeout, home.account, contract, &app.config.txs.withdraw_relay, gas, &payloads),
+						relays,
+						checkpoint,
+					}
+				},
+				WithdrawRelayState::EstimateGas { ref mut future, ref mut relays, checkpoint } => {
+					let gas = try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "estimating gas of withdraw relays")));
+					let balance = self.home_balance.read().unwrap().expect("balance is known before relaying; qed");
+					let relays = sendable(relays.drain(..).collect(), gas, balance, &mut self.queued)?;
+					let len = relays.len();
+
+					self.queued.extend(relays.iter().map(|(transfer, _, _)| BridgeChecked::Transfer(*transfer)));
+					let relays = relays.into_iter()
+						.map(|(transfer, payload, gas)| {
 							let tx = Transaction {
 									gas,
-									gas_price,
+									gas_price: transfer.gas_price,
 									value: U256::zero(),
 									data: payload.0,
 									nonce: U256::zero(),
 									action: Action::Call(contract),
 								};
-							    api::send_transaction_with_nonce(t.clone(), app.clone(), home.clone(), tx, chain_id, SendRawTransaction(t.clone()))
+							(transfer, api::send_transaction_with_nonce(t.clone(), app.clone(), home.clone(), tx, chain_id, SendRawTransaction { transport: t.clone(), dry_run: app.dry_run }))
 							}).collect_vec();
 
 					info!("relaying {} withdraws", len);
 					WithdrawRelayState::RelayWithdraws {
-						future: futures_unordered(relays).collect(),
-						block,
+						future: EventBatch::new(relays),
+						checkpoint,
 					}
 				},
-				WithdrawRelayState::RelayWithdraws { ref mut future, block } => {
-					let _ = try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "sending withdrawal to home")));
-					info!("relaying withdraws complete");
-					WithdrawRelayState::Yield(Some(block))
+				WithdrawRelayState::RelayWithdraws { ref mut future, checkpoint } => {
+					match try_ready!(future.poll().map_err(|e| ErrorKind::ContextualizedError(Box::new(e), "sending withdrawal to home"))) {
+						Some((transfer, sent)) => {
+							if !self.app.dry_run {
+								self.home_pending.add_at_fixed_gas_price(&sent)?;
+							}
+							let transfer = transfer.sent(sent.result, sent.transaction.nonce);
+							self.relayed.insert(transfer.event);
+							return Ok(Some(BridgeChecked::Transfer(transfer)).into());
+						},
+						None => {
+							info!("relaying withdraws complete");
+							if let Some((block, _)) = checkpoint {
+								self.relayed.retain(|id| id.block_number > block);
+							}
+							WithdrawRelayState::Yield(checkpoint)
+						},
+					}
 				},
 				WithdrawRelayState::Yield(ref mut block) => match block.take() {
 					None => {
 						info!("waiting for signed withdraws to relay");
 						WithdrawRelayState::Wait
 					},
-					Some(v) => return Ok(Some(BridgeChecked::WithdrawRelay(v)).into()),
+					Some((block, hash)) => return Ok(Some(BridgeChecked::WithdrawRelay(block, hash)).into()),
 				}
 			};
 			self.state = next_state;
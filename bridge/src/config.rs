@@ -9,6 +9,7 @@ use web3::types::Address;
 #[cfg(feature = "deploy")]
 use web3::types::Bytes;
 use error::{ResultExt, Error, ErrorKind};
+use nonce_manager::NonceManager;
 use {toml};
 
 const DEFAULT_POLL_INTERVAL: u64 = 1;
@@ -16,9 +17,21 @@ const DEFAULT_CONFIRMATIONS: usize = 12;
 const DEFAULT_TIMEOUT: u64 = 3600;
 const DEFAULT_RPC_PORT: u16 = 8545;
 pub(crate) const DEFAULT_CONCURRENCY: usize = 64;
+pub(crate) const DEFAULT_RPC_MAX_FAILURES: u32 = 3;
+pub(crate) const DEFAULT_RPC_PRIMARY_RETRY_INTERVAL: u64 = 60;
+pub(crate) const DEFAULT_LOG_QUORUM_MAX_ATTEMPTS: u32 = 5;
 const DEFAULT_GAS_PRICE_SPEED: GasPriceSpeed = GasPriceSpeed::Fast;
 const DEFAULT_GAS_PRICE_TIMEOUT_SECS: u64 = 10;
 const DEFAULT_GAS_PRICE_WEI: u64 = 15_000_000_000;
+pub(crate) const DEFAULT_GAS_PRICE_BUMP_BLOCKS: u64 = 20;
+pub(crate) const DEFAULT_GAS_PRICE_BUMP_PERCENT: u64 = 20;
+pub(crate) const DEFAULT_MAX_GAS_PRICE_WEI: u64 = 100_000_000_000;
+pub(crate) const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+pub(crate) const DEFAULT_PRIORITY_FEE_WEI: u64 = 1_500_000_000;
+pub(crate) const DEFAULT_GAS_MULTIPLIER: f64 = 1.2;
+const DEFAULT_SIGNER_TIMEOUT_SECS: u64 = 10;
+/// Nodes reject replacement transactions which don't raise the gas price by at least 10%.
+const MIN_GAS_PRICE_BUMP_PERCENT: u64 = 10;
 
 /// Application config.
 #[derive(Debug, PartialEq, Clone)]
@@ -29,7 +42,6 @@ pub struct Config {
 	pub txs: Transactions,
 	#[cfg(feature = "deploy")]
 	pub estimated_gas_cost_of_withdraw: u32,
-	pub keystore: PathBuf,
 }
 
 impl Config {
@@ -46,25 +58,85 @@ impl Config {
 	}
 
 	fn from_load_struct(config: load::Config, allow_insecure_rpc_endpoints: bool) -> Result<Config, Error> {
+		let home = Node::from_load_struct(config.home, &config.keystore, &config.signer, allow_insecure_rpc_endpoints)?;
+		let foreign = Node::from_load_struct(config.foreign, &config.keystore, &config.signer, allow_insecure_rpc_endpoints)?;
+		if home.account == foreign.account && home.signer != foreign.signer {
+			return Err(ErrorKind::ConfigError("home and foreign use the same account, so they must use the same signer".into()).into());
+		}
+		// HomeBridge.withdraw requires the gas price of the relay to be the one chosen on foreign
+		if home.transaction_type == TransactionType::Eip1559 {
+			return Err(ErrorKind::ConfigError("home.transaction_type can't be eip1559, withdraw relays must pay exactly the gas price of their message".into()).into());
+		}
+
 		let result = Config {
-			home: Node::from_load_struct(config.home, allow_insecure_rpc_endpoints)?,
-			foreign: Node::from_load_struct(config.foreign, allow_insecure_rpc_endpoints)?,
+			home,
+			foreign,
 			authorities: Authorities {
 				#[cfg(feature = "deploy")]
 				accounts: config.authorities.accounts,
 				#[cfg(feature = "deploy")]
 				required_signatures: config.authorities.required_signatures,
 			},
-			txs: config.transactions.map(Transactions::from_load_struct).unwrap_or_default(),
+			txs: match config.transactions {
+				Some(txs) => Transactions::from_load_struct(txs)?,
+				None => Transactions::default(),
+			},
 			#[cfg(feature = "deploy")]
 			estimated_gas_cost_of_withdraw: config.estimated_gas_cost_of_withdraw,
-			keystore: config.keystore,
 		};
 
 		Ok(result)
 	}
 }
 
+/// Backend signing the transactions and messages of a bridge account.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SignerConfig {
+	/// JSON keys in a keystore directory, decrypted with the `password` file of the node.
+	Keystore(PathBuf),
+	/// File holding a hex encoded private key.
+	PrivateKey(PathBuf),
+	/// Remote signer speaking `eth_signTransaction` and `eth_sign` over JSON-RPC.
+	Remote {
+		url: String,
+		timeout: Duration,
+	},
+}
+
+impl SignerConfig {
+	fn from_load_struct(signer: Option<load::Signer>, keystore: Option<PathBuf>, allow_insecure_rpc_endpoints: bool) -> Result<Self, Error> {
+		let keystore = || keystore
+			.map(SignerConfig::Keystore)
+			.ok_or_else(|| Error::from(ErrorKind::ConfigError("keystore is required by the keystore signer".into())));
+		let signer = match signer {
+			Some(signer) => signer,
+			None => return keystore(),
+		};
+
+		let result = match signer.kind.as_str() {
+			"keystore" => keystore()?,
+			"private_key" => SignerConfig::PrivateKey(signer.private_key
+				.ok_or_else(|| ErrorKind::ConfigError("signer.private_key is required by the private_key signer".into()))?),
+			"remote" => {
+				let url = signer.url
+					.ok_or_else(|| ErrorKind::ConfigError("signer.url is required by the remote signer".into()))?;
+				if !url.starts_with("https://") {
+					if !allow_insecure_rpc_endpoints {
+						return Err(ErrorKind::ConfigError(format!("remote signer must use TLS, {} doesn't", url)).into());
+					}
+					warn!("remote signer must use TLS, {} doesn't", url);
+				}
+				SignerConfig::Remote {
+					url,
+					timeout: Duration::from_secs(signer.timeout.unwrap_or(DEFAULT_SIGNER_TIMEOUT_SECS)),
+				}
+			},
+			kind => return Err(ErrorKind::ConfigError(format!("invalid signer type {}, expected one of: keystore, private_key, remote", kind)).into()),
+		};
+		Ok(result)
+	}
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Node {
 	pub account: Address,
@@ -73,41 +145,85 @@ pub struct Node {
 	pub request_timeout: Duration,
 	pub poll_interval: Duration,
 	pub required_confirmations: usize,
-	pub rpc_host: String,
+	/// `None` if the node is connected to over `ipc_path`.
+	pub rpc_host: Option<String>,
 	pub rpc_port: u16,
-	pub password: PathBuf,
+	/// Path to the IPC socket of a co-located node, used instead of `rpc_host`.
+	pub ipc_path: Option<PathBuf>,
+	/// Urls of rpc endpoints to fall back to when `rpc_host` is unavailable, in order of preference.
+	pub fallback_rpc_urls: Vec<String>,
+	/// Number of consecutive failures after which the next endpoint is used.
+	pub rpc_max_failures: u32,
+	/// How often the primary endpoint is retried while a fallback one is used.
+	pub rpc_primary_retry_interval: Duration,
+	/// Urls of independent rpc endpoints asked for the same logs as the main endpoint.
+	pub log_quorum_rpc_urls: Vec<String>,
+	/// Number of endpoints, including the main one, which must report a log identically for it to be relayed.
+	pub log_quorum: usize,
+	/// Number of times a block range is requested again before the bridge gives up on reaching the quorum.
+	pub log_quorum_max_attempts: u32,
+	/// Password file of the account, required by the keystore signer.
+	pub password: Option<PathBuf>,
+	/// Backend signing for the account.
+	pub signer: SignerConfig,
 	pub info: NodeInfo,
 	pub gas_price_oracle_url: Option<String>,
 	pub gas_price_speed: GasPriceSpeed,
 	pub gas_price_timeout: Duration,
 	pub default_gas_price: u64,
 	pub concurrent_http_requests: usize,
+	pub max_block_range: Option<u64>,
+	pub finality: Finality,
+	/// Number of blocks after which a transaction which is still not mined is replaced with a higher gas price.
+	pub gas_price_bump_blocks: u64,
+	/// Percentage by which the gas price of a replaced transaction is raised.
+	pub gas_price_bump_percent: u64,
+	/// Gas price above which transactions are never bumped.
+	pub max_gas_price: u64,
+	/// Whether transactions are sent as legacy or EIP-1559 ones.
+	pub transaction_type: TransactionType,
+	/// Priority fee of EIP-1559 transactions until one is read from `eth_feeHistory`.
+	pub default_priority_fee: u64,
 }
 
 use std::sync::{Arc, RwLock};
-use web3::types::U256;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct NodeInfo {
-    pub nonce: Arc<RwLock<U256>>,
+	pub nonces: NonceManager,
+	/// Priority fee paid by EIP-1559 transactions.
+	pub priority_fee: Arc<RwLock<u64>>,
 }
 
-impl Default for NodeInfo {
-	fn default() -> Self {
+impl NodeInfo {
+	fn new(priority_fee: u64) -> Self {
 		NodeInfo {
-			nonce: Arc::new(RwLock::new(U256::zero())),
+			nonces: NonceManager::default(),
+			priority_fee: Arc::new(RwLock::new(priority_fee)),
 		}
 	}
 }
 
 impl PartialEq for NodeInfo {
 	fn eq(&self, rhs: &Self) -> bool {
-		*self.nonce.read().unwrap() == *rhs.nonce.read().unwrap()
+		self.nonces == rhs.nonces && *self.priority_fee.read().unwrap() == *rhs.priority_fee.read().unwrap()
 	}
 }
 
 impl Node {
-	fn from_load_struct(node: load::Node, allow_insecure_rpc_endpoints: bool) -> Result<Node, Error> {
+	/// `keystore` and `signer` are the ones of all nodes, used unless the node has its own.
+	fn from_load_struct(node: load::Node, keystore: &Option<PathBuf>, signer: &Option<load::Signer>, allow_insecure_rpc_endpoints: bool) -> Result<Node, Error> {
+		let signer = SignerConfig::from_load_struct(
+			node.signer.or_else(|| signer.clone()),
+			node.keystore.or_else(|| keystore.clone()),
+			allow_insecure_rpc_endpoints,
+		)?;
+		if let SignerConfig::Keystore(_) = signer {
+			if node.password.is_none() {
+				return Err(ErrorKind::ConfigError(format!("password of {:?} is required by the keystore signer", node.account)).into());
+			}
+		}
+
 		let gas_price_oracle_url = node.gas_price_oracle_url.clone();
 
 		let gas_price_speed = match node.gas_price_speed {
@@ -120,16 +236,62 @@ impl Node {
 			Duration::from_secs(n_secs)
 		};
 
+		let finality = match node.finality {
+			Some(ref s) => Finality::from_str(s)
+				.map_err(|_| ErrorKind::ConfigError(format!("invalid finality {}, expected one of: depth, finalized, safe", s)))?,
+			None => Finality::Depth,
+		};
+
 		let default_gas_price = node.default_gas_price.unwrap_or(DEFAULT_GAS_PRICE_WEI);
+		let gas_price_bump_percent = node.gas_price_bump_percent.unwrap_or(DEFAULT_GAS_PRICE_BUMP_PERCENT);
+		if gas_price_bump_percent < MIN_GAS_PRICE_BUMP_PERCENT {
+			return Err(ErrorKind::ConfigError(format!("gas_price_bump_percent must be at least {}", MIN_GAS_PRICE_BUMP_PERCENT)).into());
+		}
+		let max_gas_price = node.max_gas_price.unwrap_or(DEFAULT_MAX_GAS_PRICE_WEI);
+		if max_gas_price < default_gas_price {
+			return Err(ErrorKind::ConfigError("max_gas_price can't be lower than default_gas_price".into()).into());
+		}
+		let transaction_type = match node.transaction_type {
+			Some(ref s) => TransactionType::from_str(s)
+				.map_err(|_| ErrorKind::ConfigError(format!("invalid transaction_type {}, expected one of: legacy, eip1559", s)))?,
+			None => TransactionType::Legacy,
+		};
+		let default_priority_fee = node.default_priority_fee.unwrap_or(DEFAULT_PRIORITY_FEE_WEI);
+		if default_priority_fee > max_gas_price {
+			return Err(ErrorKind::ConfigError("default_priority_fee can't be higher than max_gas_price".into()).into());
+		}
 		let concurrent_http_requests = node.concurrent_http_requests.unwrap_or(DEFAULT_CONCURRENCY);
 
-		let rpc_host = node.rpc_host.unwrap();
+		let rpc_host = match (node.rpc_host, node.ipc_path.is_some()) {
+			(Some(_), true) => return Err(ErrorKind::ConfigError("rpc_host and ipc_path can't be used together".into()).into()),
+			(None, false) => return Err(ErrorKind::ConfigError("either rpc_host or ipc_path is required".into()).into()),
+			(rpc_host, _) => rpc_host,
+		};
+
+		let fallback_rpc_urls = node.fallback_rpc_urls.unwrap_or_default();
+		if node.ipc_path.is_some() && !fallback_rpc_urls.is_empty() {
+			return Err(ErrorKind::ConfigError("fallback_rpc_urls can't be used with ipc_path".into()).into());
+		}
+
+		let log_quorum_rpc_urls = node.log_quorum_rpc_urls.unwrap_or_default();
+		let log_quorum = node.log_quorum.unwrap_or(log_quorum_rpc_urls.len() + 1);
+		if log_quorum == 0 || log_quorum > log_quorum_rpc_urls.len() + 1 {
+			return Err(ErrorKind::ConfigError(format!("log_quorum must be between 1 and the number of endpoints ({})", log_quorum_rpc_urls.len() + 1)).into());
+		}
+		let log_quorum_max_attempts = node.log_quorum_max_attempts.unwrap_or(DEFAULT_LOG_QUORUM_MAX_ATTEMPTS);
+		if log_quorum_max_attempts == 0 {
+			return Err(ErrorKind::ConfigError("log_quorum_max_attempts must be at least 1".into()).into());
+		}
 
-		if !rpc_host.starts_with("https://") {
+		let insecure = fallback_rpc_urls.iter()
+			.chain(log_quorum_rpc_urls.iter())
+			.chain(rpc_host.as_ref())
+			.filter(|url| !url.starts_with("https://") && !url.starts_with("wss://"));
+		for url in insecure {
 			if !allow_insecure_rpc_endpoints {
-				return Err(ErrorKind::ConfigError(format!("RPC endpoints must use TLS, {} doesn't", rpc_host)).into());
+				return Err(ErrorKind::ConfigError(format!("RPC endpoints must use TLS, {} doesn't", url)).into());
 			} else {
-				warn!("RPC endpoints must use TLS, {} doesn't", rpc_host);
+				warn!("RPC endpoints must use TLS, {} doesn't", url);
 			}
 		}
 
@@ -149,22 +311,52 @@ impl Node {
 			required_confirmations: node.required_confirmations.unwrap_or(DEFAULT_CONFIRMATIONS),
 			rpc_host,
 			rpc_port: node.rpc_port.unwrap_or(DEFAULT_RPC_PORT),
+			ipc_path: node.ipc_path,
+			fallback_rpc_urls,
+			rpc_max_failures: node.rpc_max_failures.unwrap_or(DEFAULT_RPC_MAX_FAILURES),
+			rpc_primary_retry_interval: Duration::from_secs(node.rpc_primary_retry_interval.unwrap_or(DEFAULT_RPC_PRIMARY_RETRY_INTERVAL)),
+			log_quorum_rpc_urls,
+			log_quorum,
+			log_quorum_max_attempts,
 			password: node.password,
-			info: Default::default(),
+			signer,
+			info: NodeInfo::new(default_priority_fee),
 			gas_price_oracle_url,
 			gas_price_speed,
 			gas_price_timeout,
 			default_gas_price,
 			concurrent_http_requests,
+			max_block_range: node.max_block_range,
+			finality,
+			gas_price_bump_blocks: node.gas_price_bump_blocks.unwrap_or(DEFAULT_GAS_PRICE_BUMP_BLOCKS),
+			gas_price_bump_percent,
+			max_gas_price,
+			transaction_type,
+			default_priority_fee,
 		};
 
 		Ok(result)
 	}
 
+	/// Returns url of the node rpc endpoint, `None` if the node is connected to over IPC.
+	pub fn rpc_url(&self) -> Option<String> {
+		self.rpc_host.as_ref().map(|host| format!("{}:{}", host, self.rpc_port))
+	}
+
+	/// Returns human readable description of the node endpoint.
+	pub fn endpoint(&self) -> String {
+		match self.ipc_path {
+			Some(ref path) => format!("ipc:{}", path.display()),
+			None => self.rpc_url().unwrap_or_default(),
+		}
+	}
+
 	pub fn password(&self) -> Result<String, Error> {
 		use std::io::Read;
 		use std::fs;
-		let mut f = fs::File::open(&self.password)?;
+		let path = self.password.as_ref()
+			.ok_or_else(|| ErrorKind::ConfigError(format!("password of {:?} is not set", self.account)))?;
+		let mut f = fs::File::open(path)?;
 		let mut s = String::new();
 		f.read_to_string(&mut s)?;
 		Ok(s.split("\n").next().unwrap().to_string())
@@ -183,31 +375,75 @@ pub struct Transactions {
 }
 
 impl Transactions {
-	fn from_load_struct(cfg: load::Transactions) -> Self {
-		Transactions {
+	fn from_load_struct(cfg: load::Transactions) -> Result<Self, Error> {
+		let result = Transactions {
 			#[cfg(feature = "deploy")]
-			home_deploy: cfg.home_deploy.map(TransactionConfig::from_load_struct).unwrap_or_default(),
+			home_deploy: TransactionConfig::from_load_option(cfg.home_deploy)?,
 			#[cfg(feature = "deploy")]
-			foreign_deploy: cfg.foreign_deploy.map(TransactionConfig::from_load_struct).unwrap_or_default(),
-			deposit_relay: cfg.deposit_relay.map(TransactionConfig::from_load_struct).unwrap_or_default(),
-			withdraw_confirm: cfg.withdraw_confirm.map(TransactionConfig::from_load_struct).unwrap_or_default(),
-			withdraw_relay: cfg.withdraw_relay.map(TransactionConfig::from_load_struct).unwrap_or_default(),
-		}
+			foreign_deploy: TransactionConfig::from_load_option(cfg.foreign_deploy)?,
+			deposit_relay: TransactionConfig::from_load_option(cfg.deposit_relay)?,
+			withdraw_confirm: TransactionConfig::from_load_option(cfg.withdraw_confirm)?,
+			withdraw_relay: TransactionConfig::from_load_option(cfg.withdraw_relay)?,
+		};
+
+		Ok(result)
 	}
 }
 
-#[derive(Debug, PartialEq, Default, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct TransactionConfig {
-	pub gas: u64,
+	/// Gas of the transactions, or the most gas they can use if it's estimated,
+	/// overriding the gas limit set in the bridge contract.
+	pub gas: Option<u64>,
 	pub gas_price: u64,
+	/// Number of reverted transactions after which an event is moved to the dead letters.
+	pub max_attempts: u32,
+	/// Whether the gas of every transaction is estimated with `eth_estimateGas`.
+	pub estimate_gas: bool,
+	/// Safety margin the estimated gas is multiplied by.
+	pub gas_multiplier: f64,
 }
 
-impl TransactionConfig {
-	fn from_load_struct(cfg: load::TransactionConfig) -> Self {
+impl Default for TransactionConfig {
+	fn default() -> Self {
 		TransactionConfig {
-			gas: cfg.gas.unwrap_or_default(),
-			gas_price: cfg.gas_price.unwrap_or_default(),
+			gas: None,
+			gas_price: 0,
+			max_attempts: DEFAULT_MAX_ATTEMPTS,
+			estimate_gas: false,
+			gas_multiplier: DEFAULT_GAS_MULTIPLIER,
+		}
+	}
+}
+
+impl TransactionConfig {
+	fn from_load_option(cfg: Option<load::TransactionConfig>) -> Result<Self, Error> {
+		match cfg {
+			Some(cfg) => TransactionConfig::from_load_struct(cfg),
+			None => Ok(TransactionConfig::default()),
+		}
+	}
+
+	fn from_load_struct(cfg: load::TransactionConfig) -> Result<Self, Error> {
+		let gas_multiplier = cfg.gas_multiplier.unwrap_or(DEFAULT_GAS_MULTIPLIER);
+		if gas_multiplier < 1.0 {
+			return Err(ErrorKind::ConfigError("gas_multiplier can't be lower than 1".into()).into());
 		}
+
+		let result = TransactionConfig {
+			gas: cfg.gas,
+			gas_price: cfg.gas_price.unwrap_or_default(),
+			max_attempts: cfg.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS),
+			estimate_gas: cfg.estimate_gas.unwrap_or_default(),
+			gas_multiplier,
+		};
+
+		Ok(result)
+	}
+
+	/// Gas of the transactions, the configured one if any, otherwise `contract_limit` set in the bridge contract.
+	pub fn gas_limit(&self, contract_limit: u64) -> u64 {
+		self.gas.unwrap_or(contract_limit)
 	}
 }
 
@@ -225,6 +461,64 @@ pub struct Authorities {
 	pub required_signatures: u32,
 }
 
+/// Defines when a block is considered confirmed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Finality {
+	/// Block is confirmed when `required_confirmations` blocks have been mined on top of it.
+	Depth,
+	/// Block is confirmed when it's not later than the `finalized` block.
+	Finalized,
+	/// Block is confirmed when it's not later than the `safe` block.
+	Safe,
+}
+
+impl FromStr for Finality {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let finality = match s {
+			"depth" => Finality::Depth,
+			"finalized" => Finality::Finalized,
+			"safe" => Finality::Safe,
+			_ => return Err(()),
+		};
+		Ok(finality)
+	}
+}
+
+impl Finality {
+	/// Block tag of the last confirmed block, if confirmations are not depth-based.
+	pub fn tag(&self) -> Option<&'static str> {
+		match *self {
+			Finality::Depth => None,
+			Finality::Finalized => Some("finalized"),
+			Finality::Safe => Some("safe"),
+		}
+	}
+}
+
+/// Kind of transactions sent on a chain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransactionType {
+	/// Transactions with a single gas price.
+	Legacy,
+	/// EIP-1559 transactions with a maximum fee and a priority fee.
+	Eip1559,
+}
+
+impl FromStr for TransactionType {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let transaction_type = match s {
+			"legacy" => TransactionType::Legacy,
+			"eip1559" => TransactionType::Eip1559,
+			_ => return Err(()),
+		};
+		Ok(transaction_type)
+	}
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum GasPriceSpeed {
     Instant,
@@ -257,6 +551,16 @@ impl GasPriceSpeed {
 			GasPriceSpeed::Slow => "slow",
 		}
 	}
+
+	/// Percentile of the priority fees paid in recent blocks used by EIP-1559 transactions.
+	pub fn percentile(&self) -> f64 {
+		match *self {
+			GasPriceSpeed::Instant => 90.0,
+			GasPriceSpeed::Fast => 75.0,
+			GasPriceSpeed::Standard => 50.0,
+			GasPriceSpeed::Slow => 25.0,
+		}
+	}
 }
 
 /// Some config values may not be defined in `toml` file, but they should be specified at runtime.
@@ -275,7 +579,18 @@ mod load {
 		pub transactions: Option<Transactions>,
 		#[cfg(feature = "deploy")]
 		pub estimated_gas_cost_of_withdraw: u32,
-		pub keystore: PathBuf,
+		pub keystore: Option<PathBuf>,
+		pub signer: Option<Signer>,
+	}
+
+	#[derive(Deserialize, Clone)]
+	#[serde(deny_unknown_fields)]
+	pub struct Signer {
+		#[serde(rename = "type")]
+		pub kind: String,
+		pub private_key: Option<PathBuf>,
+		pub url: Option<String>,
+		pub timeout: Option<u64>,
 	}
 
 	#[derive(Deserialize)]
@@ -289,12 +604,28 @@ mod load {
 		pub required_confirmations: Option<usize>,
 		pub rpc_host: Option<String>,
 		pub rpc_port: Option<u16>,
-		pub password: PathBuf,
+		pub ipc_path: Option<PathBuf>,
+		pub fallback_rpc_urls: Option<Vec<String>>,
+		pub rpc_max_failures: Option<u32>,
+		pub rpc_primary_retry_interval: Option<u64>,
+		pub log_quorum_rpc_urls: Option<Vec<String>>,
+		pub log_quorum: Option<usize>,
+		pub log_quorum_max_attempts: Option<u32>,
+		pub password: Option<PathBuf>,
+		pub keystore: Option<PathBuf>,
+		pub signer: Option<Signer>,
 		pub gas_price_oracle_url: Option<String>,
 		pub gas_price_speed: Option<String>,
 		pub gas_price_timeout: Option<u64>,
 		pub default_gas_price: Option<u64>,
 		pub concurrent_http_requests: Option<usize>,
+		pub max_block_range: Option<u64>,
+		pub finality: Option<String>,
+		pub gas_price_bump_blocks: Option<u64>,
+		pub gas_price_bump_percent: Option<u64>,
+		pub max_gas_price: Option<u64>,
+		pub transaction_type: Option<String>,
+		pub default_priority_fee: Option<u64>,
 	}
 
 	#[derive(Deserialize)]
@@ -314,6 +645,9 @@ mod load {
 	pub struct TransactionConfig {
 		pub gas: Option<u64>,
 		pub gas_price: Option<u64>,
+		pub max_attempts: Option<u32>,
+		pub estimate_gas: Option<bool>,
+		pub gas_multiplier: Option<f64>,
 	}
 
 	#[derive(Deserialize)]
@@ -337,12 +671,16 @@ mod tests {
 	use std::time::Duration;
 	#[cfg(feature = "deploy")]
 	use rustc_hex::FromHex;
-	use super::{Config, Node, Transactions, Authorities};
+	use super::{Config, Node, NodeInfo, Transactions, Authorities, Finality, TransactionType, SignerConfig};
 	#[cfg(feature = "deploy")]
 	use super::ContractConfig;
 	#[cfg(feature = "deploy")]
     use super::TransactionConfig;
 	use super::{DEFAULT_TIMEOUT, DEFAULT_CONCURRENCY, DEFAULT_GAS_PRICE_SPEED, DEFAULT_GAS_PRICE_TIMEOUT_SECS, DEFAULT_GAS_PRICE_WEI};
+	use super::{DEFAULT_RPC_MAX_FAILURES, DEFAULT_RPC_PRIMARY_RETRY_INTERVAL, DEFAULT_LOG_QUORUM_MAX_ATTEMPTS};
+	use super::{DEFAULT_GAS_PRICE_BUMP_BLOCKS, DEFAULT_GAS_PRICE_BUMP_PERCENT, DEFAULT_MAX_GAS_PRICE_WEI, DEFAULT_PRIORITY_FEE_WEI};
+	use super::DEFAULT_SIGNER_TIMEOUT_SECS;
+	use error::{Error, ErrorKind};
 
 	#[test]
 	fn load_full_setup_from_str() {
@@ -356,11 +694,16 @@ required_confirmations = 100
 rpc_host = "127.0.0.1"
 rpc_port = 8545
 password = "password"
+max_block_range = 1000
 
 [foreign]
 account = "0x0000000000000000000000000000000000000001"
 rpc_host = "127.0.0.1"
 rpc_port = 8545
+fallback_rpc_urls = ["127.0.0.2:8545", "127.0.0.3:8545"]
+rpc_max_failures = 5
+finality = "finalized"
+transaction_type = "eip1559"
 password = "password"
 
 [authorities]
@@ -377,37 +720,66 @@ required_signatures = 2
 				poll_interval: Duration::from_secs(2),
 				request_timeout: Duration::from_secs(DEFAULT_TIMEOUT),
 				required_confirmations: 100,
-				rpc_host: "127.0.0.1".into(),
+				rpc_host: Some("127.0.0.1".into()),
 				rpc_port: 8545,
-				password: "password".into(),
-				info: Default::default(),
+				ipc_path: None,
+				fallback_rpc_urls: vec![],
+				rpc_max_failures: DEFAULT_RPC_MAX_FAILURES,
+				rpc_primary_retry_interval: Duration::from_secs(DEFAULT_RPC_PRIMARY_RETRY_INTERVAL),
+				log_quorum_rpc_urls: vec![],
+				log_quorum: 1,
+				log_quorum_max_attempts: DEFAULT_LOG_QUORUM_MAX_ATTEMPTS,
+				password: Some("password".into()),
+				signer: SignerConfig::Keystore("/keys/".into()),
+				info: NodeInfo::new(DEFAULT_PRIORITY_FEE_WEI),
 				gas_price_oracle_url: None,
 				gas_price_speed: DEFAULT_GAS_PRICE_SPEED,
 				gas_price_timeout: Duration::from_secs(DEFAULT_GAS_PRICE_TIMEOUT_SECS),
 				default_gas_price: DEFAULT_GAS_PRICE_WEI,
 				concurrent_http_requests: DEFAULT_CONCURRENCY,
+				max_block_range: Some(1000),
+				finality: Finality::Depth,
+				gas_price_bump_blocks: DEFAULT_GAS_PRICE_BUMP_BLOCKS,
+				gas_price_bump_percent: DEFAULT_GAS_PRICE_BUMP_PERCENT,
+				max_gas_price: DEFAULT_MAX_GAS_PRICE_WEI,
+				transaction_type: TransactionType::Legacy,
+				default_priority_fee: DEFAULT_PRIORITY_FEE_WEI,
 			},
 			foreign: Node {
 				account: "0000000000000000000000000000000000000001".into(),
 				poll_interval: Duration::from_secs(1),
 				request_timeout: Duration::from_secs(DEFAULT_TIMEOUT),
 				required_confirmations: 12,
-				rpc_host: "127.0.0.1".into(),
+				rpc_host: Some("127.0.0.1".into()),
 				rpc_port: 8545,
-				password: "password".into(),
-				info: Default::default(),
+				ipc_path: None,
+				fallback_rpc_urls: vec!["127.0.0.2:8545".into(), "127.0.0.3:8545".into()],
+				rpc_max_failures: 5,
+				rpc_primary_retry_interval: Duration::from_secs(DEFAULT_RPC_PRIMARY_RETRY_INTERVAL),
+				log_quorum_rpc_urls: vec![],
+				log_quorum: 1,
+				log_quorum_max_attempts: DEFAULT_LOG_QUORUM_MAX_ATTEMPTS,
+				password: Some("password".into()),
+				signer: SignerConfig::Keystore("/keys/".into()),
+				info: NodeInfo::new(DEFAULT_PRIORITY_FEE_WEI),
 				gas_price_oracle_url: None,
 				gas_price_speed: DEFAULT_GAS_PRICE_SPEED,
 				gas_price_timeout: Duration::from_secs(DEFAULT_GAS_PRICE_TIMEOUT_SECS),
 				default_gas_price: DEFAULT_GAS_PRICE_WEI,
 				concurrent_http_requests: DEFAULT_CONCURRENCY,
+				max_block_range: None,
+				finality: Finality::Finalized,
+				gas_price_bump_blocks: DEFAULT_GAS_PRICE_BUMP_BLOCKS,
+				gas_price_bump_percent: DEFAULT_GAS_PRICE_BUMP_PERCENT,
+				max_gas_price: DEFAULT_MAX_GAS_PRICE_WEI,
+				transaction_type: TransactionType::Eip1559,
+				default_priority_fee: DEFAULT_PRIORITY_FEE_WEI,
 			},
 			authorities: Authorities {
 				#[cfg(feature = "deploy")]
 				accounts: vec![
 				],
 			},
-			keystore: "/keys/".into(),
 		};
 
 		let config = Config::load_from_str(toml, true).unwrap();
@@ -439,40 +811,229 @@ required_signatures = 2
 				poll_interval: Duration::from_secs(1),
 				request_timeout: Duration::from_secs(DEFAULT_TIMEOUT),
 				required_confirmations: 12,
-				rpc_host: "".into(),
+				rpc_host: Some("".into()),
 				rpc_port: 8545,
-				password: "password".into(),
-				info: Default::default(),
+				ipc_path: None,
+				fallback_rpc_urls: vec![],
+				rpc_max_failures: DEFAULT_RPC_MAX_FAILURES,
+				rpc_primary_retry_interval: Duration::from_secs(DEFAULT_RPC_PRIMARY_RETRY_INTERVAL),
+				log_quorum_rpc_urls: vec![],
+				log_quorum: 1,
+				log_quorum_max_attempts: DEFAULT_LOG_QUORUM_MAX_ATTEMPTS,
+				password: Some("password".into()),
+				signer: SignerConfig::Keystore("/keys/".into()),
+				info: NodeInfo::new(DEFAULT_PRIORITY_FEE_WEI),
 				gas_price_oracle_url: None,
 				gas_price_speed: DEFAULT_GAS_PRICE_SPEED,
 				gas_price_timeout: Duration::from_secs(DEFAULT_GAS_PRICE_TIMEOUT_SECS),
 				default_gas_price: DEFAULT_GAS_PRICE_WEI,
 				concurrent_http_requests: DEFAULT_CONCURRENCY,
+				max_block_range: None,
+				finality: Finality::Depth,
+				gas_price_bump_blocks: DEFAULT_GAS_PRICE_BUMP_BLOCKS,
+				gas_price_bump_percent: DEFAULT_GAS_PRICE_BUMP_PERCENT,
+				max_gas_price: DEFAULT_MAX_GAS_PRICE_WEI,
+				transaction_type: TransactionType::Legacy,
+				default_priority_fee: DEFAULT_PRIORITY_FEE_WEI,
 			},
 			foreign: Node {
 				account: "0000000000000000000000000000000000000001".into(),
 				poll_interval: Duration::from_secs(1),
 				request_timeout: Duration::from_secs(DEFAULT_TIMEOUT),
 				required_confirmations: 12,
-				rpc_host: "".into(),
+				rpc_host: Some("".into()),
 				rpc_port: 8545,
-				password: "password".into(),
-				info: Default::default(),
+				ipc_path: None,
+				fallback_rpc_urls: vec![],
+				rpc_max_failures: DEFAULT_RPC_MAX_FAILURES,
+				rpc_primary_retry_interval: Duration::from_secs(DEFAULT_RPC_PRIMARY_RETRY_INTERVAL),
+				log_quorum_rpc_urls: vec![],
+				log_quorum: 1,
+				log_quorum_max_attempts: DEFAULT_LOG_QUORUM_MAX_ATTEMPTS,
+				password: Some("password".into()),
+				signer: SignerConfig::Keystore("/keys/".into()),
+				info: NodeInfo::new(DEFAULT_PRIORITY_FEE_WEI),
 				gas_price_oracle_url: None,
 				gas_price_speed: DEFAULT_GAS_PRICE_SPEED,
 				gas_price_timeout: Duration::from_secs(DEFAULT_GAS_PRICE_TIMEOUT_SECS),
 				default_gas_price: DEFAULT_GAS_PRICE_WEI,
 				concurrent_http_requests: DEFAULT_CONCURRENCY,
+				max_block_range: None,
+				finality: Finality::Depth,
+				gas_price_bump_blocks: DEFAULT_GAS_PRICE_BUMP_BLOCKS,
+				gas_price_bump_percent: DEFAULT_GAS_PRICE_BUMP_PERCENT,
+				max_gas_price: DEFAULT_MAX_GAS_PRICE_WEI,
+				transaction_type: TransactionType::Legacy,
+				default_priority_fee: DEFAULT_PRIORITY_FEE_WEI,
 			},
 			authorities: Authorities {
 				#[cfg(feature = "deploy")]
 				accounts: vec![
 				],
 			},
-			keystore: "/keys/".into(),
 		};
 
 		let config = Config::load_from_str(toml, true).unwrap();
 		assert_eq!(expected, config);
 	}
+
+	#[test]
+	fn load_ipc_setup_from_str() {
+		let toml = r#"
+keystore = "/keys/"
+
+[home]
+account = "0x1B68Cb0B50181FC4006Ce572cF346e596E51818b"
+ipc_path = "/home/node/jsonrpc.ipc"
+password = "password"
+
+[foreign]
+account = "0x0000000000000000000000000000000000000001"
+rpc_host = "https://rpc"
+password = "password"
+
+[authorities]
+required_signatures = 2
+"#;
+
+		let config = Config::load_from_str(toml, false).unwrap();
+		assert_eq!(config.home.ipc_path, Some("/home/node/jsonrpc.ipc".into()));
+		assert_eq!(config.home.rpc_url(), None);
+		assert_eq!(config.home.endpoint(), "ipc:/home/node/jsonrpc.ipc");
+		assert_eq!(config.foreign.ipc_path, None);
+		assert_eq!(config.foreign.endpoint(), "https://rpc:8545");
+	}
+
+	#[test]
+	fn load_setup_with_both_rpc_host_and_ipc_path() {
+		let toml = r#"
+keystore = "/keys/"
+
+[home]
+account = "0x1B68Cb0B50181FC4006Ce572cF346e596E51818b"
+rpc_host = "https://rpc"
+ipc_path = "/home/node/jsonrpc.ipc"
+password = "password"
+
+[foreign]
+account = "0x0000000000000000000000000000000000000001"
+rpc_host = "https://rpc"
+password = "password"
+
+[authorities]
+required_signatures = 2
+"#;
+
+		assert!(Config::load_from_str(toml, false).is_err());
+	}
+
+	#[test]
+	fn load_log_quorum_setup_from_str() {
+		let toml = r#"
+keystore = "/keys/"
+
+[home]
+account = "0x1B68Cb0B50181FC4006Ce572cF346e596E51818b"
+rpc_host = "https://rpc"
+log_quorum_rpc_urls = ["https://rpc2:443", "https://rpc3:443"]
+password = "password"
+
+[foreign]
+account = "0x0000000000000000000000000000000000000001"
+rpc_host = "https://rpc"
+log_quorum_rpc_urls = ["https://rpc2:443"]
+log_quorum = 1
+password = "password"
+
+[authorities]
+required_signatures = 2
+"#;
+
+		let config = Config::load_from_str(toml, false).unwrap();
+		assert_eq!(config.home.log_quorum_rpc_urls.len(), 2);
+		assert_eq!(config.home.log_quorum, 3);
+		assert_eq!(config.foreign.log_quorum, 1);
+		assert_eq!(config.home.log_quorum_max_attempts, DEFAULT_LOG_QUORUM_MAX_ATTEMPTS);
+
+		let invalid = toml.replace("log_quorum = 1", "log_quorum = 3");
+		assert!(Config::load_from_str(&invalid, false).is_err());
+	}
+
+	#[test]
+	fn load_eip1559_setup_from_str() {
+		let toml = r#"
+keystore = "/keys/"
+
+[home]
+account = "0x1B68Cb0B50181FC4006Ce572cF346e596E51818b"
+rpc_host = "https://rpc"
+password = "password"
+
+[foreign]
+account = "0x0000000000000000000000000000000000000001"
+rpc_host = "https://rpc"
+transaction_type = "eip1559"
+password = "password"
+
+[authorities]
+required_signatures = 2
+"#;
+
+		let config = Config::load_from_str(toml, false).unwrap();
+		assert_eq!(config.home.transaction_type, TransactionType::Legacy);
+		assert_eq!(config.foreign.transaction_type, TransactionType::Eip1559);
+
+		// withdraw relays on home must pay the gas price of their message
+		let home = toml.replace("rpc_host = \"https://rpc\"\npassword", "rpc_host = \"https://rpc\"\ntransaction_type = \"eip1559\"\npassword");
+		match Config::load_from_str(&home, false) {
+			Err(Error(ErrorKind::ConfigError(_), _)) => {},
+			other => panic!("expected a config error, got {:?}", other.map(|_| ())),
+		}
+	}
+
+	#[test]
+	fn load_signer_setup_from_str() {
+		let toml = r#"
+[home]
+account = "0x1B68Cb0B50181FC4006Ce572cF346e596E51818b"
+rpc_host = "https://rpc"
+
+[foreign]
+account = "0x0000000000000000000000000000000000000001"
+rpc_host = "https://rpc"
+
+[signer]
+type = "remote"
+url = "https://signer:8550"
+
+[authorities]
+required_signatures = 2
+"#;
+
+		let config = Config::load_from_str(toml, false).unwrap();
+		let remote = SignerConfig::Remote {
+			url: "https://signer:8550".into(),
+			timeout: Duration::from_secs(DEFAULT_SIGNER_TIMEOUT_SECS),
+		};
+		assert_eq!(config.home.signer, remote);
+		assert_eq!(config.foreign.signer, remote);
+		assert_eq!(config.home.password, None);
+
+		let insecure = toml.replace("https://signer", "http://signer");
+		assert!(Config::load_from_str(&insecure, false).is_err());
+
+		let private_key = toml.replace("type = \"remote\"\nurl = \"https://signer:8550\"", "type = \"private_key\"\nprivate_key = \"key.txt\"");
+		assert_eq!(Config::load_from_str(&private_key, false).unwrap().foreign.signer, SignerConfig::PrivateKey("key.txt".into()));
+
+		let keystore = toml.replace("type = \"remote\"", "type = \"keystore\"");
+		assert!(Config::load_from_str(&keystore, false).is_err());
+
+		let home_keystore = toml.replace("rpc_host = \"https://rpc\"\n\n[foreign]", "rpc_host = \"https://rpc\"\nkeystore = \"/home-keys\"\npassword = \"password\"\nsigner = { type = \"keystore\" }\n\n[foreign]");
+		let config = Config::load_from_str(&home_keystore, false).unwrap();
+		assert_eq!(config.home.signer, SignerConfig::Keystore("/home-keys".into()));
+		assert_eq!(config.foreign.signer, remote);
+
+		let shared_account = home_keystore.replace("0x0000000000000000000000000000000000000001", "0x1B68Cb0B50181FC4006Ce572cF346e596E51818b");
+		assert!(Config::load_from_str(&shared_account, false).is_err());
+	}
 }
@@ -1,13 +1,27 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{io, str, fs, fmt};
 use std::io::{Read, Write};
-use web3::types::Address;
+use web3::types::{Address, H256, U256, Log};
 use toml;
 use error::{Error, ResultExt, ErrorKind};
+use util::sibling_path;
+
+/// Version of the database format written by this bridge.
+pub const DATABASE_VERSION: u32 = 1;
+
+/// Upgrades a database table from version `n` to `n + 1`, where `n` is the migration's index.
+type Migration = fn(&mut toml::value::Table) -> Result<(), Error>;
+
+/// Migrations of older database formats, in order.
+const MIGRATIONS: &'static [Migration] = &[
+	migrate_to_v1,
+];
 
 /// Application "database".
-#[derive(Debug, PartialEq, Deserialize, Serialize, Default, Clone)]
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
 pub struct Database {
+	/// Version of the database format.
+	pub version: u32,
 	/// Address of home contract.
 	pub home_contract_address: Address,
 	/// Address of foreign contract.
@@ -22,13 +36,135 @@ pub struct Database {
 	pub checked_withdraw_relay: u64,
 	/// Number of last block which has been checked for withdraw confirms.
 	pub checked_withdraw_confirm: u64,
+	/// Hash of the last block which has been checked for deposit relays.
+	pub checked_deposit_relay_hash: Option<H256>,
+	/// Hash of the last block which has been checked for withdraw relays.
+	pub checked_withdraw_relay_hash: Option<H256>,
+	/// Hash of the last block which has been checked for withdraw confirms.
+	pub checked_withdraw_confirm_hash: Option<H256>,
+	/// Deposits relayed in blocks after `checked_deposit_relay`.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub relayed_deposits: Vec<EventId>,
+	/// Withdraws relayed in blocks after `checked_withdraw_relay`.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub relayed_withdraws: Vec<EventId>,
+	/// Withdraws confirmed in blocks after `checked_withdraw_confirm`.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub confirmed_withdraws: Vec<EventId>,
+	/// Deposits whose relay transaction reverted, to be relayed again.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub reverted_deposit_relays: Vec<EventId>,
+	/// Withdraws whose relay transaction reverted, to be relayed again.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub reverted_withdraw_relays: Vec<EventId>,
+	/// Withdraws whose confirmation transaction reverted, to be confirmed again.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub reverted_withdraw_confirms: Vec<EventId>,
+}
+
+/// Identifies a single event handled by the bridge.
+#[derive(Debug, PartialEq, Eq, Hash, Deserialize, Serialize, Clone, Copy)]
+pub struct EventId {
+	pub block_number: u64,
+	pub transaction_hash: H256,
+	pub log_index: U256,
+}
+
+impl EventId {
+	pub fn from_log(log: &Log) -> Result<Self, Error> {
+		match (log.block_number, log.transaction_hash, log.log_index) {
+			(Some(block_number), Some(transaction_hash), Some(log_index)) => Ok(EventId {
+				block_number: block_number.low_u64(),
+				transaction_hash,
+				log_index,
+			}),
+			_ => Err(ErrorKind::OtherError(format!("log {:?} is not mined", log.transaction_hash)).into()),
+		}
+	}
+}
+
+impl fmt::Display for EventId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}/{}", self.transaction_hash, self.log_index)
+	}
+}
+
+impl Default for Database {
+	fn default() -> Self {
+		Database {
+			version: DATABASE_VERSION,
+			home_contract_address: Address::default(),
+			foreign_contract_address: Address::default(),
+			home_deploy: None,
+			foreign_deploy: None,
+			checked_deposit_relay: 0,
+			checked_withdraw_relay: 0,
+			checked_withdraw_confirm: 0,
+			checked_deposit_relay_hash: None,
+			checked_withdraw_relay_hash: None,
+			checked_withdraw_confirm_hash: None,
+			relayed_deposits: Vec::new(),
+			relayed_withdraws: Vec::new(),
+			confirmed_withdraws: Vec::new(),
+			reverted_deposit_relays: Vec::new(),
+			reverted_withdraw_relays: Vec::new(),
+			reverted_withdraw_confirms: Vec::new(),
+		}
+	}
+}
+
+/// Version 1 introduces the `version` field and renames the keys of a parity-bridge database,
+/// the unversioned format written by this bridge is otherwise unchanged.
+fn migrate_to_v1(table: &mut toml::value::Table) -> Result<(), Error> {
+	const KEYS: &'static [(&'static str, &'static str)] = &[
+		("main_contract_address", "home_contract_address"),
+		("side_contract_address", "foreign_contract_address"),
+		("main_deployed_at_block", "home_deploy"),
+		("side_deployed_at_block", "foreign_deploy"),
+		("last_main_to_side_sign_at_block", "checked_deposit_relay"),
+		("last_side_to_main_signatures_at_block", "checked_withdraw_relay"),
+		("last_side_to_main_sign_at_block", "checked_withdraw_confirm"),
+	];
+
+	if table.contains_key("main_contract_address") {
+		info!("migrating parity-bridge database");
+	}
+	for &(old, new) in KEYS {
+		if let Some(value) = table.remove(old) {
+			table.insert(new.into(), value);
+		}
+	}
+	Ok(())
+}
+
+/// Upgrades a database table of any supported version to `DATABASE_VERSION`.
+fn migrate(table: &mut toml::value::Table) -> Result<(), Error> {
+	let version = match table.get("version") {
+		None => 0,
+		Some(&toml::Value::Integer(version)) if version >= 0 => version as u32,
+		Some(version) => return Err(ErrorKind::OtherError(format!("invalid database version {}", version)).into()),
+	};
+
+	if version > DATABASE_VERSION {
+		return Err(ErrorKind::UnsupportedDatabaseVersion(version, DATABASE_VERSION).into());
+	}
+
+	for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+		info!("migrating database from version {} to {}", from, from + 1);
+		migration(table)?;
+		table.insert("version".into(), toml::Value::Integer(from as i64 + 1));
+	}
+
+	Ok(())
 }
 
 impl str::FromStr for Database {
 	type Err = Error;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
-		toml::from_str(s).chain_err(|| "Cannot parse database")
+		let mut table: toml::value::Table = toml::from_str(s).chain_err(|| "Cannot parse database")?;
+		migrate(&mut table)?;
+		toml::Value::Table(table).try_into().chain_err(|| "Cannot parse database")
 	}
 }
 
@@ -38,11 +174,68 @@ impl fmt::Display for Database {
 	}
 }
 
+/// Path of the last good copy of the database.
+fn backup_path(path: &Path) -> PathBuf {
+	sibling_path(path, ".bak")
+}
+
+/// Makes the directory entries (e.g. renames) of `path`'s directory durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+	let dir = match path.parent() {
+		Some(dir) if dir != Path::new("") => dir,
+		_ => Path::new("."),
+	};
+	fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+	Ok(())
+}
+
+/// Writes `contents` to a temporary file which is synced to disk and renamed over `path`.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+	let tmp = sibling_path(path, ".tmp");
+	{
+		let mut file = fs::File::create(&tmp)?;
+		file.write_all(contents)?;
+		file.sync_all()?;
+	}
+	fs::rename(&tmp, path)
+}
+
 impl Database {
+	/// Loads the database from `path`.
+	///
+	/// If the file exists but can't be read or parsed, the last good copy written by `save` is used instead.
 	pub fn load<P: AsRef<Path>>(path: P) -> Result<Database, Error> {
-		let mut file = match fs::File::open(&path) {
+		let path = path.as_ref();
+		let err = match Database::load_file(path) {
+			Ok(database) => return Ok(database),
+			Err(err) => match *err.kind() {
+				// a missing database means there is nothing to recover
+				ErrorKind::MissingFile(_) => return Err(err),
+				// a database written by a newer bridge is not corrupted
+				ErrorKind::UnsupportedDatabaseVersion(..) => return Err(err),
+				_ => err,
+			},
+		};
+
+		let backup = backup_path(path);
+		match Database::load_file(&backup) {
+			Ok(database) => {
+				warn!("database {:?} is corrupted ({}), recovered the last good copy from {:?}", path, err, backup);
+				Ok(database)
+			},
+			Err(_) => Err(err),
+		}
+	}
+
+	fn load_file(path: &Path) -> Result<Database, Error> {
+		let mut file = match fs::File::open(path) {
 			Ok(file) => file,
-			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Err(ErrorKind::MissingFile(format!("{:?}", path.as_ref())).into()),
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Err(ErrorKind::MissingFile(format!("{:?}", path)).into()),
 			Err(err) => return Err(err).chain_err(|| "Cannot open database"),
 		};
 
@@ -51,29 +244,48 @@ impl Database {
 		buffer.parse()
 	}
 
-	pub fn save<W: Write>(&self, mut write: W) -> Result<(), Error> {
-		write.write_all(self.to_string().as_bytes())?;
+	/// Atomically replaces the database at `path`.
+	///
+	/// The database is written to a temporary file which is synced to disk and renamed over `path`,
+	/// so a crash leaves either the old or the new version in place, never a partial one.
+	/// The previous version is kept next to it as the last good copy, written the same way.
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+		let path = path.as_ref();
+		if path.exists() {
+			let previous = fs::read(path).chain_err(|| "Cannot read database")?;
+			write_atomically(&backup_path(path), &previous).chain_err(|| "Cannot back up database")?;
+		}
+
+		write_atomically(path, self.to_string().as_bytes()).chain_err(|| "Cannot write database")?;
+		sync_dir(path).chain_err(|| "Cannot sync database directory")?;
 		Ok(())
 	}
 }
 
 #[cfg(test)]
 mod tests {
-	use super::Database;
+	extern crate tempdir;
+	use std::fs;
+	use self::tempdir::TempDir;
+	use error::ErrorKind;
+	use super::{Database, EventId, DATABASE_VERSION, backup_path};
 
 	#[test]
 	fn database_to_and_from_str() {
 		let toml =
-r#"home_contract_address = "0x49edf201c1e139282643d5e7c6fb0c7219ad1db7"
+r#"version = 1
+home_contract_address = "0x49edf201c1e139282643d5e7c6fb0c7219ad1db7"
 foreign_contract_address = "0x49edf201c1e139282643d5e7c6fb0c7219ad1db8"
 home_deploy = 100
 foreign_deploy = 101
 checked_deposit_relay = 120
 checked_withdraw_relay = 121
 checked_withdraw_confirm = 121
+checked_deposit_relay_hash = "0x884edad9ce6fa2440d8a54cc123490eb96d2768479d49ff9c7366125a9424364"
 "#;
 
 		let expected = Database {
+			version: DATABASE_VERSION,
 			home_contract_address: "49edf201c1e139282643d5e7c6fb0c7219ad1db7".into(),
 			foreign_contract_address: "49edf201c1e139282643d5e7c6fb0c7219ad1db8".into(),
 			home_deploy: Some(100),
@@ -81,6 +293,15 @@ checked_withdraw_confirm = 121
 			checked_deposit_relay: 120,
 			checked_withdraw_relay: 121,
 			checked_withdraw_confirm: 121,
+			checked_deposit_relay_hash: Some("884edad9ce6fa2440d8a54cc123490eb96d2768479d49ff9c7366125a9424364".into()),
+			checked_withdraw_relay_hash: None,
+			checked_withdraw_confirm_hash: None,
+			relayed_deposits: vec![],
+			relayed_withdraws: vec![],
+			confirmed_withdraws: vec![],
+			reverted_deposit_relays: vec![],
+			reverted_withdraw_relays: vec![],
+			reverted_withdraw_confirms: vec![],
 		};
 
 		let database = toml.parse().unwrap();
@@ -88,4 +309,147 @@ checked_withdraw_confirm = 121
 		let s = database.to_string();
 		assert_eq!(s, toml);
 	}
+
+	#[test]
+	fn database_with_events_to_and_from_str() {
+		let toml =
+r#"version = 1
+home_contract_address = "0x49edf201c1e139282643d5e7c6fb0c7219ad1db7"
+foreign_contract_address = "0x49edf201c1e139282643d5e7c6fb0c7219ad1db8"
+checked_deposit_relay = 120
+checked_withdraw_relay = 121
+checked_withdraw_confirm = 121
+
+[[relayed_deposits]]
+block_number = 122
+transaction_hash = "0x884edad9ce6fa2440d8a54cc123490eb96d2768479d49ff9c7366125a9424364"
+log_index = "0x1"
+"#;
+
+		let expected = Database {
+			home_contract_address: "49edf201c1e139282643d5e7c6fb0c7219ad1db7".into(),
+			foreign_contract_address: "49edf201c1e139282643d5e7c6fb0c7219ad1db8".into(),
+			checked_deposit_relay: 120,
+			checked_withdraw_relay: 121,
+			checked_withdraw_confirm: 121,
+			relayed_deposits: vec![EventId {
+				block_number: 122,
+				transaction_hash: "884edad9ce6fa2440d8a54cc123490eb96d2768479d49ff9c7366125a9424364".into(),
+				log_index: 1u64.into(),
+			}],
+			..Database::default()
+		};
+
+		let database = toml.parse().unwrap();
+		assert_eq!(expected, database);
+		let s = database.to_string();
+		assert_eq!(s, toml);
+	}
+
+	#[test]
+	fn database_migrates_unversioned_format() {
+		let toml =
+r#"home_contract_address = "0x49edf201c1e139282643d5e7c6fb0c7219ad1db7"
+foreign_contract_address = "0x49edf201c1e139282643d5e7c6fb0c7219ad1db8"
+checked_deposit_relay = 120
+checked_withdraw_relay = 121
+checked_withdraw_confirm = 122
+"#;
+
+		let expected = Database {
+			home_contract_address: "49edf201c1e139282643d5e7c6fb0c7219ad1db7".into(),
+			foreign_contract_address: "49edf201c1e139282643d5e7c6fb0c7219ad1db8".into(),
+			checked_deposit_relay: 120,
+			checked_withdraw_relay: 121,
+			checked_withdraw_confirm: 122,
+			..Database::default()
+		};
+
+		let database: Database = toml.parse().unwrap();
+		assert_eq!(expected, database);
+		assert!(database.to_string().starts_with("version = 1\n"));
+	}
+
+	#[test]
+	fn database_migrates_parity_bridge_format() {
+		let toml =
+r#"main_contract_address = "0x49edf201c1e139282643d5e7c6fb0c7219ad1db7"
+side_contract_address = "0x49edf201c1e139282643d5e7c6fb0c7219ad1db8"
+main_deployed_at_block = 100
+side_deployed_at_block = 101
+last_main_to_side_sign_at_block = 120
+last_side_to_main_signatures_at_block = 121
+last_side_to_main_sign_at_block = 122
+"#;
+
+		let expected = Database {
+			home_contract_address: "49edf201c1e139282643d5e7c6fb0c7219ad1db7".into(),
+			foreign_contract_address: "49edf201c1e139282643d5e7c6fb0c7219ad1db8".into(),
+			home_deploy: Some(100),
+			foreign_deploy: Some(101),
+			checked_deposit_relay: 120,
+			checked_withdraw_relay: 121,
+			checked_withdraw_confirm: 122,
+			..Database::default()
+		};
+
+		assert_eq!(expected, toml.parse().unwrap());
+	}
+
+	#[test]
+	fn database_from_newer_version_is_refused() {
+		let toml =
+r#"version = 2
+home_contract_address = "0x49edf201c1e139282643d5e7c6fb0c7219ad1db7"
+foreign_contract_address = "0x49edf201c1e139282643d5e7c6fb0c7219ad1db8"
+checked_deposit_relay = 120
+checked_withdraw_relay = 121
+checked_withdraw_confirm = 122
+"#;
+
+		match *toml.parse::<Database>().unwrap_err().kind() {
+			ErrorKind::UnsupportedDatabaseVersion(2, DATABASE_VERSION) => (),
+			ref kind => panic!("unexpected error {:?}", kind),
+		}
+	}
+
+	#[test]
+	fn database_save_replaces_file() {
+		let tempdir = TempDir::new("database").unwrap();
+		let path = tempdir.path().join("db.toml");
+
+		let mut database = Database {
+			checked_deposit_relay: 1000,
+			..Database::default()
+		};
+		database.save(&path).unwrap();
+		database.checked_deposit_relay = 1;
+		database.save(&path).unwrap();
+
+		// a shorter database doesn't leave stale bytes behind
+		assert_eq!(database, Database::load(&path).unwrap());
+		assert_eq!(1000, Database::load(backup_path(&path)).unwrap().checked_deposit_relay);
+		assert!(!tempdir.path().join("db.toml.tmp").exists());
+		assert!(!tempdir.path().join("db.toml.bak.tmp").exists());
+	}
+
+	#[test]
+	fn database_load_recovers_last_good_copy() {
+		let tempdir = TempDir::new("database").unwrap();
+		let path = tempdir.path().join("db.toml");
+
+		let mut database = Database::default();
+		database.save(&path).unwrap();
+		database.checked_withdraw_relay = 5;
+		database.save(&path).unwrap();
+		fs::write(&path, "checked_deposit_relay = ").unwrap();
+
+		assert_eq!(Database::default(), Database::load(&path).unwrap());
+	}
+
+	#[test]
+	fn database_load_missing_file() {
+		let tempdir = TempDir::new("database").unwrap();
+		assert!(Database::load(tempdir.path().join("db.toml")).is_err());
+	}
 }
@@ -7,6 +7,7 @@ use ethcore::ethstore;
 use ethcore::account_provider::{SignError, Error as AccountError};
 use serde_json;
 use hyper;
+use rusqlite;
 
 error_chain! {
 	types {
@@ -21,6 +22,7 @@ error_chain! {
 		Hex(rustc_hex::FromHexError);
 		Json(serde_json::Error);
 		Hyper(hyper::Error);
+		Sqlite(rusqlite::Error);
 	}
 
 	errors {
@@ -57,6 +59,10 @@ error_chain! {
 		    description("account error")
 		    display("account error {:?}", err),
 		}
+		SignerError(err: String) {
+		    description("signer error")
+		    display("signer error: {}", err),
+		}
 		ContextualizedError(err: Box<Error>, context: &'static str) {
 		    description("contextualized error")
 		    display("{:?} in {}", err, context)
@@ -69,6 +75,14 @@ error_chain! {
 		    description("config error")
 		    display("{}", err)
 		}
+		UnsupportedDatabaseVersion(found: u32, supported: u32) {
+		    description("unsupported database version")
+		    display("Database version {} is newer than the supported version {}, upgrade the bridge", found, supported)
+		}
+		LogQuorumNotReached(from: u64, to: u64) {
+		    description("log quorum not reached")
+		    display("RPC endpoints disagree on logs in blocks {}-{}", from, to)
+		}
 	}
 }
 
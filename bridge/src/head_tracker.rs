@@ -0,0 +1,178 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::{Async, Future, Poll, Stream};
+use futures::task::{self, Task};
+use tokio_timer::{Interval, Timeout, Timer};
+use web3::Transport;
+use web3::types::U256;
+use api::{self, ApiCall, NewHeads, NewHeadsStream};
+use error::Error;
+
+/// Tracks the head of a chain and shares it with all subscribed `LogStream`s,
+/// so that the node is asked for the best block once per chain rather than once per stream.
+///
+/// Heads come from the node subscription if there is one, otherwise the node is polled
+/// every `poll_interval`. The tracker is driven by its subscribers, it doesn't need a task of its own.
+pub struct HeadTracker<T: Transport> {
+	inner: Arc<Mutex<Inner<T>>>,
+}
+
+struct Inner<T: Transport> {
+	transport: T,
+	timer: Timer,
+	interval: Interval,
+	request_timeout: Duration,
+	heads: Option<Arc<NewHeads>>,
+	heads_stream: Option<NewHeadsStream>,
+	fetch: Option<Timeout<ApiCall<U256, T::Out>>>,
+	head: Option<u64>,
+	/// Number of distinct heads seen so far.
+	version: usize,
+	/// Subscribers waiting for a new head.
+	tasks: Vec<Task>,
+}
+
+impl<T: Transport> HeadTracker<T> {
+	pub fn new(transport: T, timer: Timer, poll_interval: Duration, request_timeout: Duration, heads: Option<Arc<NewHeads>>) -> Self {
+		let inner = Inner {
+			transport,
+			interval: timer.interval(poll_interval),
+			timer,
+			request_timeout,
+			heads_stream: heads.as_ref().map(|heads| heads.subscribe()),
+			heads,
+			fetch: None,
+			head: None,
+			version: 0,
+			tasks: Vec::new(),
+		};
+
+		HeadTracker {
+			inner: Arc::new(Mutex::new(inner)),
+		}
+	}
+}
+
+impl<T: Transport> Inner<T> {
+	fn on_head(&mut self, head: u64) -> bool {
+		if self.head == Some(head) {
+			return false;
+		}
+
+		self.head = Some(head);
+		self.version += 1;
+		for task in self.tasks.drain(..) {
+			task.notify();
+		}
+		true
+	}
+
+	/// Polls the node until a new head is found or nothing is ready.
+	fn poll_head(&mut self) -> Result<(), Error> {
+		loop {
+			if let Some(result) = self.fetch.as_mut().map(|fetch| fetch.poll()) {
+				match result {
+					Ok(Async::NotReady) => return Ok(()),
+					Ok(Async::Ready(head)) => {
+						self.fetch = None;
+						if self.on_head(head.low_u64()) {
+							return Ok(());
+						}
+					},
+					Err(err) => {
+						self.fetch = None;
+						// let the other subscribers drive the tracker further
+						for task in self.tasks.drain(..) {
+							task.notify();
+						}
+						return Err(err);
+					},
+				}
+			}
+
+			let next = match self.heads_stream.as_mut().map(|stream| stream.poll()) {
+				Some(Ok(Async::Ready(Some(head)))) => Some(head),
+				Some(Ok(Async::NotReady)) => return Ok(()),
+				Some(Ok(Async::Ready(None))) => {
+					warn!("new heads subscription closed, falling back to polling");
+					self.heads_stream = None;
+					None
+				},
+				Some(Err(err)) => {
+					warn!("new heads subscription failed ({}), falling back to polling", err);
+					self.heads_stream = None;
+					None
+				},
+				None => match self.interval.poll()? {
+					Async::Ready(_) => {
+						if let Some(ref heads) = self.heads {
+							self.heads_stream = Some(heads.subscribe());
+						}
+						self.fetch = Some(self.timer.timeout(api::block_number(&self.transport), self.request_timeout));
+						None
+					},
+					Async::NotReady => return Ok(()),
+				},
+			};
+
+			if let Some(head) = next {
+				if self.on_head(head) {
+					return Ok(());
+				}
+			}
+		}
+	}
+}
+
+impl<T: Transport + 'static> NewHeads for HeadTracker<T> where T::Out: 'static {
+	fn subscribe(&self) -> NewHeadsStream {
+		Box::new(HeadSubscription {
+			inner: self.inner.clone(),
+			seen: 0,
+		})
+	}
+}
+
+/// Stream of heads shared by a `HeadTracker`.
+struct HeadSubscription<T: Transport> {
+	inner: Arc<Mutex<Inner<T>>>,
+	/// Version of the last head yielded by this subscription.
+	seen: usize,
+}
+
+impl<T: Transport> Stream for HeadSubscription<T> {
+	type Item = u64;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		let mut inner = self.inner.lock().expect("head tracker lock poisoned");
+		if inner.version == self.seen {
+			inner.poll_head()?;
+		}
+
+		match inner.head {
+			Some(head) if inner.version != self.seen => {
+				self.seen = inner.version;
+				Ok(Async::Ready(Some(head)))
+			},
+			_ => {
+				if !inner.tasks.iter().any(|task| task.will_notify_current()) {
+					inner.tasks.push(task::current());
+				}
+				Ok(Async::NotReady)
+			},
+		}
+	}
+}
+
+impl<T: Transport> Drop for HeadSubscription<T> {
+	fn drop(&mut self) {
+		// the dropped subscription might have been the one woken up by the node,
+		// make sure that the remaining ones keep driving the tracker
+		if let Ok(mut inner) = self.inner.lock() {
+			for task in inner.tasks.drain(..) {
+				task.notify();
+			}
+		}
+	}
+}
@@ -0,0 +1,898 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, str};
+use rusqlite::{Connection, Row};
+use rusqlite::types::ToSql;
+use rustc_hex::{FromHex, ToHex};
+use web3::types::{Address, Bytes, H256, U256};
+use ethcore_transaction::{Action, Transaction};
+use database::EventId;
+use error::{Error, ErrorKind};
+use util::sibling_path;
+
+const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS transfers (
+	id INTEGER PRIMARY KEY,
+	kind TEXT NOT NULL,
+	block_number INTEGER NOT NULL,
+	event_tx_hash TEXT NOT NULL,
+	log_index TEXT NOT NULL,
+	source_tx_hash TEXT NOT NULL,
+	recipient TEXT NOT NULL,
+	value TEXT NOT NULL,
+	tx_hash TEXT,
+	nonce TEXT,
+	gas_price TEXT NOT NULL,
+	status TEXT NOT NULL,
+	timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS transfers_source_tx_hash ON transfers (source_tx_hash);
+CREATE INDEX IF NOT EXISTS transfers_tx_hash ON transfers (tx_hash);
+CREATE INDEX IF NOT EXISTS transfers_recipient ON transfers (recipient);
+CREATE INDEX IF NOT EXISTS transfers_timestamp ON transfers (timestamp);
+CREATE INDEX IF NOT EXISTS transfers_event ON transfers (event_tx_hash, log_index);
+CREATE TABLE IF NOT EXISTS dead_letters (
+	id INTEGER PRIMARY KEY,
+	kind TEXT NOT NULL,
+	block_number INTEGER NOT NULL,
+	event_tx_hash TEXT NOT NULL,
+	log_index TEXT NOT NULL,
+	attempts INTEGER NOT NULL,
+	reason TEXT NOT NULL,
+	status TEXT NOT NULL,
+	timestamp INTEGER NOT NULL,
+	UNIQUE (kind, event_tx_hash, log_index)
+);
+CREATE TABLE IF NOT EXISTS pending_transactions (
+	id INTEGER PRIMARY KEY,
+	chain TEXT NOT NULL,
+	nonce TEXT NOT NULL,
+	gas_price TEXT NOT NULL,
+	gas TEXT NOT NULL,
+	recipient TEXT,
+	value TEXT NOT NULL,
+	data TEXT NOT NULL,
+	raw TEXT NOT NULL,
+	priority_fee TEXT,
+	hashes TEXT NOT NULL,
+	sent_at INTEGER,
+	replaceable INTEGER NOT NULL DEFAULT 1
+);
+CREATE INDEX IF NOT EXISTS pending_transactions_chain ON pending_transactions (chain);
+";
+
+const COLUMNS: &'static str = "kind, block_number, event_tx_hash, log_index, source_tx_hash, recipient, value, tx_hash, nonce, gas_price, status, timestamp";
+
+const DEAD_LETTER_COLUMNS: &'static str = "id, kind, block_number, event_tx_hash, log_index, attempts, reason, status, timestamp";
+
+const PENDING_COLUMNS: &'static str = "nonce, gas_price, gas, recipient, value, data, raw, priority_fee, hashes, sent_at, replaceable";
+
+/// How long a write waits for the other process (the bridge or the CLI) to release the ledger.
+const BUSY_TIMEOUT_MS: u64 = 5000;
+
+/// Returns the path of the ledger kept next to the database at `database`.
+pub fn ledger_path(database: &Path) -> PathBuf {
+	sibling_path(database, ".ledger")
+}
+
+/// Seconds since the unix epoch.
+fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+/// Kind of a transfer handled by the bridge.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransferKind {
+	/// Deposit on home relayed to foreign.
+	DepositRelay,
+	/// Withdraw on foreign signed by this authority.
+	WithdrawConfirm,
+	/// Withdraw with collected signatures relayed to home.
+	WithdrawRelay,
+}
+
+impl fmt::Display for TransferKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match *self {
+			TransferKind::DepositRelay => "deposit_relay",
+			TransferKind::WithdrawConfirm => "withdraw_confirm",
+			TransferKind::WithdrawRelay => "withdraw_relay",
+		})
+	}
+}
+
+impl str::FromStr for TransferKind {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"deposit_relay" => Ok(TransferKind::DepositRelay),
+			"withdraw_confirm" => Ok(TransferKind::WithdrawConfirm),
+			"withdraw_relay" => Ok(TransferKind::WithdrawRelay),
+			_ => Err(ErrorKind::OtherError(format!("unknown transfer kind {}", s)).into()),
+		}
+	}
+}
+
+/// Status of a transfer.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransferStatus {
+	/// Transfer is being handled, the transaction is not sent yet.
+	Pending,
+	/// Transaction has been accepted by the node.
+	Sent,
+	/// Transaction has been mined.
+	Mined,
+	/// Transaction has been mined, but reverted. The transfer will be handled again.
+	Reverted,
+	/// Nonce of the transaction has been used by another transaction, so it will never be mined.
+	/// The transfer will be handled again.
+	Dropped,
+}
+
+impl fmt::Display for TransferStatus {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match *self {
+			TransferStatus::Pending => "pending",
+			TransferStatus::Sent => "sent",
+			TransferStatus::Mined => "mined",
+			TransferStatus::Reverted => "reverted",
+			TransferStatus::Dropped => "dropped",
+		})
+	}
+}
+
+impl str::FromStr for TransferStatus {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"pending" => Ok(TransferStatus::Pending),
+			"sent" => Ok(TransferStatus::Sent),
+			"mined" => Ok(TransferStatus::Mined),
+			"reverted" => Ok(TransferStatus::Reverted),
+			"dropped" => Ok(TransferStatus::Dropped),
+			_ => Err(ErrorKind::OtherError(format!("unknown transfer status {}", s)).into()),
+		}
+	}
+}
+
+/// Record of a single transfer handled by the bridge.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Transfer {
+	pub kind: TransferKind,
+	/// Event which triggered the transfer.
+	pub event: EventId,
+	/// Hash of the user transaction which initiated the transfer.
+	pub source_tx_hash: H256,
+	pub recipient: Address,
+	pub value: U256,
+	/// Hash of the transaction sent by the bridge.
+	pub tx_hash: Option<H256>,
+	pub nonce: Option<U256>,
+	pub gas_price: U256,
+	pub status: TransferStatus,
+	/// Seconds since the unix epoch at which the transfer got its current status.
+	pub timestamp: u64,
+}
+
+impl Transfer {
+	/// Creates a pending transfer.
+	pub fn new(kind: TransferKind, event: EventId, source_tx_hash: H256, recipient: Address, value: U256, gas_price: U256) -> Self {
+		Transfer {
+			kind,
+			event,
+			source_tx_hash,
+			recipient,
+			value,
+			tx_hash: None,
+			nonce: None,
+			gas_price,
+			status: TransferStatus::Pending,
+			timestamp: now(),
+		}
+	}
+
+	/// Marks the transfer as sent in transaction `tx_hash`.
+	pub fn sent(self, tx_hash: H256, nonce: U256) -> Self {
+		Transfer {
+			tx_hash: Some(tx_hash),
+			nonce: Some(nonce),
+			status: TransferStatus::Sent,
+			timestamp: now(),
+			..self
+		}
+	}
+}
+
+impl fmt::Display for Transfer {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} {} {} source_tx={} recipient={} value={} tx={} nonce={} gas_price={}",
+			self.timestamp,
+			self.kind,
+			self.status,
+			format_hash(&self.source_tx_hash),
+			format_hash(&self.recipient),
+			self.value,
+			self.tx_hash.as_ref().map(|hash| format_hash(hash)).unwrap_or_else(|| "-".into()),
+			self.nonce.map(|nonce| nonce.to_string()).unwrap_or_else(|| "-".into()),
+			self.gas_price)
+	}
+}
+
+/// State of an event which failed to be handled.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DeadLetterStatus {
+	/// Event is being handled again.
+	Retrying,
+	/// Event failed too many times and is skipped until an operator retries it.
+	Dead,
+	/// Operator asked for the event to be handled again.
+	RetryRequested,
+}
+
+impl fmt::Display for DeadLetterStatus {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match *self {
+			DeadLetterStatus::Retrying => "retrying",
+			DeadLetterStatus::Dead => "dead",
+			DeadLetterStatus::RetryRequested => "retry_requested",
+		})
+	}
+}
+
+impl str::FromStr for DeadLetterStatus {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"retrying" => Ok(DeadLetterStatus::Retrying),
+			"dead" => Ok(DeadLetterStatus::Dead),
+			"retry_requested" => Ok(DeadLetterStatus::RetryRequested),
+			_ => Err(ErrorKind::OtherError(format!("unknown dead letter status {}", s)).into()),
+		}
+	}
+}
+
+/// Event which failed to be handled, with the number of failed attempts.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DeadLetter {
+	pub id: i64,
+	pub kind: TransferKind,
+	pub event: EventId,
+	pub attempts: u32,
+	/// Reason of the last failure.
+	pub reason: String,
+	pub status: DeadLetterStatus,
+	/// Seconds since the unix epoch of the last change.
+	pub timestamp: u64,
+}
+
+impl fmt::Display for DeadLetter {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "#{} {} {} {} block={} event={} attempts={} reason={}",
+			self.id,
+			self.timestamp,
+			self.kind,
+			self.status,
+			self.event.block_number,
+			self.event,
+			self.attempts,
+			self.reason)
+	}
+}
+
+/// Transaction sent by the bridge which is not mined yet.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PendingTransaction {
+	pub transaction: Transaction,
+	pub raw: Bytes,
+	/// Priority fee of an EIP-1559 transaction.
+	pub priority_fee: Option<U256>,
+	/// Hashes of all versions of the transaction, the current one last.
+	pub hashes: Vec<H256>,
+	/// Block at which the current version has been first seen pending.
+	pub sent_at: Option<u64>,
+	/// Whether the transaction may be replaced by one with a higher gas price when it's stuck.
+	pub replaceable: bool,
+}
+
+impl PendingTransaction {
+	pub fn hash(&self) -> H256 {
+		*self.hashes.last().expect("pending transaction has at least one hash; qed")
+	}
+}
+
+/// Criteria of a ledger query. Transfers have to match all the given criteria.
+#[derive(Debug, Default, Clone)]
+pub struct LedgerQuery {
+	/// Hash of either the user transaction or the transaction sent by the bridge.
+	pub tx_hash: Option<H256>,
+	pub recipient: Option<Address>,
+	/// Earliest timestamp, inclusive.
+	pub since: Option<u64>,
+	/// Latest timestamp, inclusive.
+	pub until: Option<u64>,
+	pub status: Option<TransferStatus>,
+}
+
+/// Queryable record of all transfers handled by the bridge, stored in SQLite.
+pub struct Ledger {
+	connection: Connection,
+}
+
+impl Ledger {
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+		Ledger::with_connection(Connection::open(path)?)
+	}
+
+	pub fn open_in_memory() -> Result<Self, Error> {
+		Ledger::with_connection(Connection::open_in_memory()?)
+	}
+
+	fn with_connection(connection: Connection) -> Result<Self, Error> {
+		connection.busy_timeout(Duration::from_millis(BUSY_TIMEOUT_MS))?;
+		connection.execute_batch(SCHEMA)?;
+		Ok(Ledger {
+			connection,
+		})
+	}
+
+	/// Records `transfer`, replacing the record of its event if there is one,
+	/// e.g. a pending transfer once its transaction is sent or a reverted one sent again.
+	pub fn record(&self, transfer: &Transfer) -> Result<(), Error> {
+		let updated = self.connection.execute("UPDATE transfers SET block_number = ?, source_tx_hash = ?, recipient = ?, value = ?, tx_hash = ?, nonce = ?, gas_price = ?, status = ?, timestamp = ? WHERE kind = ? AND event_tx_hash = ? AND log_index = ?", &[
+			&(transfer.event.block_number as i64),
+			&format_hash(&transfer.source_tx_hash),
+			&format_hash(&transfer.recipient),
+			&transfer.value.to_string(),
+			&transfer.tx_hash.as_ref().map(|hash| format_hash(hash)),
+			&transfer.nonce.map(|nonce| nonce.to_string()),
+			&transfer.gas_price.to_string(),
+			&transfer.status.to_string(),
+			&(transfer.timestamp as i64),
+			&transfer.kind.to_string(),
+			&format_hash(&transfer.event.transaction_hash),
+			&transfer.event.log_index.to_string(),
+		])?;
+		if updated > 0 {
+			return Ok(());
+		}
+
+		let sql = format!("INSERT INTO transfers ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", COLUMNS);
+		self.connection.execute(&sql, &[
+			&transfer.kind.to_string(),
+			&(transfer.event.block_number as i64),
+			&format_hash(&transfer.event.transaction_hash),
+			&transfer.event.log_index.to_string(),
+			&format_hash(&transfer.source_tx_hash),
+			&format_hash(&transfer.recipient),
+			&transfer.value.to_string(),
+			&transfer.tx_hash.as_ref().map(|hash| format_hash(hash)),
+			&transfer.nonce.map(|nonce| nonce.to_string()),
+			&transfer.gas_price.to_string(),
+			&transfer.status.to_string(),
+			&(transfer.timestamp as i64),
+		])?;
+		Ok(())
+	}
+
+	/// Sets the status of the transfer sent in transaction `tx_hash`.
+	pub fn update_status(&self, tx_hash: &H256, status: TransferStatus) -> Result<(), Error> {
+		self.connection.execute("UPDATE transfers SET status = ? WHERE tx_hash = ?", &[
+			&status.to_string(),
+			&format_hash(tx_hash),
+		])?;
+		Ok(())
+	}
+
+	/// Records that transaction `old` has been replaced by transaction `new` sent with `gas_price`.
+	pub fn replace_transaction(&self, old: &H256, new: &H256, gas_price: U256) -> Result<(), Error> {
+		self.connection.execute("UPDATE transfers SET tx_hash = ?, gas_price = ? WHERE tx_hash = ?", &[
+			&format_hash(new),
+			&gas_price.to_string(),
+			&format_hash(old),
+		])?;
+		Ok(())
+	}
+
+	/// Returns the transfer sent in transaction `tx_hash`.
+	pub fn transfer_sent_in(&self, tx_hash: &H256) -> Result<Option<Transfer>, Error> {
+		let query = LedgerQuery {
+			tx_hash: Some(*tx_hash),
+			..LedgerQuery::default()
+		};
+		Ok(self.query(&query)?.into_iter().find(|transfer| transfer.tx_hash == Some(*tx_hash)))
+	}
+
+	/// Returns the transfers matching `query`, oldest first.
+	pub fn query(&self, query: &LedgerQuery) -> Result<Vec<Transfer>, Error> {
+		let mut conditions = Vec::new();
+		let mut params: Vec<Box<ToSql>> = Vec::new();
+		if let Some(ref hash) = query.tx_hash {
+			conditions.push("(source_tx_hash = ? OR tx_hash = ?)");
+			params.push(Box::new(format_hash(hash)));
+			params.push(Box::new(format_hash(hash)));
+		}
+		if let Some(ref recipient) = query.recipient {
+			conditions.push("recipient = ?");
+			params.push(Box::new(format_hash(recipient)));
+		}
+		if let Some(since) = query.since {
+			conditions.push("timestamp >= ?");
+			params.push(Box::new(since as i64));
+		}
+		if let Some(until) = query.until {
+			conditions.push("timestamp <= ?");
+			params.push(Box::new(until as i64));
+		}
+		if let Some(status) = query.status {
+			conditions.push("status = ?");
+			params.push(Box::new(status.to_string()));
+		}
+
+		let mut sql = format!("SELECT {} FROM transfers", COLUMNS);
+		if !conditions.is_empty() {
+			sql.push_str(" WHERE ");
+			sql.push_str(&conditions.join(" AND "));
+		}
+		sql.push_str(" ORDER BY timestamp, id");
+
+		let mut statement = self.connection.prepare(&sql)?;
+		let params: Vec<&ToSql> = params.iter().map(|param| &**param).collect();
+		let rows = statement.query_map(&params, read_transfer)?;
+		let mut transfers = Vec::new();
+		for row in rows {
+			transfers.push(row??);
+		}
+		Ok(transfers)
+	}
+
+	/// Returns all transfers triggered by `event`, oldest first.
+	pub fn event_transfers(&self, kind: TransferKind, event: &EventId) -> Result<Vec<Transfer>, Error> {
+		let sql = format!("SELECT {} FROM transfers WHERE kind = ? AND event_tx_hash = ? AND log_index = ? ORDER BY timestamp, id", COLUMNS);
+		let mut statement = self.connection.prepare(&sql)?;
+		let rows = statement.query_map(&[&kind.to_string(), &format_hash(&event.transaction_hash), &event.log_index.to_string()], read_transfer)?;
+		let mut transfers = Vec::new();
+		for row in rows {
+			transfers.push(row??);
+		}
+		Ok(transfers)
+	}
+
+	/// Records a failed attempt to handle `event`, returning the status of the event and the number of failed attempts.
+	/// Once `max_attempts` attempts failed, the event becomes a dead letter.
+	pub fn record_failure(&self, kind: TransferKind, event: &EventId, reason: &str, max_attempts: u32) -> Result<(DeadLetterStatus, u32), Error> {
+		let existing = self.dead_letters_where("kind = ? AND event_tx_hash = ? AND log_index = ?",
+			&[&kind.to_string(), &format_hash(&event.transaction_hash), &event.log_index.to_string()])?;
+		let attempts = existing.first().map(|letter| letter.attempts).unwrap_or(0) + 1;
+		let status = if attempts >= max_attempts {
+			DeadLetterStatus::Dead
+		} else {
+			DeadLetterStatus::Retrying
+		};
+
+		match existing.first() {
+			Some(letter) => {
+				self.connection.execute("UPDATE dead_letters SET attempts = ?, reason = ?, status = ?, timestamp = ? WHERE id = ?", &[
+					&(attempts as i64),
+					&reason.to_string(),
+					&status.to_string(),
+					&(now() as i64),
+					&letter.id,
+				])?;
+			},
+			None => {
+				let sql = format!("INSERT INTO dead_letters ({}) VALUES (NULL, ?, ?, ?, ?, ?, ?, ?, ?)", DEAD_LETTER_COLUMNS);
+				self.connection.execute(&sql, &[
+					&kind.to_string(),
+					&(event.block_number as i64),
+					&format_hash(&event.transaction_hash),
+					&event.log_index.to_string(),
+					&(attempts as i64),
+					&reason.to_string(),
+					&status.to_string(),
+					&(now() as i64),
+				])?;
+			},
+		}
+		Ok((status, attempts))
+	}
+
+	/// Forgets the failed attempts to handle `event`, once it has been handled successfully.
+	pub fn clear_failures(&self, kind: TransferKind, event: &EventId) -> Result<(), Error> {
+		self.connection.execute("DELETE FROM dead_letters WHERE kind = ? AND event_tx_hash = ? AND log_index = ? AND status = ?", &[
+			&kind.to_string(),
+			&format_hash(&event.transaction_hash),
+			&event.log_index.to_string(),
+			&DeadLetterStatus::Retrying.to_string(),
+		])?;
+		Ok(())
+	}
+
+	/// Returns all failed events, including the ones which are still being retried.
+	pub fn dead_letters(&self) -> Result<Vec<DeadLetter>, Error> {
+		self.dead_letters_where("1", &[])
+	}
+
+	pub fn dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, Error> {
+		Ok(self.dead_letters_where("id = ?", &[&id])?.into_iter().next())
+	}
+
+	/// Asks the bridge to handle the dead letter `id` again. Returns `false` if there is no such dead letter.
+	pub fn retry_dead_letter(&self, id: i64) -> Result<bool, Error> {
+		let changed = self.connection.execute("UPDATE dead_letters SET attempts = 0, status = ?, timestamp = ? WHERE id = ? AND status = ?", &[
+			&DeadLetterStatus::RetryRequested.to_string(),
+			&(now() as i64),
+			&id,
+			&DeadLetterStatus::Dead.to_string(),
+		])?;
+		Ok(changed > 0)
+	}
+
+	/// Drops the dead letter `id` for good. Returns `false` if there is no such dead letter.
+	pub fn discard_dead_letter(&self, id: i64) -> Result<bool, Error> {
+		let changed = self.connection.execute("DELETE FROM dead_letters WHERE id = ? AND status = ?", &[
+			&id,
+			&DeadLetterStatus::Dead.to_string(),
+		])?;
+		Ok(changed > 0)
+	}
+
+	/// Returns the dead letters operators asked to retry and marks them as being retried.
+	pub fn take_retry_requests(&self) -> Result<Vec<DeadLetter>, Error> {
+		let requested = self.dead_letters_where("status = ?", &[&DeadLetterStatus::RetryRequested.to_string()])?;
+		for letter in &requested {
+			self.connection.execute("UPDATE dead_letters SET status = ? WHERE id = ?", &[
+				&DeadLetterStatus::Retrying.to_string(),
+				&letter.id,
+			])?;
+		}
+		Ok(requested)
+	}
+
+	/// Returns the transactions sent on `chain` which were not mined yet when they were last stored.
+	pub fn pending_transactions(&self, chain: &str) -> Result<Vec<PendingTransaction>, Error> {
+		let sql = format!("SELECT {} FROM pending_transactions WHERE chain = ? ORDER BY id", PENDING_COLUMNS);
+		let mut statement = self.connection.prepare(&sql)?;
+		let rows = statement.query_map(&[&chain.to_string()], read_pending_transaction)?;
+		let mut pending = Vec::new();
+		for row in rows {
+			pending.push(row??);
+		}
+		Ok(pending)
+	}
+
+	/// Replaces the stored transactions of `chain` which are not mined yet with `pending`.
+	pub fn set_pending_transactions(&self, chain: &str, pending: &[PendingTransaction]) -> Result<(), Error> {
+		self.connection.execute_batch("BEGIN")?;
+		match self.insert_pending_transactions(chain, pending) {
+			Ok(()) => {
+				self.connection.execute_batch("COMMIT")?;
+				Ok(())
+			},
+			Err(err) => {
+				let _ = self.connection.execute_batch("ROLLBACK");
+				Err(err)
+			},
+		}
+	}
+
+	fn insert_pending_transactions(&self, chain: &str, pending: &[PendingTransaction]) -> Result<(), Error> {
+		self.connection.execute("DELETE FROM pending_transactions WHERE chain = ?", &[&chain.to_string()])?;
+		let sql = format!("INSERT INTO pending_transactions (chain, {}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", PENDING_COLUMNS);
+		for tx in pending {
+			let recipient = match tx.transaction.action {
+				Action::Call(ref address) => Some(format_hash(address)),
+				Action::Create => None,
+			};
+			let hashes = tx.hashes.iter().map(|hash| format_hash(hash)).collect::<Vec<_>>().join(",");
+			self.connection.execute(&sql, &[
+				&chain.to_string(),
+				&tx.transaction.nonce.to_string(),
+				&tx.transaction.gas_price.to_string(),
+				&tx.transaction.gas.to_string(),
+				&recipient,
+				&tx.transaction.value.to_string(),
+				&format_hash(&tx.transaction.data),
+				&format_hash(&tx.raw.0),
+				&tx.priority_fee.map(|fee| fee.to_string()),
+				&hashes,
+				&tx.sent_at.map(|block| block as i64),
+				&tx.replaceable,
+			])?;
+		}
+		Ok(())
+	}
+
+	fn dead_letters_where(&self, condition: &str, params: &[&ToSql]) -> Result<Vec<DeadLetter>, Error> {
+		let sql = format!("SELECT {} FROM dead_letters WHERE {} ORDER BY id", DEAD_LETTER_COLUMNS, condition);
+		let mut statement = self.connection.prepare(&sql)?;
+		let rows = statement.query_map(params, read_dead_letter)?;
+		let mut letters = Vec::new();
+		for row in rows {
+			letters.push(row??);
+		}
+		Ok(letters)
+	}
+}
+
+fn format_hash(hash: &[u8]) -> String {
+	format!("0x{}", hash.to_hex())
+}
+
+fn parse_hash<T: str::FromStr>(s: &str) -> Result<T, Error> {
+	s.trim_left_matches("0x").parse().map_err(|_| ErrorKind::OtherError(format!("invalid hash {} in ledger", s)).into())
+}
+
+fn parse_bytes(s: &str) -> Result<Vec<u8>, Error> {
+	s.trim_left_matches("0x").from_hex().map_err(|_| ErrorKind::OtherError(format!("invalid bytes {} in ledger", s)).into())
+}
+
+fn parse_u256(s: &str) -> Result<U256, Error> {
+	U256::from_dec_str(s).map_err(|_| ErrorKind::OtherError(format!("invalid number {} in ledger", s)).into())
+}
+
+fn read_transfer(row: &Row) -> Result<Transfer, Error> {
+	let tx_hash: Option<String> = row.get_checked(7)?;
+	let nonce: Option<String> = row.get_checked(8)?;
+	let block_number: i64 = row.get_checked(1)?;
+	let timestamp: i64 = row.get_checked(11)?;
+	Ok(Transfer {
+		kind: row.get_checked::<_, String>(0)?.parse()?,
+		event: EventId {
+			block_number: block_number as u64,
+			transaction_hash: parse_hash(&row.get_checked::<_, String>(2)?)?,
+			log_index: parse_u256(&row.get_checked::<_, String>(3)?)?,
+		},
+		source_tx_hash: parse_hash(&row.get_checked::<_, String>(4)?)?,
+		recipient: parse_hash(&row.get_checked::<_, String>(5)?)?,
+		value: parse_u256(&row.get_checked::<_, String>(6)?)?,
+		tx_hash: match tx_hash {
+			Some(hash) => Some(parse_hash(&hash)?),
+			None => None,
+		},
+		nonce: match nonce {
+			Some(nonce) => Some(parse_u256(&nonce)?),
+			None => None,
+		},
+		gas_price: parse_u256(&row.get_checked::<_, String>(9)?)?,
+		status: row.get_checked::<_, String>(10)?.parse()?,
+		timestamp: timestamp as u64,
+	})
+}
+
+fn read_pending_transaction(row: &Row) -> Result<PendingTransaction, Error> {
+	let recipient: Option<String> = row.get_checked(3)?;
+	let priority_fee: Option<String> = row.get_checked(7)?;
+	let hashes: String = row.get_checked(8)?;
+	let sent_at: Option<i64> = row.get_checked(9)?;
+	Ok(PendingTransaction {
+		transaction: Transaction {
+			nonce: parse_u256(&row.get_checked::<_, String>(0)?)?,
+			gas_price: parse_u256(&row.get_checked::<_, String>(1)?)?,
+			gas: parse_u256(&row.get_checked::<_, String>(2)?)?,
+			action: match recipient {
+				Some(address) => Action::Call(parse_hash(&address)?),
+				None => Action::Create,
+			},
+			value: parse_u256(&row.get_checked::<_, String>(4)?)?,
+			data: parse_bytes(&row.get_checked::<_, String>(5)?)?,
+		},
+		raw: Bytes(parse_bytes(&row.get_checked::<_, String>(6)?)?),
+		priority_fee: match priority_fee {
+			Some(fee) => Some(parse_u256(&fee)?),
+			None => None,
+		},
+		hashes: hashes.split(',').map(parse_hash::<H256>).collect::<Result<_, _>>()?,
+		sent_at: sent_at.map(|block| block as u64),
+		replaceable: row.get_checked(10)?,
+	})
+}
+
+fn read_dead_letter(row: &Row) -> Result<DeadLetter, Error> {
+	let block_number: i64 = row.get_checked(2)?;
+	let attempts: i64 = row.get_checked(5)?;
+	let timestamp: i64 = row.get_checked(8)?;
+	Ok(DeadLetter {
+		id: row.get_checked(0)?,
+		kind: row.get_checked::<_, String>(1)?.parse()?,
+		event: EventId {
+			block_number: block_number as u64,
+			transaction_hash: parse_hash(&row.get_checked::<_, String>(3)?)?,
+			log_index: parse_u256(&row.get_checked::<_, String>(4)?)?,
+		},
+		attempts: attempts as u32,
+		reason: row.get_checked(6)?,
+		status: row.get_checked::<_, String>(7)?.parse()?,
+		timestamp: timestamp as u64,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use web3::types::{Bytes, U256};
+	use ethcore_transaction::{Action, Transaction};
+	use database::EventId;
+	use super::{Ledger, LedgerQuery, Transfer, TransferKind, TransferStatus, DeadLetterStatus, PendingTransaction};
+
+	fn transfer(kind: TransferKind, n: u64, recipient: u64, timestamp: u64) -> Transfer {
+		let event = EventId {
+			block_number: n,
+			transaction_hash: n.into(),
+			log_index: 0u64.into(),
+		};
+		let mut transfer = Transfer::new(kind, event, n.into(), recipient.into(), 1000u64.into(), 10u64.into())
+			.sent((n + 100).into(), n.into());
+		transfer.timestamp = timestamp;
+		transfer
+	}
+
+	#[test]
+	fn ledger_record_and_query() {
+		let ledger = Ledger::open_in_memory().unwrap();
+		let deposit = transfer(TransferKind::DepositRelay, 1, 0xaa, 100);
+		let confirm = transfer(TransferKind::WithdrawConfirm, 2, 0xbb, 200);
+		let relay = transfer(TransferKind::WithdrawRelay, 3, 0xbb, 300);
+		for transfer in &[deposit, confirm, relay] {
+			ledger.record(transfer).unwrap();
+		}
+
+		assert_eq!(vec![deposit, confirm, relay], ledger.query(&LedgerQuery::default()).unwrap());
+
+		let by_source = LedgerQuery {
+			tx_hash: Some(2u64.into()),
+			..LedgerQuery::default()
+		};
+		assert_eq!(vec![confirm], ledger.query(&by_source).unwrap());
+
+		let by_sent = LedgerQuery {
+			tx_hash: Some(103u64.into()),
+			..LedgerQuery::default()
+		};
+		assert_eq!(vec![relay], ledger.query(&by_sent).unwrap());
+
+		let by_recipient_and_time = LedgerQuery {
+			recipient: Some(0xbbu64.into()),
+			since: Some(250),
+			..LedgerQuery::default()
+		};
+		assert_eq!(vec![relay], ledger.query(&by_recipient_and_time).unwrap());
+
+		let by_time = LedgerQuery {
+			until: Some(200),
+			..LedgerQuery::default()
+		};
+		assert_eq!(vec![deposit, confirm], ledger.query(&by_time).unwrap());
+		assert_eq!(TransferStatus::Sent, relay.status);
+	}
+
+	#[test]
+	fn ledger_updates_transfer_of_the_same_event() {
+		let ledger = Ledger::open_in_memory().unwrap();
+		let sent = transfer(TransferKind::DepositRelay, 1, 0xaa, 100);
+		let pending = Transfer::new(sent.kind, sent.event, sent.source_tx_hash, sent.recipient, sent.value, sent.gas_price);
+		ledger.record(&pending).unwrap();
+		assert_eq!(vec![pending], ledger.query(&LedgerQuery::default()).unwrap());
+
+		ledger.record(&sent).unwrap();
+		assert_eq!(vec![sent], ledger.query(&LedgerQuery::default()).unwrap());
+
+		// the same event of another kind is a separate transfer
+		let confirm = Transfer { kind: TransferKind::WithdrawConfirm, ..sent };
+		ledger.record(&confirm).unwrap();
+		assert_eq!(vec![sent, confirm], ledger.query(&LedgerQuery::default()).unwrap());
+	}
+
+	#[test]
+	fn ledger_follows_replaced_and_mined_transactions() {
+		let ledger = Ledger::open_in_memory().unwrap();
+		ledger.record(&transfer(TransferKind::DepositRelay, 1, 0xaa, 100)).unwrap();
+		ledger.replace_transaction(&101u64.into(), &201u64.into(), 12u64.into()).unwrap();
+		ledger.update_status(&201u64.into(), TransferStatus::Mined).unwrap();
+
+		let transfers = ledger.query(&LedgerQuery::default()).unwrap();
+		assert_eq!(1, transfers.len());
+		assert_eq!(Some(201u64.into()), transfers[0].tx_hash);
+		assert_eq!(U256::from(12), transfers[0].gas_price);
+		assert_eq!(TransferStatus::Mined, transfers[0].status);
+	}
+
+	#[test]
+	fn ledger_finds_reverted_transfers() {
+		let ledger = Ledger::open_in_memory().unwrap();
+		let deposit = transfer(TransferKind::DepositRelay, 1, 0xaa, 100);
+		let confirm = transfer(TransferKind::WithdrawConfirm, 2, 0xbb, 200);
+		ledger.record(&deposit).unwrap();
+		ledger.record(&confirm).unwrap();
+		ledger.update_status(&102u64.into(), TransferStatus::Reverted).unwrap();
+
+		let reverted = LedgerQuery {
+			status: Some(TransferStatus::Reverted),
+			..LedgerQuery::default()
+		};
+		let transfers = ledger.query(&reverted).unwrap();
+		assert_eq!(1, transfers.len());
+		assert_eq!(confirm.event, transfers[0].event);
+		assert_eq!(Some(deposit), ledger.transfer_sent_in(&101u64.into()).unwrap());
+		// the user transaction hash doesn't match
+		assert_eq!(None, ledger.transfer_sent_in(&1u64.into()).unwrap());
+	}
+
+	#[test]
+	fn ledger_moves_event_to_dead_letters_after_max_attempts() {
+		let ledger = Ledger::open_in_memory().unwrap();
+		let deposit = transfer(TransferKind::DepositRelay, 1, 0xaa, 100);
+		let confirm = transfer(TransferKind::WithdrawConfirm, 2, 0xbb, 200);
+
+		assert_eq!((DeadLetterStatus::Retrying, 1), ledger.record_failure(deposit.kind, &deposit.event, "reverted", 3).unwrap());
+		assert_eq!((DeadLetterStatus::Retrying, 2), ledger.record_failure(deposit.kind, &deposit.event, "reverted", 3).unwrap());
+		assert_eq!((DeadLetterStatus::Retrying, 1), ledger.record_failure(confirm.kind, &confirm.event, "reverted", 3).unwrap());
+		assert_eq!((DeadLetterStatus::Dead, 3), ledger.record_failure(deposit.kind, &deposit.event, "reverted again", 3).unwrap());
+		ledger.clear_failures(confirm.kind, &confirm.event).unwrap();
+
+		let letters = ledger.dead_letters().unwrap();
+		assert_eq!(1, letters.len());
+		assert_eq!(deposit.event, letters[0].event);
+		assert_eq!(3, letters[0].attempts);
+		assert_eq!("reverted again", letters[0].reason);
+		assert_eq!(Some(letters[0].clone()), ledger.dead_letter(letters[0].id).unwrap());
+
+		// dead letters are not cleared by a successful transfer of another attempt
+		ledger.clear_failures(deposit.kind, &deposit.event).unwrap();
+		assert_eq!(1, ledger.dead_letters().unwrap().len());
+
+		assert!(ledger.take_retry_requests().unwrap().is_empty());
+		assert!(ledger.retry_dead_letter(letters[0].id).unwrap());
+		assert!(!ledger.discard_dead_letter(letters[0].id).unwrap());
+		let requested = ledger.take_retry_requests().unwrap();
+		assert_eq!(1, requested.len());
+		assert_eq!(0, requested[0].attempts);
+		assert_eq!(DeadLetterStatus::Retrying, ledger.dead_letters().unwrap()[0].status);
+		assert!(ledger.take_retry_requests().unwrap().is_empty());
+
+		assert_eq!((DeadLetterStatus::Dead, 1), ledger.record_failure(deposit.kind, &deposit.event, "undecodable", 1).unwrap());
+		assert!(ledger.discard_dead_letter(letters[0].id).unwrap());
+		assert!(ledger.dead_letters().unwrap().is_empty());
+	}
+
+	#[test]
+	fn ledger_keeps_pending_transactions_per_chain() {
+		let ledger = Ledger::open_in_memory().unwrap();
+		let call = PendingTransaction {
+			transaction: Transaction {
+				nonce: 5.into(),
+				gas_price: 10.into(),
+				gas: 21_000.into(),
+				action: Action::Call(0xaau64.into()),
+				value: U256::zero(),
+				data: vec![1, 2, 3],
+			},
+			raw: Bytes(vec![4, 5, 6]),
+			priority_fee: Some(2.into()),
+			hashes: vec![1u64.into(), 2u64.into()],
+			sent_at: Some(100),
+			replaceable: true,
+		};
+		let create = PendingTransaction {
+			transaction: Transaction {
+				nonce: 6.into(),
+				action: Action::Create,
+				..call.transaction.clone()
+			},
+			priority_fee: None,
+			hashes: vec![3u64.into()],
+			sent_at: None,
+			replaceable: false,
+			..call.clone()
+		};
+
+		ledger.set_pending_transactions("home", &[call.clone(), create.clone()]).unwrap();
+		ledger.set_pending_transactions("foreign", &[create.clone()]).unwrap();
+		assert_eq!(vec![call.clone(), create.clone()], ledger.pending_transactions("home").unwrap());
+
+		ledger.set_pending_transactions("home", &[call.clone()]).unwrap();
+		assert_eq!(vec![call], ledger.pending_transactions("home").unwrap());
+		assert_eq!(vec![create], ledger.pending_transactions("foreign").unwrap());
+	}
+}
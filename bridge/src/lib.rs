@@ -32,6 +32,7 @@ extern crate jsonrpc_core as rpc;
 extern crate itertools;
 extern crate hyper;
 extern crate hyper_tls;
+extern crate rusqlite;
 
 #[cfg(test)]
 #[macro_use]
@@ -47,7 +48,13 @@ pub mod bridge;
 pub mod contracts;
 pub mod database;
 pub mod error;
+pub mod head_tracker;
+pub mod ledger;
 pub mod util;
 pub mod message_to_mainnet;
+pub mod nonce_manager;
+pub mod rpc_failure;
 pub mod signature;
+pub mod signer;
 pub mod transaction;
+pub mod transport;
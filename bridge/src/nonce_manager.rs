@@ -0,0 +1,212 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use web3::types::U256;
+
+/// Nonces of the transactions sent from a bridge account, shared by everything sending them.
+///
+/// Nonces are handed out in order. A nonce whose transaction never reached the node leaves a gap,
+/// which blocks all transactions sent after it, so it's handed out again before any new one.
+/// Transactions sent from the account by someone else are noticed whenever the node knows
+/// more transactions than the bridge has sent.
+#[derive(Debug, Clone, Default)]
+pub struct NonceManager {
+	inner: Arc<Mutex<Nonces>>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Nonces {
+	/// Number of mined transactions sent from the account.
+	confirmed: U256,
+	/// Nonce handed out next if there are no gaps.
+	next: U256,
+	/// Nonces handed out whose transactions are not mined yet.
+	in_flight: BTreeSet<U256>,
+	/// Nonces handed out whose transactions never reached the node.
+	gaps: BTreeSet<U256>,
+}
+
+impl Nonces {
+	/// Takes back the gaps at the end, so that they are not filled needlessly.
+	fn trim_gaps(&mut self) {
+		while self.next > self.confirmed && self.gaps.remove(&(self.next - U256::one())) {
+			self.next = self.next - U256::one();
+		}
+	}
+}
+
+impl NonceManager {
+	/// Starts handing out nonces from `nonce`, the number of transactions the node knows.
+	pub fn reset(&self, nonce: U256) {
+		*self.lock() = Nonces {
+			confirmed: nonce,
+			next: nonce,
+			in_flight: BTreeSet::new(),
+			gaps: BTreeSet::new(),
+		};
+	}
+
+	/// Hands out the lowest gap or the next nonce.
+	pub fn next(&self) -> U256 {
+		let mut nonces = self.lock();
+		let nonce = match nonces.gaps.iter().next().cloned() {
+			Some(gap) => {
+				nonces.gaps.remove(&gap);
+				gap
+			},
+			None => {
+				let nonce = nonces.next;
+				nonces.next = nonce + U256::one();
+				nonce
+			},
+		};
+		nonces.in_flight.insert(nonce);
+		nonce
+	}
+
+	/// Gives back `nonce` whose transaction failed before it reached the node.
+	pub fn release(&self, nonce: U256) {
+		let mut nonces = self.lock();
+		nonces.in_flight.remove(&nonce);
+		if nonce >= nonces.confirmed && nonce < nonces.next {
+			nonces.gaps.insert(nonce);
+		}
+		nonces.trim_gaps();
+	}
+
+	/// Drops `nonce` which turned out to be used by a transaction the bridge didn't send.
+	pub fn skip(&self, nonce: U256) {
+		let mut nonces = self.lock();
+		nonces.in_flight.remove(&nonce);
+		nonces.gaps.remove(&nonce);
+		if nonce >= nonces.next {
+			nonces.next = nonce + U256::one();
+		}
+	}
+
+	/// Takes back `nonce` of a transaction sent before a restart which is not mined yet,
+	/// so that it's not handed out again even if the node no longer knows the transaction.
+	pub fn restore(&self, nonce: U256) {
+		let mut nonces = self.lock();
+		if nonce < nonces.confirmed {
+			return;
+		}
+
+		nonces.gaps.remove(&nonce);
+		nonces.in_flight.insert(nonce);
+		if nonce >= nonces.next {
+			nonces.next = nonce + U256::one();
+		}
+	}
+
+	/// Updates the nonces with the number of transactions the node knows, including pending ones.
+	/// Returns the number of transactions sent from the account by someone else.
+	pub fn sync(&self, pending: U256) -> U256 {
+		let mut nonces = self.lock();
+		if pending <= nonces.next {
+			return U256::zero();
+		}
+
+		let unknown = pending - nonces.next;
+		nonces.next = pending;
+		unknown
+	}
+
+	/// Forgets the nonces of the first `mined` transactions which are mined.
+	/// Returns the number of them sent from the account by someone else.
+	pub fn confirm(&self, mined: U256) -> U256 {
+		let mut nonces = self.lock();
+		if mined <= nonces.confirmed {
+			return U256::zero();
+		}
+
+		let filled_gaps = nonces.gaps.iter().filter(|gap| **gap < mined).count();
+		let mut unknown = U256::from(filled_gaps);
+		if mined > nonces.next {
+			unknown = unknown + mined - nonces.next;
+			nonces.next = mined;
+		}
+		nonces.confirmed = mined;
+		nonces.in_flight = nonces.in_flight.split_off(&mined);
+		nonces.gaps = nonces.gaps.split_off(&mined);
+		unknown
+	}
+
+	/// Hands out all gaps, so that they can be filled with transactions which do nothing.
+	pub fn take_gaps(&self) -> Vec<U256> {
+		let mut nonces = self.lock();
+		let gaps = ::std::mem::replace(&mut nonces.gaps, BTreeSet::new());
+		nonces.in_flight.extend(gaps.iter().cloned());
+		gaps.into_iter().collect()
+	}
+
+	fn lock(&self) -> ::std::sync::MutexGuard<Nonces> {
+		self.inner.lock().expect("nonces lock poisoned")
+	}
+}
+
+impl PartialEq for NonceManager {
+	fn eq(&self, rhs: &Self) -> bool {
+		Arc::ptr_eq(&self.inner, &rhs.inner) || *self.lock() == *rhs.lock()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use web3::types::U256;
+	use super::NonceManager;
+
+	#[test]
+	fn test_nonce_manager_fills_gaps_first() {
+		let nonces = NonceManager::default();
+		nonces.reset(5.into());
+		assert_eq!(U256::from(5), nonces.next());
+		assert_eq!(U256::from(6), nonces.next());
+		assert_eq!(U256::from(7), nonces.next());
+
+		nonces.release(6.into());
+		assert_eq!(U256::from(6), nonces.next());
+		assert_eq!(U256::from(8), nonces.next());
+
+		// the last nonce is simply handed out again
+		nonces.release(8.into());
+		assert!(nonces.take_gaps().is_empty());
+		assert_eq!(U256::from(8), nonces.next());
+
+		nonces.release(6.into());
+		assert_eq!(vec![U256::from(6)], nonces.take_gaps());
+		assert_eq!(U256::from(9), nonces.next());
+	}
+
+	#[test]
+	fn test_nonce_manager_notices_external_transactions() {
+		let nonces = NonceManager::default();
+		nonces.reset(5.into());
+		assert_eq!(U256::from(5), nonces.next());
+		assert_eq!(U256::from(6), nonces.next());
+		assert_eq!(U256::from(7), nonces.next());
+		nonces.release(6.into());
+
+		// 6 has been used by someone else, as well as 8 and 9
+		assert_eq!(U256::from(3), nonces.confirm(10.into()));
+		assert!(nonces.take_gaps().is_empty());
+		assert_eq!(U256::from(10), nonces.next());
+
+		assert_eq!(U256::from(2), nonces.sync(13.into()));
+		assert_eq!(U256::from(13), nonces.next());
+
+		nonces.skip(14.into());
+		assert_eq!(U256::from(15), nonces.next());
+	}
+
+	#[test]
+	fn test_nonce_manager_restores_pending_nonces() {
+		let nonces = NonceManager::default();
+		// the node lost the transactions with nonces 5 and 6 sent before a restart
+		nonces.reset(5.into());
+		nonces.restore(5.into());
+		nonces.restore(6.into());
+		nonces.restore(4.into());
+		assert_eq!(U256::from(7), nonces.next());
+		assert!(nonces.take_gaps().is_empty());
+	}
+}
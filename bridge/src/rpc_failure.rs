@@ -0,0 +1,187 @@
+use rpc::{self, ErrorCode};
+
+/// Reason a node rejected a transaction, whatever client the node runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RpcFailure {
+	/// Nonce is used by a mined transaction.
+	NonceTooLow,
+	/// Another transaction with the same nonce is pending and the new one doesn't pay enough to replace it.
+	ReplacementUnderpriced,
+	/// The very same transaction has been sent before.
+	AlreadyKnown,
+	/// Account can't pay for the transaction.
+	InsufficientFunds,
+	/// Gas limit doesn't cover the intrinsic cost of the transaction.
+	GasTooLow,
+	/// Gas price is below the node's minimum.
+	GasPriceTooLow,
+	/// Any other failure.
+	Other,
+}
+
+/// Recognizes the errors of a single client, `None` if the error is not one of them.
+type Classifier = fn(&rpc::Error) -> Option<RpcFailure>;
+
+const CLASSIFIERS: &[Classifier] = &[parity, nethermind, geth, besu];
+
+impl RpcFailure {
+	/// Classifies an error returned by any of the supported clients: Parity/OpenEthereum, Geth, Nethermind and Besu.
+	pub fn classify(err: &rpc::Error) -> RpcFailure {
+		CLASSIFIERS.iter()
+			.filter_map(|classifier| classifier(err))
+			.next()
+			.unwrap_or(RpcFailure::Other)
+	}
+
+	/// Returns true if the nonce of the transaction is already used by a mined one.
+	/// A nonce taken by a pending transaction is `ReplacementUnderpriced`.
+	pub fn is_nonce_used(&self) -> bool {
+		*self == RpcFailure::NonceTooLow
+	}
+
+	/// Returns true if the failure is not about a single transaction, but any transaction of the account
+	/// would fail the same way until its balance or the gas settings of the bridge are fixed.
+	pub fn is_account_failure(&self) -> bool {
+		match *self {
+			RpcFailure::InsufficientFunds | RpcFailure::GasTooLow | RpcFailure::GasPriceTooLow => true,
+			_ => false,
+		}
+	}
+}
+
+fn parity(err: &rpc::Error) -> Option<RpcFailure> {
+	if err.code != ErrorCode::ServerError(-32010) {
+		return None;
+	}
+
+	let message = &err.message;
+	if message.ends_with("already imported.") {
+		Some(RpcFailure::AlreadyKnown)
+	} else if message.starts_with("Transaction nonce is too low") {
+		Some(RpcFailure::NonceTooLow)
+	} else if message.starts_with("Transaction gas price is too low. There is another") {
+		Some(RpcFailure::ReplacementUnderpriced)
+	} else if message.starts_with("Transaction gas price is too low") {
+		Some(RpcFailure::GasPriceTooLow)
+	} else if message.starts_with("Insufficient funds") {
+		Some(RpcFailure::InsufficientFunds)
+	} else if message.starts_with("Transaction gas is too low") {
+		Some(RpcFailure::GasTooLow)
+	} else {
+		None
+	}
+}
+
+fn nethermind(err: &rpc::Error) -> Option<RpcFailure> {
+	if err.code != ErrorCode::ServerError(-32010) {
+		return None;
+	}
+
+	// the result name may be followed by the details, e.g. `OldNonce, Current nonce: 5, nonce of rejected tx: 3`
+	match err.message.split(|c| c == ',' || c == ':').next().unwrap_or_default().trim() {
+		"OldNonce" => Some(RpcFailure::NonceTooLow),
+		"ReplacementNotAllowed" => Some(RpcFailure::ReplacementUnderpriced),
+		"AlreadyKnown" => Some(RpcFailure::AlreadyKnown),
+		"InsufficientFunds" => Some(RpcFailure::InsufficientFunds),
+		"FeeTooLow" => Some(RpcFailure::GasPriceTooLow),
+		// the pool is full of transactions paying more, it's not about the node's minimum
+		"FeeTooLowToCompete" => Some(RpcFailure::Other),
+		_ => None,
+	}
+}
+
+fn geth(err: &rpc::Error) -> Option<RpcFailure> {
+	if err.code != ErrorCode::ServerError(-32000) {
+		return None;
+	}
+
+	let message = &err.message;
+	if message.starts_with("nonce too low") {
+		Some(RpcFailure::NonceTooLow)
+	} else if message.starts_with("replacement transaction underpriced") {
+		Some(RpcFailure::ReplacementUnderpriced)
+	} else if message.starts_with("already known") || message.starts_with("known transaction") {
+		Some(RpcFailure::AlreadyKnown)
+	} else if message.starts_with("insufficient funds") {
+		Some(RpcFailure::InsufficientFunds)
+	} else if message.starts_with("intrinsic gas too low") {
+		Some(RpcFailure::GasTooLow)
+	} else if message.starts_with("transaction underpriced") {
+		Some(RpcFailure::GasPriceTooLow)
+	} else {
+		None
+	}
+}
+
+fn besu(err: &rpc::Error) -> Option<RpcFailure> {
+	match err.code {
+		ErrorCode::ServerError(code) if code <= -32000 && code >= -32009 => (),
+		_ => return None,
+	}
+
+	let message = &err.message;
+	if message.starts_with("Nonce too low") {
+		Some(RpcFailure::NonceTooLow)
+	} else if message.starts_with("Replacement transaction underpriced") {
+		Some(RpcFailure::ReplacementUnderpriced)
+	} else if message.starts_with("Known transaction") {
+		Some(RpcFailure::AlreadyKnown)
+	} else if message.starts_with("Upfront cost exceeds account balance") {
+		Some(RpcFailure::InsufficientFunds)
+	} else if message.starts_with("Intrinsic gas exceeds gas limit") {
+		Some(RpcFailure::GasTooLow)
+	} else if message.starts_with("Gas price below configured minimum gas price") {
+		Some(RpcFailure::GasPriceTooLow)
+	} else {
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rpc::{self, ErrorCode};
+	use super::RpcFailure;
+
+	fn classify(code: i64, message: &str) -> RpcFailure {
+		RpcFailure::classify(&rpc::Error {
+			code: ErrorCode::ServerError(code),
+			message: message.into(),
+			data: None,
+		})
+	}
+
+	#[test]
+	fn test_classify_parity() {
+		assert_eq!(RpcFailure::AlreadyKnown, classify(-32010, "Transaction with the same hash was already imported."));
+		assert_eq!(RpcFailure::NonceTooLow, classify(-32010, "Transaction nonce is too low. Try incrementing the nonce."));
+		assert_eq!(RpcFailure::ReplacementUnderpriced, classify(-32010, "Transaction gas price is too low. There is another transaction with same nonce in the queue. Try increasing the gas price or incrementing the nonce."));
+		assert_eq!(RpcFailure::GasPriceTooLow, classify(-32010, "Transaction gas price is too low. It does not satisfy your node's minimal gas price (minimal: 20, got: 1). Try increasing the gas price."));
+		assert_eq!(RpcFailure::InsufficientFunds, classify(-32010, "Insufficient funds. The account you tried to send transaction from does not have enough funds. Required 100 and got: 1."));
+		assert_eq!(RpcFailure::GasTooLow, classify(-32010, "Transaction gas is too low. There is not enough gas to cover minimal cost of the transaction (minimal: 21000, got: 1). Try increasing supplied gas."));
+	}
+
+	#[test]
+	fn test_classify_geth() {
+		assert_eq!(RpcFailure::NonceTooLow, classify(-32000, "nonce too low"));
+		assert_eq!(RpcFailure::ReplacementUnderpriced, classify(-32000, "replacement transaction underpriced"));
+		assert_eq!(RpcFailure::AlreadyKnown, classify(-32000, "already known"));
+		assert_eq!(RpcFailure::InsufficientFunds, classify(-32000, "insufficient funds for gas * price + value"));
+		assert_eq!(RpcFailure::GasTooLow, classify(-32000, "intrinsic gas too low"));
+		assert_eq!(RpcFailure::GasPriceTooLow, classify(-32000, "transaction underpriced"));
+	}
+
+	#[test]
+	fn test_classify_nethermind_and_besu() {
+		assert_eq!(RpcFailure::NonceTooLow, classify(-32010, "OldNonce, Current nonce: 5, nonce of rejected tx: 3"));
+		assert_eq!(RpcFailure::AlreadyKnown, classify(-32010, "AlreadyKnown"));
+		assert_eq!(RpcFailure::InsufficientFunds, classify(-32010, "InsufficientFunds, Account balance: 0, cumulative cost: 100"));
+		assert_eq!(RpcFailure::GasPriceTooLow, classify(-32010, "FeeTooLow, MaxFeePerGas too low. MaxFeePerGas: 1, BaseFee: 7"));
+		assert_eq!(RpcFailure::Other, classify(-32010, "FeeTooLowToCompete"));
+		assert_eq!(RpcFailure::ReplacementUnderpriced, classify(-32010, "ReplacementNotAllowed"));
+		assert_eq!(RpcFailure::NonceTooLow, classify(-32001, "Nonce too low"));
+		assert_eq!(RpcFailure::AlreadyKnown, classify(-32000, "Known transaction"));
+		assert_eq!(RpcFailure::InsufficientFunds, classify(-32004, "Upfront cost exceeds account balance"));
+		assert_eq!(RpcFailure::Other, classify(-32000, "execution reverted"));
+		assert_eq!(RpcFailure::Other, classify(-32602, "nonce too low"));
+	}
+}
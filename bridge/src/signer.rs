@@ -0,0 +1,440 @@
+use std::fmt::Debug;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+use futures::{future, Future};
+use futures::future::MapErr;
+use ethcore::account_provider::{AccountProvider, AccountProviderSettings};
+use ethcore::ethstore::{EthStore, accounts_dir::RootDiskDirectory};
+use ethcore::ethstore::ethkey::{self, KeyPair, Secret, Signature};
+use ethcore_transaction::{Action, Transaction};
+use serde_json;
+use tokio_timer::{Timeout, Timer};
+use web3::{self, Transport};
+use web3::helpers;
+use web3::transports::{EventLoopHandle, Http};
+use web3::types::{Address, Bytes, H256, H520, U256};
+use api;
+use config::{Config, Node, SignerConfig};
+use error::{Error, ErrorKind};
+use rpc::Value;
+use transaction::{decode_raw_transaction, sign_raw_transaction};
+
+/// Signature, or signed transaction, being made by a `Signer`.
+pub type SignFuture<T> = Box<Future<Item = T, Error = Error>>;
+
+/// Signs the transactions and messages of the bridge accounts.
+///
+/// Signing may take a request to a signer service, so signers return futures rather than block the event loop.
+pub trait Signer {
+	/// Signs `tx` of `account` for `chain_id` and returns the raw transaction, an EIP-1559 one
+	/// paying `priority_fee` to the miner if it's given, otherwise a legacy one.
+	fn sign_transaction(&self, account: Address, tx: Transaction, priority_fee: Option<U256>, chain_id: u64) -> SignFuture<Bytes>;
+
+	/// Signs `message` of `account` the way `eth_sign` does, i.e. signs `api::eth_data_hash(message)`.
+	/// The signature is `r`, `s` and `v`, which is 27 or 28.
+	fn sign_message(&self, account: Address, message: Vec<u8>) -> SignFuture<H520>;
+}
+
+/// Creates the signer of the bridge accounts, signing for each with the signer configured on its chain.
+pub fn create_signer(config: &Config, timer: &Timer) -> Result<Box<Signer>, Error> {
+	Ok(Box::new(AccountSigners {
+		signers: vec![
+			(config.home.account, create_node_signer(&config.home, timer)?),
+			(config.foreign.account, create_node_signer(&config.foreign, timer)?),
+		],
+	}))
+}
+
+fn create_node_signer(node: &Node, timer: &Timer) -> Result<Box<Signer>, Error> {
+	match node.signer {
+		SignerConfig::Keystore(ref path) => {
+			let signer = KeystoreSigner::open(path)?;
+			signer.unlock(node.account, node.password()?)?;
+			Ok(Box::new(signer))
+		},
+		SignerConfig::PrivateKey(ref path) => {
+			let signer = PrivateKeySigner::from_file(path)?;
+			if node.account != signer.address() {
+				return Err(ErrorKind::ConfigError(format!("private key in {:?} is not the key of {:?}", path, node.account)).into());
+			}
+			Ok(Box::new(signer))
+		},
+		SignerConfig::Remote { ref url, timeout } => Ok(Box::new(RemoteSigner::new(url, timer.clone(), timeout)?)),
+	}
+}
+
+/// Signs for every account with the signer of that account.
+pub struct AccountSigners {
+	signers: Vec<(Address, Box<Signer>)>,
+}
+
+impl AccountSigners {
+	fn signer(&self, account: Address) -> Result<&Signer, Error> {
+		self.signers.iter()
+			.find(|&&(signer_account, _)| signer_account == account)
+			.map(|&(_, ref signer)| &**signer)
+			.ok_or_else(|| ErrorKind::SignerError(format!("no signer of {:?}", account)).into())
+	}
+}
+
+impl Signer for AccountSigners {
+	fn sign_transaction(&self, account: Address, tx: Transaction, priority_fee: Option<U256>, chain_id: u64) -> SignFuture<Bytes> {
+		match self.signer(account) {
+			Ok(signer) => signer.sign_transaction(account, tx, priority_fee, chain_id),
+			Err(err) => Box::new(future::err(err)),
+		}
+	}
+
+	fn sign_message(&self, account: Address, message: Vec<u8>) -> SignFuture<H520> {
+		match self.signer(account) {
+			Ok(signer) => signer.sign_message(account, message),
+			Err(err) => Box::new(future::err(err)),
+		}
+	}
+}
+
+/// Signs with the JSON keys of a keystore directory.
+pub struct KeystoreSigner {
+	keystore: AccountProvider,
+}
+
+impl KeystoreSigner {
+	pub fn new(keystore: AccountProvider) -> Self {
+		KeystoreSigner {
+			keystore,
+		}
+	}
+
+	/// Opens the keystore directory at `path`.
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+		let keystore = EthStore::open(Box::new(RootDiskDirectory::at(path))).map_err(|e| ErrorKind::KeyStore(e))?;
+
+		let keystore = AccountProvider::new(Box::new(keystore), AccountProviderSettings {
+			enable_hardware_wallets: false,
+			hardware_wallet_classic_key: false,
+			unlock_keep_secret: true,
+			blacklisted_accounts: vec![],
+		});
+		Ok(KeystoreSigner::new(keystore))
+	}
+
+	/// Decrypts the key of `account` with `password` for as long as the bridge runs.
+	pub fn unlock(&self, account: Address, password: String) -> Result<(), Error> {
+		self.keystore.unlock_account_permanently(account, password).map_err(|e| ErrorKind::AccountError(e).into())
+	}
+
+	fn sign_hash(&self, account: Address, hash: H256) -> Result<Signature, Error> {
+		self.keystore.sign(account, None, hash).map_err(|e| ErrorKind::SignError(e).into())
+	}
+}
+
+impl Signer for KeystoreSigner {
+	fn sign_transaction(&self, account: Address, tx: Transaction, priority_fee: Option<U256>, chain_id: u64) -> SignFuture<Bytes> {
+		Box::new(future::result(sign_raw_transaction(tx, priority_fee, chain_id, |hash| self.sign_hash(account, hash))))
+	}
+
+	fn sign_message(&self, account: Address, message: Vec<u8>) -> SignFuture<H520> {
+		let sig = self.sign_hash(account, api::eth_data_hash(message))
+			.map(|sig| H520::from(sig.into_electrum()));
+		Box::new(future::result(sig))
+	}
+}
+
+/// Signs with a private key read from a file.
+pub struct PrivateKeySigner {
+	key: KeyPair,
+}
+
+impl PrivateKeySigner {
+	/// Reads the hex encoded private key in the file at `path`.
+	pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+		let mut contents = String::new();
+		fs::File::open(path)?.read_to_string(&mut contents)?;
+		let secret = contents.trim().trim_left_matches("0x").parse::<H256>()
+			.map_err(|_| ErrorKind::SignerError("private key is not a 32 bytes hex string".into()))?;
+		let key = Secret::from_unsafe_slice(&secret)
+			.and_then(KeyPair::from_secret)
+			.map_err(|e| ErrorKind::SignerError(format!("invalid private key: {}", e)))?;
+		Ok(PrivateKeySigner {
+			key,
+		})
+	}
+
+	/// Address of the account of the key.
+	pub fn address(&self) -> Address {
+		self.key.address()
+	}
+
+	fn sign_hash(&self, account: Address, hash: H256) -> Result<Signature, Error> {
+		if account != self.key.address() {
+			return Err(ErrorKind::SignerError(format!("no private key of {:?}", account)).into());
+		}
+		ethkey::sign(self.key.secret(), &hash).map_err(|e| ErrorKind::SignerError(e.to_string()).into())
+	}
+}
+
+impl Signer for PrivateKeySigner {
+	fn sign_transaction(&self, account: Address, tx: Transaction, priority_fee: Option<U256>, chain_id: u64) -> SignFuture<Bytes> {
+		Box::new(future::result(sign_raw_transaction(tx, priority_fee, chain_id, |hash| self.sign_hash(account, hash))))
+	}
+
+	fn sign_message(&self, account: Address, message: Vec<u8>) -> SignFuture<H520> {
+		let sig = self.sign_hash(account, api::eth_data_hash(message))
+			.map(|sig| H520::from(sig.into_electrum()));
+		Box::new(future::result(sig))
+	}
+}
+
+/// Transaction to sign with `eth_signTransaction`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TransactionRequest {
+	from: Address,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	to: Option<Address>,
+	gas: U256,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	gas_price: Option<U256>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	max_fee_per_gas: Option<U256>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	max_priority_fee_per_gas: Option<U256>,
+	#[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+	transaction_type: Option<U256>,
+	value: U256,
+	data: Bytes,
+	nonce: U256,
+	chain_id: U256,
+}
+
+impl TransactionRequest {
+	fn new(from: Address, tx: Transaction, priority_fee: Option<U256>, chain_id: u64) -> Self {
+		let to = match tx.action {
+			Action::Create => None,
+			Action::Call(to) => Some(to),
+		};
+		let (gas_price, max_fee_per_gas, transaction_type) = match priority_fee {
+			Some(_) => (None, Some(tx.gas_price), Some(2.into())),
+			None => (Some(tx.gas_price), None, None),
+		};
+
+		TransactionRequest {
+			from,
+			to,
+			gas: tx.gas,
+			gas_price,
+			max_fee_per_gas,
+			max_priority_fee_per_gas: priority_fee.map(|fee| fee.min(tx.gas_price)),
+			transaction_type,
+			value: tx.value,
+			data: Bytes(tx.data),
+			nonce: tx.nonce,
+			chain_id: chain_id.into(),
+		}
+	}
+}
+
+/// Signs with a signer service, so that the keys never reach the bridge host.
+///
+/// Requests are made on an event loop of their own. The bridge doesn't trust the service with more than
+/// the keys: signed transactions must be the requested ones and signatures must be made by the account.
+pub struct RemoteSigner {
+	transport: Http,
+	timer: Timer,
+	timeout: Duration,
+	_event_loop: EventLoopHandle,
+}
+
+impl RemoteSigner {
+	/// Connects to the signer at `url`, waiting at most `timeout` for every signature.
+	pub fn new(url: &str, timer: Timer, timeout: Duration) -> Result<Self, Error> {
+		let (event_loop, transport) = Http::new(url).map_err(ErrorKind::Web3)?;
+		Ok(RemoteSigner {
+			transport,
+			timer,
+			timeout,
+			_event_loop: event_loop,
+		})
+	}
+
+	fn request(&self, method: &str, params: Vec<Value>) -> Timeout<MapErr<<Http as Transport>::Out, fn(web3::Error) -> Error>> {
+		let request = self.transport.execute(method, params)
+			.map_err(signer_request_error as fn(web3::Error) -> Error);
+		self.timer.timeout(request, self.timeout)
+	}
+}
+
+fn signer_request_error(err: web3::Error) -> Error {
+	ErrorKind::Web3(err).into()
+}
+
+/// Fails unless the signer signed the `requested` value of `field`.
+fn check_signed<V: PartialEq + Debug>(field: &str, signed: V, requested: V) -> Result<(), Error> {
+	if signed != requested {
+		return Err(ErrorKind::SignerError(format!("signer returned a transaction with {} {:?}, requested {:?}", field, signed, requested)).into());
+	}
+	Ok(())
+}
+
+/// Fails unless `raw` is `tx` of `account`, signed for `chain_id` with `priority_fee`.
+fn check_signed_transaction(raw: &Bytes, account: Address, tx: &Transaction, priority_fee: Option<U256>, chain_id: u64) -> Result<(), Error> {
+	let signed = decode_raw_transaction(&raw.0)?;
+	check_signed("sender", signed.sender, account)?;
+	check_signed("chain id", signed.chain_id, Some(chain_id))?;
+	check_signed("nonce", signed.transaction.nonce, tx.nonce)?;
+	check_signed("action", &signed.transaction.action, &tx.action)?;
+	check_signed("data", &signed.transaction.data, &tx.data)?;
+	check_signed("value", signed.transaction.value, tx.value)?;
+	check_signed("gas", signed.transaction.gas, tx.gas)?;
+	check_signed("gas price", signed.transaction.gas_price, tx.gas_price)?;
+	check_signed("priority fee", signed.priority_fee, priority_fee.map(|fee| fee.min(tx.gas_price)))
+}
+
+/// Normalizes `v` of `sig` to 27 or 28 and fails unless `sig` is the signature of `hash` by `account`.
+fn check_signature(mut sig: H520, account: Address, hash: H256) -> Result<H520, Error> {
+	// some signers return `v` as the recovery id
+	if sig[64] < 27 {
+		sig[64] += 27;
+	}
+	let signature = Signature::from_electrum(&sig.0);
+	let signer = ethkey::recover(&signature, &hash)
+		.map_err(|e| ErrorKind::SignerError(format!("invalid signature: {}", e)))?;
+	if ethkey::public_to_address(&signer) != account {
+		return Err(ErrorKind::SignerError(format!("signer returned a signature of {:?}, requested one of {:?}", ethkey::public_to_address(&signer), account)).into());
+	}
+	Ok(sig)
+}
+
+impl Signer for RemoteSigner {
+	fn sign_transaction(&self, account: Address, tx: Transaction, priority_fee: Option<U256>, chain_id: u64) -> SignFuture<Bytes> {
+		let request = TransactionRequest::new(account, tx.clone(), priority_fee, chain_id);
+		let future = self.request("eth_signTransaction", vec![helpers::serialize(&request)])
+			.and_then(move |result| -> Result<Bytes, Error> {
+				// signers either return the raw transaction, or an object with the raw and the decoded transaction
+				let raw = match result {
+					Value::Object(mut object) => object.remove("raw")
+						.ok_or_else(|| ErrorKind::SignerError("eth_signTransaction returned no raw transaction".into()))?,
+					raw => raw,
+				};
+				let raw: Bytes = serde_json::from_value(raw)?;
+				check_signed_transaction(&raw, account, &tx, priority_fee, chain_id)?;
+				Ok(raw)
+			});
+		Box::new(future)
+	}
+
+	fn sign_message(&self, account: Address, message: Vec<u8>) -> SignFuture<H520> {
+		let hash = api::eth_data_hash(message.clone());
+		let future = self.request("eth_sign", vec![helpers::serialize(&account), helpers::serialize(&Bytes(message))])
+			.and_then(move |result| -> Result<H520, Error> {
+				let sig: H520 = serde_json::from_value(result)?;
+				check_signature(sig, account, hash)
+			});
+		Box::new(future)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{Read, Write};
+	use std::net::TcpListener;
+	use std::thread;
+	use std::time::Duration;
+	use ethcore::ethstore::ethkey::{self, KeyPair, Secret};
+	use ethcore_transaction::{Action, Transaction};
+	use futures::Future;
+	use rustc_hex::ToHex;
+	use serde_json;
+	use tokio_timer::Timer;
+	use web3::types::H520;
+	use api;
+	use rpc::Value;
+	use transaction::sign_raw_transaction;
+	use super::{RemoteSigner, Signer};
+
+	/// Starts a signer answering a single request with `result`. Returns its url and the request it receives.
+	fn stub_signer(result: String) -> (String, thread::JoinHandle<Value>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		let handle = thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut request = Vec::new();
+			let mut buffer = [0u8; 4096];
+			let body = loop {
+				let read = stream.read(&mut buffer).unwrap();
+				request.extend_from_slice(&buffer[..read]);
+				let text = String::from_utf8_lossy(&request).into_owned();
+				let headers_end = match text.find("\r\n\r\n") {
+					Some(end) => end + 4,
+					None => continue,
+				};
+				let length = text[..headers_end].lines()
+					.map(str::to_lowercase)
+					.filter(|line| line.starts_with("content-length:"))
+					.filter_map(|line| line["content-length:".len()..].trim().parse::<usize>().ok())
+					.next()
+					.unwrap_or(0);
+				if text.len() >= headers_end + length {
+					break text[headers_end..headers_end + length].to_owned();
+				}
+			};
+
+			let request: Value = serde_json::from_str(&body).unwrap();
+			let response = format!(r#"{{"jsonrpc":"2.0","id":{},"result":{}}}"#, request["id"], result);
+			write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", response.len(), response).unwrap();
+			request
+		});
+		(url, handle)
+	}
+
+	#[test]
+	fn test_remote_signer() {
+		let key = KeyPair::from_secret(Secret::from_unsafe_slice(&[1u8; 32]).unwrap()).unwrap();
+		let account = key.address();
+		let mut expected = H520::from(ethkey::sign(key.secret(), &api::eth_data_hash(vec![1, 2, 3])).unwrap().into_electrum());
+		// returned with `v` as the recovery id
+		let mut sig = expected;
+		sig[64] -= 27;
+		let (url, request) = stub_signer(format!("\"0x{}\"", sig.0.to_hex()));
+		let signer = RemoteSigner::new(&url, Timer::default(), Duration::from_secs(5)).unwrap();
+		assert_eq!(expected, signer.sign_message(account, vec![1, 2, 3]).wait().unwrap());
+		let request = request.join().unwrap();
+		assert_eq!(request["method"], "eth_sign");
+		assert_eq!(request["params"][1], "0x010203");
+
+		// a signature which is not made by the account is refused
+		expected[64] = 27 + 28 - expected[64];
+		let (url, _) = stub_signer(format!("\"0x{}\"", expected.0.to_hex()));
+		let signer = RemoteSigner::new(&url, Timer::default(), Duration::from_secs(5)).unwrap();
+		assert!(signer.sign_message(account, vec![1, 2, 3]).wait().is_err());
+
+		let tx = Transaction {
+			nonce: 1.into(),
+			gas_price: 100.into(),
+			gas: 21000.into(),
+			action: Action::Call(account),
+			value: 0.into(),
+			data: vec![],
+		};
+		let sign = |tx: Transaction| sign_raw_transaction(tx, Some(2.into()), 1, |hash| Ok(ethkey::sign(key.secret(), &hash).unwrap())).unwrap();
+		let raw = sign(tx.clone());
+		let (url, request) = stub_signer(format!(r#"{{"raw":"0x{}","tx":{{}}}}"#, raw.0.to_hex()));
+		let signer = RemoteSigner::new(&url, Timer::default(), Duration::from_secs(5)).unwrap();
+		assert_eq!(raw, signer.sign_transaction(account, tx.clone(), Some(2.into()), 1).wait().unwrap());
+		let request = request.join().unwrap();
+		assert_eq!(request["method"], "eth_signTransaction");
+		assert_eq!(request["params"][0]["maxFeePerGas"], "0x64");
+		assert_eq!(request["params"][0]["maxPriorityFeePerGas"], "0x2");
+		assert_eq!(request["params"][0]["chainId"], "0x1");
+
+		// a transaction other than the requested one is refused
+		let mut other = tx.clone();
+		other.nonce = 2.into();
+		let (url, _) = stub_signer(format!("\"0x{}\"", sign(other).0.to_hex()));
+		let signer = RemoteSigner::new(&url, Timer::default(), Duration::from_secs(5)).unwrap();
+		let err = signer.sign_transaction(account, tx, Some(2.into()), 1).wait().unwrap_err();
+		assert!(err.to_string().contains("nonce"), "unexpected error {}", err);
+	}
+}
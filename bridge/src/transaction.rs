@@ -1,19 +1,196 @@
+use std::cmp;
 use error::{Error, ErrorKind};
-use ethcore_transaction::{Transaction, SignedTransaction};
-use web3::types::Bytes;
-use config::Node;
+use ethcore::ethstore::ethkey::{self, Signature};
+use ethcore_transaction::{Action, Transaction, SignedTransaction, UnverifiedTransaction};
+use keccak_hash::keccak;
+use rlp::{RlpStream, Encodable, UntrustedRlp, DecoderError};
+use web3::types::{Address, Bytes, H256, U256};
+use config::{Node, TransactionType};
 use app::App;
+use signer::SignFuture;
 use web3::Transport;
 
-pub fn prepare_raw_transaction<T: Transport>(tx: Transaction, app: &App<T>, node: &Node, chain_id: u64) -> Result<Bytes, Error> {
-	let hash = tx.hash(Some(chain_id));
+/// Type byte of EIP-1559 transactions.
+const DYNAMIC_FEE_TRANSACTION_TYPE: u8 = 2;
 
-	let sig = app.keystore.sign(node.account, None, hash).map_err(|e| ErrorKind::SignError(e))?;
+/// Signs `tx` the way the chain of `node` expects, with the current priority fee if it uses EIP-1559.
+pub fn prepare_raw_transaction<T: Transport>(tx: Transaction, app: &App<T>, node: &Node, chain_id: u64) -> SignFuture<Bytes> {
+	sign_transaction(tx, priority_fee(node), app, node, chain_id)
+}
+
+/// Priority fee paid by new transactions, `None` if the chain of `node` uses legacy transactions.
+pub fn priority_fee(node: &Node) -> Option<U256> {
+	match node.transaction_type {
+		TransactionType::Legacy => None,
+		TransactionType::Eip1559 => Some((*node.info.priority_fee.read().unwrap()).into()),
+	}
+}
+
+/// Signs `tx` with the signer of the account of `node`, see `Signer::sign_transaction`.
+pub fn sign_transaction<T: Transport>(tx: Transaction, priority_fee: Option<U256>, app: &App<T>, node: &Node, chain_id: u64) -> SignFuture<Bytes> {
+	app.signer.sign_transaction(node.account, tx, priority_fee, chain_id)
+}
+
+/// Encodes `tx` signed with the signature `sign` returns for its hash, as a legacy transaction if there is
+/// no `priority_fee`, otherwise as an EIP-1559 transaction paying at most `tx.gas_price` per gas,
+/// `priority_fee` of which goes to the miner.
+pub fn sign_raw_transaction<F>(tx: Transaction, priority_fee: Option<U256>, chain_id: u64, sign: F) -> Result<Bytes, Error> where F: FnOnce(H256) -> Result<Signature, Error> {
+	let priority_fee = match priority_fee {
+		Some(priority_fee) => cmp::min(priority_fee, tx.gas_price),
+		None => return sign_legacy_transaction(tx, chain_id, sign),
+	};
+
+	let mut stream = RlpStream::new_list(9);
+	append_dynamic_fee_fields(&mut stream, &tx, priority_fee, chain_id);
+	let sig = sign(keccak(typed_envelope(DYNAMIC_FEE_TRANSACTION_TYPE, stream.out())))?;
+
+	let mut stream = RlpStream::new_list(12);
+	append_dynamic_fee_fields(&mut stream, &tx, priority_fee, chain_id);
+	stream.append(&sig.v());
+	stream.append(&U256::from(sig.r()));
+	stream.append(&U256::from(sig.s()));
+
+	Ok(Bytes(typed_envelope(DYNAMIC_FEE_TRANSACTION_TYPE, stream.out())))
+}
+
+fn sign_legacy_transaction<F>(tx: Transaction, chain_id: u64, sign: F) -> Result<Bytes, Error> where F: FnOnce(H256) -> Result<Signature, Error> {
+	let sig = sign(tx.hash(Some(chain_id)))?;
 	let tx = SignedTransaction::new(tx.with_signature(sig, Some(chain_id))).unwrap();
 
-	use rlp::{RlpStream, Encodable};
 	let mut stream = RlpStream::new();
 	tx.rlp_append(&mut stream);
 
 	Ok(Bytes(stream.out()))
 }
+
+/// Appends the fields of an EIP-1559 transaction which are signed, with an empty access list.
+fn append_dynamic_fee_fields(stream: &mut RlpStream, tx: &Transaction, priority_fee: U256, chain_id: u64) {
+	stream.append(&chain_id);
+	stream.append(&tx.nonce);
+	stream.append(&priority_fee);
+	stream.append(&tx.gas_price);
+	stream.append(&tx.gas);
+	stream.append(&tx.action);
+	stream.append(&tx.value);
+	stream.append(&tx.data);
+	stream.begin_list(0);
+}
+
+fn typed_envelope(transaction_type: u8, payload: Vec<u8>) -> Vec<u8> {
+	let mut envelope = Vec::with_capacity(payload.len() + 1);
+	envelope.push(transaction_type);
+	envelope.extend(payload);
+	envelope
+}
+
+/// Raw transaction decoded, with the account which signed it.
+#[derive(Debug, PartialEq)]
+pub struct DecodedTransaction {
+	pub transaction: Transaction,
+	/// Priority fee of an EIP-1559 transaction, `None` for a legacy one.
+	pub priority_fee: Option<U256>,
+	/// Chain the transaction is signed for, `None` for a legacy transaction without replay protection.
+	pub chain_id: Option<u64>,
+	pub sender: Address,
+}
+
+/// Decodes a legacy or an EIP-1559 raw transaction and recovers its sender.
+pub fn decode_raw_transaction(raw: &[u8]) -> Result<DecodedTransaction, Error> {
+	let invalid = |err: DecoderError| Error::from(ErrorKind::SignerError(format!("invalid raw transaction: {:?}", err)));
+	let recovery = |err: ethkey::Error| Error::from(ErrorKind::SignerError(format!("invalid transaction signature: {}", err)));
+
+	if raw.first() != Some(&DYNAMIC_FEE_TRANSACTION_TYPE) {
+		let tx: UnverifiedTransaction = UntrustedRlp::new(raw).as_val().map_err(invalid)?;
+		let chain_id = tx.chain_id();
+		let tx = SignedTransaction::new(tx).map_err(recovery)?;
+		return Ok(DecodedTransaction {
+			transaction: tx.as_unsigned().clone(),
+			priority_fee: None,
+			chain_id,
+			sender: tx.sender(),
+		});
+	}
+
+	let rlp = UntrustedRlp::new(&raw[1..]);
+	let chain_id: u64 = rlp.val_at(0).map_err(invalid)?;
+	let priority_fee: U256 = rlp.val_at(2).map_err(invalid)?;
+	let transaction = Transaction {
+		nonce: rlp.val_at(1).map_err(invalid)?,
+		gas_price: rlp.val_at(3).map_err(invalid)?,
+		gas: rlp.val_at(4).map_err(invalid)?,
+		action: rlp.val_at::<Action>(5).map_err(invalid)?,
+		value: rlp.val_at(6).map_err(invalid)?,
+		data: rlp.val_at(7).map_err(invalid)?,
+	};
+	let v: u8 = rlp.val_at(9).map_err(invalid)?;
+	let r: U256 = rlp.val_at(10).map_err(invalid)?;
+	let s: U256 = rlp.val_at(11).map_err(invalid)?;
+
+	let mut stream = RlpStream::new_list(9);
+	append_dynamic_fee_fields(&mut stream, &transaction, priority_fee, chain_id);
+	let hash = keccak(typed_envelope(DYNAMIC_FEE_TRANSACTION_TYPE, stream.out()));
+	let public = ethkey::recover(&Signature::from_rsv(&r.into(), &s.into(), v), &hash).map_err(recovery)?;
+
+	Ok(DecodedTransaction {
+		transaction,
+		priority_fee: Some(priority_fee),
+		chain_id: Some(chain_id),
+		sender: ethkey::public_to_address(&public),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use ethcore::ethstore::ethkey::{self, KeyPair, Secret};
+	use ethcore_transaction::{Action, Transaction};
+	use rlp::RlpStream;
+	use rustc_hex::FromHex;
+	use super::{append_dynamic_fee_fields, typed_envelope, sign_raw_transaction, decode_raw_transaction, DecodedTransaction, DYNAMIC_FEE_TRANSACTION_TYPE};
+
+	#[test]
+	fn test_dynamic_fee_transaction_payload() {
+		let tx = Transaction {
+			nonce: 1.into(),
+			gas_price: 100.into(),
+			gas: 21000.into(),
+			action: Action::Call("aff3454fce5edbc8cca8697c15331677e6ebcccc".into()),
+			value: 0.into(),
+			data: vec![],
+		};
+
+		let mut stream = RlpStream::new_list(9);
+		append_dynamic_fee_fields(&mut stream, &tx, 2.into(), 1);
+		let payload = typed_envelope(DYNAMIC_FEE_TRANSACTION_TYPE, stream.out());
+		let expected = "02df0101026482520894aff3454fce5edbc8cca8697c15331677e6ebcccc8080c0".from_hex().unwrap();
+		assert_eq!(expected, payload);
+	}
+
+	#[test]
+	fn test_decode_raw_transaction() {
+		let key = KeyPair::from_secret(Secret::from_unsafe_slice(&[1u8; 32]).unwrap()).unwrap();
+		let tx = Transaction {
+			nonce: 7.into(),
+			gas_price: 100.into(),
+			gas: 21000.into(),
+			action: Action::Call("aff3454fce5edbc8cca8697c15331677e6ebcccc".into()),
+			value: 5.into(),
+			data: vec![1, 2, 3],
+		};
+		let sign = |priority_fee| sign_raw_transaction(tx.clone(), priority_fee, 77, |hash| Ok(ethkey::sign(key.secret(), &hash).unwrap())).unwrap();
+
+		assert_eq!(DecodedTransaction {
+			transaction: tx.clone(),
+			priority_fee: None,
+			chain_id: Some(77),
+			sender: key.address(),
+		}, decode_raw_transaction(&sign(None).0).unwrap());
+		// the priority fee is capped at the gas price
+		assert_eq!(DecodedTransaction {
+			transaction: tx.clone(),
+			priority_fee: Some(100.into()),
+			chain_id: Some(77),
+			sender: key.address(),
+		}, decode_raw_transaction(&sign(Some(200.into())).0).unwrap());
+		assert!(decode_raw_transaction(&[2, 0xc0]).is_err());
+	}
+}
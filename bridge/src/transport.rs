@@ -0,0 +1,466 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use futures::{future, stream, Future};
+use futures::future::Either;
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
+use web3::{self, Transport};
+use web3::transports::http::Http;
+use web3::transports::ipc::Ipc;
+use web3::transports::ws::WebSocket;
+use api::{NewHeads, NewHeadsStream, SubscribeNewHeads};
+use config::Node;
+use error::{Error, ErrorKind};
+use rpc;
+
+/// Connection to a node over any of the transports supported by the bridge.
+#[derive(Debug, Clone)]
+pub enum Connection {
+	Http(Http),
+	Ws(WebSocket),
+	Ipc(Ipc),
+	Failover(Failover<Connection>),
+}
+
+impl Connection {
+	/// Connects to `url`. `ws://` and `wss://` urls use WebSocket transport,
+	/// all other urls use HTTP.
+	pub fn new(url: &str, handle: &Handle, concurrent_http_requests: usize) -> Result<Self, Error> {
+		let connection = if url.starts_with("ws://") || url.starts_with("wss://") {
+			Connection::Ws(WebSocket::with_event_loop(url, handle).map_err(ErrorKind::Web3)?)
+		} else {
+			Connection::Http(Http::with_event_loop(url, handle, concurrent_http_requests).map_err(ErrorKind::Web3)?)
+		};
+		Ok(connection)
+	}
+
+	/// Connects to the IPC socket at `path`.
+	pub fn ipc<P: AsRef<Path>>(path: P, handle: &Handle) -> Result<Self, Error> {
+		let ipc = Ipc::with_event_loop(path, handle).map_err(ErrorKind::Web3)?;
+		Ok(Connection::Ipc(ipc))
+	}
+
+	/// Connects to `node` over IPC if `ipc_path` is set, otherwise using its rpc url
+	/// and falling back to `fallback_rpc_urls` if any are given.
+	pub fn for_node(node: &Node, handle: &Handle, timer: &Timer) -> Result<Self, Error> {
+		if let Some(ref path) = node.ipc_path {
+			return Connection::ipc(path, handle);
+		}
+
+		let url = node.rpc_url().ok_or_else(|| ErrorKind::ConfigError("either rpc_host or ipc_path is required".into()))?;
+		let primary = Connection::new(&url, handle, node.concurrent_http_requests)?;
+		if node.fallback_rpc_urls.is_empty() {
+			return Ok(primary);
+		}
+
+		let mut endpoints = vec![primary];
+		for url in &node.fallback_rpc_urls {
+			endpoints.push(Connection::new(url, handle, node.concurrent_http_requests)?);
+		}
+		let failover = Failover::new(endpoints, node.rpc_max_failures, node.rpc_primary_retry_interval, timer.clone(), node.request_timeout);
+		Ok(Connection::Failover(failover))
+	}
+
+	/// Returns new heads subscriptions source if the transport supports them.
+	pub fn new_heads(&self) -> Option<Arc<NewHeads>> {
+		match *self {
+			Connection::Http(_) => None,
+			Connection::Ws(ref ws) => Some(Arc::new(SubscribeNewHeads(ws.clone()))),
+			Connection::Ipc(ref ipc) => Some(Arc::new(SubscribeNewHeads(ipc.clone()))),
+			Connection::Failover(ref failover) => if failover.endpoints().iter().all(|e| e.new_heads().is_some()) {
+				Some(Arc::new(failover.clone()))
+			} else {
+				None
+			},
+		}
+	}
+}
+
+impl Transport for Connection {
+	type Out = Box<Future<Item = rpc::Value, Error = web3::Error>>;
+
+	fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (usize, rpc::Call) {
+		match *self {
+			Connection::Http(ref http) => http.prepare(method, params),
+			Connection::Ws(ref ws) => ws.prepare(method, params),
+			Connection::Ipc(ref ipc) => ipc.prepare(method, params),
+			Connection::Failover(ref failover) => failover.prepare(method, params),
+		}
+	}
+
+	fn send(&self, id: usize, request: rpc::Call) -> Self::Out {
+		match *self {
+			Connection::Http(ref http) => Box::new(http.send(id, request)),
+			Connection::Ws(ref ws) => Box::new(ws.send(id, request)),
+			Connection::Ipc(ref ipc) => Box::new(ipc.send(id, request)),
+			Connection::Failover(ref failover) => failover.send(id, request),
+		}
+	}
+}
+
+/// Transport sending requests to an ordered list of endpoints.
+///
+/// Requests go to the active endpoint and are retried on the other ones if it can't
+/// be reached or doesn't respond within `request_timeout`. After `max_failures`
+/// consecutive failures the next endpoint becomes active. While a fallback endpoint
+/// is active, every `primary_retry_interval` the primary endpoint is health checked
+/// with `eth_blockNumber`, and if it responds the primary becomes active again.
+#[derive(Clone)]
+pub struct Failover<T> {
+	endpoints: Arc<Vec<T>>,
+	state: Arc<Mutex<FailoverState>>,
+	id: Arc<AtomicUsize>,
+	max_failures: u32,
+	primary_retry_interval: Duration,
+	timer: Timer,
+	request_timeout: Duration,
+}
+
+impl<T: fmt::Debug> fmt::Debug for Failover<T> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Failover")
+			.field("endpoints", &self.endpoints)
+			.field("state", &self.state)
+			.field("max_failures", &self.max_failures)
+			.field("primary_retry_interval", &self.primary_retry_interval)
+			.field("request_timeout", &self.request_timeout)
+			.finish()
+	}
+}
+
+#[derive(Debug)]
+struct FailoverState {
+	active: usize,
+	failures: u32,
+	primary_retried_at: Instant,
+}
+
+/// Returns true if `err` means that the endpoint couldn't serve the request,
+/// rather than that the node responded with an error.
+fn is_endpoint_failure(err: &web3::Error) -> bool {
+	match *err.kind() {
+		web3::error::ErrorKind::Rpc(_) => false,
+		_ => true,
+	}
+}
+
+impl<T: Transport + 'static> Failover<T> {
+	pub fn new(endpoints: Vec<T>, max_failures: u32, primary_retry_interval: Duration, timer: Timer, request_timeout: Duration) -> Self {
+		assert!(!endpoints.is_empty(), "at least one endpoint is required");
+		Failover {
+			endpoints: Arc::new(endpoints),
+			state: Arc::new(Mutex::new(FailoverState {
+				active: 0,
+				failures: 0,
+				primary_retried_at: Instant::now(),
+			})),
+			id: Arc::new(AtomicUsize::new(1)),
+			max_failures,
+			primary_retry_interval,
+			timer,
+			request_timeout,
+		}
+	}
+
+	pub fn endpoints(&self) -> &[T] {
+		&self.endpoints
+	}
+
+	/// Index of the endpoint currently in use.
+	pub fn active(&self) -> usize {
+		self.state.lock().expect("failover state lock poisoned").active
+	}
+
+	/// Returns true if a fallback endpoint is active and the primary endpoint
+	/// hasn't been checked for `primary_retry_interval`.
+	fn should_check_primary(&self) -> bool {
+		let mut state = self.state.lock().expect("failover state lock poisoned");
+		if state.active != 0 && state.primary_retried_at.elapsed() >= self.primary_retry_interval {
+			state.primary_retried_at = Instant::now();
+			true
+		} else {
+			false
+		}
+	}
+
+	/// Requests the best block number from the primary endpoint and switches back to it if it responds.
+	fn check_primary(&self) -> Box<Future<Item = (), Error = web3::Error>> {
+		let (id, request) = self.endpoints[0].prepare("eth_blockNumber", vec![]);
+		let failover = self.clone();
+		let future = self.with_timeout(self.endpoints[0].send(id, request)).then(move |result| -> Result<(), web3::Error> {
+			match result {
+				Ok(_) => failover.on_success(0),
+				Err(err) => debug!("primary rpc endpoint is still unavailable: {}", err),
+			}
+			Ok(())
+		});
+		Box::new(future)
+	}
+
+	/// Fails `request` if it doesn't complete within `request_timeout`,
+	/// so that an endpoint which doesn't respond counts as failed.
+	fn with_timeout<F>(&self, request: F) -> Box<Future<Item = rpc::Value, Error = web3::Error>> where
+		F: Future<Item = rpc::Value, Error = web3::Error> + 'static,
+	{
+		let timeout = self.request_timeout;
+		let future = request.select2(self.timer.sleep(timeout)).then(move |result| match result {
+			Ok(Either::A((value, _))) => Ok(value),
+			Err(Either::A((err, _))) => Err(err),
+			Ok(Either::B(_)) => Err(web3::error::ErrorKind::Transport(format!("request timed out after {}s", timeout.as_secs())).into()),
+			Err(Either::B((err, _))) => Err(web3::error::ErrorKind::Transport(format!("request timer failed: {}", err)).into()),
+		});
+		Box::new(future)
+	}
+
+	/// Picks the endpoint to retry a request that failed on endpoint `index`.
+	fn next(&self, index: usize) -> usize {
+		let active = self.active();
+		if index == active {
+			(index + 1) % self.endpoints.len()
+		} else {
+			active
+		}
+	}
+
+	fn on_success(&self, index: usize) {
+		let mut state = self.state.lock().expect("failover state lock poisoned");
+		if index == 0 && state.active != 0 {
+			info!("primary rpc endpoint is available again, switching back to it");
+			state.active = 0;
+		}
+		if index == state.active {
+			state.failures = 0;
+		}
+	}
+
+	fn on_failure(&self, index: usize) {
+		let mut state = self.state.lock().expect("failover state lock poisoned");
+		if index != state.active {
+			return;
+		}
+		state.failures += 1;
+		if state.failures >= self.max_failures {
+			let next = (state.active + 1) % self.endpoints.len();
+			warn!("rpc endpoint #{} failed {} times in a row, switching to endpoint #{}", state.active, state.failures, next);
+			state.active = next;
+			state.failures = 0;
+			state.primary_retried_at = Instant::now();
+		}
+	}
+
+	/// Sends request to endpoint `index`, retrying on other endpoints up to `attempts` times in total.
+	fn send_to(&self, index: usize, method: String, params: Vec<rpc::Value>, attempts: usize) -> Box<Future<Item = rpc::Value, Error = web3::Error>> {
+		let (id, request) = self.endpoints[index].prepare(&method, params.clone());
+		let failover = self.clone();
+		let future = self.with_timeout(self.endpoints[index].send(id, request)).then(move |result| -> Box<Future<Item = rpc::Value, Error = web3::Error>> {
+			let failed = match result {
+				Err(ref err) => is_endpoint_failure(err),
+				Ok(_) => false,
+			};
+
+			if !failed {
+				failover.on_success(index);
+				return Box::new(future::result(result));
+			}
+
+			failover.on_failure(index);
+			if attempts > 1 {
+				let next = failover.next(index);
+				warn!("{} request to rpc endpoint #{} failed, retrying on endpoint #{}", method, index, next);
+				failover.send_to(next, method, params, attempts - 1)
+			} else {
+				Box::new(future::result(result))
+			}
+		});
+		Box::new(future)
+	}
+}
+
+impl<T: Transport + 'static> Transport for Failover<T> {
+	type Out = Box<Future<Item = rpc::Value, Error = web3::Error>>;
+
+	fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (usize, rpc::Call) {
+		let id = self.id.fetch_add(1, Ordering::AcqRel);
+		(id, web3::helpers::build_request(id, method, params))
+	}
+
+	fn send(&self, _id: usize, request: rpc::Call) -> Self::Out {
+		// request ids are assigned by the endpoint the request is eventually sent to
+		let (method, params) = match request {
+			rpc::Call::MethodCall(call) => match call.params {
+				Some(rpc::Params::Array(params)) => (call.method, params),
+				_ => (call.method, vec![]),
+			},
+			_ => return Box::new(future::err(web3::error::ErrorKind::Transport("unsupported rpc call".into()).into())),
+		};
+
+		let request = self.send_to(self.active(), method, params, self.endpoints.len());
+		if self.should_check_primary() {
+			Box::new(self.check_primary().join(request).map(|(_, value)| value))
+		} else {
+			request
+		}
+	}
+}
+
+impl NewHeads for Failover<Connection> {
+	fn subscribe(&self) -> NewHeadsStream {
+		match self.endpoints[self.active()].new_heads() {
+			Some(heads) => heads.subscribe(),
+			None => Box::new(stream::empty()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+	use std::time::Duration;
+	use futures::{future, Future};
+	use tokio_timer::Timer;
+	use web3::{self, Transport};
+	use rpc;
+	use super::Failover;
+
+	/// Endpoint answering with the block number, failing or never answering, as configured.
+	#[derive(Debug, Clone)]
+	struct Endpoint {
+		up: Rc<RefCell<bool>>,
+		hung: Rc<RefCell<bool>>,
+		requests: Rc<RefCell<usize>>,
+		number: u64,
+	}
+
+	impl Endpoint {
+		fn new(number: u64) -> Self {
+			Endpoint {
+				up: Rc::new(RefCell::new(true)),
+				hung: Rc::new(RefCell::new(false)),
+				requests: Default::default(),
+				number,
+			}
+		}
+
+		fn set_up(&self, up: bool) {
+			*self.up.borrow_mut() = up;
+		}
+
+		fn set_hung(&self, hung: bool) {
+			*self.hung.borrow_mut() = hung;
+		}
+
+		fn requests(&self) -> usize {
+			*self.requests.borrow()
+		}
+	}
+
+	impl Transport for Endpoint {
+		type Out = Box<Future<Item = rpc::Value, Error = web3::Error>>;
+
+		fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (usize, rpc::Call) {
+			(0, web3::helpers::build_request(0, method, params))
+		}
+
+		fn send(&self, _id: usize, _request: rpc::Call) -> Self::Out {
+			*self.requests.borrow_mut() += 1;
+			if *self.hung.borrow() {
+				Box::new(future::empty())
+			} else if *self.up.borrow() {
+				Box::new(future::ok(self.number.into()))
+			} else {
+				Box::new(future::err(web3::error::ErrorKind::Transport("connection refused".into()).into()))
+			}
+		}
+	}
+
+	fn block_number<T: Transport>(transport: &T) -> Result<rpc::Value, web3::Error> {
+		transport.execute("eth_blockNumber", vec![]).wait()
+	}
+
+	fn failover(endpoints: Vec<Endpoint>, max_failures: u32, primary_retry_interval: Duration) -> Failover<Endpoint> {
+		Failover::new(endpoints, max_failures, primary_retry_interval, Timer::default(), Duration::from_millis(500))
+	}
+
+	#[test]
+	fn retries_request_on_next_endpoint() {
+		let primary = Endpoint::new(1);
+		let fallback = Endpoint::new(2);
+		let failover = failover(vec![primary.clone(), fallback.clone()], 3, Duration::from_secs(60));
+
+		primary.set_up(false);
+		assert_eq!(block_number(&failover).unwrap(), rpc::Value::from(2));
+		assert_eq!(failover.active(), 0);
+		assert_eq!(primary.requests(), 1);
+		assert_eq!(fallback.requests(), 1);
+	}
+
+	#[test]
+	fn switches_endpoint_after_repeated_failures() {
+		let primary = Endpoint::new(1);
+		let fallback = Endpoint::new(2);
+		let failover = failover(vec![primary.clone(), fallback.clone()], 2, Duration::from_secs(60));
+
+		primary.set_up(false);
+		block_number(&failover).unwrap();
+		block_number(&failover).unwrap();
+		assert_eq!(failover.active(), 1);
+
+		// primary is not checked before the interval passes
+		assert_eq!(block_number(&failover).unwrap(), rpc::Value::from(2));
+		assert_eq!(primary.requests(), 2);
+		assert_eq!(fallback.requests(), 3);
+	}
+
+	#[test]
+	fn switches_back_to_primary() {
+		let primary = Endpoint::new(1);
+		let fallback = Endpoint::new(2);
+		let failover = failover(vec![primary.clone(), fallback.clone()], 1, Duration::from_secs(0));
+
+		primary.set_up(false);
+		block_number(&failover).unwrap();
+		assert_eq!(failover.active(), 1);
+
+		// primary is checked, but it is still down
+		assert_eq!(block_number(&failover).unwrap(), rpc::Value::from(2));
+		assert_eq!(failover.active(), 1);
+
+		// the health check succeeds, the request is still served by the fallback
+		primary.set_up(true);
+		assert_eq!(block_number(&failover).unwrap(), rpc::Value::from(2));
+		assert_eq!(failover.active(), 0);
+		assert_eq!(block_number(&failover).unwrap(), rpc::Value::from(1));
+		assert_eq!(primary.requests(), 4);
+		assert_eq!(fallback.requests(), 3);
+	}
+
+	#[test]
+	fn counts_timeouts_as_failures() {
+		let primary = Endpoint::new(1);
+		let fallback = Endpoint::new(2);
+		let failover = failover(vec![primary.clone(), fallback.clone()], 1, Duration::from_secs(60));
+
+		primary.set_hung(true);
+		assert_eq!(block_number(&failover).unwrap(), rpc::Value::from(2));
+		assert_eq!(failover.active(), 1);
+		assert_eq!(block_number(&failover).unwrap(), rpc::Value::from(2));
+		assert_eq!(primary.requests(), 1);
+	}
+
+	#[test]
+	fn fails_when_all_endpoints_are_down() {
+		let primary = Endpoint::new(1);
+		let fallback = Endpoint::new(2);
+		let failover = failover(vec![primary.clone(), fallback.clone()], 3, Duration::from_secs(60));
+
+		primary.set_up(false);
+		fallback.set_up(false);
+		assert!(block_number(&failover).is_err());
+		assert_eq!(primary.requests(), 1);
+		assert_eq!(fallback.requests(), 1);
+	}
+}
@@ -1,3 +1,4 @@
+use std::path::{Path, PathBuf};
 use web3::types::{H256, Address, FilterBuilder};
 use ethabi;
 
@@ -20,3 +21,10 @@ pub fn web3_filter<I: IntoIterator<Item = Address>>(filter: ethabi::TopicFilter,
 		.address(addresses.into_iter().collect())
 		.topics(t0, t1, t2, t3)
 }
+
+/// Returns `path` with `suffix` appended to the file name, e.g. `db.toml` -> `db.toml.bak`.
+pub fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+	let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+	name.push(suffix);
+	path.with_file_name(name)
+}
@@ -13,7 +13,7 @@ extern crate jsonrpc_core as rpc;
 #[macro_use]
 extern crate version;
 
-use std::{env, fs, io};
+use std::{env, io};
 use std::sync::Arc;
 use std::path::PathBuf;
 use docopt::Docopt;
@@ -21,10 +21,13 @@ use futures::{Stream, future};
 use tokio_core::reactor::Core;
 
 use bridge::app::App;
-use bridge::bridge::{create_bridge, create_deploy, create_chain_id_retrieval, Deployed};
+use bridge::bridge::{create_bridge, create_deploy, create_chain_id_retrieval, create_init, Deployed};
 use bridge::config::Config;
 use bridge::error::{Error, ErrorKind};
+use bridge::rpc_failure::RpcFailure;
+use bridge::ledger::{Ledger, LedgerQuery, TransferStatus, ledger_path};
 use bridge::web3;
+use bridge::web3::types::{Address, H256};
 
 const ERR_UNKNOWN: i32 = 1;
 const ERR_IO_ERROR: i32 = 2;
@@ -32,10 +35,10 @@ const ERR_SHUTDOWN_REQUESTED: i32 = 3;
 const ERR_INSUFFICIENT_FUNDS: i32 = 4;
 const ERR_GAS_TOO_LOW: i32 = 5;
 const ERR_GAS_PRICE_TOO_LOW: i32 = 6;
-const ERR_NONCE_REUSE: i32 = 7;
 const ERR_CANNOT_CONNECT: i32 = 10;
 const ERR_CONNECTION_LOST: i32 = 11;
 const ERR_BRIDGE_CRASH: i32 = 12;
+const ERR_LOG_QUORUM_NOT_REACHED: i32 = 13;
 const ERR_RPC_ERROR: i32 = 20;
 
 pub struct UserFacingError(i32, Error);
@@ -74,6 +77,9 @@ POA-Ethereum bridge.
 
 Usage:
     bridge [options] --config <config> --database <database>
+    bridge init [options] --home-contract <address> --foreign-contract <address> --config <config> --database <database>
+    bridge ledger [--tx <hash>] [--recipient <address>] [--since <time>] [--until <time>] [--status <status>] --database <database>
+    bridge dead-letters [(inspect | retry | discard) <id>] --database <database>
     bridge -h | --help
     bridge -v | --version
 
@@ -81,14 +87,37 @@ Options:
     -h, --help                        Display help message and exit.
     -v, --version                     Print version and exit.
     --allow-insecure-rpc-endpoints    Allow non-HTTPS endpoints
+    --dry-run                         Sign transactions and log them instead of sending them, without updating the database
+    --home-contract <address>         Address of the deployed HomeBridge contract
+    --foreign-contract <address>      Address of the deployed ForeignBridge contract
+    --tx <hash>                       Show transfers with given user or bridge transaction hash
+    --recipient <address>             Show transfers to given recipient
+    --since <time>                    Show transfers recorded at or after given unix timestamp
+    --until <time>                    Show transfers recorded at or before given unix timestamp
+    --status <status>                 Show transfers with given status: pending, sent, mined, reverted or dropped
 "#;
 
 #[derive(Debug, Deserialize)]
 pub struct Args {
+	cmd_init: bool,
+	cmd_ledger: bool,
+	cmd_dead_letters: bool,
+	cmd_inspect: bool,
+	cmd_retry: bool,
+	cmd_discard: bool,
+	arg_id: Option<i64>,
 	arg_config: PathBuf,
 	arg_database: PathBuf,
 	flag_version: bool,
 	flag_allow_insecure_rpc_endpoints: bool,
+	flag_dry_run: bool,
+	flag_home_contract: Option<String>,
+	flag_foreign_contract: Option<String>,
+	flag_tx: Option<String>,
+	flag_recipient: Option<String>,
+	flag_since: Option<u64>,
+	flag_until: Option<u64>,
+	flag_status: Option<String>,
 }
 
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -129,6 +158,22 @@ fn execute<S, I>(command: I, running: Arc<AtomicBool>) -> Result<String, UserFac
 		return Ok(version!().into())
 	}
 
+	if args.cmd_ledger {
+		return query_ledger(&args);
+	}
+
+	if args.cmd_dead_letters {
+		return dead_letters(&args);
+	}
+
+	if args.cmd_init && args.arg_database.exists() {
+		return Err(format!("Database {:?} already exists", args.arg_database).into());
+	}
+
+	if args.flag_dry_run && !args.cmd_init && !args.arg_database.exists() {
+		return Err(Error::from(ErrorKind::MissingFile(format!("{:?}", args.arg_database))).into());
+	}
+
 	info!(target: "bridge", "Loading config");
 	let config = Config::load(args.arg_config, args.flag_allow_insecure_rpc_endpoints)?;
 
@@ -136,13 +181,13 @@ fn execute<S, I>(command: I, running: Arc<AtomicBool>) -> Result<String, UserFac
 	let mut event_loop = Core::new().unwrap();
 	let handle = event_loop.handle();
 
-	info!(target: "bridge", "Home rpc host {}", config.clone().home.rpc_host);
-	info!(target: "bridge", "Foreign rpc host {}", config.clone().foreign.rpc_host);
+	info!(target: "bridge", "Home rpc endpoint {}", config.home.endpoint());
+	info!(target: "bridge", "Foreign rpc endpoint {}", config.foreign.endpoint());
 
 	info!(target: "bridge", "Establishing connection:");
 
 	info!(target:"bridge", "  using RPC connection");
-	let app = match App::new_http(config.clone(), &args.arg_database, &handle, running.clone()) {
+	let mut app = match App::new(config.clone(), &args.arg_database, &handle, running.clone()) {
 		Ok(app) => app,
 		Err(e) => {
 			warn!("Can't establish an RPC connection: {:?}", e);
@@ -150,8 +195,22 @@ fn execute<S, I>(command: I, running: Arc<AtomicBool>) -> Result<String, UserFac
 		},
 	};
 
+	app.dry_run = args.flag_dry_run;
 	let app = Arc::new(app);
 
+	if args.cmd_init {
+		let home_contract = parse_address("home contract", args.flag_home_contract.as_ref())?;
+		let foreign_contract = parse_address("foreign contract", args.flag_foreign_contract.as_ref())?;
+		info!(target: "bridge", "Reading deployment data of the bridge contracts");
+		let database = event_loop.run(create_init(app.clone(), home_contract, foreign_contract))?;
+		info!(target: "bridge", "\n\n{}\n", database);
+		if app.dry_run {
+			return Ok("Dry run, database not written".into());
+		}
+		database.save(&app.database_path)?;
+		return Ok(format!("Database written to {:?}", app.database_path));
+	}
+
 	info!(target: "bridge", "Acquiring home & foreign chain ids");
 	let home_chain_id = event_loop.run(create_chain_id_retrieval(app.clone(), app.connections.home.clone(), app.config.home.clone())).expect("can't retrieve home chain_id");
 	let foreign_chain_id = event_loop.run(create_chain_id_retrieval(app.clone(), app.connections.foreign.clone(), app.config.foreign.clone())).expect("can't retrieve foreign chain_id");
@@ -160,11 +219,10 @@ fn execute<S, I>(command: I, running: Arc<AtomicBool>) -> Result<String, UserFac
 
 	{
 		use bridge::api;
-		let mut home_nonce = app.config.home.info.nonce.write().unwrap();
-		let mut foreign_nonce = app.config.foreign.info.nonce.write().unwrap();
-
-		*home_nonce = event_loop.run(api::eth_get_transaction_count(app.connections.home.clone(), app.config.home.account, None)).expect("can't initialize home nonce");
-		*foreign_nonce = event_loop.run(api::eth_get_transaction_count(app.connections.foreign.clone(), app.config.foreign.account, None)).expect("can't initialize foreign nonce");
+		let home_nonce = event_loop.run(api::eth_get_transaction_count(app.connections.home.clone(), app.config.home.account, None)).expect("can't initialize home nonce");
+		let foreign_nonce = event_loop.run(api::eth_get_transaction_count(app.connections.foreign.clone(), app.config.foreign.account, None)).expect("can't initialize foreign nonce");
+		app.config.home.info.nonces.reset(home_nonce);
+		app.config.foreign.info.nonces.reset(foreign_nonce);
 	}
 
 	#[cfg(feature = "deploy")]
@@ -178,7 +236,7 @@ fn execute<S, I>(command: I, running: Arc<AtomicBool>) -> Result<String, UserFac
 		Deployed::New(database) => {
 			info!(target: "bridge", "Deployed new bridge contracts");
 			info!(target: "bridge", "\n\n{}\n", database);
-			database.save(fs::File::create(&app.database_path)?)?;
+			database.save(&app.database_path)?;
 			database
 		},
 		Deployed::Existing(database) => {
@@ -187,8 +245,15 @@ fn execute<S, I>(command: I, running: Arc<AtomicBool>) -> Result<String, UserFac
 		},
 	};
 
+	let ledger = if app.dry_run {
+		info!(target: "bridge", "Dry run, transactions are logged instead of sent and the database is not updated");
+		Ledger::open_in_memory()?
+	} else {
+		Ledger::open(ledger_path(&app.database_path))?
+	};
+
 	info!(target: "bridge", "Starting listening to events");
-	let bridge = create_bridge(app.clone(), &database, &handle, home_chain_id, foreign_chain_id).and_then(|_| future::ok(true)).collect();
+	let bridge = create_bridge(app.clone(), &database, ledger, &handle, home_chain_id, foreign_chain_id)?.and_then(|_| future::ok(true)).collect();
 	let mut result = event_loop.run(bridge);
 	loop {
 		match result {
@@ -210,30 +275,34 @@ fn execute<S, I>(command: I, running: Arc<AtomicBool>) -> Result<String, UserFac
 				info!("Shutdown requested, terminating");
 				return Err((ERR_SHUTDOWN_REQUESTED, e.into()).into());
 			},
+			Err(e @ Error(ErrorKind::LogQuorumNotReached(..), _)) => {
+				error!("RPC endpoints disagree on bridge events, terminating: {}", e);
+				return Err((ERR_LOG_QUORUM_NOT_REACHED, e.into()).into());
+			},
 			Err(e @ Error(ErrorKind::InsufficientFunds, _)) => {
 				error!("Insufficient funds, terminating");
 				return Err((ERR_INSUFFICIENT_FUNDS, e.into()).into());
 			},
 			Err(Error(ErrorKind::Web3(web3::error::Error(web3::error::ErrorKind::Rpc(e), _)), _)) => {
-				if e.code == rpc::ErrorCode::ServerError(-32010) && e.message.starts_with("Insufficient funds") {
-					error!("Insufficient funds, terminating");
-					return Err((ERR_INSUFFICIENT_FUNDS, ErrorKind::Web3(web3::error::ErrorKind::Rpc(e).into()).into()).into());
-				} else if e.code == rpc::ErrorCode::ServerError(-32010) && e.message.starts_with("Transaction gas is too low") {
-					error!("Transaction gas is too low");
-					return Err((ERR_GAS_TOO_LOW, ErrorKind::Web3(web3::error::ErrorKind::Rpc(e).into()).into()).into());
-				} else if e.code == rpc::ErrorCode::ServerError(-32010) && e.message.starts_with("Transaction gas price is too low") {
-					error!("Transaction gas price is too low");
-					return Err((ERR_GAS_PRICE_TOO_LOW, ErrorKind::Web3(web3::error::ErrorKind::Rpc(e).into()).into()).into());
-				} else if e.code == rpc::ErrorCode::ServerError(-32010) && e.message.starts_with("Transaction gas price is too low. There is another") {
-					error!("Nonce reuse");
-					return Err((ERR_NONCE_REUSE, ErrorKind::Web3(web3::error::ErrorKind::Rpc(e).into()).into()).into());
-				} else if e.code == rpc::ErrorCode::ServerError(-32010) && e.message.starts_with("Transaction nonce is too low") {
-					error!("Nonce reuse");
-					return Err((ERR_NONCE_REUSE, ErrorKind::Web3(web3::error::ErrorKind::Rpc(e).into()).into()).into());
-				} else {
-					error!("RPC error {:?}", e);
-					return Err((ERR_RPC_ERROR, ErrorKind::Web3(web3::error::ErrorKind::Rpc(e).into()).into()).into());
-				}
+				let code = match RpcFailure::classify(&e) {
+					RpcFailure::InsufficientFunds => {
+						error!("Insufficient funds, terminating");
+						ERR_INSUFFICIENT_FUNDS
+					},
+					RpcFailure::GasTooLow => {
+						error!("Transaction gas is too low");
+						ERR_GAS_TOO_LOW
+					},
+					RpcFailure::GasPriceTooLow => {
+						error!("Transaction gas price is too low");
+						ERR_GAS_PRICE_TOO_LOW
+					},
+					_ => {
+						error!("RPC error {:?}", e);
+						ERR_RPC_ERROR
+					},
+				};
+				return Err((code, ErrorKind::Web3(web3::error::ErrorKind::Rpc(e).into()).into()).into());
 			},
 			Err(e) => {
 				error!("Bridge crashed with {}", e);
@@ -246,6 +315,88 @@ fn execute<S, I>(command: I, running: Arc<AtomicBool>) -> Result<String, UserFac
 	Ok("Done".into())
 }
 
+fn parse_address(name: &str, address: Option<&String>) -> Result<Address, UserFacingError> {
+	let address = address.ok_or_else(|| format!("Missing {} address", name))?;
+	Ok(address.trim_left_matches("0x").parse().map_err(|_| format!("Invalid {} address {}", name, address))?)
+}
+
+fn query_ledger(args: &Args) -> Result<String, UserFacingError> {
+	let path = ledger_path(&args.arg_database);
+	if !path.exists() {
+		return Err(Error::from(ErrorKind::MissingFile(format!("{:?}", path))).into());
+	}
+
+	let query = LedgerQuery {
+		tx_hash: match args.flag_tx {
+			Some(ref hash) => Some(hash.trim_left_matches("0x").parse::<H256>().map_err(|_| format!("Invalid transaction hash {}", hash))?),
+			None => None,
+		},
+		recipient: match args.flag_recipient {
+			Some(ref recipient) => Some(parse_address("recipient", Some(recipient))?),
+			None => None,
+		},
+		since: args.flag_since,
+		until: args.flag_until,
+		status: match args.flag_status {
+			Some(ref status) => Some(status.parse::<TransferStatus>()?),
+			None => None,
+		},
+	};
+
+	let transfers = Ledger::open(path)?.query(&query)?;
+	if transfers.is_empty() {
+		return Ok("No transfers found".into());
+	}
+
+	Ok(transfers.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))
+}
+
+fn dead_letters(args: &Args) -> Result<String, UserFacingError> {
+	let path = ledger_path(&args.arg_database);
+	if !path.exists() {
+		return Err(Error::from(ErrorKind::MissingFile(format!("{:?}", path))).into());
+	}
+
+	let ledger = Ledger::open(path)?;
+	let id = match args.arg_id {
+		Some(id) => id,
+		None => {
+			let letters = ledger.dead_letters()?;
+			if letters.is_empty() {
+				return Ok("No dead letters".into());
+			}
+			return Ok(letters.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"));
+		},
+	};
+
+	if args.cmd_retry {
+		if !ledger.retry_dead_letter(id)? {
+			return Err(format!("No dead letter #{} to retry", id).into());
+		}
+		return Ok(format!("Dead letter #{} will be handled again by the running bridge", id));
+	}
+
+	if args.cmd_discard {
+		if !ledger.discard_dead_letter(id)? {
+			return Err(format!("No dead letter #{} to discard", id).into());
+		}
+		return Ok(format!("Dead letter #{} discarded", id));
+	}
+
+	let letter = match ledger.dead_letter(id)? {
+		Some(letter) => letter,
+		None => return Err(format!("No dead letter #{}", id).into()),
+	};
+	let transfers = ledger.event_transfers(letter.kind, &letter.event)?;
+	let mut lines = vec![letter.to_string()];
+	if transfers.is_empty() {
+		lines.push("No transfers sent for this event".into());
+	} else {
+		lines.extend(transfers.iter().map(ToString::to_string));
+	}
+	Ok(lines.join("\n"))
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -6,9 +6,19 @@ extern crate bridge;
 #[macro_use]
 extern crate pretty_assertions;
 extern crate ethcore;
+extern crate ethcore_transaction;
 
 use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 use web3::Transport;
+use web3::types::{Address, Bytes, H520, U256};
+use ethcore_transaction::Transaction;
+use bridge::app::{App, Connections};
+use bridge::config::{Authorities, Config, Finality, GasPriceSpeed, Node, NodeInfo, SignerConfig, TransactionType, Transactions};
+use bridge::contracts::{foreign, home};
+use bridge::signer::{SignFuture, Signer};
 
 #[derive(Debug, Clone)]
 pub struct MockedRequest {
@@ -85,11 +95,100 @@ impl Transport for MockedTransport {
 
 	fn send(&self, _id: usize, _request: rpc::Call) -> web3::Result<rpc::Value> {
 		let response = self.mocked_responses.iter().nth(self.requests.get() - 1).expect("missing response");
+		// `{"error": {"code": .., "message": ..}}` responses are returned as rpc errors
+		if let Some(error) = response.get("error") {
+			let error: rpc::Error = serde_json::from_value(error.clone()).expect("invalid mocked rpc error");
+			return Box::new(futures::failed(web3::error::ErrorKind::Rpc(error).into()));
+		}
+		// `{"transport_error": ".."}` responses are returned as failures to reach the node
+		if let Some(error) = response.get("transport_error") {
+			let error = error.as_str().expect("invalid mocked transport error").to_owned();
+			return Box::new(futures::failed(web3::error::ErrorKind::Transport(error).into()));
+		}
 		let f = futures::finished(response.clone());
 		Box::new(f)
 	}
 }
 
+/// Signs transactions as `nonce:gas_price`, so that tests know the raw transactions in advance.
+pub struct MockedSigner;
+
+impl Signer for MockedSigner {
+	fn sign_transaction(&self, _account: Address, tx: Transaction, _priority_fee: Option<U256>, _chain_id: u64) -> SignFuture<Bytes> {
+		Box::new(futures::future::ok(mocked_raw_transaction(tx.nonce, tx.gas_price)))
+	}
+
+	fn sign_message(&self, _account: Address, _message: Vec<u8>) -> SignFuture<H520> {
+		Box::new(futures::future::ok(H520::default()))
+	}
+}
+
+/// Raw transaction `MockedSigner` signs for `nonce` and `gas_price`.
+pub fn mocked_raw_transaction(nonce: U256, gas_price: U256) -> Bytes {
+	Bytes(format!("{}:{}", nonce, gas_price).into_bytes())
+}
+
+/// Node of a chain served by a `MockedTransport`, polled without delay.
+pub fn mocked_node(account: Address) -> Node {
+	Node {
+		account,
+		request_timeout: Duration::from_secs(5),
+		poll_interval: Duration::from_secs(0),
+		required_confirmations: 0,
+		rpc_host: Some("".into()),
+		rpc_port: 8545,
+		ipc_path: None,
+		fallback_rpc_urls: vec![],
+		rpc_max_failures: 3,
+		rpc_primary_retry_interval: Duration::from_secs(60),
+		log_quorum_rpc_urls: vec![],
+		log_quorum: 1,
+		log_quorum_max_attempts: 5,
+		password: None,
+		signer: SignerConfig::Keystore("/keys/".into()),
+		info: NodeInfo::default(),
+		gas_price_oracle_url: None,
+		gas_price_speed: GasPriceSpeed::Fast,
+		gas_price_timeout: Duration::from_secs(5),
+		default_gas_price: 0,
+		concurrent_http_requests: 64,
+		max_block_range: None,
+		finality: Finality::Depth,
+		gas_price_bump_blocks: 20,
+		gas_price_bump_percent: 20,
+		max_gas_price: 100_000_000_000,
+		transaction_type: TransactionType::Legacy,
+		default_priority_fee: 1_500_000_000,
+	}
+}
+
+/// App connected to `home` and `foreign`, signing with `MockedSigner`.
+pub fn mocked_app<T: Transport>(home: T, foreign: T, home_node: Node, foreign_node: Node) -> App<T> {
+	App {
+		config: Config {
+			home: home_node,
+			foreign: foreign_node,
+			authorities: Authorities {},
+			txs: Transactions::default(),
+		},
+		database_path: "".into(),
+		connections: Connections {
+			home,
+			foreign,
+			home_heads: None,
+			foreign_heads: None,
+			home_log_quorum: vec![],
+			foreign_log_quorum: vec![],
+		},
+		home_bridge: home::HomeBridge::default(),
+		foreign_bridge: foreign::ForeignBridge::default(),
+		timer: Default::default(),
+		running: Arc::new(AtomicBool::new(true)),
+		signer: Box::new(MockedSigner),
+		dry_run: false,
+	}
+}
+
 #[macro_export]
 macro_rules! test_transport_stream {
 	(
@@ -137,8 +236,9 @@ macro_rules! test_app_stream {
 			use self::futures::{Future, Stream};
 			use self::bridge::app::{App, Connections};
 			use self::bridge::contracts::{foreign, home};
-			use self::bridge::config::{Config, Authorities, Node, NodeInfo, ContractConfig, Transactions, TransactionConfig, GasPriceSpeed};
+			use self::bridge::config::{Config, Authorities, Node, NodeInfo, ContractConfig, Transactions, TransactionConfig, GasPriceSpeed, Finality, TransactionType, SignerConfig};
 			use self::bridge::database::Database;
+			use self::bridge::signer::KeystoreSigner;
 			use ethcore::account_provider::AccountProvider;
 			
 			let home = $crate::MockedTransport {
@@ -163,14 +263,29 @@ macro_rules! test_app_stream {
 					poll_interval: Duration::from_secs(0),
 					request_timeout: Duration::from_secs(5),
 					required_confirmations: $home_conf,
-					rpc_host: "".into(),
+					rpc_host: Some("".into()),
 					rpc_port: 8545,
-					password: "password.txt".into(),
+					ipc_path: None,
+					fallback_rpc_urls: vec![],
+					rpc_max_failures: 3,
+					rpc_primary_retry_interval: Duration::from_secs(60),
+					log_quorum_rpc_urls: vec![],
+					log_quorum: 1,
+					log_quorum_max_attempts: 5,
+					password: Some("password.txt".into()),
+					signer: SignerConfig::Keystore("/keys/".into()),
 					info: NodeInfo::default(),
 					gas_price_oracle_url: None,
 					gas_price_speed: GasPriceSpeed::Fast,
 					gas_price_timeout: Duration::from_secs(5),
 					default_gas_price: 0,
+					max_block_range: None,
+					finality: Finality::Depth,
+					gas_price_bump_blocks: 20,
+					gas_price_bump_percent: 20,
+					max_gas_price: 100_000_000_000,
+					transaction_type: TransactionType::Legacy,
+					default_priority_fee: 1_500_000_000,
 				},
 				foreign: Node {
 					account: $foreign_acc.parse().unwrap(),
@@ -180,21 +295,35 @@ macro_rules! test_app_stream {
 					poll_interval: Duration::from_secs(0),
 					request_timeout: Duration::from_secs(5),
 					required_confirmations: $foreign_conf,
-					rpc_host: "".into(),
+					rpc_host: Some("".into()),
 					rpc_port: 8545,
-					password: "password.txt".into(),
+					ipc_path: None,
+					fallback_rpc_urls: vec![],
+					rpc_max_failures: 3,
+					rpc_primary_retry_interval: Duration::from_secs(60),
+					log_quorum_rpc_urls: vec![],
+					log_quorum: 1,
+					log_quorum_max_attempts: 5,
+					password: Some("password.txt".into()),
+					signer: SignerConfig::Keystore("/keys/".into()),
 					info: NodeInfo::default(),
 					gas_price_oracle_url: None,
 					gas_price_speed: GasPriceSpeed::Fast,
 					gas_price_timeout: Duration::from_secs(5),
 					default_gas_price: 0,
+					max_block_range: None,
+					finality: Finality::Depth,
+					gas_price_bump_blocks: 20,
+					gas_price_bump_percent: 20,
+					max_gas_price: 100_000_000_000,
+					transaction_type: TransactionType::Legacy,
+					default_priority_fee: 1_500_000_000,
 				},
 				authorities: Authorities {
 					accounts: $authorities_accs.iter().map(|a: &&str| a.parse().unwrap()).collect(),
 					required_signatures: $signatures,
 				},
 				estimated_gas_cost_of_withdraw: 100_000,
-				keystore: "/keys/".into(),
 			};
 
 			let app = App {
@@ -203,12 +332,17 @@ macro_rules! test_app_stream {
 				connections: Connections {
 					home: &home,
 					foreign: &foreign,
+					home_heads: None,
+					foreign_heads: None,
+					home_log_quorum: vec![],
+					foreign_log_quorum: vec![],
 				},
 				home_bridge: home::HomeBridge::default(),
 				foreign_bridge: foreign::ForeignBridge::default(),
 				timer: Default::default(),
 				running: Arc::new(AtomicBool::new(true)),
-				keystore: AccountProvider::transient_provider(),
+				signer: Box::new(KeystoreSigner::new(AccountProvider::transient_provider())),
+				dry_run: false,
 			};
 
 			let app = Arc::new(app);			
@@ -28,7 +28,7 @@ test_app_stream! {
 		],
 		signatures => 1;
 	txs => Transactions::default(),
-	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(2),
+	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1)), Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(2),
 	expected => vec![0x1005, 0x1006],
 	home_transport => [
 		"eth_blockNumber" =>
@@ -78,7 +78,7 @@ test_app_stream! {
 		],
 		signatures => 1;
 	txs => Transactions::default(),
-	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(2),
+	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1)), Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(2),
 	expected => vec![0x1005, 0x1006],
 	home_transport => [
 		"eth_blockNumber" =>
@@ -117,7 +117,7 @@ test_app_stream! {
 			req => json!([{
 				"data": "0x26b3293f000000000000000000000000aff3454fce5edbc8cca8697c15331677e6ebcccc00000000000000000000000000000000000000000000000000000000000000f0884edad9ce6fa2440d8a54cc123490eb96d2768479d49ff9c7366125a9424364",
 				"from": "0x0000000000000000000000000000000000000001",
-				"gas": "0x0",
+				"gas": "0x186a0",
 				"gasPrice": "0x0",
 				"to": "0x0000000000000000000000000000000000000000"
 			}]),
@@ -145,13 +145,13 @@ test_app_stream! {
 		signatures => 1;
 	txs => Transactions {
 		deposit_relay: TransactionConfig {
-			gas: 0xfd,
+			gas: Some(0xfd),
 			gas_price: 0xa0,
 			concurrency: 100,
 		},
 		..Default::default()
 	},
-	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(1),
+	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1)), Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(1),
 	expected => vec![0x1005],
 	home_transport => [
 		"eth_blockNumber" =>
@@ -204,7 +204,7 @@ test_app_stream! {
 		],
 		signatures => 1;
 	txs => Transactions::default(),
-	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(1),
+	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1)), Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(1),
 	expected => vec![0x1005],
 	home_transport => [
 		"eth_blockNumber" =>
@@ -231,7 +231,7 @@ test_app_stream! {
 			req => json!([{
 				"data": "0x26b3293f000000000000000000000000aff3454fce5edbc8cca8697c15331677e6ebcccc00000000000000000000000000000000000000000000000000000000000000f0884edad9ce6fa2440d8a54cc123490eb96d2768479d49ff9c7366125a9424364",
 				"from": "0x0000000000000000000000000000000000000001",
-				"gas": "0x0",
+				"gas": "0x186a0",
 				"gasPrice": "0x0",
 				"to": "0x0000000000000000000000000000000000000dd1"
 			}]),
@@ -259,7 +259,7 @@ test_app_stream! {
 		],
 		signatures => 1;
 	txs => Transactions::default(),
-	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(1),
+	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1)), Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(1),
 	expected => vec![0x1005],
 	home_transport => [
 		"eth_blockNumber" =>
@@ -286,7 +286,7 @@ test_app_stream! {
 			req => json!([{
 				"data": "0x26b3293f000000000000000000000000aff3454fce5edbc8cca8697c15331677e6ebcccc00000000000000000000000000000000000000000000000000000000000000f0884edad9ce6fa2440d8a54cc123490eb96d2768479d49ff9c7366125a9424364",
 				"from": "0x00000000000000000000000000000000000000ee",
-				"gas": "0x0",
+				"gas": "0x186a0",
 				"gasPrice": "0x0",
 				"to":"0x0000000000000000000000000000000000000dd1"
 			}]),
@@ -310,7 +310,7 @@ test_app_stream! {
 		],
 		signatures => 1;
 	txs => Transactions::default(),
-	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(1),
+	init => |app, db| create_deposit_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1)), Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(1),
 	expected => vec![0x1005],
 	home_transport => [
 		"eth_blockNumber" =>
@@ -346,7 +346,7 @@ test_app_stream! {
 			req => json!([{
 				"data": "0x26b3293f000000000000000000000000aff3454fce5edbc8cca8697c15331677e6ebcccc00000000000000000000000000000000000000000000000000000000000000f0884edad9ce6fa2440d8a54cc123490eb96d2768479d49ff9c7366125a9424364",
 				"from": "0x0000000000000000000000000000000000000001",
-				"gas": "0x0",
+				"gas": "0x186a0",
 				"gasPrice": "0x0",
 				"to": "0x0000000000000000000000000000000000000000"
 			}]),
@@ -355,7 +355,7 @@ test_app_stream! {
 			req => json!([{
 				"data": "0x26b3293f000000000000000000000000aff3454fce5edbc8cca8697c15331677e6ebcccc00000000000000000000000000000000000000000000000000000000000000f0884edad9ce6fa2440d8a54cc123490eb96d2768479d49ff9c7366125a942436f",
 				"from": "0x0000000000000000000000000000000000000001",
-				"gas": "0x0",
+				"gas": "0x186a0",
 				"gasPrice": "0x0",
 				"to": "0x0000000000000000000000000000000000000000"
 			}]),
@@ -0,0 +1,145 @@
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
+extern crate web3;
+extern crate bridge;
+extern crate tests;
+extern crate keccak_hash;
+
+use std::sync::{Arc, RwLock};
+use futures::{Future, Stream};
+use web3::types::U256;
+use keccak_hash::keccak;
+use bridge::bridge::{BridgeChecked, PendingTransactions, Retries, create_deposit_relay};
+use bridge::database::{Database, EventId};
+use bridge::error::{Error, ErrorKind};
+use bridge::ledger::{TransferKind, TransferStatus};
+use tests::{MockedTransport, mocked_app, mocked_node, mocked_raw_transaction};
+
+const DEPOSIT_TOPIC: &str = "0xe1fffcc4923d04b559f4d29a8bfc6cda04eb5b0d3c460751c2402c5c5cc9109c";
+const DEPOSIT_DATA: &str = "0x000000000000000000000000aff3454fce5edbc8cca8697c15331677e6ebcccc00000000000000000000000000000000000000000000000000000000000000f0";
+
+fn mocked_transport(requests: Vec<(&'static str, serde_json::Value)>, responses: Vec<serde_json::Value>) -> MockedTransport {
+	MockedTransport {
+		requests: Default::default(),
+		expected_requests: requests.into_iter().map(Into::into).collect(),
+		mocked_responses: responses,
+	}
+}
+
+fn deposit_logs_request(block: &str) -> (&'static str, serde_json::Value) {
+	("eth_getLogs", json!([{
+		"address": ["0x0000000000000000000000000000000000000000"],
+		"fromBlock": block,
+		"limit": null,
+		"toBlock": block,
+		"topics": [[DEPOSIT_TOPIC], null, null, null]
+	}]))
+}
+
+fn deposit_log(event: &EventId) -> serde_json::Value {
+	json!([{
+		"address": "0x0000000000000000000000000000000000000000",
+		"topics": [DEPOSIT_TOPIC],
+		"data": DEPOSIT_DATA,
+		"blockNumber": format!("0x{:x}", event.block_number),
+		"transactionHash": event.transaction_hash,
+		"logIndex": "0x0"
+	}])
+}
+
+#[test]
+fn deposit_relay_uses_estimated_gas_and_fails_deposits_which_can_not_be_estimated() {
+	let event = |block_number: u64, hash: &[u8]| EventId {
+		block_number,
+		transaction_hash: keccak(hash),
+		log_index: 0.into(),
+	};
+	let (estimated, failed) = (event(0x10, b"estimated"), event(0x11, b"failed"));
+	let raw = mocked_raw_transaction(0.into(), 10.into());
+
+	let home = mocked_transport(vec![
+		deposit_logs_request("0x10"),
+		deposit_logs_request("0x11"),
+	], vec![
+		deposit_log(&estimated),
+		deposit_log(&failed),
+	]);
+	let foreign = mocked_transport(vec![
+		("eth_call", json!([])),
+		("eth_call", json!([])),
+		("eth_estimateGas", json!([])),
+		("eth_estimateGas", json!([])),
+		("eth_sendRawTransaction", json!([])),
+	], vec![
+		json!("0x"),
+		json!("0x"),
+		json!("0x5208"),
+		json!({"error": {"code": -32000, "message": "gas required exceeds allowance"}}),
+		json!(keccak(&raw.0)),
+	]);
+
+	let node = mocked_node(0xaau64.into());
+	node.info.nonces.reset(0.into());
+	let mut app = mocked_app(&home, &foreign, node.clone(), node);
+	app.config.txs.deposit_relay.estimate_gas = true;
+	app.config.txs.deposit_relay.gas_multiplier = 1.0;
+
+	let retries = Retries::default();
+	retries.deposit_relay.push(estimated);
+	retries.deposit_relay.push(failed);
+	// enough for the estimated 21000 gas, but not for the contract's gas limit
+	let balance = Arc::new(RwLock::new(Some(U256::from(210_000))));
+	let deposit_relay = create_deposit_relay(Arc::new(app), &Database::default(), balance, 1,
+		Arc::new(RwLock::new(10)), Arc::new(RwLock::new(100_000)), PendingTransactions::default(), retries.deposit_relay);
+	let items = deposit_relay.take(3).collect().wait().unwrap();
+
+	match (&items[0], &items[1], &items[2]) {
+		(&BridgeChecked::Failed(kind, event, _), &BridgeChecked::Transfer(ref pending), &BridgeChecked::Transfer(ref sent)) => {
+			assert_eq!((TransferKind::DepositRelay, failed), (kind, event));
+			assert_eq!((estimated, TransferStatus::Pending), (pending.event, pending.status));
+			assert_eq!((estimated, TransferStatus::Sent, Some(keccak(&raw.0))), (sent.event, sent.status, sent.tx_hash));
+		},
+		items => panic!("expected a failed deposit, then pending and sent transfers, got {:?}", items),
+	}
+	assert_eq!(home.expected_requests.len(), home.requests.get());
+	assert_eq!(foreign.expected_requests.len(), foreign.requests.get());
+}
+
+#[test]
+fn deposit_relay_stops_instead_of_failing_deposits_when_the_balance_is_too_low() {
+	let event = EventId {
+		block_number: 0x10,
+		transaction_hash: keccak(b"deposit"),
+		log_index: 0.into(),
+	};
+
+	let home = mocked_transport(vec![
+		deposit_logs_request("0x10"),
+	], vec![
+		deposit_log(&event),
+	]);
+	let foreign = mocked_transport(vec![
+		("eth_call", json!([])),
+	], vec![
+		json!("0x"),
+	]);
+
+	let node = mocked_node(0xaau64.into());
+	node.info.nonces.reset(0.into());
+	let app = mocked_app(&home, &foreign, node.clone(), node);
+
+	let retries = Retries::default();
+	retries.deposit_relay.push(event);
+	// one wei short of the contract's gas limit at the gas price
+	let balance = Arc::new(RwLock::new(Some(U256::from(999_999))));
+	let deposit_relay = create_deposit_relay(Arc::new(app), &Database::default(), balance, 1,
+		Arc::new(RwLock::new(10)), Arc::new(RwLock::new(100_000)), PendingTransactions::default(), retries.deposit_relay);
+
+	match deposit_relay.take(1).collect().wait() {
+		Err(Error(ErrorKind::InsufficientFunds, _)) => (),
+		result => panic!("expected insufficient funds, got {:?}", result),
+	}
+	assert_eq!(home.expected_requests.len(), home.requests.get());
+	assert_eq!(foreign.expected_requests.len(), foreign.requests.get());
+}
@@ -0,0 +1,143 @@
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
+extern crate web3;
+extern crate bridge;
+extern crate tests;
+extern crate keccak_hash;
+
+use std::sync::Arc;
+use futures::{Future, Stream};
+use keccak_hash::keccak;
+use bridge::bridge::{GasLimits, create_home_gas_limits};
+use bridge::database::Database;
+use bridge::error::ErrorKind;
+use tests::{MockedTransport, mocked_app, mocked_node};
+
+fn mocked_transport(requests: Vec<(&'static str, serde_json::Value)>, responses: Vec<serde_json::Value>) -> MockedTransport {
+	MockedTransport {
+		requests: Default::default(),
+		expected_requests: requests.into_iter().map(Into::into).collect(),
+		mocked_responses: responses,
+	}
+}
+
+fn word(value: u64) -> String {
+	format!("0x{:064x}", value)
+}
+
+#[test]
+fn gas_limits_are_read_again_when_updated() {
+	let topic = keccak(b"GasConsumptionLimitsUpdated(uint256)");
+	let transport = mocked_transport(vec![
+		("eth_call", json!([])),
+		("eth_blockNumber", json!([])),
+		("eth_getBlockByNumber", json!([])),
+		("eth_getLogs", json!([{
+			"address": ["0x0000000000000000000000000000000000000000"],
+			"fromBlock": "0x1",
+			"limit": null,
+			"toBlock": "0x10",
+			"topics": [[topic], null, null, null]
+		}])),
+		("eth_call", json!([])),
+	], vec![
+		json!(word(100_000)),
+		json!("0x10"),
+		json!({"number": "0x10", "hash": "0x0000000000000000000000000000000000000000000000000000000000000010"}),
+		json!([{
+			"address": "0x0000000000000000000000000000000000000000",
+			"topics": [topic],
+			"data": word(150_000),
+			"blockNumber": "0x10",
+			"transactionHash": keccak(b"update"),
+			"logIndex": "0x0"
+		}]),
+		json!(word(150_000)),
+	]);
+
+	let node = mocked_node(0xaau64.into());
+	let app = Arc::new(mocked_app(&transport, &transport, node.clone(), node));
+	let limits = GasLimits::default();
+	let mut stream = create_home_gas_limits(app, &Database::default(), &limits);
+
+	assert_eq!(1, stream.by_ref().take(1).collect().wait().unwrap().len());
+	assert!(stream.is_loaded());
+	assert_eq!(100_000, *limits.withdraw_relay.read().unwrap());
+
+	assert_eq!(1, stream.by_ref().take(1).collect().wait().unwrap().len());
+	assert_eq!(150_000, *limits.withdraw_relay.read().unwrap());
+	assert_eq!(transport.expected_requests.len(), transport.requests.get());
+}
+
+#[test]
+fn unset_gas_limit_fails_unless_gas_is_configured() {
+	let transport = mocked_transport(vec![
+		("eth_call", json!([])),
+		("eth_call", json!([])),
+	], vec![
+		json!(word(0)),
+		json!(word(0)),
+	]);
+
+	let node = mocked_node(0xaau64.into());
+	let limits = GasLimits::default();
+	let app = mocked_app(&transport, &transport, node.clone(), node.clone());
+	let err = create_home_gas_limits(Arc::new(app), &Database::default(), &limits).into_future().wait().map(|_| ()).unwrap_err().0;
+	match *err.kind() {
+		ErrorKind::ConfigError(_) => (),
+		ref kind => panic!("expected a config error, got {:?}", kind),
+	}
+
+	let mut app = mocked_app(&transport, &transport, node.clone(), node);
+	app.config.txs.withdraw_relay.gas = Some(200_000);
+	let mut stream = create_home_gas_limits(Arc::new(app), &Database::default(), &limits);
+	assert_eq!(1, stream.by_ref().take(1).collect().wait().unwrap().len());
+	assert_eq!(0, *limits.withdraw_relay.read().unwrap());
+	assert_eq!(transport.expected_requests.len(), transport.requests.get());
+}
+
+#[test]
+fn gas_limit_set_to_zero_later_on_stops_the_bridge() {
+	let topic = keccak(b"GasConsumptionLimitsUpdated(uint256)");
+	let transport = mocked_transport(vec![
+		("eth_call", json!([])),
+		("eth_blockNumber", json!([])),
+		("eth_getBlockByNumber", json!([])),
+		("eth_getLogs", json!([{
+			"address": ["0x0000000000000000000000000000000000000000"],
+			"fromBlock": "0x1",
+			"limit": null,
+			"toBlock": "0x10",
+			"topics": [[topic], null, null, null]
+		}])),
+		("eth_call", json!([])),
+	], vec![
+		json!(word(100_000)),
+		json!("0x10"),
+		json!({"number": "0x10", "hash": "0x0000000000000000000000000000000000000000000000000000000000000010"}),
+		json!([{
+			"address": "0x0000000000000000000000000000000000000000",
+			"topics": [topic],
+			"data": word(0),
+			"blockNumber": "0x10",
+			"transactionHash": keccak(b"update"),
+			"logIndex": "0x0"
+		}]),
+		json!(word(0)),
+	]);
+
+	let node = mocked_node(0xaau64.into());
+	let app = Arc::new(mocked_app(&transport, &transport, node.clone(), node));
+	let limits = GasLimits::default();
+	let mut stream = create_home_gas_limits(app, &Database::default(), &limits);
+
+	assert_eq!(1, stream.by_ref().take(1).collect().wait().unwrap().len());
+	let err = stream.into_future().wait().map(|_| ()).unwrap_err().0;
+	match *err.kind() {
+		ErrorKind::ConfigError(_) => (),
+		ref kind => panic!("expected a config error, got {:?}", kind),
+	}
+	assert_eq!(100_000, *limits.withdraw_relay.read().unwrap());
+	assert_eq!(transport.expected_requests.len(), transport.requests.get());
+}
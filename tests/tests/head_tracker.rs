@@ -0,0 +1,84 @@
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
+extern crate web3;
+extern crate bridge;
+extern crate tests;
+
+use std::sync::Arc;
+use std::time::Duration;
+use futures::{future, stream, Future, Stream};
+use web3::types::FilterBuilder;
+use bridge::api::{LogStreamInit, log_stream, LogStreamItem, NewHeads, NewHeadsStream};
+use bridge::config::Finality;
+use bridge::head_tracker::HeadTracker;
+use tests::MockedTransport;
+
+/// Yields given block numbers and then never completes.
+struct MockedHeads(Vec<u64>);
+
+impl NewHeads for MockedHeads {
+	fn subscribe(&self) -> NewHeadsStream {
+		Box::new(stream::iter_ok(self.0.clone()).chain(future::empty().into_stream()))
+	}
+}
+
+fn transport(requests: Vec<(&'static str, serde_json::Value)>, responses: Vec<serde_json::Value>) -> MockedTransport {
+	MockedTransport {
+		requests: Default::default(),
+		expected_requests: requests.into_iter().map(Into::into).collect(),
+		mocked_responses: responses,
+	}
+}
+
+#[test]
+fn head_tracker_polls_once_for_all_subscribers() {
+	let node = transport(vec![("eth_blockNumber", json!([]))], vec![json!("0x11")]);
+	let tracker = HeadTracker::new(node, Default::default(), Duration::from_secs(0), Duration::from_secs(5), None);
+
+	assert_eq!(tracker.subscribe().take(1).collect().wait().unwrap(), vec![0x11]);
+	assert_eq!(tracker.subscribe().take(1).collect().wait().unwrap(), vec![0x11]);
+}
+
+#[test]
+fn log_streams_follow_shared_head_tracker() {
+	let node = transport(vec![], vec![]);
+	let tracker: Arc<NewHeads> = Arc::new(HeadTracker::new(node, Default::default(), Duration::from_secs(0), Duration::from_secs(5), Some(Arc::new(MockedHeads(vec![0x11])))));
+
+	let chunk = || transport(vec![
+		("eth_getBlockByNumber", json!(["0x11", false])),
+		("eth_getLogs", json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0x11",
+			"topics": null
+		}])),
+	], vec![
+		json!({"number": "0x11", "hash": "0x0000000000000000000000000000000000000000000000000000000000000011"}),
+		json!([]),
+	]);
+	let init = || LogStreamInit {
+		after: 10,
+		filter: FilterBuilder::default(),
+		poll_interval: Duration::from_secs(0),
+		request_timeout: Duration::from_secs(5),
+		confirmations: 0,
+		max_block_range: None,
+		after_hash: None,
+		heads: Some(tracker.clone()),
+		finality: Finality::Depth,
+	};
+	let expected = vec![LogStreamItem {
+		from: 0xb,
+		to: 0x11,
+		hash: 0x11u64.into(),
+		logs: vec![],
+	}];
+
+	// neither stream asks for the block number on its own
+	let first = chunk();
+	let second = chunk();
+	assert_eq!(log_stream(&first, Default::default(), init()).take(1).collect().wait().unwrap(), expected);
+	assert_eq!(log_stream(&second, Default::default(), init()).take(1).collect().wait().unwrap(), expected);
+}
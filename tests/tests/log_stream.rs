@@ -7,9 +7,48 @@ extern crate bridge;
 extern crate tests;
 extern crate ethcore;
 
+use std::sync::Arc;
 use std::time::Duration;
+use futures::{stream, task, Async, Future, Stream};
 use web3::types::{FilterBuilder, H160, H256, Log};
-use bridge::api::{LogStreamInit, log_stream, LogStreamItem};
+use bridge::api::{LogStreamInit, log_stream, quorum_log_stream, LogQuorum, LogStreamItem, NewHeads, NewHeadsStream};
+use bridge::config::Finality;
+use bridge::error::ErrorKind;
+use tests::MockedTransport;
+
+/// Subscription that is closed right away.
+struct ClosedHeads;
+
+impl NewHeads for ClosedHeads {
+	fn subscribe(&self) -> NewHeadsStream {
+		Box::new(stream::empty())
+	}
+}
+
+/// Yields given block numbers one per poll, like a node announcing new blocks,
+/// and then never completes.
+struct MockedHeads(Vec<u64>);
+
+impl NewHeads for MockedHeads {
+	fn subscribe(&self) -> NewHeadsStream {
+		let mut heads = self.0.clone().into_iter();
+		let mut ready = true;
+		Box::new(stream::poll_fn(move || {
+			if !ready {
+				ready = true;
+				task::current().notify();
+				return Ok(Async::NotReady);
+			}
+			match heads.next() {
+				Some(head) => {
+					ready = false;
+					Ok(Async::Ready(Some(head)))
+				},
+				None => Ok(Async::NotReady),
+			}
+		}))
+	}
+}
 
 test_transport_stream! {
 	name => log_stream_basic,
@@ -20,6 +59,10 @@ test_transport_stream! {
 			poll_interval: Duration::from_secs(0),
 			request_timeout: Duration::from_secs(5),
 			confirmations: 10,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Depth,
 		};
 
 		log_stream(transport, Default::default(), init).take(2)
@@ -27,15 +70,20 @@ test_transport_stream! {
 	expected => vec![LogStreamItem {
 		from: 0xb,
 		to: 0x1006,
+		hash: 0x1006u64.into(),
 		logs: vec![],
 	}, LogStreamItem {
 		from: 0x1007,
 		to: 0x1007,
+		hash: 0x1007u64.into(),
 		logs: vec![],
 	}],
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x1010");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1006", false]),
+		res => json!({"number": "0x1006", "hash": "0x0000000000000000000000000000000000000000000000000000000000001006"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -48,9 +96,18 @@ test_transport_stream! {
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x1010");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1006", false]),
+		res => json!({"number": "0x1006", "hash": "0x0000000000000000000000000000000000000000000000000000000000001006"});
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x1011");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1006", false]),
+		res => json!({"number": "0x1006", "hash": "0x0000000000000000000000000000000000000000000000000000000000001006"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1007", false]),
+		res => json!({"number": "0x1007", "hash": "0x0000000000000000000000000000000000000000000000000000000000001007"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -71,6 +128,10 @@ test_transport_stream! {
 			poll_interval: Duration::from_secs(0),
 			request_timeout: Duration::from_secs(5),
 			confirmations: 10,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Depth,
 		};
 
 		log_stream(transport, Default::default(), init).take(2)
@@ -78,15 +139,20 @@ test_transport_stream! {
 	expected => vec![LogStreamItem {
 		from: 0xb,
 		to: 0xd,
+		hash: 0xdu64.into(),
 		logs: vec![],
 	}, LogStreamItem {
 		from: 0xe,
 		to: 0xf,
+		hash: 0xfu64.into(),
 		logs: vec![],
 	}],
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x17");
+	"eth_getBlockByNumber" =>
+		req => json!(["0xd", false]),
+		res => json!({"number": "0xd", "hash": "0x000000000000000000000000000000000000000000000000000000000000000d"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -99,12 +165,24 @@ test_transport_stream! {
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x16");
+	"eth_getBlockByNumber" =>
+		req => json!(["0xd", false]),
+		res => json!({"number": "0xd", "hash": "0x000000000000000000000000000000000000000000000000000000000000000d"});
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x17");
+	"eth_getBlockByNumber" =>
+		req => json!(["0xd", false]),
+		res => json!({"number": "0xd", "hash": "0x000000000000000000000000000000000000000000000000000000000000000d"});
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x19");
+	"eth_getBlockByNumber" =>
+		req => json!(["0xd", false]),
+		res => json!({"number": "0xd", "hash": "0x000000000000000000000000000000000000000000000000000000000000000d"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0xf", false]),
+		res => json!({"number": "0xf", "hash": "0x000000000000000000000000000000000000000000000000000000000000000f"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -125,6 +203,10 @@ test_transport_stream! {
 			poll_interval: Duration::from_secs(0),
 			request_timeout: Duration::from_secs(5),
 			confirmations: 10,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Depth,
 		};
 
 		log_stream(transport, Default::default(), init).take(1)
@@ -132,6 +214,7 @@ test_transport_stream! {
 	expected => vec![LogStreamItem {
 		from: 0xb,
 		to: 0xd,
+		hash: 0xdu64.into(),
 		logs: vec![],
 	}],
 	"eth_blockNumber" =>
@@ -143,6 +226,9 @@ test_transport_stream! {
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x17");
+	"eth_getBlockByNumber" =>
+		req => json!(["0xd", false]),
+		res => json!({"number": "0xd", "hash": "0x000000000000000000000000000000000000000000000000000000000000000d"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -163,6 +249,10 @@ test_transport_stream! {
 			poll_interval: Duration::from_secs(0),
 			request_timeout: Duration::from_secs(5),
 			confirmations: 0,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Depth,
 		};
 
 		log_stream(transport, Default::default(), init).take(3)
@@ -170,19 +260,25 @@ test_transport_stream! {
 	expected => vec![LogStreamItem {
 		from: 0xb,
 		to: 0x13,
+		hash: 0x13u64.into(),
 		logs: vec![],
 	}, LogStreamItem {
 		from: 0x14,
 		to: 0x14,
+		hash: 0x14u64.into(),
 		logs: vec![],
 	}, LogStreamItem {
 		from: 0x15,
 		to: 0x17,
+		hash: 0x17u64.into(),
 		logs: vec![],
 	}],
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x13");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x13", false]),
+		res => json!({"number": "0x13", "hash": "0x0000000000000000000000000000000000000000000000000000000000000013"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -195,6 +291,12 @@ test_transport_stream! {
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x14");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x13", false]),
+		res => json!({"number": "0x13", "hash": "0x0000000000000000000000000000000000000000000000000000000000000013"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x14", false]),
+		res => json!({"number": "0x14", "hash": "0x0000000000000000000000000000000000000000000000000000000000000014"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -207,9 +309,18 @@ test_transport_stream! {
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x14");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x14", false]),
+		res => json!({"number": "0x14", "hash": "0x0000000000000000000000000000000000000000000000000000000000000014"});
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x17");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x14", false]),
+		res => json!({"number": "0x14", "hash": "0x0000000000000000000000000000000000000000000000000000000000000014"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x17", false]),
+		res => json!({"number": "0x17", "hash": "0x0000000000000000000000000000000000000000000000000000000000000017"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -230,6 +341,10 @@ test_transport_stream! {
 			poll_interval: Duration::from_secs(0),
 			request_timeout: Duration::from_secs(5),
 			confirmations: 0,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Depth,
 		};
 
 		log_stream(transport, Default::default(), init).take(2)
@@ -237,15 +352,20 @@ test_transport_stream! {
 	expected => vec![LogStreamItem {
 		from: 0xc,
 		to: 0x13,
+		hash: 0x13u64.into(),
 		logs: vec![],
 	}, LogStreamItem {
 		from: 0x14,
 		to: 0x14,
+		hash: 0x14u64.into(),
 		logs: vec![],
 	}],
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x13");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x13", false]),
+		res => json!({"number": "0x13", "hash": "0x0000000000000000000000000000000000000000000000000000000000000013"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": ["0x1111111111111111111111111111111111111111"],
@@ -258,6 +378,12 @@ test_transport_stream! {
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x14");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x13", false]),
+		res => json!({"number": "0x13", "hash": "0x0000000000000000000000000000000000000000000000000000000000000013"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x14", false]),
+		res => json!({"number": "0x14", "hash": "0x0000000000000000000000000000000000000000000000000000000000000014"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address":["0x1111111111111111111111111111111111111111"],
@@ -278,6 +404,10 @@ test_transport_stream! {
 			poll_interval: Duration::from_secs(0),
 			request_timeout: Duration::from_secs(5),
 			confirmations: 0,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Depth,
 		};
 
 		log_stream(transport, Default::default(), init).take(2)
@@ -285,15 +415,20 @@ test_transport_stream! {
 	expected => vec![LogStreamItem {
 		from: 0xc,
 		to: 0x13,
+		hash: 0x13u64.into(),
 		logs: vec![],
 	}, LogStreamItem {
 		from: 0x14,
 		to: 0x14,
+		hash: 0x14u64.into(),
 		logs: vec![],
 	}],
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x13");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x13", false]),
+		res => json!({"number": "0x13", "hash": "0x0000000000000000000000000000000000000000000000000000000000000013"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -306,6 +441,12 @@ test_transport_stream! {
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x14");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x13", false]),
+		res => json!({"number": "0x13", "hash": "0x0000000000000000000000000000000000000000000000000000000000000013"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x14", false]),
+		res => json!({"number": "0x14", "hash": "0x0000000000000000000000000000000000000000000000000000000000000014"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -326,6 +467,10 @@ test_transport_stream! {
 			poll_interval: Duration::from_secs(0),
 			request_timeout: Duration::from_secs(5),
 			confirmations: 10,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Depth,
 		};
 
 		log_stream(transport, Default::default(), init).take(1)
@@ -333,6 +478,7 @@ test_transport_stream! {
 	expected => vec![LogStreamItem {
 		from: 0xb,
 		to: 0x1006,
+		hash: 0x1006u64.into(),
 		logs: vec![Log {
 			address: "0000000000000000000000000000000000000001".into(),
 			topics: vec![],
@@ -350,6 +496,9 @@ test_transport_stream! {
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x1010");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1006", false]),
+		res => json!({"number": "0x1006", "hash": "0x0000000000000000000000000000000000000000000000000000000000001006"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -375,6 +524,10 @@ test_transport_stream! {
 			poll_interval: Duration::from_secs(0),
 			request_timeout: Duration::from_secs(5),
 			confirmations: 10,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Depth,
 		};
 
 		log_stream(transport, Default::default(), init).take(3)
@@ -382,6 +535,7 @@ test_transport_stream! {
 	expected => vec![LogStreamItem {
 		from: 0xb,
 		to: 0x1006,
+		hash: 0x1006u64.into(),
 		logs: vec![Log {
 			address: "0000000000000000000000000000000000000001".into(),
 			topics: vec![],
@@ -398,10 +552,12 @@ test_transport_stream! {
 	}, LogStreamItem {
 		from: 0x1007,
 		to: 0x1007,
+		hash: 0x1007u64.into(),
 		logs: vec![],
 	}, LogStreamItem {
 		from: 0x1008,
 		to: 0x1008,
+		hash: 0x1008u64.into(),
 		logs: vec![Log {
 			address: "0000000000000000000000000000000000000002".into(),
 			topics: vec![],
@@ -431,6 +587,9 @@ test_transport_stream! {
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x1010");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1006", false]),
+		res => json!({"number": "0x1006", "hash": "0x0000000000000000000000000000000000000000000000000000000000001006"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -448,6 +607,12 @@ test_transport_stream! {
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x1011");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1006", false]),
+		res => json!({"number": "0x1006", "hash": "0x0000000000000000000000000000000000000000000000000000000000001006"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1007", false]),
+		res => json!({"number": "0x1007", "hash": "0x0000000000000000000000000000000000000000000000000000000000001007"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -460,6 +625,12 @@ test_transport_stream! {
 	"eth_blockNumber" =>
 		req => json!([]),
 		res => json!("0x1012");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1007", false]),
+		res => json!({"number": "0x1007", "hash": "0x0000000000000000000000000000000000000000000000000000000000001007"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1008", false]),
+		res => json!({"number": "0x1008", "hash": "0x0000000000000000000000000000000000000000000000000000000000001008"});
 	"eth_getLogs" =>
 		req => json!([{
 			"address": null,
@@ -483,3 +654,767 @@ test_transport_stream! {
 			}
 		]);
 }
+
+test_transport_stream! {
+	name => log_stream_max_block_range,
+	init => |transport| {
+		let init = LogStreamInit {
+			after: 10,
+			filter: FilterBuilder::default(),
+			poll_interval: Duration::from_secs(0),
+			request_timeout: Duration::from_secs(5),
+			confirmations: 0,
+			max_block_range: Some(4),
+			after_hash: None,
+			heads: None,
+			finality: Finality::Depth,
+		};
+
+		log_stream(transport, Default::default(), init).take(3)
+	},
+	expected => vec![LogStreamItem {
+		from: 0xb,
+		to: 0xe,
+		hash: 0xeu64.into(),
+		logs: vec![],
+	}, LogStreamItem {
+		from: 0xf,
+		to: 0x12,
+		hash: 0x12u64.into(),
+		logs: vec![],
+	}, LogStreamItem {
+		from: 0x13,
+		to: 0x13,
+		hash: 0x13u64.into(),
+		logs: vec![],
+	}],
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0x13");
+	"eth_getBlockByNumber" =>
+		req => json!(["0xe", false]),
+		res => json!({"number": "0xe", "hash": "0x000000000000000000000000000000000000000000000000000000000000000e"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0xe",
+			"topics": null
+		}]),
+		res => json!([]);
+	"eth_getBlockByNumber" =>
+		req => json!(["0x12", false]),
+		res => json!({"number": "0x12", "hash": "0x0000000000000000000000000000000000000000000000000000000000000012"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xf",
+			"limit": null,
+			"toBlock": "0x12",
+			"topics": null
+		}]),
+		res => json!([]);
+	"eth_getBlockByNumber" =>
+		req => json!(["0x13", false]),
+		res => json!({"number": "0x13", "hash": "0x0000000000000000000000000000000000000000000000000000000000000013"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0x13",
+			"limit": null,
+			"toBlock": "0x13",
+			"topics": null
+		}]),
+		res => json!([]);
+}
+
+test_transport_stream! {
+	name => log_stream_split_range_on_too_many_results,
+	init => |transport| {
+		let init = LogStreamInit {
+			after: 10,
+			filter: FilterBuilder::default(),
+			poll_interval: Duration::from_secs(0),
+			request_timeout: Duration::from_secs(5),
+			confirmations: 0,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Depth,
+		};
+
+		log_stream(transport, Default::default(), init).take(2)
+	},
+	expected => vec![LogStreamItem {
+		from: 0xb,
+		to: 0x12,
+		hash: 0x12u64.into(),
+		logs: vec![],
+	}, LogStreamItem {
+		from: 0x13,
+		to: 0x1a,
+		hash: 0x1au64.into(),
+		logs: vec![],
+	}],
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0x1a");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1a", false]),
+		res => json!({"number": "0x1a", "hash": "0x000000000000000000000000000000000000000000000000000000000000001a"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0x1a",
+			"topics": null
+		}]),
+		res => json!({"error": {"code": -32005, "message": "query returned more than 10000 results"}});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x12", false]),
+		res => json!({"number": "0x12", "hash": "0x0000000000000000000000000000000000000000000000000000000000000012"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0x12",
+			"topics": null
+		}]),
+		res => json!([]);
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1a", false]),
+		res => json!({"number": "0x1a", "hash": "0x000000000000000000000000000000000000000000000000000000000000001a"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0x13",
+			"limit": null,
+			"toBlock": "0x1a",
+			"topics": null
+		}]),
+		res => json!([]);
+}
+
+test_transport_stream! {
+	name => log_stream_reorg_rewinds_to_previous_checkpoint,
+	init => |transport| {
+		let init = LogStreamInit {
+			after: 10,
+			filter: FilterBuilder::default(),
+			poll_interval: Duration::from_secs(0),
+			request_timeout: Duration::from_secs(5),
+			confirmations: 0,
+			max_block_range: None,
+			after_hash: Some(0xau64.into()),
+			heads: None,
+			finality: Finality::Depth,
+		};
+
+		log_stream(transport, Default::default(), init).take(2)
+	},
+	expected => vec![LogStreamItem {
+		from: 0xb,
+		to: 0xc,
+		hash: 0xcu64.into(),
+		logs: vec![],
+	}, LogStreamItem {
+		from: 0xb,
+		to: 0xd,
+		hash: 0xdu64.into(),
+		logs: vec![],
+	}],
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0xc");
+	"eth_getBlockByNumber" =>
+		req => json!(["0xa", false]),
+		res => json!({"number": "0xa", "hash": "0x000000000000000000000000000000000000000000000000000000000000000a"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0xc", false]),
+		res => json!({"number": "0xc", "hash": "0x000000000000000000000000000000000000000000000000000000000000000c"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0xc",
+			"topics": null
+		}]),
+		res => json!([]);
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0xd");
+	"eth_getBlockByNumber" =>
+		req => json!(["0xc", false]),
+		res => json!({"number": "0xc", "hash": "0x00000000000000000000000000000000000000000000000000000000000000ff"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0xa", false]),
+		res => json!({"number": "0xa", "hash": "0x000000000000000000000000000000000000000000000000000000000000000a"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0xd", false]),
+		res => json!({"number": "0xd", "hash": "0x000000000000000000000000000000000000000000000000000000000000000d"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0xd",
+			"topics": null
+		}]),
+		res => json!([]);
+}
+
+test_transport_stream! {
+	name => log_stream_does_not_rewind_when_checkpoint_block_is_not_found,
+	init => |transport| {
+		let init = LogStreamInit {
+			after: 10,
+			filter: FilterBuilder::default(),
+			poll_interval: Duration::from_secs(0),
+			request_timeout: Duration::from_secs(5),
+			confirmations: 0,
+			max_block_range: None,
+			after_hash: Some(0xau64.into()),
+			heads: None,
+			finality: Finality::Depth,
+		};
+
+		log_stream(transport, Default::default(), init).take(1)
+	},
+	expected => vec![LogStreamItem {
+		from: 0xb,
+		to: 0xc,
+		hash: 0xcu64.into(),
+		logs: vec![],
+	}],
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0xc");
+	// a node lagging behind doesn't know the block yet, it's verified again on the next poll
+	"eth_getBlockByNumber" =>
+		req => json!(["0xa", false]),
+		res => json!(null);
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0xc");
+	"eth_getBlockByNumber" =>
+		req => json!(["0xa", false]),
+		res => json!({"number": "0xa", "hash": "0x000000000000000000000000000000000000000000000000000000000000000a"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0xc", false]),
+		res => json!({"number": "0xc", "hash": "0x000000000000000000000000000000000000000000000000000000000000000c"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0xc",
+			"topics": null
+		}]),
+		res => json!([]);
+}
+
+test_transport_stream! {
+	name => log_stream_reorg_of_unknown_depth_and_removed_logs,
+	init => |transport| {
+		let init = LogStreamInit {
+			after: 30,
+			filter: FilterBuilder::default(),
+			poll_interval: Duration::from_secs(0),
+			request_timeout: Duration::from_secs(5),
+			confirmations: 0,
+			max_block_range: None,
+			after_hash: Some(0x1eu64.into()),
+			heads: None,
+			finality: Finality::Depth,
+		};
+
+		log_stream(transport, Default::default(), init).take(1)
+	},
+	expected => vec![LogStreamItem {
+		from: 0x15,
+		to: 0x20,
+		hash: 0x20u64.into(),
+		logs: vec![Log {
+			address: "0000000000000000000000000000000000000001".into(),
+			topics: vec![],
+			data: vec![0x10].into(),
+			block_hash: None,
+			block_number: None,
+			transaction_hash: None,
+			transaction_index: None,
+			log_index: None,
+			transaction_log_index: None,
+			log_type: None,
+			removed: Some(false),
+		}],
+	}],
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0x20");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x1e", false]),
+		res => json!({"number": "0x1e", "hash": "0x00000000000000000000000000000000000000000000000000000000000000ff"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x20", false]),
+		res => json!({"number": "0x20", "hash": "0x0000000000000000000000000000000000000000000000000000000000000020"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0x15",
+			"limit": null,
+			"toBlock": "0x20",
+			"topics": null
+		}]),
+		res => json!([{
+			"address": "0x0000000000000000000000000000000000000001",
+			"topics": [],
+			"data": "0x10",
+			"type": "",
+			"removed": false
+		}, {
+			"address": "0x0000000000000000000000000000000000000002",
+			"topics": [],
+			"data": "0x20",
+			"type": "",
+			"removed": true
+		}]);
+}
+
+test_transport_stream! {
+	name => log_stream_new_heads_subscription,
+	init => |transport| {
+		let init = LogStreamInit {
+			after: 10,
+			filter: FilterBuilder::default(),
+			poll_interval: Duration::from_secs(0),
+			request_timeout: Duration::from_secs(5),
+			confirmations: 0,
+			max_block_range: None,
+			after_hash: None,
+			heads: Some(Arc::new(MockedHeads(vec![0x11, 0x13]))),
+			finality: Finality::Depth,
+		};
+
+		log_stream(transport, Default::default(), init).take(2)
+	},
+	expected => vec![LogStreamItem {
+		from: 0xb,
+		to: 0x11,
+		hash: 0x11u64.into(),
+		logs: vec![],
+	}, LogStreamItem {
+		from: 0x12,
+		to: 0x13,
+		hash: 0x13u64.into(),
+		logs: vec![],
+	}],
+	"eth_getBlockByNumber" =>
+		req => json!(["0x11", false]),
+		res => json!({"number": "0x11", "hash": "0x0000000000000000000000000000000000000000000000000000000000000011"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0x11",
+			"topics": null
+		}]),
+		res => json!([]);
+	"eth_getBlockByNumber" =>
+		req => json!(["0x11", false]),
+		res => json!({"number": "0x11", "hash": "0x0000000000000000000000000000000000000000000000000000000000000011"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x13", false]),
+		res => json!({"number": "0x13", "hash": "0x0000000000000000000000000000000000000000000000000000000000000013"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0x12",
+			"limit": null,
+			"toBlock": "0x13",
+			"topics": null
+		}]),
+		res => json!([]);
+}
+
+test_transport_stream! {
+	name => log_stream_new_heads_subscription_closed,
+	init => |transport| {
+		let init = LogStreamInit {
+			after: 10,
+			filter: FilterBuilder::default(),
+			poll_interval: Duration::from_secs(0),
+			request_timeout: Duration::from_secs(5),
+			confirmations: 0,
+			max_block_range: None,
+			after_hash: None,
+			heads: Some(Arc::new(ClosedHeads)),
+			finality: Finality::Depth,
+		};
+
+		log_stream(transport, Default::default(), init).take(2)
+	},
+	expected => vec![LogStreamItem {
+		from: 0xb,
+		to: 0x11,
+		hash: 0x11u64.into(),
+		logs: vec![],
+	}, LogStreamItem {
+		from: 0x12,
+		to: 0x12,
+		hash: 0x12u64.into(),
+		logs: vec![],
+	}],
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0x11");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x11", false]),
+		res => json!({"number": "0x11", "hash": "0x0000000000000000000000000000000000000000000000000000000000000011"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0x11",
+			"topics": null
+		}]),
+		res => json!([]);
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0x12");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x11", false]),
+		res => json!({"number": "0x11", "hash": "0x0000000000000000000000000000000000000000000000000000000000000011"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x12", false]),
+		res => json!({"number": "0x12", "hash": "0x0000000000000000000000000000000000000000000000000000000000000012"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0x12",
+			"limit": null,
+			"toBlock": "0x12",
+			"topics": null
+		}]),
+		res => json!([]);
+}
+
+/// Transport expecting a single `eth_getLogs` call for blocks `0xb`-`0x11`.
+fn quorum_endpoint(logs: serde_json::Value) -> MockedTransport {
+	MockedTransport {
+		requests: Default::default(),
+		expected_requests: vec![("eth_getLogs", json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0x11",
+			"topics": null
+		}])).into()],
+		mocked_responses: vec![logs],
+	}
+}
+
+/// Main transport of a quorum log stream, returning `logs` for blocks `0xb`-`0x11`.
+fn quorum_main_endpoint(logs: serde_json::Value) -> MockedTransport {
+	MockedTransport {
+		requests: Default::default(),
+		expected_requests: vec![
+			("eth_blockNumber", json!([])).into(),
+			("eth_getBlockByNumber", json!(["0x11", false])).into(),
+			("eth_getLogs", json!([{
+				"address": null,
+				"fromBlock": "0xb",
+				"limit": null,
+				"toBlock": "0x11",
+				"topics": null
+			}])).into(),
+		],
+		mocked_responses: vec![
+			json!("0x11"),
+			json!({"number": "0x11", "hash": "0x0000000000000000000000000000000000000000000000000000000000000011"}),
+			logs,
+		],
+	}
+}
+
+fn quorum_log(transaction: u64, data: &str) -> serde_json::Value {
+	json!({
+		"address": "0x0000000000000000000000000000000000000001",
+		"topics": [],
+		"data": data,
+		"transactionHash": format!("0x{:064x}", transaction),
+		"logIndex": "0x0",
+		"type": ""
+	})
+}
+
+fn quorum_init() -> LogStreamInit {
+	LogStreamInit {
+		after: 10,
+		filter: FilterBuilder::default(),
+		poll_interval: Duration::from_secs(0),
+		request_timeout: Duration::from_secs(5),
+		confirmations: 0,
+		max_block_range: None,
+		after_hash: None,
+		heads: None,
+		finality: Finality::Depth,
+	}
+}
+
+#[test]
+fn log_stream_quorum_tolerates_missing_log() {
+	let main = quorum_main_endpoint(json!([quorum_log(1, "0x10"), quorum_log(2, "0x20")]));
+	let first = quorum_endpoint(json!([quorum_log(1, "0x10"), quorum_log(2, "0x20")]));
+	let second = quorum_endpoint(json!([quorum_log(1, "0x10")]));
+	let quorum = LogQuorum {
+		endpoints: vec![&first, &second],
+		required: 2,
+		max_attempts: 1,
+	};
+
+	let items = quorum_log_stream(&main, quorum, Default::default(), quorum_init()).take(1).collect().wait().unwrap();
+	assert_eq!(items.len(), 1);
+	let hashes: Vec<_> = items[0].logs.iter().map(|log| log.transaction_hash).collect();
+	assert_eq!(hashes, vec![Some(H256::from(1u64)), Some(H256::from(2u64))]);
+}
+
+#[test]
+fn log_stream_quorum_not_reached() {
+	let main = quorum_main_endpoint(json!([quorum_log(1, "0x10"), quorum_log(2, "0x20")]));
+	let first = quorum_endpoint(json!([quorum_log(1, "0x10"), quorum_log(2, "0x21")]));
+	let second = quorum_endpoint(json!([quorum_log(1, "0x10")]));
+	let quorum = LogQuorum {
+		endpoints: vec![&first, &second],
+		required: 2,
+		max_attempts: 1,
+	};
+
+	let result = quorum_log_stream(&main, quorum, Default::default(), quorum_init()).take(1).collect().wait();
+	match result {
+		Err(ref err) => match *err.kind() {
+			ErrorKind::LogQuorumNotReached(0xb, 0x11) => (),
+			ref kind => panic!("unexpected error {:?}", kind),
+		},
+		Ok(items) => panic!("unexpected items {:?}", items),
+	}
+}
+
+#[test]
+fn log_stream_quorum_retries_lagging_endpoint() {
+	let get_logs = ("eth_getLogs", json!([{
+		"address": null,
+		"fromBlock": "0xb",
+		"limit": null,
+		"toBlock": "0x11",
+		"topics": null
+	}]));
+	let block = json!({"number": "0x11", "hash": "0x0000000000000000000000000000000000000000000000000000000000000011"});
+	let logs = json!([quorum_log(1, "0x10"), quorum_log(2, "0x20")]);
+	let main = MockedTransport {
+		requests: Default::default(),
+		expected_requests: vec![
+			("eth_blockNumber", json!([])).into(),
+			("eth_getBlockByNumber", json!(["0x11", false])).into(),
+			get_logs.clone().into(),
+			("eth_blockNumber", json!([])).into(),
+			("eth_getBlockByNumber", json!(["0x11", false])).into(),
+			get_logs.clone().into(),
+		],
+		mocked_responses: vec![json!("0x11"), block.clone(), logs.clone(), json!("0x11"), block, logs.clone()],
+	};
+	// the endpoint hasn't seen the second log yet when it's asked for the first time
+	let lagging = MockedTransport {
+		requests: Default::default(),
+		expected_requests: vec![get_logs.clone().into(), get_logs.into()],
+		mocked_responses: vec![json!([quorum_log(1, "0x10")]), logs],
+	};
+	let quorum = LogQuorum {
+		endpoints: vec![&lagging],
+		required: 2,
+		max_attempts: 2,
+	};
+
+	let items = quorum_log_stream(&main, quorum, Default::default(), quorum_init()).take(1).collect().wait().unwrap();
+	assert_eq!(items.len(), 1);
+	assert_eq!(items[0].logs.len(), 2);
+	assert_eq!(main.requests.get(), 6);
+	assert_eq!(lagging.requests.get(), 2);
+}
+
+test_transport_stream! {
+	name => log_stream_finalized_block,
+	init => |transport| {
+		let init = LogStreamInit {
+			after: 10,
+			filter: FilterBuilder::default(),
+			poll_interval: Duration::from_secs(0),
+			request_timeout: Duration::from_secs(5),
+			confirmations: 10,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Finalized,
+		};
+
+		log_stream(transport, Default::default(), init).take(1)
+	},
+	expected => vec![LogStreamItem {
+		from: 0xb,
+		to: 0x11,
+		hash: 0x11u64.into(),
+		logs: vec![],
+	}],
+	"eth_getBlockByNumber" =>
+		req => json!(["finalized", false]),
+		res => json!({"number": "0x11", "hash": "0x0000000000000000000000000000000000000000000000000000000000000011"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x11", false]),
+		res => json!({"number": "0x11", "hash": "0x0000000000000000000000000000000000000000000000000000000000000011"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0x11",
+			"topics": null
+		}]),
+		res => json!([]);
+}
+
+test_transport_stream! {
+	name => log_stream_finality_tag_not_supported,
+	init => |transport| {
+		let init = LogStreamInit {
+			after: 10,
+			filter: FilterBuilder::default(),
+			poll_interval: Duration::from_secs(0),
+			request_timeout: Duration::from_secs(5),
+			confirmations: 10,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Safe,
+		};
+
+		log_stream(transport, Default::default(), init).take(2)
+	},
+	expected => vec![LogStreamItem {
+		from: 0xb,
+		to: 0x12,
+		hash: 0x12u64.into(),
+		logs: vec![],
+	}, LogStreamItem {
+		from: 0x13,
+		to: 0x13,
+		hash: 0x13u64.into(),
+		logs: vec![],
+	}],
+	"eth_getBlockByNumber" =>
+		req => json!(["safe", false]),
+		res => json!({"error": {"code": -32602, "message": "Invalid params: unknown block tag"}});
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0x1c");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x12", false]),
+		res => json!({"number": "0x12", "hash": "0x0000000000000000000000000000000000000000000000000000000000000012"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0x12",
+			"topics": null
+		}]),
+		res => json!([]);
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0x1d");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x12", false]),
+		res => json!({"number": "0x12", "hash": "0x0000000000000000000000000000000000000000000000000000000000000012"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x13", false]),
+		res => json!({"number": "0x13", "hash": "0x0000000000000000000000000000000000000000000000000000000000000013"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0x13",
+			"limit": null,
+			"toBlock": "0x13",
+			"topics": null
+		}]),
+		res => json!([]);
+}
+
+test_transport_stream! {
+	name => log_stream_finality_tag_transient_error,
+	init => |transport| {
+		let init = LogStreamInit {
+			after: 10,
+			filter: FilterBuilder::default(),
+			poll_interval: Duration::from_secs(0),
+			request_timeout: Duration::from_secs(5),
+			confirmations: 10,
+			max_block_range: None,
+			after_hash: None,
+			heads: None,
+			finality: Finality::Safe,
+		};
+
+		log_stream(transport, Default::default(), init).take(2)
+	},
+	expected => vec![LogStreamItem {
+		from: 0xb,
+		to: 0x12,
+		hash: 0x12u64.into(),
+		logs: vec![],
+	}, LogStreamItem {
+		from: 0x13,
+		to: 0x13,
+		hash: 0x13u64.into(),
+		logs: vec![],
+	}],
+	"eth_getBlockByNumber" =>
+		req => json!(["safe", false]),
+		res => json!({"error": {"code": -32000, "message": "request failed"}});
+	"eth_blockNumber" =>
+		req => json!([]),
+		res => json!("0x1c");
+	"eth_getBlockByNumber" =>
+		req => json!(["0x12", false]),
+		res => json!({"number": "0x12", "hash": "0x0000000000000000000000000000000000000000000000000000000000000012"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0xb",
+			"limit": null,
+			"toBlock": "0x12",
+			"topics": null
+		}]),
+		res => json!([]);
+	"eth_getBlockByNumber" =>
+		req => json!(["safe", false]),
+		res => json!({"number": "0x13", "hash": "0x0000000000000000000000000000000000000000000000000000000000000013"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x12", false]),
+		res => json!({"number": "0x12", "hash": "0x0000000000000000000000000000000000000000000000000000000000000012"});
+	"eth_getBlockByNumber" =>
+		req => json!(["0x13", false]),
+		res => json!({"number": "0x13", "hash": "0x0000000000000000000000000000000000000000000000000000000000000013"});
+	"eth_getLogs" =>
+		req => json!([{
+			"address": null,
+			"fromBlock": "0x13",
+			"limit": null,
+			"toBlock": "0x13",
+			"topics": null
+		}]),
+		res => json!([]);
+}
@@ -0,0 +1,129 @@
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
+extern crate web3;
+extern crate bridge;
+extern crate tests;
+extern crate ethcore_transaction;
+extern crate keccak_hash;
+
+use std::sync::Arc;
+use futures::Future;
+use web3::types::U256;
+use ethcore_transaction::{Action, Transaction};
+use keccak_hash::keccak;
+use bridge::api::send_transaction_with_nonce;
+use bridge::bridge::nonce::SendRawTransaction;
+use tests::{MockedTransport, mocked_app, mocked_node, mocked_raw_transaction};
+
+fn transaction() -> Transaction {
+	Transaction {
+		nonce: U256::zero(),
+		gas_price: 10.into(),
+		gas: 100_000.into(),
+		action: Action::Call(0xbbu64.into()),
+		value: U256::zero(),
+		data: vec![],
+	}
+}
+
+fn mocked_transport(requests: Vec<(&'static str, serde_json::Value)>, responses: Vec<serde_json::Value>) -> MockedTransport {
+	MockedTransport {
+		requests: Default::default(),
+		expected_requests: requests.into_iter().map(Into::into).collect(),
+		mocked_responses: responses,
+	}
+}
+
+#[test]
+fn transaction_is_sent_again_when_its_nonce_is_used() {
+	let raw = mocked_raw_transaction(7.into(), 10.into());
+	let transport = mocked_transport(vec![
+		("eth_sendRawTransaction", json!([])),
+		// someone else sent transactions with nonces 5 and 6
+		("eth_getTransactionCount", json!([])),
+		("eth_sendRawTransaction", json!([])),
+	], vec![
+		json!({"error": {"code": -32000, "message": "nonce too low"}}),
+		json!("0x7"),
+		json!(keccak(&raw.0)),
+	]);
+
+	let node = mocked_node(0xaau64.into());
+	node.info.nonces.reset(5.into());
+	let app = Arc::new(mocked_app(&transport, &transport, node.clone(), node.clone()));
+
+	let sent = send_transaction_with_nonce(&transport, app, node.clone(), transaction(), 1, SendRawTransaction { transport: &transport, dry_run: false }).wait().unwrap();
+	assert_eq!(U256::from(7), sent.transaction.nonce);
+	assert_eq!(raw, sent.raw);
+	assert_eq!(keccak(&raw.0), sent.result);
+	assert_eq!(transport.expected_requests.len(), transport.requests.get());
+	assert!(node.info.nonces.take_gaps().is_empty());
+	assert_eq!(U256::from(8), node.info.nonces.next());
+}
+
+#[test]
+fn nonce_is_released_only_when_transaction_is_rejected() {
+	let transport = mocked_transport(vec![
+		("eth_sendRawTransaction", json!([])),
+		("eth_sendRawTransaction", json!([])),
+	], vec![
+		json!({"error": {"code": -32000, "message": "insufficient funds for gas * price + value"}}),
+		json!({"transport_error": "connection reset"}),
+	]);
+
+	let node = mocked_node(0xaau64.into());
+	node.info.nonces.reset(5.into());
+	let app = Arc::new(mocked_app(&transport, &transport, node.clone(), node.clone()));
+	let send = || send_transaction_with_nonce(&transport, app.clone(), node.clone(), transaction(), 1, SendRawTransaction { transport: &transport, dry_run: false });
+
+	// rejected by the node, the nonce is handed out again
+	assert!(send().wait().is_err());
+
+	// the node might have received it, the transaction is tracked and its nonce stays in flight
+	let sent = send().wait().unwrap();
+	let raw = mocked_raw_transaction(5.into(), 10.into());
+	assert_eq!((U256::from(5), raw.clone(), keccak(&raw.0)), (sent.transaction.nonce, sent.raw, sent.result));
+	assert!(node.info.nonces.take_gaps().is_empty());
+	assert_eq!(U256::from(6), node.info.nonces.next());
+}
+
+#[test]
+fn transaction_is_sent_with_another_nonce_when_its_nonce_is_pending() {
+	let raw = mocked_raw_transaction(6.into(), 10.into());
+	let transport = mocked_transport(vec![
+		("eth_sendRawTransaction", json!([])),
+		("eth_getTransactionCount", json!([])),
+		("eth_sendRawTransaction", json!([])),
+	], vec![
+		json!({"error": {"code": -32000, "message": "replacement transaction underpriced"}}),
+		json!("0x6"),
+		json!(keccak(&raw.0)),
+	]);
+
+	let node = mocked_node(0xaau64.into());
+	node.info.nonces.reset(5.into());
+	let app = Arc::new(mocked_app(&transport, &transport, node.clone(), node.clone()));
+
+	let sent = send_transaction_with_nonce(&transport, app, node.clone(), transaction(), 1, SendRawTransaction { transport: &transport, dry_run: false }).wait().unwrap();
+	assert_eq!((U256::from(6), raw), (sent.transaction.nonce, sent.raw));
+	assert_eq!(transport.expected_requests.len(), transport.requests.get());
+	// nonce 5 is neither handed out again nor counted as used by someone else until it's mined
+	assert!(node.info.nonces.take_gaps().is_empty());
+	assert_eq!(U256::from(7), node.info.nonces.next());
+	assert!(node.info.nonces.confirm(6.into()).is_zero());
+}
+
+#[test]
+fn transaction_is_only_logged_in_a_dry_run() {
+	let raw = mocked_raw_transaction(5.into(), 10.into());
+	let transport = mocked_transport(vec![], vec![]);
+
+	let node = mocked_node(0xaau64.into());
+	node.info.nonces.reset(5.into());
+	let app = Arc::new(mocked_app(&transport, &transport, node.clone(), node.clone()));
+
+	let sent = send_transaction_with_nonce(&transport, app, node.clone(), transaction(), 1, SendRawTransaction { transport: &transport, dry_run: true }).wait().unwrap();
+	assert_eq!((U256::from(5), raw.clone(), keccak(&raw.0)), (sent.transaction.nonce, sent.raw, sent.result));
+	assert_eq!(0, transport.requests.get());
+}
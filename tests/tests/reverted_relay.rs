@@ -0,0 +1,188 @@
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
+extern crate web3;
+extern crate bridge;
+extern crate tests;
+extern crate ethcore_transaction;
+extern crate keccak_hash;
+
+use std::sync::{Arc, RwLock};
+use futures::{Future, Stream};
+use web3::types::{Bytes, H256, U256};
+use ethcore_transaction::{Action, Transaction};
+use keccak_hash::keccak;
+use bridge::bridge::{BridgeChecked, PendingTransactions, Retries, create_deposit_relay, create_transaction_tracker};
+use bridge::bridge::nonce::SentTransaction;
+use bridge::database::{Database, EventId};
+use bridge::error::{Error, ErrorKind};
+use bridge::ledger::{TransferKind, TransferStatus};
+use bridge::rpc_failure::RpcFailure;
+use tests::{MockedTransport, mocked_app, mocked_node, mocked_raw_transaction};
+
+const DEPOSIT_TOPIC: &str = "0xe1fffcc4923d04b559f4d29a8bfc6cda04eb5b0d3c460751c2402c5c5cc9109c";
+const DEPOSIT_TX: &str = "0x884edad9ce6fa2440d8a54cc123490eb96d2768479d49ff9c7366125a9424364";
+
+fn mocked_transport(requests: Vec<(&'static str, serde_json::Value)>, responses: Vec<serde_json::Value>) -> MockedTransport {
+	MockedTransport {
+		requests: Default::default(),
+		expected_requests: requests.into_iter().map(Into::into).collect(),
+		mocked_responses: responses,
+	}
+}
+
+#[test]
+fn reverted_deposit_relay_is_fetched_again_and_resent() {
+	let relay = keccak(b"relay");
+	let resent = mocked_raw_transaction(1.into(), 10.into());
+	let event = EventId {
+		block_number: 0x10,
+		transaction_hash: DEPOSIT_TX.parse::<H256>().unwrap(),
+		log_index: 0.into(),
+	};
+
+	let home = mocked_transport(vec![
+		("eth_getLogs", json!([{
+			"address": ["0x0000000000000000000000000000000000000000"],
+			"fromBlock": "0x10",
+			"limit": null,
+			"toBlock": "0x10",
+			"topics": [[DEPOSIT_TOPIC], null, null, null]
+		}])),
+	], vec![
+		json!([{
+			"address": "0x0000000000000000000000000000000000000000",
+			"topics": [DEPOSIT_TOPIC],
+			"data": "0x000000000000000000000000aff3454fce5edbc8cca8697c15331677e6ebcccc00000000000000000000000000000000000000000000000000000000000000f0",
+			"blockNumber": "0x10",
+			"transactionHash": DEPOSIT_TX,
+			"logIndex": "0x0"
+		}]),
+	]);
+	let foreign = mocked_transport(vec![
+		("eth_blockNumber", json!([])),
+		("eth_getTransactionCount", json!([])),
+		("eth_getTransactionReceipt", json!([relay])),
+		// the deposit fetched again is simulated and relayed with the next nonce
+		("eth_call", json!([])),
+		("eth_sendRawTransaction", json!([])),
+	], vec![
+		json!("0x20"),
+		json!("0x1"),
+		json!({"blockNumber": "0x1f", "status": "0x0"}),
+		json!("0x"),
+		json!(keccak(&resent.0)),
+	]);
+
+	let node = mocked_node(0xaau64.into());
+	node.info.nonces.reset(1.into());
+	let app = Arc::new(mocked_app(&home, &foreign, node.clone(), node.clone()));
+
+	let pending = PendingTransactions::default();
+	pending.add(&SentTransaction {
+		transaction: Transaction {
+			nonce: 0.into(),
+			gas_price: 10.into(),
+			gas: 100_000.into(),
+			action: Action::Call(0u64.into()),
+			value: U256::zero(),
+			data: vec![],
+		},
+		raw: Bytes(b"relay".to_vec()),
+		priority_fee: None,
+		result: (),
+	}).unwrap();
+	let tracker = create_transaction_tracker(app.clone(), &foreign, node, 1, pending, Arc::new(RwLock::new(10)));
+	assert_eq!(vec![BridgeChecked::TransactionReverted(relay)], tracker.take(1).collect().wait().unwrap());
+
+	// the bridge hands the event of the reverted transaction back to the deposit relay
+	let retries = Retries::default();
+	retries.deposit_relay.push(event);
+	let deposit_relay = create_deposit_relay(app.clone(), &Database::default(), Arc::new(RwLock::new(Some(U256::max_value()))), 1,
+		Arc::new(RwLock::new(10)), Arc::new(RwLock::new(100_000)), PendingTransactions::default(), retries.deposit_relay);
+	let items = deposit_relay.take(2).collect().wait().unwrap();
+
+	assert_eq!(2, items.len());
+	match (&items[0], &items[1]) {
+		(&BridgeChecked::Transfer(ref pending), &BridgeChecked::Transfer(ref sent)) => {
+			assert_eq!((TransferKind::DepositRelay, event, TransferStatus::Pending), (pending.kind, pending.event, pending.status));
+			assert_eq!((event, TransferStatus::Sent, Some(keccak(&resent.0)), Some(1.into())), (sent.event, sent.status, sent.tx_hash, sent.nonce));
+		},
+		items => panic!("expected pending and sent transfers, got {:?}", items),
+	}
+	assert_eq!(home.expected_requests.len(), home.requests.get());
+	assert_eq!(foreign.expected_requests.len(), foreign.requests.get());
+}
+
+#[test]
+fn deposit_relay_rejected_for_insufficient_funds_stops_the_relay_instead_of_failing_the_deposit() {
+	let event = EventId {
+		block_number: 0x10,
+		transaction_hash: DEPOSIT_TX.parse::<H256>().unwrap(),
+		log_index: 0.into(),
+	};
+
+	let home = mocked_transport(vec![
+		("eth_getLogs", json!([{
+			"address": ["0x0000000000000000000000000000000000000000"],
+			"fromBlock": "0x10",
+			"limit": null,
+			"toBlock": "0x10",
+			"topics": [[DEPOSIT_TOPIC], null, null, null]
+		}])),
+	], vec![
+		json!([{
+			"address": "0x0000000000000000000000000000000000000000",
+			"topics": [DEPOSIT_TOPIC],
+			"data": "0x000000000000000000000000aff3454fce5edbc8cca8697c15331677e6ebcccc00000000000000000000000000000000000000000000000000000000000000f0",
+			"blockNumber": "0x10",
+			"transactionHash": DEPOSIT_TX,
+			"logIndex": "0x0"
+		}]),
+	]);
+	let foreign = mocked_transport(vec![
+		("eth_call", json!([])),
+		("eth_sendRawTransaction", json!([])),
+	], vec![
+		json!("0x"),
+		json!({"error": {"code": -32000, "message": "insufficient funds for gas * price + value"}}),
+	]);
+
+	let node = mocked_node(0xaau64.into());
+	node.info.nonces.reset(0.into());
+	let app = Arc::new(mocked_app(&home, &foreign, node.clone(), node));
+
+	let retries = Retries::default();
+	retries.deposit_relay.push(event);
+	let deposit_relay = create_deposit_relay(app, &Database::default(), Arc::new(RwLock::new(Some(U256::max_value()))), 1,
+		Arc::new(RwLock::new(10)), Arc::new(RwLock::new(100_000)), PendingTransactions::default(), retries.deposit_relay);
+	let mut items = Vec::new();
+	let result = deposit_relay.for_each(|item| {
+		items.push(item);
+		Ok(())
+	}).wait();
+
+	// the transfer is only recorded as pending, the error reaches the bridge which terminates
+	assert_eq!(1, items.len());
+	match items[0] {
+		BridgeChecked::Transfer(ref pending) => assert_eq!((event, TransferStatus::Pending), (pending.event, pending.status)),
+		ref item => panic!("expected a pending transfer, got {:?}", item),
+	}
+	let mut err = match result {
+		Err(err) => err,
+		Ok(()) => panic!("expected the relay to fail"),
+	};
+	let err = loop {
+		err = match err {
+			Error(ErrorKind::ContextualizedError(inner, _), _) => *inner,
+			err => break err,
+		};
+	};
+	match err {
+		Error(ErrorKind::Web3(web3::error::Error(web3::error::ErrorKind::Rpc(ref rpc_err), _)), _) =>
+			assert_eq!(RpcFailure::InsufficientFunds, RpcFailure::classify(rpc_err)),
+		err => panic!("expected an rpc error, got {}", err),
+	}
+	assert_eq!(home.expected_requests.len(), home.requests.get());
+	assert_eq!(foreign.expected_requests.len(), foreign.requests.get());
+}
@@ -0,0 +1,132 @@
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
+extern crate web3;
+extern crate bridge;
+extern crate tests;
+extern crate keccak_hash;
+
+use std::sync::{Arc, RwLock};
+use futures::{Future, Stream};
+use web3::types::U256;
+use keccak_hash::keccak;
+use bridge::bridge::{BridgeChecked, PendingTransactions, Retries, create_deposit_relay};
+use bridge::database::{Database, EventId};
+use bridge::ledger::{TransferKind, TransferStatus};
+use tests::{MockedTransport, mocked_app, mocked_node, mocked_raw_transaction};
+
+const DEPOSIT_TOPIC: &str = "0xe1fffcc4923d04b559f4d29a8bfc6cda04eb5b0d3c460751c2402c5c5cc9109c";
+/// Deposit of 0xf0 tokens.
+const DEPOSIT_DATA: &str = "0x000000000000000000000000aff3454fce5edbc8cca8697c15331677e6ebcccc00000000000000000000000000000000000000000000000000000000000000f0";
+const UNSET: &str = "0x0000000000000000000000000000000000000000000000000000000000000000";
+const SET: &str = "0x0000000000000000000000000000000000000000000000000000000000000001";
+
+fn mocked_transport(requests: Vec<(&'static str, serde_json::Value)>, responses: Vec<serde_json::Value>) -> MockedTransport {
+	MockedTransport {
+		requests: Default::default(),
+		expected_requests: requests.into_iter().map(Into::into).collect(),
+		mocked_responses: responses,
+	}
+}
+
+fn deposit_logs_request(block: &str) -> (&'static str, serde_json::Value) {
+	("eth_getLogs", json!([{
+		"address": ["0x0000000000000000000000000000000000000000"],
+		"fromBlock": block,
+		"limit": null,
+		"toBlock": block,
+		"topics": [[DEPOSIT_TOPIC], null, null, null]
+	}]))
+}
+
+fn deposit_log(event: &EventId) -> serde_json::Value {
+	json!([{
+		"address": "0x0000000000000000000000000000000000000000",
+		"topics": [DEPOSIT_TOPIC],
+		"data": DEPOSIT_DATA,
+		"blockNumber": format!("0x{:x}", event.block_number),
+		"transactionHash": event.transaction_hash,
+		"logIndex": "0x0"
+	}])
+}
+
+fn reverted() -> serde_json::Value {
+	json!({"error": {"code": -32015, "message": "VM execution error.", "data": "Reverted 0x"}})
+}
+
+#[test]
+fn deposit_relay_sends_deposits_whose_simulation_succeeds_and_tells_reverts_apart_by_contract_state() {
+	let event = |block_number: u64, hash: &[u8]| EventId {
+		block_number,
+		transaction_hash: keccak(hash),
+		log_index: 0.into(),
+	};
+	let succeeds = event(0x10, b"succeeds");
+	let handled = event(0x11, b"handled");
+	let out_of_tokens = event(0x12, b"out of tokens");
+	let not_simulated = event(0x13, b"not simulated");
+	let raw = mocked_raw_transaction(0.into(), 10.into());
+
+	let home = mocked_transport(vec![
+		deposit_logs_request("0x10"),
+		deposit_logs_request("0x11"),
+		deposit_logs_request("0x12"),
+		deposit_logs_request("0x13"),
+	], vec![
+		deposit_log(&succeeds),
+		deposit_log(&handled),
+		deposit_log(&out_of_tokens),
+		deposit_log(&not_simulated),
+	]);
+	let foreign = mocked_transport(vec![
+		("eth_call", json!([])),
+		("eth_call", json!([])),
+		("eth_call", json!([])),
+		("eth_call", json!([])),
+		// both reverts without a reason are told apart by `deposits_signed`
+		("eth_getStorageAt", json!([])),
+		("eth_getStorageAt", json!([])),
+		// then by `authorities`, `erc20token` and the token balance of the bridge
+		("eth_getStorageAt", json!([])),
+		("eth_getStorageAt", json!([])),
+		("eth_call", json!([])),
+		("eth_sendRawTransaction", json!([])),
+	], vec![
+		json!("0x"),
+		reverted(),
+		reverted(),
+		json!({"error": {"code": -32000, "message": "header not found"}}),
+		json!(SET),
+		json!(UNSET),
+		json!(SET),
+		json!("0x00000000000000000000000000000000000000000000000000000000000000bb"),
+		json!("0x0000000000000000000000000000000000000000000000000000000000000010"),
+		json!(keccak(&raw.0)),
+	]);
+
+	let node = mocked_node(0xaau64.into());
+	node.info.nonces.reset(0.into());
+	let app = mocked_app(&home, &foreign, node.clone(), node);
+
+	let retries = Retries::default();
+	for event in &[succeeds, handled, out_of_tokens, not_simulated] {
+		retries.deposit_relay.push(*event);
+	}
+	let deposit_relay = create_deposit_relay(Arc::new(app), &Database::default(), Arc::new(RwLock::new(Some(U256::max_value()))), 1,
+		Arc::new(RwLock::new(10)), Arc::new(RwLock::new(100_000)), PendingTransactions::default(), retries.deposit_relay);
+	let items = deposit_relay.take(5).collect().wait().unwrap();
+
+	assert_eq!(BridgeChecked::Skipped(TransferKind::DepositRelay, handled), items[0]);
+	assert_eq!(BridgeChecked::DeadLetter(TransferKind::DepositRelay, out_of_tokens, "simulation: bridge out of tokens".into()), items[1]);
+	match (&items[2], &items[3], &items[4]) {
+		(&BridgeChecked::Failed(kind, event, ref reason), &BridgeChecked::Transfer(ref pending), &BridgeChecked::Transfer(ref sent)) => {
+			assert_eq!((TransferKind::DepositRelay, not_simulated), (kind, event));
+			assert!(reason.starts_with("simulation failed"), "unexpected reason {}", reason);
+			assert_eq!((succeeds, TransferStatus::Pending), (pending.event, pending.status));
+			assert_eq!((succeeds, TransferStatus::Sent, Some(keccak(&raw.0))), (sent.event, sent.status, sent.tx_hash));
+		},
+		items => panic!("expected a failed deposit, then pending and sent transfers, got {:?}", items),
+	}
+	assert_eq!(home.expected_requests.len(), home.requests.get());
+	assert_eq!(foreign.expected_requests.len(), foreign.requests.get());
+}
@@ -0,0 +1,149 @@
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
+extern crate web3;
+extern crate bridge;
+extern crate tests;
+extern crate ethcore_transaction;
+extern crate keccak_hash;
+
+use std::sync::{Arc, RwLock};
+use futures::{Future, Stream};
+use web3::types::{Bytes, U256};
+use ethcore_transaction::{Action, Transaction};
+use keccak_hash::keccak;
+use bridge::bridge::{BridgeChecked, PendingTransactions, create_transaction_tracker};
+use bridge::bridge::nonce::SentTransaction;
+use tests::{MockedTransport, mocked_app, mocked_node, mocked_raw_transaction};
+
+fn sent(nonce: u64, raw: &str) -> SentTransaction<()> {
+	SentTransaction {
+		transaction: Transaction {
+			nonce: nonce.into(),
+			gas_price: 10.into(),
+			gas: 100_000.into(),
+			action: Action::Call(0xbbu64.into()),
+			value: U256::zero(),
+			data: vec![],
+		},
+		raw: Bytes(raw.as_bytes().to_vec()),
+		priority_fee: None,
+		result: (),
+	}
+}
+
+#[test]
+fn transaction_tracker_rebroadcasts_replaces_and_reports_mined() {
+	let mined = keccak(b"mined");
+	let stuck = keccak(b"stuck");
+	let replacement = keccak(&mocked_raw_transaction(1.into(), 12.into()).0);
+
+	let transport = MockedTransport {
+		requests: Default::default(),
+		expected_requests: vec![
+			("eth_blockNumber", json!([])),
+			("eth_getTransactionCount", json!([])),
+			("eth_getTransactionReceipt", json!([mined])),
+			("eth_getTransactionReceipt", json!([stuck])),
+			// neither is mined, both are broadcast again
+			("eth_sendRawTransaction", json!([])),
+			("eth_sendRawTransaction", json!([])),
+			("eth_blockNumber", json!([])),
+			("eth_getTransactionCount", json!([])),
+			("eth_getTransactionReceipt", json!([mined])),
+			("eth_getTransactionReceipt", json!([stuck])),
+			// pending for 2 blocks, replaced with a higher gas price
+			("eth_sendRawTransaction", json!([])),
+		].into_iter().map(Into::into).collect(),
+		mocked_responses: vec![
+			json!("0x10"),
+			json!("0x0"),
+			json!(null),
+			json!(null),
+			json!(mined),
+			json!(stuck),
+			json!("0x12"),
+			json!("0x1"),
+			json!({"blockNumber": "0x11", "status": "0x1"}),
+			json!(null),
+			json!(replacement),
+		],
+	};
+
+	let mut node = mocked_node(0xaau64.into());
+	node.gas_price_bump_blocks = 2;
+	node.info.nonces.reset(0.into());
+	let app = Arc::new(mocked_app(&transport, &transport, node.clone(), node.clone()));
+
+	let pending = PendingTransactions::default();
+	pending.add(&sent(0, "mined")).unwrap();
+	pending.add(&sent(1, "stuck")).unwrap();
+	let tracker = create_transaction_tracker(app, &transport, node, 1, pending.clone(), Arc::new(RwLock::new(10)));
+
+	let items = tracker.take(2).collect().wait().unwrap();
+	assert_eq!(vec![
+		BridgeChecked::TransactionMined(mined),
+		BridgeChecked::TransactionReplaced(stuck, replacement, 12.into()),
+	], items);
+	assert_eq!(transport.expected_requests.len(), transport.requests.get());
+	// the replacement is still tracked
+	assert!(!pending.is_empty());
+}
+
+#[test]
+fn transaction_tracker_reports_dropped_and_does_not_replace_transactions_at_fixed_gas_price() {
+	let dropped = keccak(b"dropped");
+	let fixed = keccak(b"fixed");
+
+	let transport = MockedTransport {
+		requests: Default::default(),
+		expected_requests: vec![
+			("eth_blockNumber", json!([])),
+			("eth_getTransactionCount", json!([])),
+			("eth_getTransactionReceipt", json!([dropped])),
+			("eth_getTransactionReceipt", json!([fixed])),
+			// nonce of the dropped transaction is used, only the other one is broadcast again
+			("eth_sendRawTransaction", json!([])),
+			("eth_blockNumber", json!([])),
+			("eth_getTransactionCount", json!([])),
+			("eth_getTransactionReceipt", json!([fixed])),
+			// pending for 3 blocks, but broadcast again instead of being replaced
+			("eth_sendRawTransaction", json!([])),
+			("eth_blockNumber", json!([])),
+			("eth_getTransactionCount", json!([])),
+			("eth_getTransactionReceipt", json!([fixed])),
+		].into_iter().map(Into::into).collect(),
+		mocked_responses: vec![
+			json!("0x10"),
+			json!("0x1"),
+			json!(null),
+			json!(null),
+			json!(fixed),
+			json!("0x13"),
+			json!("0x1"),
+			json!(null),
+			json!(fixed),
+			json!("0x14"),
+			json!("0x2"),
+			json!({"blockNumber": "0x14", "status": "0x1"}),
+		],
+	};
+
+	let mut node = mocked_node(0xaau64.into());
+	node.gas_price_bump_blocks = 2;
+	node.info.nonces.reset(0.into());
+	let app = Arc::new(mocked_app(&transport, &transport, node.clone(), node.clone()));
+
+	let pending = PendingTransactions::default();
+	pending.add(&sent(0, "dropped")).unwrap();
+	pending.add_at_fixed_gas_price(&sent(1, "fixed")).unwrap();
+	let tracker = create_transaction_tracker(app, &transport, node, 1, pending.clone(), Arc::new(RwLock::new(10)));
+
+	let items = tracker.take(2).collect().wait().unwrap();
+	assert_eq!(vec![
+		BridgeChecked::TransactionDropped(dropped),
+		BridgeChecked::TransactionMined(fixed),
+	], items);
+	assert_eq!(transport.expected_requests.len(), transport.requests.get());
+	assert!(pending.is_empty());
+}
@@ -0,0 +1,139 @@
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
+extern crate web3;
+extern crate websocket;
+extern crate bridge;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use futures::{Future, Stream};
+use web3::transports::WebSocket;
+use web3::types::FilterBuilder;
+use websocket::OwnedMessage;
+use websocket::sync::{Server, Writer};
+use bridge::api::{LogStreamInit, log_stream, LogStreamItem, SubscribeNewHeads};
+use bridge::config::Finality;
+
+const HEAD_HASH: &str = "0x0000000000000000000000000000000000000000000000000000000000000011";
+
+fn send(sender: &Mutex<Writer<TcpStream>>, message: serde_json::Value) -> bool {
+	sender.lock().unwrap().send_message(&OwnedMessage::Text(message.to_string())).is_ok()
+}
+
+/// Announces block `0x11` to subscription `0x1` until `done` is set.
+fn announce_head(sender: Arc<Mutex<Writer<TcpStream>>>, done: Arc<AtomicBool>) {
+	let notification = json!({
+		"jsonrpc": "2.0",
+		"method": "eth_subscription",
+		"params": {
+			"subscription": "0x1",
+			"result": {
+				"hash": HEAD_HASH,
+				"parentHash": "0x0000000000000000000000000000000000000000000000000000000000000010",
+				"sha3Uncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+				"miner": "0x0000000000000000000000000000000000000000",
+				"author": "0x0000000000000000000000000000000000000000",
+				"stateRoot": "0x0000000000000000000000000000000000000000000000000000000000000000",
+				"transactionsRoot": "0x0000000000000000000000000000000000000000000000000000000000000000",
+				"receiptsRoot": "0x0000000000000000000000000000000000000000000000000000000000000000",
+				"number": "0x11",
+				"gasUsed": "0x0",
+				"gasLimit": "0x0",
+				"extraData": "0x",
+				"logsBloom": format!("0x{}", "0".repeat(512)),
+				"timestamp": "0x0",
+				"difficulty": "0x0",
+				"sealFields": []
+			}
+		}
+	});
+
+	thread::spawn(move || {
+		// the notification is repeated, because it's dropped by the client
+		// if it arrives before the subscription is registered
+		while !done.load(Ordering::SeqCst) {
+			thread::sleep(Duration::from_millis(50));
+			if !send(&sender, notification.clone()) {
+				break;
+			}
+		}
+	});
+}
+
+/// Starts a WebSocket server answering a single client like a node with best block `0x11`.
+fn mock_node() -> String {
+	let mut server = Server::bind("127.0.0.1:0").unwrap();
+	let url = format!("ws://{}", server.local_addr().unwrap());
+
+	thread::spawn(move || {
+		let upgrade = server.accept().unwrap_or_else(|_| panic!("websocket handshake failed"));
+		let client = upgrade.accept().map_err(|(_, err)| err).unwrap();
+		let (mut receiver, sender) = client.split().unwrap();
+		let sender = Arc::new(Mutex::new(sender));
+		let done = Arc::new(AtomicBool::new(false));
+
+		for message in receiver.incoming_messages() {
+			let request: serde_json::Value = match message {
+				Ok(OwnedMessage::Text(text)) => serde_json::from_str(&text).unwrap(),
+				Ok(OwnedMessage::Close(_)) | Err(_) => break,
+				Ok(_) => continue,
+			};
+
+			let result = match request["method"].as_str().unwrap() {
+				"eth_subscribe" => {
+					assert_eq!(request["params"], json!(["newHeads"]));
+					announce_head(sender.clone(), done.clone());
+					json!("0x1")
+				},
+				"eth_getBlockByNumber" => {
+					assert_eq!(request["params"], json!(["0x11", false]));
+					json!({"number": "0x11", "hash": HEAD_HASH})
+				},
+				"eth_getLogs" => {
+					done.store(true, Ordering::SeqCst);
+					json!([])
+				},
+				"eth_unsubscribe" => json!(true),
+				method => panic!("unexpected request {}", method),
+			};
+
+			if !send(&sender, json!({"jsonrpc": "2.0", "id": request["id"], "result": result})) {
+				break;
+			}
+		}
+
+		done.store(true, Ordering::SeqCst);
+	});
+
+	url
+}
+
+#[test]
+fn log_stream_follows_websocket_new_heads() {
+	let url = mock_node();
+	let (_event_loop, ws) = WebSocket::new(&url).unwrap();
+	let init = LogStreamInit {
+		after: 10,
+		filter: FilterBuilder::default(),
+		// the stream is driven by the subscription only, the timer never fires during the test
+		poll_interval: Duration::from_secs(60),
+		request_timeout: Duration::from_secs(5),
+		confirmations: 0,
+		max_block_range: None,
+		after_hash: None,
+		heads: Some(Arc::new(SubscribeNewHeads(ws.clone()))),
+		finality: Finality::Depth,
+	};
+
+	let items = log_stream(ws, Default::default(), init).take(1).collect().wait().unwrap();
+	assert_eq!(items, vec![LogStreamItem {
+		from: 0xb,
+		to: 0x11,
+		hash: 0x11u64.into(),
+		logs: vec![],
+	}]);
+}
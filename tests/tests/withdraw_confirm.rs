@@ -37,7 +37,7 @@ test_app_stream! {
 		],
 		signatures => 1;
 	txs => Transactions::default(),
-	init => |app, db| create_withdraw_confirm(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(2),
+	init => |app, db| create_withdraw_confirm(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1)), Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(2),
 	expected => vec![0x1005, 0x1006],
 	home_transport => [],
 	foreign_transport => [
@@ -87,7 +87,7 @@ test_app_stream! {
 		],
 		signatures => 1;
 	txs => Transactions::default(),
-	init => |app, db| create_withdraw_confirm(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(2),
+	init => |app, db| create_withdraw_confirm(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1)), Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(2),
 	expected => vec![0x1005, 0x1006],
 	home_transport => [],
 	foreign_transport => [
@@ -141,7 +141,7 @@ test_app_stream! {
 		],
 		signatures => 1;
 	txs => Transactions::default(),
-	init => |app, db| create_withdraw_confirm(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(2),
+	init => |app, db| create_withdraw_confirm(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1)), Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(2),
 	expected => vec![0x1005, 0x1006],
 	home_transport => [],
 	foreign_transport => [
@@ -197,13 +197,13 @@ test_app_stream! {
 		signatures => 1;
 	txs => Transactions {
 		withdraw_confirm: TransactionConfig {
-			gas: 0xfe,
+			gas: Some(0xfe),
 			gas_price: 0xa1,
 			concurrency: 100,
 		},
 		..Default::default()
 	},
-	init => |app, db| create_withdraw_confirm(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(2),
+	init => |app, db| create_withdraw_confirm(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1)), Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(2),
 	expected => vec![0x1005, 0x1006],
 	home_transport => [],
 	foreign_transport => [
@@ -303,13 +303,13 @@ test_app_stream! {
 		signatures => 1;
 	txs => Transactions {
 		withdraw_confirm: TransactionConfig {
-			gas: 0xff,
+			gas: Some(0xff),
 			gas_price: 0xaa,
 			concurrency: 100,
 		},
 		..Default::default()
 	},
-	init => |app, db| create_withdraw_confirm(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(2),
+	init => |app, db| create_withdraw_confirm(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1)), Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(2),
 	expected => vec![0x2, 0x1006],
 	home_transport => [],
 	foreign_transport => [
@@ -42,7 +42,7 @@ test_app_stream! {
 		],
 		signatures => 1;
 	txs => Transactions::default(),
-	init => |app, db| create_withdraw_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(2),
+	init => |app, db| create_withdraw_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(2),
 	expected => vec![0x1005, 0x1006],
 	home_transport => [],
 	foreign_transport => [
@@ -93,7 +93,7 @@ test_app_stream! {
 		],
 		signatures => 1;
 	txs => Transactions::default(),
-	init => |app, db| create_withdraw_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(1),
+	init => |app, db| create_withdraw_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(1),
 	expected => vec![0x1005],
 	home_transport => [],
 	foreign_transport => [
@@ -141,7 +141,7 @@ test_app_stream! {
 		],
 		signatures => 2;
 	txs => Transactions::default(),
-	init => |app, db| create_withdraw_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(1))).take(1),
+	init => |app, db| create_withdraw_relay(app, db, Arc::new(RwLock::new(Some(99999999999u64.into()))), 17, Arc::new(RwLock::new(100_000)), Default::default(), Default::default()).take(1),
 	expected => vec![0x1005],
 	home_transport => [
 		// `HomeBridge.withdraw`
@@ -162,7 +162,7 @@ test_app_stream! {
 						}.to_bytes()
 					).to_hex()),
 				"from": "0x0000000000000000000000000000000000000001",
-				"gas": "0x0",
+				"gas": "0x186a0",
 				"gasPrice": "0x3e8",
 				"to": "0x00000000000000000000000000000000000000dd"
 			}]),